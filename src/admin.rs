@@ -0,0 +1,187 @@
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::Utc;
+
+use crate::capsules::CAPSULES;
+use crate::contributors::{Contributor, CONTRIBUTORS};
+use crate::database;
+use crate::indexes::{Indexes, INDEXES};
+use crate::items::ITEMS;
+use crate::locks;
+use crate::field_history;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::residency;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReassignRequest {
+    pub capsule_ids: Vec<CapsuleId>,
+    pub contributor_id: ContributorId,  // New owner of all listed capsules
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Reassignment {
+    pub capsule_id: CapsuleId,
+    pub from_contributor_id: ContributorId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReassignResult {
+    pub contributor_id: ContributorId,
+    pub reassigned: Vec<Reassignment>,
+    pub unchanged: Vec<CapsuleId>,            // Capsules the target already owned
+    pub contributors: Vec<Contributor>,  // Every contributor whose capsule list changed
+}
+
+// Moves capsules to another contributor. Every capsule is checked before anything is
+// changed, so the request either reassigns all of them or none.
+#[post("/admin/capsules/reassign", format = "json", data = "<reassign_request>")]
+pub fn reassign_capsules(reassign_request: Json<ReassignRequest>) -> Result<Json<ReassignResult>, status::Custom<Json<String>>> {
+    let target = reassign_request.contributor_id;
+    let mut capsule_ids = reassign_request.capsule_ids.clone();
+    capsule_ids.sort_unstable();
+    capsule_ids.dedup();
+    if capsule_ids.is_empty() {
+        return Err(status::Custom(Status::BadRequest, Json("capsule_ids must list at least one capsule".into())));
+    }
+
+    // The current owners have to be locked too, but they are only known after reading
+    // the capsules. Retry if one of them changed hands before the locks were taken.
+    let (owners, _contributor_guards, _capsule_guards) = loop {
+        let owners: Vec<Option<ContributorId>> = capsule_ids.iter().map(|&id| CAPSULES.read(id, |c| c.contributor_id)).collect();
+        let mut contributor_ids: Vec<ContributorId> = owners.iter().flatten().copied().collect();
+        contributor_ids.push(target);
+        let contributor_guards = locks::lock_contributors(&contributor_ids);
+        let capsule_guards = locks::lock_capsules(&capsule_ids);
+
+        let locked_owners: Vec<Option<ContributorId>> = capsule_ids.iter().map(|&id| CAPSULES.read(id, |c| c.contributor_id)).collect();
+        if locked_owners == owners {
+            break (owners, contributor_guards, capsule_guards);
+        }
+    };
+
+    if !CONTRIBUTORS.contains(target) {
+        return Err(status::Custom(Status::NotFound, Json(format!("No contributor found with ID {}", target))));
+    }
+    let missing: Vec<String> = capsule_ids.iter().zip(&owners)
+        .filter(|(_, owner)| owner.is_none())
+        .map(|(id, _)| id.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(status::Custom(Status::NotFound, Json(format!("No capsules found with IDs {}", missing.join(", ")))));
+    }
+    for (&capsule_id, owner) in capsule_ids.iter().zip(&owners) {
+        residency::check_owner_change(capsule_id, owner.unwrap(), target)?;
+    }
+
+    let time_now = Utc::now();
+    let mut reassigned = Vec::new();
+    let mut unchanged = Vec::new();
+    for (&capsule_id, owner) in capsule_ids.iter().zip(owners) {
+        let from = owner.unwrap();
+        if from == target {
+            unchanged.push(capsule_id);
+            continue;
+        }
+
+        database::atomically(|| {
+            CAPSULES.update(capsule_id, |capsule| {
+                capsule.contributor_id = target;
+                capsule.co_owner_ids.retain(|&id| id != target);
+                capsule.time_changed = Some(time_now);
+                capsule.version += 1;
+                field_history::record(capsule_id, capsule.version, vec!["contributor_id"]);
+            });
+            {
+                let mut indexes = INDEXES.write().unwrap();
+                indexes.unlink_capsule(from, capsule_id);
+                indexes.link_capsule(target, capsule_id);
+            }
+            // Both capsule lists changed with the index
+            CONTRIBUTORS.touch(from);
+            CONTRIBUTORS.touch(target);
+        });
+        reassigned.push(Reassignment { capsule_id, from_contributor_id: from });
+    }
+
+    let mut changed: Vec<ContributorId> = reassigned.iter().map(|r| r.from_contributor_id).collect();
+    if !reassigned.is_empty() {
+        changed.push(target);
+    }
+    changed.sort_unstable();
+    changed.dedup();
+    let contributors = changed.into_iter().filter_map(|id| CONTRIBUTORS.get(id)).collect();
+
+    Ok(Json(ReassignResult { contributor_id: target, reassigned, unchanged, contributors }))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RebuildResult {
+    pub dry_run: bool,
+    pub issues: Vec<String>,                      // Drift found between the records and what's derived from them
+    pub capsules_repaired: Vec<CapsuleId>,        // Capsules whose item_ids change with the rebuilt indexes
+    pub contributors_repaired: Vec<ContributorId>,  // Contributors whose capsule_ids change with them
+    pub metadata_items: usize,                    // Items in the rebuilt metadata index
+}
+
+fn id_list<I: std::fmt::Display>(ids: &[I]) -> String {
+    format!("[{}]", ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))
+}
+
+// Regenerates everything derived from the owning side of the relations (capsule.contributor_id
+// and item.id_capsule): the reverse indexes, and with them the item_ids of capsules and the
+// capsule_ids of contributors, the metadata index and the id sequences. A recovery tool for
+// when the indexes drift; with `?dry_run=true` it only reports the drift.
+#[post("/admin/rebuild?<dry_run>")]
+pub fn rebuild_derived_data(dry_run: Option<bool>) -> Json<RebuildResult> {
+    let dry_run = dry_run.unwrap_or(false);
+    // Every contributor and capsule is locked, so nothing changes while the indexes are swapped
+    let contributor_ids = CONTRIBUTORS.ids();
+    let capsule_ids = CAPSULES.ids();
+    let _contributor_guards = locks::lock_contributors(&contributor_ids);
+    let _capsule_guards = locks::lock_capsules(&capsule_ids);
+
+    let indexes = Indexes::rebuild(&CAPSULES, &ITEMS);
+    let mut issues = Vec::new();
+    let mut capsules_repaired = Vec::new();
+    let mut contributors_repaired = Vec::new();
+    {
+        let current = INDEXES.read().unwrap();
+        if !current.same_links(&indexes) {
+            issues.push("The reverse indexes differ from the records".to_string());
+        }
+        for capsule_id in capsule_ids {
+            let (listed, owned) = (current.items_of(capsule_id), indexes.items_of(capsule_id));
+            if listed != owned {
+                issues.push(format!("Capsule {} lists items {} but owns items {}", capsule_id, id_list(&listed), id_list(&owned)));
+                capsules_repaired.push(capsule_id);
+            }
+        }
+        for contributor_id in contributor_ids {
+            let (listed, owned) = (current.capsules_of(contributor_id), indexes.capsules_of(contributor_id));
+            if listed != owned {
+                issues.push(format!("Contributor {} lists capsules {} but owns capsules {}", contributor_id, id_list(&listed), id_list(&owned)));
+                contributors_repaired.push(contributor_id);
+            }
+        }
+    }
+
+    let metadata_items = if dry_run {
+        0
+    } else {
+        *INDEXES.write().unwrap() = indexes;
+        // The lists of the repaired rows changed with the indexes
+        capsules_repaired.iter().for_each(|&id| CAPSULES.touch(id));
+        contributors_repaired.iter().for_each(|&id| CONTRIBUTORS.touch(id));
+        CONTRIBUTORS.reserve_stored_ids();
+        CAPSULES.reserve_stored_ids();
+        ITEMS.reserve_stored_ids();
+        ITEMS.rebuild_metadata()
+    };
+
+    Json(RebuildResult { dry_run, issues, capsules_repaired, contributors_repaired, metadata_items })
+}
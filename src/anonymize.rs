@@ -0,0 +1,114 @@
+// Replaces personal data with realistic fake values for demo environments.
+//
+// Contributor names and emails, item descriptions and the recipients of scheduled
+// deliveries are rewritten; ids, relations, timestamps and everything else stay as
+// they are. The fake values are derived from the record id, so anonymizing twice
+// gives the same result.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::capsules::CAPSULES;
+use crate::config;
+use crate::contributors::{CONTRIBUTORS, EMAIL_CHECK};
+use crate::ids::EntityId;
+use crate::items::ITEMS;
+use crate::locks;
+
+const FIRST_NAMES: &[&str] = &[
+    "Alice", "Bruno", "Carla", "Daniel", "Elena", "Felix", "Greta", "Hugo", "Irene", "Jonas",
+    "Klara", "Leon", "Marta", "Nico", "Olga", "Pavel", "Rosa", "Simon", "Tereza", "Viktor",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Adler", "Berger", "Costa", "Dumont", "Eriksen", "Fischer", "Garcia", "Hansen", "Ivanova", "Jansen",
+    "Kowalski", "Lindqvist", "Moreau", "Novak", "Olsen", "Petrova", "Rossi", "Schmidt", "Torres", "Weber",
+];
+
+const PLACES: &[&str] = &["the lake", "grandma's house", "the mountains", "the old town", "the beach", "the garden", "the city park"];
+
+const OCCASIONS: &[&str] = &["a birthday party", "New Year's Eve", "a summer holiday", "graduation day", "a family dinner", "a weekend trip"];
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AnonymizeResult {
+    pub contributors: usize,
+    pub items: usize,
+}
+
+fn fake_description(rng: &mut StdRng, type_c: &str) -> String {
+    let place = PLACES.choose(rng).unwrap();
+    let occasion = OCCASIONS.choose(rng).unwrap();
+    match type_c {
+        "photo" => format!("Photo from {} at {}", occasion, place),
+        "video" => format!("Video of {} at {}", occasion, place),
+        "audio" => format!("Voice message recorded at {}", place),
+        "letter" => format!("Letter written after {}", occasion),
+        _ => format!("Keepsake from {}", occasion),
+    }
+}
+
+pub fn anonymize_all() -> AnonymizeResult {
+    let mut result = AnonymizeResult { contributors: 0, items: 0 };
+
+    // Emails stay unique because they include the contributor id
+    {
+        let _email_guard = EMAIL_CHECK.lock().unwrap();
+        for id in CONTRIBUTORS.ids() {
+            let _guard = locks::lock_contributor(id);
+            let mut rng = StdRng::seed_from_u64(id.number() as u64);
+            let first = FIRST_NAMES.choose(&mut rng).unwrap();
+            let last = LAST_NAMES.choose(&mut rng).unwrap();
+            let updated = CONTRIBUTORS.update(id, |contributor| {
+                contributor.name = format!("{} {}", first, last);
+                contributor.email = format!("{}.{}{}@example.com", first.to_lowercase(), last.to_lowercase(), id);
+            });
+            result.contributors += updated.is_some() as usize;
+        }
+    }
+
+    for id in ITEMS.ids() {
+        let Some(capsule_id) = ITEMS.get(id).map(|item| item.id_capsule) else { continue };
+        let _guard = locks::lock_capsule(capsule_id);
+        // Items use a separate seed range from contributors
+        let mut rng = StdRng::seed_from_u64((1 << 32) | id.number() as u64);
+        let updated = ITEMS.update(id, |item| {
+            item.description = fake_description(&mut rng, &item.type_c);
+        });
+        result.items += updated.is_some() as usize;
+    }
+
+    // Delivery recipients of scheduled capsules are personal data as well, and signature
+    // messages are free text written by contributors
+    for id in CAPSULES.ids() {
+        let _guard = locks::lock_capsule(id);
+        CAPSULES.update(id, |capsule| {
+            for (n, recipient) in capsule.delivery.iter_mut().flat_map(|d| d.recipients.iter_mut()).enumerate() {
+                recipient.email = format!("recipient{}.capsule{}@example.com", n + 1, id);
+                if recipient.name.is_some() {
+                    recipient.name = Some(format!("Recipient {}", n + 1));
+                }
+            }
+            for signature in capsule.signing.iter_mut().flat_map(|signing| signing.signatures.iter_mut()) {
+                signature.message = format!("Signed by contributor {} on capsule {}", signature.contributor_id, id);
+            }
+        });
+    }
+
+    result
+}
+
+// Refused while changes are written back, the fake values would replace the real data
+#[post("/admin/anonymize")]
+pub fn anonymize_data() -> Result<Json<AnonymizeResult>, status::Custom<Json<String>>> {
+    if config::get().persistence.enabled {
+        return Err(status::Custom(Status::Conflict, Json("Data can't be anonymized while persistence is enabled".into())));
+    }
+    if config::get().storage.backend != config::StorageBackend::Memory {
+        return Err(status::Custom(Status::Conflict, Json("Data can't be anonymized while it's stored in a database".into())));
+    }
+    Ok(Json(anonymize_all()))
+}
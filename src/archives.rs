@@ -0,0 +1,239 @@
+// Long-term archives of opened capsules.
+//
+// An archive is a BagIt bag (RFC 8493) packed into a tar file: the capsule, its
+// contributor and items as JSON plus copies of the item files under `data/`, with
+// SHA-256 manifests so the content can be verified decades later without this app.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::fs::NamedFile;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use rocket::tokio::{fs, task};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use reqwest::{header, Method, RequestBuilder, Url};
+use std::path::Path;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::config::{self, TimeFormat};
+use crate::contributors::CONTRIBUTORS;
+use crate::destinations;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::reveals;
+use crate::ids::CapsuleId;
+use crate::residency;
+use crate::time_format;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum ArchiveTarget {
+    S3 { upload_url: String },  // Presigned PUT URL for the object
+    Ipfs,                       // Added and pinned through `archives.ipfs_api`
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveRequest {
+    pub target: Option<ArchiveTarget>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveExport {
+    pub capsule_id: CapsuleId,
+    pub file_name: String,
+    pub download_url: String,
+    pub size: u64,
+    pub sha256: String,              // Checksum of the whole tar file
+    pub payload_files: usize,
+    pub missing_files: Vec<String>,  // Item paths that could not be copied into the bag
+    #[serde(serialize_with = "time_format::time")]
+    pub time_created: DateTime<Utc>,
+    pub location: Option<String>,    // Where the archive was pushed, if a target was given
+}
+
+struct PayloadFile {
+    path: String,  // Relative to the bag root
+    content: Vec<u8>,
+}
+
+fn hex_sha256(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// Reads an item's file, downloading it when the path is a URL at a public address
+async fn item_file(item: &Item) -> Option<Vec<u8>> {
+    if let Some(message) = &item.message {
+        Some(message.text.clone().into_bytes())
+    } else if item.path.starts_with("http://") || item.path.starts_with("https://") {
+        let url = Url::parse(&item.path).ok()?;
+        let response = destinations::send(Method::GET, &url, None, |request| request).await.ok()?.error_for_status().ok()?;
+        response.bytes().await.ok().map(|bytes| bytes.to_vec())
+    } else {
+        fs::read(&item.path).await.ok()
+    }
+}
+
+// Safe file name for an item's copy, prefixed with the item id so names stay unique
+pub fn file_name(item: &Item) -> String {
+    if let Some(message) = &item.message {
+        return format!("{}-message.{}", item.id, message.format.extension());
+    }
+    let name = item.path.split(['?', '#']).next().unwrap_or_default()
+        .rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || ".-_".contains(c) { c } else { '_' }).collect();
+    format!("{}-{}", item.id, name.trim_matches('.'))
+}
+
+fn payload_name(item: &Item) -> String {
+    format!("data/files/{}", file_name(item))
+}
+
+// Writes the bag into a tar file and returns the tar's size and checksum
+fn write_bag(path: &Path, bag_name: &str, payload: &[PayloadFile], bag_info: &str) -> std::io::Result<(u64, String)> {
+    let manifest: String = payload.iter()
+        .map(|file| format!("{}  {}\n", hex_sha256(&file.content), file.path))
+        .collect();
+    let bagit = "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n";
+    let tag_files = [("bagit.txt", bagit), ("bag-info.txt", bag_info), ("manifest-sha256.txt", manifest.as_str())];
+    let tag_manifest: String = tag_files.iter()
+        .map(|(name, content)| format!("{}  {}\n", hex_sha256(content.as_bytes()), name))
+        .collect();
+
+    let mut builder = tar::Builder::new(std::fs::File::create(path)?);
+    let mut append = |name: &str, content: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, format!("{}/{}", bag_name, name), content)
+    };
+    for (name, content) in tag_files {
+        append(name, content.as_bytes())?;
+    }
+    append("tagmanifest-sha256.txt", tag_manifest.as_bytes())?;
+    for file in payload {
+        append(&file.path, &file.content)?;
+    }
+    builder.into_inner()?.sync_all()?;
+
+    let written = std::fs::read(path)?;
+    Ok((written.len() as u64, hex_sha256(&written)))
+}
+
+async fn push(client: &reqwest::Client, target: &ArchiveTarget, path: &Path, file_name: &str) -> Result<String, String> {
+    let content = fs::read(path).await.map_err(|e| e.to_string())?;
+    match target {
+        ArchiveTarget::S3 { upload_url } => {
+            // The URL comes with the request, so it has to be at a public address
+            let url = Url::parse(upload_url).map_err(|e| format!("{}: {}", upload_url, e))?;
+            let request = |request: RequestBuilder| request.header(header::CONTENT_TYPE, "application/x-tar").body(content.clone());
+            destinations::send(Method::PUT, &url, None, request).await?
+                .error_for_status().map_err(|e| e.to_string())?;
+            // The presigned query string is not part of the object's address
+            Ok(upload_url.split('?').next().unwrap_or_default().to_string())
+        },
+        ArchiveTarget::Ipfs => {
+            let api = config::get().archives.ipfs_api.as_deref()
+                .ok_or("archives.ipfs_api is not configured")?;
+            let part = reqwest::multipart::Part::bytes(content).file_name(file_name.to_string());
+            let added: serde_json::Value = client.post(format!("{}/api/v0/add?pin=true", api.trim_end_matches('/')))
+                .multipart(reqwest::multipart::Form::new().part("file", part))
+                .send().await.and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?
+                .json().await.map_err(|e| e.to_string())?;
+            added["Hash"].as_str()
+                .map(|hash| format!("ipfs://{}", hash))
+                .ok_or_else(|| "IPFS did not return a content hash".to_string())
+        }
+    }
+}
+
+// The body is optional, without a target the archive is only kept locally
+#[post("/capsules/<cid>/archive-export", data = "<archive_request>")]
+pub async fn export_archive(cid: CapsuleId, archive_request: Option<Json<ArchiveRequest>>, clock: &State<SharedClock>) -> Result<Json<ArchiveExport>, ApiError> {
+    let capsule = CAPSULES.get(cid)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    let now = clock.now();
+    if capsule.time_open > now {
+        return Err(ApiError::Other(Status::Conflict, format!("Capsule {} opens on {} and can only be archived after that", cid, capsule.time_open)));
+    }
+
+    let contributor = CONTRIBUTORS.get(capsule.contributor_id);
+    let item_ids = reveals::visible(cid, INDEXES.read().unwrap().items_of(cid));
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+
+    // The archive outlives the request, its times are RFC 3339 whatever the client asked for
+    let to_json = |value: serde_json::Value| serde_json::to_vec_pretty(&value).unwrap_or_default();
+    let mut payload = time_format::rendering(TimeFormat::Rfc3339, || vec![
+        PayloadFile { path: "data/capsule.json".into(), content: to_json(serde_json::json!(capsule)) },
+        PayloadFile { path: "data/contributor.json".into(), content: to_json(serde_json::json!(contributor)) },
+        PayloadFile { path: "data/items.json".into(), content: to_json(serde_json::json!(items)) },
+    ]);
+
+    let client = reqwest::Client::new();
+    let mut missing_files = Vec::new();
+    for item in &items {
+        match item_file(item).await {
+            Some(content) => payload.push(PayloadFile { path: payload_name(item), content }),
+            None => missing_files.push(item.path.clone()),
+        }
+    }
+
+    let payload_bytes: usize = payload.iter().map(|file| file.content.len()).sum();
+    let bag_name = format!("capsule-{}-{}", cid, now.format("%Y%m%dT%H%M%S%3fZ"));
+    let bag_info = format!(
+        "Bagging-Date: {}\nExternal-Identifier: capsule-{}\nExternal-Description: {}\nPayload-Oxum: {}.{}\nBag-Software-Agent: {} {}\n",
+        now.format("%Y-%m-%d"), cid, capsule.name.default_text().replace('\n', " "), payload_bytes, payload.len(),
+        env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),
+    );
+
+    let dir = residency::capsule_dir(&config::get().archives.dir, cid)
+        .map_err(|e| status::Custom(Status::InternalServerError, Json(format!("Failed to write archive: {}", e))))?;
+    let file_name = format!("{}.tar", bag_name);
+    let path = dir.join(&file_name);
+    let payload_files = payload.len();
+    let (size, sha256) = task::spawn_blocking({
+        let path = path.clone();
+        move || {
+            std::fs::create_dir_all(&dir)?;
+            write_bag(&path, &bag_name, &payload, &bag_info)
+        }
+    }).await
+        .map_err(|e| e.to_string())
+        .and_then(|written| written.map_err(|e| e.to_string()))
+        .map_err(|e| status::Custom(Status::InternalServerError, Json(format!("Failed to write archive: {}", e))))?;
+
+    let location = match archive_request.and_then(|request| request.into_inner().target) {
+        Some(target) => Some(push(&client, &target, &path, &file_name).await
+            .map_err(|e| status::Custom(Status::BadGateway, Json(format!("Archive {} was written but could not be pushed: {}", file_name, e))))?),
+        None => None,
+    };
+
+    Ok(Json(ArchiveExport {
+        capsule_id: cid,
+        download_url: format!("/archives/{}", file_name),
+        file_name,
+        size,
+        sha256,
+        payload_files,
+        missing_files,
+        time_created: now,
+        location,
+    }))
+}
+
+#[get("/archives/<file_name>")]
+pub async fn download_archive(file_name: &str) -> Option<NamedFile> {
+    if !file_name.ends_with(".tar") || file_name.starts_with('.') {
+        return None;
+    }
+    let path = residency::all_dirs(&config::get().archives.dir).into_iter()
+        .map(|dir| dir.join(file_name))
+        .find(|path| path.is_file())?;
+    NamedFile::open(path).await.ok()
+}
@@ -0,0 +1,196 @@
+// Record of changes the server makes on its own, e.g. scheduled jobs, of moderation and
+// of changes made to capsules through the API, so owners can see what happened to their
+// capsules, when and by whom. Server and moderation entries are filled from the event bus
+// (see bus.rs), API changes by a fairing once the request succeeded. The log is kept as
+// state (see state.rs), one entry per change.
+use rocket::serde::{Deserialize, Serialize};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::tokio::sync::broadcast::{error::RecvError, Receiver};
+use rocket::{Request, Response};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::RwLock;
+
+use crate::bus::{DomainEvent, Published};
+use crate::capsules::CAPSULES;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::ids::{CapsuleId, ContributorId};
+use crate::ownership;
+use crate::reports;
+use crate::state;
+use crate::store;
+use crate::time_format;
+use crate::tokens::Caller;
+
+// Routes under /capsules/<cid> that are answered without changing the capsule
+const NOT_CHANGES: [&str; 6] = ["validate_capsule", "validate_item", "simulate_open", "export_archive", "pin_capsule", "unpin_capsule"];
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditEntry {
+    #[serde(default, skip_serializing_if = "store::answering")]
+    pub id: u32,  // Order of the entries, stored only
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub actor: String,   // Who made the change: "scheduler", "moderator", "contributor" or "api" without a key
+    pub action: String,  // What happened, e.g. "capsule.published", or the route of an API change
+    pub capsule_id: Option<CapsuleId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contributor_id: Option<ContributorId>,  // Whose API key made the change
+    pub detail: String,
+}
+
+pub struct AuditLog {
+    pub entries: Vec<AuditEntry>,  // Oldest first
+    next_id: u32,
+}
+
+pub static AUDIT_LOG: Lazy<RwLock<AuditLog>> = Lazy::new(|| RwLock::new(AuditLog { entries: Vec::new(), next_id: 1 }));
+
+pub fn record(mut entry: AuditEntry) {
+    let mut log = AUDIT_LOG.write().unwrap();
+    let id = state::next_id(&mut log.next_id);
+    entry.id = id;
+    log.entries.push(entry);
+    state::changed("audit_log", id);
+}
+
+// Records the events of the scheduled jobs and moderators as they are published
+pub async fn follow(mut events: Receiver<Published>) {
+    loop {
+        let published = match events.recv().await {
+            Ok(published) => published,
+            Err(RecvError::Closed) => return,
+            Err(RecvError::Lagged(missed)) => {
+                eprintln!("Audit log fell behind the event bus, {} events are missing", missed);
+                continue;
+            },
+        };
+        let (actor, detail) = match &published.event {
+            DomainEvent::CapsulePublished { publish_at, .. } => ("scheduler", format!("Visibility changed from private to public, scheduled for {}", publish_at.to_rfc3339())),
+            DomainEvent::ItemRevealed { item_id, .. } => ("scheduler", format!("Item {} revealed", item_id)),
+            DomainEvent::ItemsExpired { item_ids, .. } => ("scheduler", format!("Items deleted by the retention policy: {}", item_ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))),
+            DomainEvent::CapsuleModerated { action, .. } => ("moderator", format!("Moderation action: {}", action)),
+            DomainEvent::CapsuleOpened { .. } => ("scheduler", "Capsule opened, owner and co-owners notified".to_string()),
+            _ => continue,
+        };
+        record(AuditEntry {
+            id: 0,
+            time: published.time,
+            actor: actor.into(),
+            action: published.event.name().into(),
+            capsule_id: Some(published.event.capsule_id()),
+            contributor_id: None,
+            detail,
+        });
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Created {
+    id: CapsuleId,
+}
+
+// The capsule a request changed: the one in its path, or the one a POST /capsules created
+async fn changed_capsule(request: &Request<'_>, response: &mut Response<'_>) -> Option<CapsuleId> {
+    match (request.routed_segment(0), request.routed_segment(1)) {
+        (Some("capsules"), Some(id)) => id.parse().ok(),
+        (Some("capsules"), None) if request.method() == Method::Post => {
+            let body = response.body_mut().to_bytes().await.ok()?;
+            let created = serde_json::from_slice::<Created>(&body).ok().map(|created| created.id);
+            response.set_sized_body(body.len(), Cursor::new(body));
+            created
+        },
+        _ => None,
+    }
+}
+
+// Fairing recording every successful change to a capsule made through the API
+pub struct ApiChanges;
+
+#[rocket::async_trait]
+impl Fairing for ApiChanges {
+    fn info(&self) -> Info {
+        Info { name: "Audit of API changes", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let changes = matches!(request.method(), Method::Post | Method::Put | Method::Patch | Method::Delete);
+        let Some(name) = request.route().and_then(|route| route.name.as_deref()) else { return };
+        let dry_run = request.query_value::<bool>("dry_run").and_then(Result::ok).unwrap_or(false);
+        if !changes || dry_run || NOT_CHANGES.contains(&name) || !response.status().class().is_success() {
+            return;
+        }
+        let Some(capsule_id) = changed_capsule(request, response).await else { return };
+        let contributor_id = request.guard::<Caller>().await.succeeded().and_then(|caller| caller.0);
+        record(AuditEntry {
+            id: 0,
+            time: Utc::now(),
+            actor: if contributor_id.is_some() { "contributor" } else { "api" }.into(),
+            action: name.to_string(),
+            capsule_id: Some(capsule_id),
+            contributor_id,
+            detail: format!("{} {}", request.method(), request.uri()),
+        });
+    }
+}
+
+#[get("/admin/audit?<pagination..>")]
+pub fn get_audit_log(pagination: Pagination) -> Paginated<AuditEntry> {
+    // Newest entries first
+    let log = AUDIT_LOG.read().unwrap();
+    Paginated::new(&pagination, Collection::Audit, log.entries.len(), |start, per_page| log.entries.iter().rev().skip(start).take(per_page).cloned().collect())
+}
+
+// Changes to the capsules a contributor owns or co-owns, newest first, so owners of shared
+// capsules can review what their co-owners did. Capsules they no longer edit aren't included.
+#[get("/contributors/<id>/changes?<since>&<pagination..>")]
+pub fn contributor_changes(id: ContributorId, since: Option<&str>, pagination: Pagination, caller: Caller) -> Result<Paginated<AuditEntry>, ApiError> {
+    let since = reports::parse_bound(since, "since")?;
+    if !CONTRIBUTORS.contains(id) {
+        return Err(ApiError::ContributorNotFound);
+    }
+    if caller.required()? != id {
+        return Err(ApiError::Other(Status::Forbidden, "Changes can only be read by their own contributor".into()));
+    }
+    let mut capsule_ids = HashSet::new();
+    CAPSULES.for_each(|capsule| {
+        if ownership::is_editor(capsule, id) {
+            capsule_ids.insert(capsule.id);
+        }
+    });
+
+    let changes: Vec<AuditEntry> = AUDIT_LOG.read().unwrap().entries.iter().rev()
+        .take_while(|entry| since.is_none_or(|since| entry.time >= since))
+        .filter(|entry| entry.capsule_id.is_some_and(|capsule_id| capsule_ids.contains(&capsule_id)))
+        .cloned()
+        .collect();
+    Ok(Paginated::new(&pagination, Collection::Audit, changes.len(), |start, per_page| changes.iter().skip(start).take(per_page).cloned().collect()))
+}
+
+// The log as state entries by id, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    AUDIT_LOG.read().unwrap().entries.iter().map(|entry| (entry.id.to_string(), state::to_entry(entry))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    AUDIT_LOG.read().unwrap().entries.iter().find(|entry| entry.id.to_string() == key).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let entry: Option<AuditEntry> = entry.map(state::from_entry).transpose()?;
+    let mut log = AUDIT_LOG.write().unwrap();
+    log.entries.retain(|old| old.id.to_string() != key);
+    if let Some(entry) = entry {
+        log.next_id = log.next_id.max(entry.id + 1);
+        let at = log.entries.partition_point(|old| old.id < entry.id);
+        log.entries.insert(at, entry);
+    }
+    Ok(())
+}
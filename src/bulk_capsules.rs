@@ -0,0 +1,134 @@
+// The same partial change applied to many capsules at once, e.g. retagging a whole year
+// of capsules. All of them are locked for the duration, so the batch doesn't interleave
+// with other changes, and every capsule gets its own result.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use std::collections::HashSet;
+
+use crate::capsules::{self, CAPSULES};
+use crate::clock::SharedClock;
+use crate::field_history;
+use crate::ids::CapsuleId;
+use crate::locks;
+use crate::publishing::{Publishing, Visibility};
+use crate::signatures;
+
+const MAX_CAPSULES: usize = 1000;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkChanges {
+    #[serde(default)]
+    tags_add: Vec<String>,
+    #[serde(default)]
+    tags_remove: Vec<String>,
+    visibility: Option<Visibility>,  // Public right away, or back to private
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkPatch {
+    ids: Vec<CapsuleId>,
+    changes: BulkChanges,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum PatchStatus {
+    Updated,
+    Unchanged,  // Already as requested
+    NotFound,
+    Conflict,   // The capsule can't be changed anymore
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PatchResult {
+    pub capsule_id: CapsuleId,
+    pub status: PatchStatus,
+    pub version: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkPatchResult {
+    pub updated: usize,
+    pub unchanged: usize,
+    pub not_found: usize,
+    pub conflict: usize,
+    pub results: Vec<PatchResult>,
+}
+
+#[patch("/capsules", format = "json", data = "<patch>")]
+pub fn patch_capsules(patch: Json<BulkPatch>, clock: &State<SharedClock>) -> Result<Json<BulkPatchResult>, status::Custom<Json<String>>> {
+    let BulkPatch { mut ids, changes } = patch.into_inner();
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    if ids.is_empty() {
+        return Err(status::Custom(Status::BadRequest, Json("ids can't be empty".into())));
+    }
+    if ids.len() > MAX_CAPSULES {
+        return Err(status::Custom(Status::BadRequest, Json(format!("At most {} capsules can be changed at once", MAX_CAPSULES))));
+    }
+    let tags_add = capsules::clean_tags(&changes.tags_add);
+    let tags_remove = capsules::clean_tags(&changes.tags_remove);
+    if tags_add.is_empty() && tags_remove.is_empty() && changes.visibility.is_none() {
+        return Err(status::Custom(Status::BadRequest, Json("No changes provided".into())));
+    }
+    if let Some(tag) = tags_add.iter().find(|tag| tags_remove.contains(tag)) {
+        return Err(status::Custom(Status::BadRequest, Json(format!("Tag '{}' is both added and removed", tag))));
+    }
+
+    let _guards = locks::lock_capsules(&ids);
+    let now = clock.now();
+
+    let results: Vec<PatchResult> = ids.into_iter().map(|capsule_id| {
+        let result = CAPSULES.try_update(capsule_id, |capsule| {
+            if signatures::is_sealed(capsule, now) {
+                return Err("The modification period for this capsule has expired".to_string());
+            }
+            let mut updated = Vec::new();
+            let tags = {
+                let mut tags = capsule.tags.clone();
+                tags.retain(|tag| !tags_remove.contains(tag));
+                tags.extend(tags_add.iter().filter(|tag| !capsule.tags.contains(tag)).cloned());
+                tags
+            };
+            if tags != capsule.tags {
+                capsule.tags = tags;
+                updated.push("tags");
+            }
+            if let Some(visibility) = changes.visibility.filter(|&visibility| visibility != capsule.publishing.visibility) {
+                capsule.publishing = match visibility {
+                    Visibility::Private => Publishing::default(),
+                    Visibility::Public => Publishing { visibility, publish_at: None, time_published: Some(now) },
+                };
+                updated.push("publishing");
+            }
+            if updated.is_empty() {
+                return Ok((PatchStatus::Unchanged, capsule.version));
+            }
+            capsule.time_changed = Some(now);
+            capsule.version += 1;
+            field_history::record(capsule_id, capsule.version, updated);
+            Ok((PatchStatus::Updated, capsule.version))
+        });
+        match result {
+            Some(Ok((status, version))) => PatchResult { capsule_id, status, version: Some(version), error: None },
+            Some(Err(e)) => PatchResult { capsule_id, status: PatchStatus::Conflict, version: None, error: Some(e) },
+            None => PatchResult { capsule_id, status: PatchStatus::NotFound, version: None, error: Some(format!("No capsule found with ID {}", capsule_id)) },
+        }
+    }).collect();
+
+    let count = |status: PatchStatus| results.iter().filter(|result| result.status == status).count();
+    Ok(Json(BulkPatchResult {
+        updated: count(PatchStatus::Updated),
+        unchanged: count(PatchStatus::Unchanged),
+        not_found: count(PatchStatus::NotFound),
+        conflict: count(PatchStatus::Conflict),
+        results,
+    }))
+}
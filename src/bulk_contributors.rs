@@ -0,0 +1,181 @@
+// Onboarding a whole class or company at once: contributors sent as a JSON array or a
+// CSV file with a `name,email,timezone` header. Every row gets its own result, so one
+// bad row doesn't hold up the rest.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::data::{Data, ToByteUnit};
+use std::collections::HashMap;
+
+use crate::contributors::{Contributor, NewContributor, CONTRIBUTORS, EMAIL_CHECK};
+use crate::locks;
+use crate::timezones;
+use crate::ids::ContributorId;
+use crate::residency;
+use crate::open_notices;
+
+const MAX_ROWS: usize = 1000;
+
+// What to do with a row whose email is already taken, by an existing contributor or
+// an earlier row of the same import
+#[derive(FromFormField, Clone, Copy, PartialEq)]
+pub enum OnDuplicate {
+    Skip,   // Leave the existing contributor as it is
+    Error,  // Report the row as failed
+    Merge,  // Update the existing contributor's name and timezone from the row
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum RowStatus {
+    Created,
+    Updated,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RowResult {
+    pub row: usize,  // 1-based, CSV rows are counted after the header
+    pub email: Option<String>,
+    pub status: RowStatus,
+    pub contributor_id: Option<ContributorId>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkImportResult {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub rows: Vec<RowResult>,
+}
+
+// CSV columns, timezone and region may be left out or empty
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CsvRow {
+    name: String,
+    email: String,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    region: Option<String>,
+}
+
+fn validate(row: NewContributor) -> Result<NewContributor, String> {
+    let name = row.name.trim().to_string();
+    let email = row.email.trim().to_string();
+    let timezone = row.timezone.map(|timezone| timezone.trim().to_string()).filter(|timezone| !timezone.is_empty());
+    let region = row.region.map(|region| region.trim().to_string()).filter(|region| !region.is_empty());
+    if name.is_empty() {
+        return Err("name is required".into());
+    }
+    if email.parse::<lettre::Address>().is_err() {
+        return Err(format!("'{}' is not a valid email address", email));
+    }
+    if let Some(ref timezone) = timezone {
+        timezones::parse(timezone).map_err(|e| e.to_string())?;
+    }
+    if let Some(ref region) = region {
+        residency::check_region(region).map_err(|e| e.1.into_inner())?;
+    }
+    Ok(NewContributor { name, email, timezone, region })
+}
+
+// `emails` maps every email in use to its contributor, new ones are added to it
+fn import_row(row: NewContributor, on_duplicate: OnDuplicate, emails: &mut HashMap<String, ContributorId>) -> (RowStatus, ContributorId, Option<String>) {
+    match (emails.get(&row.email).copied(), on_duplicate) {
+        (None, _) => {
+            let id = CONTRIBUTORS.next_id();
+            emails.insert(row.email.clone(), id);
+            CONTRIBUTORS.insert(Contributor { id, name: row.name, email: row.email, timezone: row.timezone, defaults: Default::default(), pinned_capsule_ids: Vec::new(), capsule_order: Vec::new(), region: row.region, leaderboard: false, open_channels: open_notices::default_channels() });
+            (RowStatus::Created, id, None)
+        },
+        (Some(id), OnDuplicate::Skip) => (RowStatus::Skipped, id, Some("Email already in use".into())),
+        (Some(id), OnDuplicate::Error) => (RowStatus::Failed, id, Some("Email already in use".into())),
+        (Some(id), OnDuplicate::Merge) => {
+            let _guard = locks::lock_contributor(id);
+            if let Some(Err(e)) = row.region.as_deref().map(|region| residency::check_region_change(id, region)) {
+                return (RowStatus::Failed, id, Some(e.1.into_inner()));
+            }
+            CONTRIBUTORS.update(id, |contributor| {
+                contributor.name = row.name;
+                if row.timezone.is_some() {
+                    contributor.timezone = row.timezone;
+                }
+                if row.region.is_some() {
+                    contributor.region = row.region;
+                }
+            });
+            (RowStatus::Updated, id, None)
+        },
+    }
+}
+
+// Rows that couldn't be read carry the reason instead
+fn import(rows: Vec<Result<NewContributor, String>>, on_duplicate: Option<OnDuplicate>) -> Result<Json<BulkImportResult>, status::Custom<Json<String>>> {
+    if rows.is_empty() {
+        return Err(status::Custom(Status::BadRequest, Json("No contributors to import".into())));
+    }
+    if rows.len() > MAX_ROWS {
+        return Err(status::Custom(Status::PayloadTooLarge, Json(format!("At most {} contributors can be imported at once", MAX_ROWS))));
+    }
+    let on_duplicate = on_duplicate.unwrap_or(OnDuplicate::Error);
+
+    // Held for the whole import, so rows are checked against each other as well
+    let _email_guard = EMAIL_CHECK.lock().unwrap();
+    let mut emails = HashMap::new();
+    CONTRIBUTORS.for_each(|contributor| {
+        emails.insert(contributor.email.clone(), contributor.id);
+    });
+
+    let mut result = BulkImportResult { created: 0, updated: 0, skipped: 0, failed: 0, rows: Vec::with_capacity(rows.len()) };
+    for (index, row) in rows.into_iter().enumerate() {
+        let row_result = match row.and_then(validate) {
+            Ok(row) => {
+                let email = row.email.clone();
+                let (status, id, error) = import_row(row, on_duplicate, &mut emails);
+                RowResult { row: index + 1, email: Some(email), status, contributor_id: Some(id), error }
+            },
+            Err(error) => RowResult { row: index + 1, email: None, status: RowStatus::Failed, contributor_id: None, error: Some(error) },
+        };
+        match row_result.status {
+            RowStatus::Created => result.created += 1,
+            RowStatus::Updated => result.updated += 1,
+            RowStatus::Skipped => result.skipped += 1,
+            RowStatus::Failed => result.failed += 1,
+        }
+        result.rows.push(row_result);
+    }
+    Ok(Json(result))
+}
+
+#[post("/contributors/bulk?<on_duplicate>", format = "json", data = "<contributors>")]
+pub fn import_contributors_json(contributors: Json<Vec<serde_json::Value>>, on_duplicate: Option<OnDuplicate>) -> Result<Json<BulkImportResult>, status::Custom<Json<String>>> {
+    // Rows are read one by one, so a malformed row fails on its own
+    let rows = contributors.into_inner().into_iter()
+        .map(|row| serde_json::from_value::<NewContributor>(row).map_err(|e| e.to_string()))
+        .collect();
+    import(rows, on_duplicate)
+}
+
+#[post("/contributors/bulk?<on_duplicate>", format = "text/csv", data = "<data>")]
+pub async fn import_contributors_csv(data: Data<'_>, on_duplicate: Option<OnDuplicate>) -> Result<Json<BulkImportResult>, status::Custom<Json<String>>> {
+    // Same size limit as JSON bodies, plain strings are capped much lower by default
+    let csv = data.open(1.mebibytes()).into_string().await
+        .map_err(|e| status::Custom(Status::BadRequest, Json(format!("Failed to read the CSV: {}", e))))?;
+    if !csv.is_complete() {
+        return Err(status::Custom(Status::PayloadTooLarge, Json("The CSV is larger than 1 MiB".into())));
+    }
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(csv.as_bytes());
+    let rows = reader.deserialize::<CsvRow>()
+        .map(|row| row
+            .map(|row| NewContributor { name: row.name, email: row.email, timezone: row.timezone, region: row.region })
+            .map_err(|e| e.to_string()))
+        .collect();
+    import(rows, on_duplicate)
+}
@@ -0,0 +1,249 @@
+// Internal bus of domain events. A change is published once where it happens, and the
+// features interested in it subscribe: the audit log, the live stream at /events and the
+// contributors' webhooks.
+// Created, updated and deleted capsules are published by watching the capsule table, so
+// no handler has to announce those itself.
+// The latest events are kept in memory, so SSE clients that reconnect with `Last-Event-ID`
+// and webhooks asked for a replay get what they missed. Numbering starts over with the
+// server, as does what is kept.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::Serialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Rocket, Shutdown};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::audit;
+use crate::capsules::CAPSULES;
+use crate::ids::{CapsuleId, ItemId};
+use crate::time_format;
+use crate::webhooks;
+
+// Events a subscriber can fall behind by before it misses some
+const CAPACITY: usize = 1024;
+
+// Latest events kept for replays
+const REPLAY_SIZE: usize = 4096;
+
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum DomainEvent {
+    #[serde(rename = "capsule.created")]
+    CapsuleCreated { capsule_id: CapsuleId },
+    #[serde(rename = "capsule.updated")]
+    CapsuleUpdated { capsule_id: CapsuleId, version: u32 },
+    #[serde(rename = "capsule.deleted")]
+    CapsuleDeleted { capsule_id: CapsuleId },
+    #[serde(rename = "capsule.published")]
+    CapsulePublished {
+        capsule_id: CapsuleId,
+        #[serde(serialize_with = "time_format::time")]
+        publish_at: DateTime<Utc>,
+    },
+    #[serde(rename = "capsule.moderated")]
+    CapsuleModerated { capsule_id: CapsuleId, action: &'static str },  // unlist, hide or restore
+    #[serde(rename = "capsule.opened")]
+    CapsuleOpened { capsule_id: CapsuleId },  // Announced to the owner and co-owners, see open_notices.rs
+    #[serde(rename = "capsule.merged")]
+    CapsuleMerged { capsule_id: CapsuleId, removed_capsule_id: CapsuleId, moved_item_ids: Vec<ItemId> },
+    #[serde(rename = "item.added")]
+    ItemAdded { capsule_id: CapsuleId, item_id: ItemId },
+    #[serde(rename = "item.removed")]
+    ItemRemoved { capsule_id: CapsuleId, item_id: ItemId },
+    #[serde(rename = "item.revealed")]
+    ItemRevealed { capsule_id: CapsuleId, item_id: ItemId },
+    #[serde(rename = "items.expired")]
+    ItemsExpired { capsule_id: CapsuleId, item_ids: Vec<ItemId> },  // Deleted by the retention policy
+}
+
+impl DomainEvent {
+    // Every `type`, as in name()
+    pub const NAMES: &'static [&'static str] = &[
+        "capsule.created", "capsule.updated", "capsule.deleted", "capsule.published", "capsule.moderated", "capsule.opened", "capsule.merged",
+        "item.added", "item.removed", "item.revealed", "items.expired",
+    ];
+
+    pub fn capsule_id(&self) -> CapsuleId {
+        match self {
+            DomainEvent::CapsuleCreated { capsule_id }
+            | DomainEvent::CapsuleUpdated { capsule_id, .. }
+            | DomainEvent::CapsuleDeleted { capsule_id }
+            | DomainEvent::CapsulePublished { capsule_id, .. }
+            | DomainEvent::CapsuleModerated { capsule_id, .. }
+            | DomainEvent::CapsuleOpened { capsule_id }
+            | DomainEvent::CapsuleMerged { capsule_id, .. }
+            | DomainEvent::ItemAdded { capsule_id, .. }
+            | DomainEvent::ItemRemoved { capsule_id, .. }
+            | DomainEvent::ItemRevealed { capsule_id, .. }
+            | DomainEvent::ItemsExpired { capsule_id, .. } => *capsule_id,
+        }
+    }
+
+    // The `type` it is serialized with
+    pub fn name(&self) -> &'static str {
+        match self {
+            DomainEvent::CapsuleCreated { .. } => "capsule.created",
+            DomainEvent::CapsuleUpdated { .. } => "capsule.updated",
+            DomainEvent::CapsuleDeleted { .. } => "capsule.deleted",
+            DomainEvent::CapsulePublished { .. } => "capsule.published",
+            DomainEvent::CapsuleModerated { .. } => "capsule.moderated",
+            DomainEvent::CapsuleOpened { .. } => "capsule.opened",
+            DomainEvent::CapsuleMerged { .. } => "capsule.merged",
+            DomainEvent::ItemAdded { .. } => "item.added",
+            DomainEvent::ItemRemoved { .. } => "item.removed",
+            DomainEvent::ItemRevealed { .. } => "item.revealed",
+            DomainEvent::ItemsExpired { .. } => "items.expired",
+        }
+    }
+}
+
+// An event as subscribers receive it
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Published {
+    pub seq: u64,
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+static BUS: Lazy<broadcast::Sender<Published>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+static RECENT: Lazy<Mutex<VecDeque<Published>>> = Lazy::new(|| Mutex::new(VecDeque::with_capacity(REPLAY_SIZE)));
+
+// Ids of the capsules seen so far, to tell a created capsule from an updated one
+static KNOWN_CAPSULES: Lazy<Mutex<HashSet<CapsuleId>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+pub fn publish(event: DomainEvent, time: DateTime<Utc>) -> Published {
+    // Numbered, kept and sent under one lock, so a replay joins the live events without a gap
+    let mut recent = RECENT.lock().unwrap();
+    let published = Published { seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed), time, event };
+    if recent.len() >= REPLAY_SIZE {
+        recent.pop_front();
+    }
+    recent.push_back(published.clone());
+    // Nobody listening is fine, the event is just dropped
+    let _ = BUS.send(published.clone());
+    published
+}
+
+pub fn subscribe() -> broadcast::Receiver<Published> {
+    BUS.subscribe()
+}
+
+// Kept events after `seq`, oldest first, and how many after it aren't kept anymore. A
+// `seq` ahead of the bus is from before a restart and gets everything kept.
+fn kept_after(recent: &VecDeque<Published>, seq: u64) -> (Vec<Published>, u64) {
+    let seq = if seq >= NEXT_SEQ.load(Ordering::Relaxed) { 0 } else { seq };
+    let oldest = recent.front().map_or(seq + 1, |published| published.seq);
+    let events = recent.iter().filter(|published| published.seq > seq).cloned().collect();
+    (events, oldest.saturating_sub(seq + 1))
+}
+
+pub fn replay_after(seq: u64) -> (Vec<Published>, u64) {
+    kept_after(&RECENT.lock().unwrap(), seq)
+}
+
+// Kept events from `time` on, oldest first, and whether some of them may not be kept anymore
+pub fn replay_from(time: DateTime<Utc>) -> (Vec<Published>, bool) {
+    let recent = RECENT.lock().unwrap();
+    let events = recent.iter().filter(|published| published.time >= time).cloned().collect();
+    let incomplete = recent.front().is_some_and(|oldest| oldest.seq > 1 && oldest.time >= time);
+    (events, incomplete)
+}
+
+// Subscribes and replays what came after `seq` in one go, so no event is missed or repeated
+fn subscribe_after(seq: u64) -> (broadcast::Receiver<Published>, Vec<Published>, u64) {
+    let recent = RECENT.lock().unwrap();
+    let (events, missed) = kept_after(&recent, seq);
+    (BUS.subscribe(), events, missed)
+}
+
+// The id of the last event an SSE client saw, sent again when it reconnects
+pub struct LastEventId(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let id = request.headers().get_one("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+        request::Outcome::Success(LastEventId(id))
+    }
+}
+
+// Starts publishing capsule changes, once the capsules are loaded
+pub fn start() {
+    KNOWN_CAPSULES.lock().unwrap().extend(CAPSULES.ids());
+    CAPSULES.observe(|capsule_id, capsule| {
+        let mut known = KNOWN_CAPSULES.lock().unwrap();
+        let event = match capsule {
+            Some(_) if known.insert(capsule_id) => DomainEvent::CapsuleCreated { capsule_id },
+            Some(capsule) => DomainEvent::CapsuleUpdated { capsule_id, version: capsule.version },
+            None if known.remove(&capsule_id) => DomainEvent::CapsuleDeleted { capsule_id },
+            None => return,
+        };
+        publish(event, Utc::now());
+    });
+}
+
+// Starts the subscribers that run for the whole life of the server
+pub struct Subscribers;
+
+#[rocket::async_trait]
+impl Fairing for Subscribers {
+    fn info(&self) -> Info {
+        Info { name: "Event bus subscribers", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<rocket::Orbit>) {
+        rocket::tokio::spawn(audit::follow(subscribe()));
+        rocket::tokio::spawn(webhooks::follow(subscribe()));
+    }
+}
+
+// Live domain events as server-sent events, optionally of one capsule. A client that
+// reconnects with `Last-Event-ID` first gets the kept events it missed.
+#[get("/events?<capsule_id>")]
+pub fn event_stream(capsule_id: Option<CapsuleId>, last_event_id: LastEventId, mut shutdown: Shutdown) -> EventStream![] {
+    let (mut events, replayed, missed) = match last_event_id.0 {
+        Some(seq) => subscribe_after(seq),
+        None => (subscribe(), Vec::new(), 0),
+    };
+    EventStream! {
+        if missed > 0 {
+            yield Event::data(missed.to_string()).event("lagged");
+        }
+        for published in replayed {
+            if capsule_id.is_none_or(|id| id == published.event.capsule_id()) {
+                yield Event::json(&published).event(published.event.name()).id(published.seq.to_string());
+            }
+        }
+        loop {
+            let received = select! {
+                received = events.recv() => received,
+                _ = &mut shutdown => break,
+            };
+            let published = match received {
+                Ok(published) => published,
+                Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(missed)) => {
+                    // The client missed events and should reload what it shows
+                    yield Event::data(missed.to_string()).event("lagged");
+                    continue;
+                },
+            };
+            if capsule_id.is_some_and(|id| id != published.event.capsule_id()) {
+                continue;
+            }
+            yield Event::json(&published).event(published.event.name()).id(published.seq.to_string());
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use once_cell::sync::Lazy;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use crate::config::TimeFormat;
+use crate::ids::CapsuleId;
+use crate::time_format;
+
+// Rendered JSON of hot reads, keyed by what was rendered and the format of its times and
+// checked against the ETag of the current data, so any change to the underlying rows
+// invalidates it
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    Capsule,
+    CapsuleItems,
+}
+
+const CAPACITY: usize = 1024;
+
+struct Entry {
+    etag: String,
+    body: Arc<String>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<(CacheKind, CapsuleId, TimeFormat), Entry>,
+    tick: u64,
+}
+
+static RESPONSE_CACHE: Lazy<Mutex<ResponseCache>> = Lazy::new(|| {
+    Mutex::new(ResponseCache::default())
+});
+
+// Weak ETag derived from the revisions of everything a response is rendered from and
+// the format of its times
+pub fn etag(revisions: impl Hash) -> String {
+    let mut hasher = DefaultHasher::new();
+    (revisions, time_format::current()).hash(&mut hasher);
+    format!("W/\"{:x}\"", hasher.finish())
+}
+
+// The cached body, if it was rendered from data that still has this ETag
+pub fn lookup(kind: CacheKind, id: CapsuleId, etag: &str) -> Option<Arc<String>> {
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    cache.tick += 1;
+    let tick = cache.tick;
+    let entry = cache.entries.get_mut(&(kind, id, time_format::current())).filter(|entry| entry.etag == etag)?;
+    entry.last_used = tick;
+    Some(entry.body.clone())
+}
+
+// Stores a freshly rendered body, evicting the least recently used entry when full
+pub fn store(kind: CacheKind, id: CapsuleId, etag: &str, body: String) -> Arc<String> {
+    let key = (kind, id, time_format::current());
+    let body = Arc::new(body);
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    cache.tick += 1;
+    let tick = cache.tick;
+
+    if cache.entries.len() >= CAPACITY && !cache.entries.contains_key(&key) {
+        let oldest = cache.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            cache.entries.remove(&oldest);
+        }
+    }
+    cache.entries.insert(key, Entry { etag: etag.to_string(), body: body.clone(), last_used: tick });
+    body
+}
+
+// JSON response with an ETag header, answered with 304 when the client already has it
+pub struct CachedJson {
+    pub body: Arc<String>,
+    pub etag: String,
+}
+
+// Lets the response body borrow the cached string instead of copying it
+pub struct SharedBody(pub Arc<String>);
+
+impl AsRef<[u8]> for SharedBody {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+// Whether the request's If-None-Match names this ETag
+pub fn not_modified(request: &Request<'_>, etag: &str) -> bool {
+    request.headers().get("If-None-Match")
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == etag || tag.trim() == "*")
+}
+
+impl<'r> Responder<'r, 'static> for CachedJson {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let not_modified = not_modified(request, &self.etag);
+
+        let mut build = Response::build();
+        build.raw_header("ETag", self.etag);
+        if not_modified {
+            build.status(Status::NotModified);
+        } else {
+            let len = self.body.len();
+            build.header(ContentType::JSON).sized_body(len, Cursor::new(SharedBody(self.body)));
+        }
+        build.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etags_follow_the_revisions() {
+        assert_eq!(etag((1u64, 2u64)), etag((1u64, 2u64)));
+        assert_ne!(etag((1u64, 2u64)), etag((1u64, 3u64)));
+        assert!(etag(1u64).starts_with("W/\""));
+    }
+}
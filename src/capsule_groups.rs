@@ -0,0 +1,112 @@
+// Capsules split into groups with the first page of each, so a home screen with
+// "Sealed", "Opening soon" and "Opened" sections needs one call instead of three.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::contributors::CONTRIBUTORS;
+use crate::i18n::AcceptLanguage;
+use crate::ids::ContributorId;
+use crate::pagination::{Collection, Pagination};
+
+const DEFAULT_SOON_DAYS: i64 = 7;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleGroup {
+    pub key: String,  // Status name or contributor id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,  // Contributor name when grouped by contributor
+    pub count: usize,
+    pub capsules: Vec<Capsule>,  // The requested page, the first one by default
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GroupedCapsules {
+    pub by: &'static str,
+    pub page: usize,
+    pub per_page: usize,
+    pub groups: Vec<CapsuleGroup>,
+}
+
+pub const OPEN_STATES: [&str; 3] = ["sealed", "opening_soon", "opened"];
+
+// Sealed capsules open later than `soon`, opening soon ones within it. A capsule is
+// opened from the instant of its open time on, as in its countdown.
+pub fn open_state(capsule: &Capsule, now: DateTime<Utc>, soon: Duration) -> &'static str {
+    if capsule.time_open <= now {
+        "opened"
+    } else if capsule.time_open - now <= soon {
+        "opening_soon"
+    } else {
+        "sealed"
+    }
+}
+
+// How far ahead "opening soon" reaches, from a `soon_days` parameter
+pub fn soon(soon_days: Option<i64>) -> Result<Duration, status::Custom<Json<String>>> {
+    let soon_days = soon_days.unwrap_or(DEFAULT_SOON_DAYS);
+    if soon_days < 0 {
+        return Err(status::Custom(Status::BadRequest, Json("soon_days can't be negative".into())));
+    }
+    Ok(Duration::days(soon_days))
+}
+
+#[get("/capsules/grouped?<by>&<soon_days>&<pagination..>")]
+pub fn grouped_capsules(by: Option<&str>, soon_days: Option<i64>, pagination: Pagination, languages: AcceptLanguage, clock: &State<SharedClock>) -> Result<Json<GroupedCapsules>, status::Custom<Json<String>>> {
+    let soon = soon(soon_days)?;
+    let now = clock.now();
+
+    let mut capsules = Vec::new();
+    CAPSULES.for_each(|capsule| capsules.push(capsule.clone()));
+
+    let (by, mut groups): (&'static str, Vec<CapsuleGroup>) = match by.unwrap_or("status") {
+        "status" => {
+            let mut by_status: BTreeMap<&str, Vec<Capsule>> = BTreeMap::new();
+            for capsule in capsules {
+                by_status.entry(open_state(&capsule, now, soon)).or_default().push(capsule);
+            }
+            // Soonest first while waiting, most recently opened first after
+            let groups = OPEN_STATES.into_iter()
+                .map(|key| {
+                    let mut group = by_status.remove(key).unwrap_or_default();
+                    if key == "opened" {
+                        group.sort_by_key(|capsule| std::cmp::Reverse(capsule.time_open));
+                    } else {
+                        group.sort_by_key(|capsule| capsule.time_open);
+                    }
+                    CapsuleGroup { key: key.to_string(), label: None, count: group.len(), capsules: group }
+                })
+                .collect();
+            ("status", groups)
+        },
+        "contributor" => {
+            let mut by_contributor: BTreeMap<ContributorId, Vec<Capsule>> = BTreeMap::new();
+            for capsule in capsules {
+                by_contributor.entry(capsule.contributor_id).or_default().push(capsule);
+            }
+            let groups = by_contributor.into_iter()
+                .map(|(contributor_id, group)| {
+                    let name = CONTRIBUTORS.get(contributor_id).map(|contributor| contributor.name);
+                    CapsuleGroup { key: contributor_id.to_string(), label: name, count: group.len(), capsules: group }
+                })
+                .collect();
+            ("contributor", groups)
+        },
+        other => return Err(status::Custom(Status::BadRequest, Json(format!("Unknown grouping '{}', use status or contributor", other)))),
+    };
+
+    let per_page = pagination.per_page(Collection::Capsules);
+    let start = pagination.start(Collection::Capsules);
+    for group in &mut groups {
+        group.capsules = group.capsules.iter().skip(start).take(per_page).map(|capsule| capsule.localized(&languages.0)).collect();
+    }
+
+    Ok(Json(GroupedCapsules { by, page: pagination.page(), per_page, groups }))
+}
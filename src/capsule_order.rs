@@ -0,0 +1,104 @@
+// Owners arranging their own list of capsules: pinned capsules come first, then the
+// capsules in the order the owner chose, then the rest by id. Both lists are stored on
+// the contributor and used by `GET /capsules?contributor_id=<id>&sort=custom`. Ids of
+// capsules that were deleted or moved to another owner since are skipped when sorting.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use std::collections::HashSet;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::indexes::INDEXES;
+use crate::locks;
+use crate::tokens::Caller;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleOrder {
+    #[serde(default)]
+    pub pinned_capsule_ids: Vec<CapsuleId>,
+    #[serde(default)]
+    pub capsule_order: Vec<CapsuleId>,
+}
+
+// Sorts a contributor's capsules into their custom order
+pub fn sort(contributor_id: ContributorId, capsules: &mut [Capsule]) {
+    let Some(order) = CONTRIBUTORS.read(contributor_id, |c| CapsuleOrder { pinned_capsule_ids: c.pinned_capsule_ids.clone(), capsule_order: c.capsule_order.clone() }) else { return };
+    let position = |ids: &[CapsuleId], id: CapsuleId| ids.iter().position(|&listed| listed == id);
+    capsules.sort_by_key(|capsule| {
+        match (position(&order.pinned_capsule_ids, capsule.id), position(&order.capsule_order, capsule.id)) {
+            (Some(pinned), _) => (0, pinned, capsule.id),
+            (None, Some(ordered)) => (1, ordered, capsule.id),
+            (None, None) => (2, 0, capsule.id),
+        }
+    });
+}
+
+// Checks that the ids are the contributor's capsules, each listed once
+fn check_owned(contributor_id: ContributorId, ids: &[CapsuleId], list: &str) -> Result<(), status::Custom<Json<String>>> {
+    let owned: HashSet<CapsuleId> = INDEXES.read().unwrap().capsules_of(contributor_id).into_iter().collect();
+    let mut seen = HashSet::new();
+    for &id in ids {
+        if !owned.contains(&id) {
+            return Err(status::Custom(Status::BadRequest, Json(format!("Capsule {} in {} isn't one of contributor {}'s capsules", id, list, contributor_id))));
+        }
+        if !seen.insert(id) {
+            return Err(status::Custom(Status::BadRequest, Json(format!("Capsule {} is listed twice in {}", id, list))));
+        }
+    }
+    Ok(())
+}
+
+// Replaces both lists at once
+#[put("/contributors/<id>/capsule-order", format = "json", data = "<order>")]
+pub fn set_capsule_order(id: ContributorId, order: Json<CapsuleOrder>, caller: Caller) -> Result<Json<CapsuleOrder>, ApiError> {
+    caller.must_be(id)?;
+    let order = order.into_inner();
+    let _guard = locks::lock_contributor(id);
+    if !CONTRIBUTORS.contains(id) {
+        return Err(ApiError::ContributorNotFound);
+    }
+    check_owned(id, &order.pinned_capsule_ids, "pinned_capsule_ids")?;
+    check_owned(id, &order.capsule_order, "capsule_order")?;
+
+    CONTRIBUTORS.update(id, |contributor| {
+        contributor.pinned_capsule_ids = order.pinned_capsule_ids.clone();
+        contributor.capsule_order = order.capsule_order.clone();
+    });
+    Ok(Json(order))
+}
+
+// Pins a capsule on its owner's list, after the capsules pinned before
+#[post("/capsules/<cid>/pin")]
+pub fn pin_capsule(cid: CapsuleId) -> Result<Json<CapsuleOrder>, ApiError> {
+    change_pin(cid, |pinned| {
+        if !pinned.contains(&cid) {
+            pinned.push(cid);
+        }
+    })
+}
+
+#[delete("/capsules/<cid>/pin")]
+pub fn unpin_capsule(cid: CapsuleId) -> Result<Json<CapsuleOrder>, ApiError> {
+    change_pin(cid, |pinned| pinned.retain(|&id| id != cid))
+}
+
+fn change_pin(cid: CapsuleId, change: impl FnOnce(&mut Vec<CapsuleId>)) -> Result<Json<CapsuleOrder>, ApiError> {
+    let owner = || CAPSULES.read(cid, |capsule| capsule.contributor_id)
+        .ok_or(ApiError::CapsuleNotFound(cid));
+    let contributor_id = owner()?;
+    let _guard = locks::lock_contributor(contributor_id);
+    // Contributor locks come before capsule locks, so the owner is checked again instead
+    if owner()? != contributor_id {
+        return Err(ApiError::Other(Status::Conflict, format!("Capsule {} changed owner meanwhile, please retry", cid)));
+    }
+    CONTRIBUTORS.update(contributor_id, |contributor| {
+        change(&mut contributor.pinned_capsule_ids);
+        CapsuleOrder { pinned_capsule_ids: contributor.pinned_capsule_ids.clone(), capsule_order: contributor.capsule_order.clone() }
+    })
+    .map(Json)
+    .ok_or(ApiError::ContributorNotFound)
+}
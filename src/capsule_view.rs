@@ -0,0 +1,147 @@
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::error_messages::ApiError;
+use crate::i18n::AcceptLanguage;
+use crate::contributors::{Contributor, CONTRIBUTORS};
+use crate::items::{Item, ITEMS};
+use crate::owner_only;
+use crate::reveals;
+use crate::indexes::INDEXES;
+use crate::merges::MERGE_RECORDS;
+use crate::ids::{CapsuleId, ContributorId, ItemId};
+use crate::letters::DeliveryStatus;
+use crate::open_notices::Channel;
+use crate::time_format;
+use crate::tokens::Caller;
+
+const RECENT_ACTIVITY: usize = 10;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Activity {
+    pub kind: &'static str,  // created, changed, item_added, merged, opened or notified
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub item_id: Option<ItemId>,
+    pub merged_capsule_id: Option<CapsuleId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification: Option<NotificationActivity>,
+}
+
+// Who was told about the opening and how it went, see open_notices.rs and letters.rs
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NotificationActivity {
+    pub contributor_id: Option<ContributorId>,
+    pub email: Option<String>,  // Of external recipients
+    pub channel: Channel,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+impl Activity {
+    fn new(kind: &'static str, time: DateTime<Utc>) -> Activity {
+        Activity { kind, time, item_id: None, merged_capsule_id: None, notification: None }
+    }
+}
+
+// Everything a capsule page needs in one response, parts left out by `include` are omitted
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FullCapsule {
+    pub capsule: Capsule,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contributor: Option<Contributor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<Item>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<Vec<Activity>>,
+}
+
+// Latest events of a capsule, newest first
+fn recent_activity(capsule: &Capsule, items: &[Item]) -> Vec<Activity> {
+    let mut activity = vec![Activity::new("created", capsule.time_created)];
+
+    if let Some(time_changed) = capsule.time_changed {
+        activity.push(Activity::new("changed", time_changed));
+    }
+
+    for item in items {
+        activity.push(Activity { item_id: Some(item.id), ..Activity::new("item_added", item.time_added) });
+    }
+
+    // Notices that are still pending are listed at the opening
+    if let Some(opening) = &capsule.opening {
+        activity.push(Activity::new("opened", opening.time));
+        for notice in &opening.notices {
+            let notification = NotificationActivity {
+                contributor_id: Some(notice.contributor_id),
+                email: None,
+                channel: notice.channel,
+                status: notice.status,
+                attempts: notice.attempts,
+                error: notice.error.clone(),
+            };
+            activity.push(Activity { notification: Some(notification), ..Activity::new("notified", notice.time_sent.unwrap_or(opening.time)) });
+        }
+    }
+    for recipient in capsule.delivery.iter().flat_map(|delivery| &delivery.recipients).filter(|recipient| recipient.attempts > 0) {
+        let notification = NotificationActivity {
+            contributor_id: None,
+            email: Some(recipient.email.clone()),
+            channel: Channel::Email,
+            status: recipient.status,
+            attempts: recipient.attempts,
+            error: recipient.error.clone(),
+        };
+        let time = recipient.time_sent.or(capsule.opening.as_ref().map(|opening| opening.time)).unwrap_or(capsule.time_open);
+        activity.push(Activity { notification: Some(notification), ..Activity::new("notified", time) });
+    }
+
+    for record in MERGE_RECORDS.read().unwrap().iter().filter(|r| r.new_merged_capsule.id == capsule.id) {
+        activity.push(Activity {
+            merged_capsule_id: Some(record.old_capsule2.id),
+            ..Activity::new("merged", record.new_merged_capsule.time_changed)
+        });
+    }
+
+    activity.sort_by_key(|a| std::cmp::Reverse(a.time));
+    activity.truncate(RECENT_ACTIVITY);
+    activity
+}
+
+#[get("/capsules/<cid>/full?<include>")]
+pub fn get_full_capsule(cid: CapsuleId, include: Option<&str>, languages: AcceptLanguage, caller: Caller, clock: &State<SharedClock>) -> Result<Json<FullCapsule>, ApiError> {
+    // Comma separated list of contributor, items and activity; everything by default
+    let parts: Vec<&str> = include.map(|i| i.split(',').map(str::trim).filter(|p| !p.is_empty()).collect())
+        .unwrap_or_else(|| vec!["contributor", "items", "activity"]);
+    if let Some(unknown) = parts.iter().find(|p| !["contributor", "items", "activity"].contains(p)) {
+        return Err(ApiError::Other(Status::BadRequest, format!("Unknown include '{}', expected contributor, items or activity", unknown)));
+    }
+
+    let capsule = CAPSULES.get(cid)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+
+    let contributor = if parts.contains(&"contributor") { CONTRIBUTORS.get(capsule.contributor_id) } else { None };
+
+    // Items are needed for the activity as well
+    let capsule_items = if parts.contains(&"items") || parts.contains(&"activity") {
+        let item_ids = reveals::visible(cid, INDEXES.read().unwrap().items_of(cid));
+        let item_ids = owner_only::visible(cid, item_ids, &caller, clock.now());
+        item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect()
+    } else {
+        Vec::new()
+    };
+
+    let activity = parts.contains(&"activity").then(|| recent_activity(&capsule, &capsule_items));
+    let items = parts.contains(&"items").then_some(capsule_items);
+
+    let capsule = capsule.localized(&languages.0);
+    Ok(Json(FullCapsule { capsule, contributor, items, activity }))
+}
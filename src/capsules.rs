@@ -1,312 +1,834 @@
-use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::http::{Status, Header};
-use rocket::response::{self, Responder, Response, Redirect};
-use rocket::Request;
-use chrono::{DateTime, Utc};
-use std::sync::Mutex;
+use rocket::serde::{json::Json, Deserialize, Deserializer, Serialize, Serializer};
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Either, State};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
 use once_cell::sync::Lazy;
-use sha2::{Sha256, Digest};
-use std::collections::HashMap;
 use rocket::response::status;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
-use crate::contributors::CONTRIBUTORS;
+use crate::contributors::{CapsuleDefaults, CONTRIBUTORS};
+use crate::database;
+use crate::error_messages::ApiError;
 use crate::items::ITEMS;
-
+use crate::indexes::INDEXES;
+use crate::store::{self, Entity, Table};
+use crate::ids::{self, CapsuleId, ContributorId, EntityId, ItemId};
+use crate::locks::{self, CAPSULE_LOCKS};
+use crate::cache::{self, CacheKind, CachedJson};
+use crate::coalesce::Flights;
+use crate::time_format;
+use crate::timezones;
+use crate::pagination::{Collection, Pagination, Paginated, RenderedPage};
+use crate::i18n::{AcceptLanguage, LocalizedText};
+use crate::clock::SharedClock;
+use crate::letters::{self, Delivery};
+use crate::open_notices::OpenNotices;
+use crate::dry_run::DeletionPlan;
+use crate::signatures::{self, Signing};
+use crate::publishing::{Publishing, Visibility};
+use crate::reveals::Reveal;
+use crate::field_history;
+use crate::reads;
+use crate::hash_chain::{self, WithChainHash};
+use crate::search::Query;
+use crate::config::{ItemsOnDelete, TimeFormat};
+use crate::orphans;
+use crate::capsule_order;
+use crate::capsule_groups;
+use crate::moderation::Moderation;
+use crate::content_policy;
+use crate::ownership;
+use crate::retention::{self, ItemRetention};
+use crate::custom_fields::{self, CustomField};
+use crate::merges::{self, MergeLineage};
+use crate::tombstones::{self, Gone};
+use crate::tokens::Caller;
+use crate::duplicates::{self, WithDuplicateOf};
+use crate::templates;
+use crate::payload::Payload;
+
+// `item_ids` isn't stored with the capsule, it's derived from the items' capsule through the
+// indexes whenever a capsule is written out
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(crate = "rocket::serde")]
+#[serde(crate = "rocket::serde", remote = "Self")]
 pub struct Capsule {
-    pub id: u32,
-    pub contributor_id: u32,
-    pub name: String,
-    pub description: String,
+    pub id: CapsuleId,
+    pub contributor_id: ContributorId,
+    pub name: LocalizedText,         // Plain string or translations by language tag
+    pub description: LocalizedText,
+    #[serde(serialize_with = "time_format::time")]
     pub time_created: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
     pub time_changed: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::time")]
     pub time_open: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::time")]
     pub time_until_changed: DateTime<Utc>, // Time until the capsule can be changed
-    pub item_ids: Option<Vec<u32>>, 
+    #[serde(default)]
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub contributions_close_at: Option<DateTime<Utc>>,  // Items can be added until then, see contributions_deadline
     pub version: u32,  // Version counter to handle concurrent updates
+    #[serde(default)]
+    pub timezone: Option<String>,  // IANA timezone the open time was given in
+    #[serde(default)]
+    pub time_open_local: Option<NaiveDateTime>,  // Open time as wall-clock time in `timezone`
+    #[serde(default)]
+    pub delivery: Option<Delivery>,  // Recipients emailed when the capsule opens, see letters.rs
+    #[serde(default)]
+    pub signing: Option<Signing>,  // Contributors invited to co-sign, see signatures.rs
+    #[serde(default)]
+    pub publishing: Publishing,  // Visibility in the public feed, see publishing.rs
+    #[serde(default)]
+    pub reveal: Option<Reveal>,  // Items revealed one by one after opening, see reveals.rs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub moderation: Option<Moderation>,  // Set when taken out of public view, see moderation.rs
+    #[serde(default)]
+    pub co_owner_ids: Vec<ContributorId>,  // May edit like the owner, see ownership.rs
+    #[serde(default)]
+    pub retention: ItemRetention,  // What happens to the items after opening, see retention.rs
+    #[serde(default)]
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_items_deleted: Option<DateTime<Utc>>,  // When the retention policy deleted the items
+    #[serde(default)]
+    pub custom_fields: Vec<CustomField>,  // Filled in by every new item's metadata, see custom_fields.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opening: Option<OpenNotices>,  // Owner and co-owners told about the opening, see open_notices.rs
+}
+
+impl Entity for Capsule {
+    type Id = CapsuleId;
+
+    fn id(&self) -> CapsuleId {
+        self.id
+    }
+}
+
+// The stored fields, as derived with `remote = "Self"`
+struct StoredCapsule<'a>(&'a Capsule);
+
+impl Serialize for StoredCapsule<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Capsule::serialize(self.0, serializer)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CapsuleWithItems<'a> {
+    #[serde(flatten)]
+    capsule: StoredCapsule<'a>,
+    item_ids: Vec<ItemId>,
+}
+
+impl Serialize for Capsule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let item_ids = INDEXES.read().unwrap().items_of(self.id);
+        CapsuleWithItems { capsule: StoredCapsule(self), item_ids }.serialize(serializer)
+    }
+}
+
+// Lists in older data files are ignored, the indexes are built from the items
+impl<'de> Deserialize<'de> for Capsule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Capsule::deserialize(deserializer)
+    }
+}
+
+impl Capsule {
+    // Copy with the text fields resolved to the best of the requested languages
+    pub fn localized(&self, languages: &[String]) -> Capsule {
+        Capsule {
+            name: self.name.localized(languages),
+            description: self.description.localized(languages),
+            ..self.clone()
+        }
+    }
+
+    // Items can be added, changed and removed until then, while the capsule itself only
+    // changes until `time_until_changed`. Never earlier than that.
+    pub fn contributions_deadline(&self) -> DateTime<Utc> {
+        self.contributions_close_at.map_or(self.time_until_changed, |close_at| close_at.max(self.time_until_changed))
+    }
 }
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct CapsulePatch {
-    name: Option<String>,
-    description: Option<String>,
+    name: Option<LocalizedText>,
+    description: Option<LocalizedText>,
     version: Option<u32>, 
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+// Body of PUT /capsules/<cid>, the fields a client may replace. Everything else
+// (id, owner, times, items, version, delivery, signers) is kept by the server.
+#[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
-pub struct NewCapsule {
-    name: String,
-    description: String,
-    contributor_id: u32,
-    time_open: DateTime<Utc>,
+pub struct CapsuleUpdate {
+    name: LocalizedText,
+    description: LocalizedText,
+    time_open: Option<DateTime<Utc>>,
+    time_open_local: Option<NaiveDateTime>,  // Alternative to `time_open`, in `timezone`
+    timezone: Option<String>,  // Defaults to the owner's timezone
+    contributions_close_at: Option<DateTime<Utc>>,
+    tags: Option<Vec<String>>,  // Kept when left out
+    retention: Option<ItemRetention>,  // Kept when left out
+    custom_fields: Option<Vec<CustomField>>,  // Kept when left out
+    version: u32,  // The version the update was made against
 }
 
-#[derive(FromForm, UriDisplayQuery)]
-pub struct Pagination {
-    page: Option<usize>,
-    per_page: Option<usize>,
+// Body of PUT /capsules/<cid>: an update of an existing capsule, or a whole new capsule
+// under an id the client picked
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum CapsulePut {
+    Update(CapsuleUpdate),
+    Create(NewCapsule),
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct NewCapsule {
+    name: LocalizedText,
+    description: LocalizedText,
+    contributor_id: ContributorId,
+    #[serde(serialize_with = "time_format::optional_time")]
+    time_open: Option<DateTime<Utc>>,
+    time_open_local: Option<NaiveDateTime>,  // Alternative to `time_open`, in `timezone`
+    timezone: Option<String>,  // Defaults to the contributor's timezone
+    #[serde(default)]
+    deliver_to: Vec<String>,  // Email addresses to send the capsule to once it opens
+    #[serde(default)]
+    signers: Vec<ContributorId>,  // Contributors invited to sign the capsule
+    required_signatures: Option<u32>,  // Signatures needed before the capsule seals
+    #[serde(serialize_with = "time_format::optional_time")]
+    contributions_close_at: Option<DateTime<Utc>>,  // Later deadline for items, defaults to the edit window
+    // Left out, these fall back to the contributor's defaults, see CapsuleDefaults
+    edit_window_days: Option<u32>,
+    visibility: Option<Visibility>,
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    retention: ItemRetention,
+    #[serde(default)]
+    custom_fields: Vec<CustomField>,
+}
 
 // Global in-memory storage for capsules
-pub static CAPSULES: Lazy<Mutex<Vec<Capsule>>> = Lazy::new(|| {
-    Mutex::new(vec![])
-});
-
+pub static CAPSULES: Lazy<Table<Capsule>> = Lazy::new(|| Table::with_store(store::configured("capsules")));
+
+// Held while a capsule is created under a client's id, so a repeat can't create it twice
+static CLAIMING: Mutex<()> = Mutex::new(());
+
+// PUT /capsules/<cid> answers 200 with an updated or already created capsule, 201 with a new one
+type PutOutcome = Result<Either<Json<Capsule>, status::Created<Json<Capsule>>>, ApiError>;
+
+// The open time in UTC and as local time in the capsule's timezone, if it has one
+fn resolve_time_open(time_open: Option<DateTime<Utc>>, time_open_local: Option<NaiveDateTime>, tz: Option<Tz>) -> Result<(DateTime<Utc>, Option<NaiveDateTime>), &'static str> {
+    match (time_open, time_open_local) {
+        (Some(_), Some(_)) => Err("Provide either time_open or time_open_local, not both"),
+        (Some(time_open), None) => Ok((time_open, tz.map(|tz| timezones::to_local(time_open, tz)))),
+        (None, Some(local)) => match tz {
+            Some(tz) => Ok((timezones::resolve_local(local, tz), Some(local))),
+            None => Err("time_open_local needs a timezone on the capsule or the contributor"),
+        },
+        (None, None) => Err("time_open or time_open_local is required"),
+    }
+}
 
+// Edit window of new capsules without one of their own or from the contributor's defaults
+const DEFAULT_EDIT_WINDOW_DAYS: u32 = 7;
+const MAX_EDIT_WINDOW_DAYS: u32 = 365;
 
-// Custom responder to add headers
-pub struct CustomResponder<T> {
-    inner: T,
-    total_items: usize,
-    page: usize,
-    per_page: usize,
+pub fn check_edit_window(days: u32) -> Result<u32, status::Custom<Json<String>>> {
+    if days == 0 || days > MAX_EDIT_WINDOW_DAYS {
+        return Err(status::Custom(Status::BadRequest, Json(format!("edit_window_days must be between 1 and {}", MAX_EDIT_WINDOW_DAYS))));
+    }
+    Ok(days)
 }
 
-impl<'r, T: Responder<'r, 'static>> Responder<'r, 'static> for CustomResponder<T> {
-    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
-        let mut build = Response::build_from(self.inner.respond_to(request)?);
-        build.raw_header("X-Total-Count", self.total_items.to_string());
-        build.raw_header("X-Page", self.page.to_string());
-        build.raw_header("X-Per-Page", self.per_page.to_string());
-        build.ok()
+// Trimmed, without blanks and repeats, in the order given
+pub fn clean_tags(tags: &[String]) -> Vec<String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for tag in tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+        if !cleaned.iter().any(|seen| seen == tag) {
+            cleaned.push(tag.to_string());
+        }
     }
+    cleaned
 }
-/*
-#[post("/capsules", format = "json", data = "<capsule_data>")]
-pub fn create_capsule(capsule_data: Json<NewCapsule>) -> Result<Json<Capsule>, status::Custom<Json<String>>> {
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
 
-    let new_capsule = capsule_data.into_inner();
-
-    // Generate the idempotency key
-   // let idempotency_key = generate_idempotency_key(&new_capsule);
-
-    if contributors.iter().any(|c| c.id == new_capsule.contributor_id) {
-        let id = capsules.iter().max_by_key(|c| c.id).map_or(1, |max| max.id + 1); // Ensure unique ID
-
-        let capsule = Capsule {
-            id,
-            name: new_capsule.name.clone(),  // Clone to avoid move
-            description: new_capsule.description.clone(),  // Clone to avoid move
-            time_created: Utc::now(),
-            time_changed: None,
-            time_open: new_capsule.time_open,
-            time_until_changed: Utc::now() + chrono::Duration::weeks(1),
-            contributor_id: new_capsule.contributor_id,
-            item_ids: None,
-            //idempotency_key: idempotency_key.clone(),
-            version: 1,
-        };
+// What a new capsule resolves to once it passes every check
+pub struct CheckedCapsule {
+    timezone: Option<String>,
+    time_open: DateTime<Utc>,
+    time_open_local: Option<NaiveDateTime>,
+    delivery: Option<Delivery>,
+    signing: Option<Signing>,
+    time_until_changed: DateTime<Utc>,
+    publishing: Publishing,
+    tags: Vec<String>,
+    retention: ItemRetention,
+    custom_fields: Vec<CustomField>,
+}
 
-        capsules.push(capsule.clone());
+// Runs every check of capsule creation and collects all failures, in the order creation
+// reports them. Shared with POST /capsules/validate, see validation.rs
+pub fn check_new_capsule(new_capsule: &NewCapsule, now: DateTime<Utc>) -> Result<CheckedCapsule, Vec<ApiError>> {
+    let mut errors = Vec::new();
+    errors.extend(content_policy::check_capsule_name(&new_capsule.name).err().map(ApiError::from));
+    errors.extend(content_policy::check_capsule_description(&new_capsule.description).err().map(ApiError::from));
 
-        // Update the contributor's list of capsule IDs
-        if let Some(contributor) = contributors.iter_mut().find(|c| c.id == new_capsule.contributor_id) {
-            if let Some(capsule_ids) = &mut contributor.capsule_ids {
-                capsule_ids.push(capsule.id);
-            } else {
-                contributor.capsule_ids = Some(vec![capsule.id]);
-            }
-        }
+    // Check for contributor existence
+    let (contributor_timezone, defaults) = match CONTRIBUTORS.read(new_capsule.contributor_id, |c| (c.timezone.clone(), c.defaults.clone())) {
+        Some(found) => found,
+        None => {
+            errors.push(ApiError::UnknownContributor);
+            (None, CapsuleDefaults::default())
+        },
+    };
 
-        Ok(Json(capsule))
-    } else {
-        Err(status::Custom(Status::BadRequest, Json("Contributor not found".to_string())))
+    // Resolve the open time, either given in UTC or as local time in the capsule's timezone
+    let timezone = new_capsule.timezone.clone().or(contributor_timezone);
+    let tz = timezone.as_deref().map(timezones::parse).transpose().unwrap_or_else(|e| {
+        errors.push(e);
+        None
+    });
+    let time_open = resolve_time_open(new_capsule.time_open, new_capsule.time_open_local, tz).map_err(|e| errors.push(ApiError::Other(Status::BadRequest, e.into()))).ok();
+
+    let delivery = letters::new_delivery(&new_capsule.deliver_to).map_err(|e| errors.push(e.into())).ok();
+    let signing = signatures::new_signing(&new_capsule.signers, new_capsule.required_signatures).map_err(|e| errors.push(e.into())).ok();
+
+    let edit_window_days = new_capsule.edit_window_days.or(defaults.edit_window_days).unwrap_or(DEFAULT_EDIT_WINDOW_DAYS);
+    let edit_window_days = check_edit_window(edit_window_days).map_err(|e| errors.push(e.into())).unwrap_or(DEFAULT_EDIT_WINDOW_DAYS);
+    let time_until_changed = now + chrono::Duration::days(edit_window_days as i64);
+    let publishing = match new_capsule.visibility.or(defaults.visibility).unwrap_or_default() {
+        Visibility::Private => Publishing::default(),
+        Visibility::Public => Publishing { visibility: Visibility::Public, publish_at: None, time_published: Some(now) },
+    };
+    let tags = clean_tags(new_capsule.tags.as_ref().unwrap_or(&defaults.tags));
+    let retention = retention::check(new_capsule.retention).map_err(|e| errors.push(e.into())).unwrap_or_default();
+    let custom_fields = custom_fields::check(new_capsule.custom_fields.clone()).map_err(|e| errors.push(e.into())).unwrap_or_default();
+    if new_capsule.contributions_close_at.is_some_and(|close_at| close_at < time_until_changed) {
+        errors.push(ApiError::Other(Status::BadRequest, format!("contributions_close_at can't be before the edit window closes at {}", time_until_changed.to_rfc3339())));
     }
-}*/
 
+    match (time_open, delivery, signing) {
+        (Some((time_open, time_open_local)), Some(delivery), Some(signing)) if errors.is_empty() => {
+            Ok(CheckedCapsule { timezone, time_open, time_open_local, delivery, signing, time_until_changed, publishing, tags, retention, custom_fields })
+        },
+        _ => Err(errors),
+    }
+}
 
 #[post("/capsules", format = "json", data = "<capsule_data>")]
-pub fn create_and_update_capsule(capsule_data: Json<NewCapsule>) -> Result<Json<Capsule>, status::Custom<Json<String>>> {
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
-
+pub fn create_and_update_capsule(capsule_data: Payload<NewCapsule>, clock: &State<SharedClock>) -> Result<WithDuplicateOf<Json<Capsule>>, ApiError> {
     let new_capsule = capsule_data.into_inner();
 
-    // Check for contributor existence
-    if !contributors.iter().any(|c| c.id == new_capsule.contributor_id) {
-        return Err(status::Custom(Status::BadRequest, Json("Contributor not found".into())));
+    // Hold the contributor so it cannot be deleted while the capsule is being attached
+    let _contributor_guard = locks::lock_contributor(new_capsule.contributor_id);
+
+    // A repeat of a request that just went through gets the capsule it created
+    if let Some(capsule) = duplicates::find(&new_capsule, clock.now()) {
+        let capsule_id = capsule.id;
+        return Ok(WithDuplicateOf(Json(capsule), Some(capsule_id)));
     }
 
+    let now = clock.now();
+    let checked = check_new_capsule(&new_capsule, now).map_err(|mut errors| errors.remove(0))?;
+
     // Generate a unique ID for the new capsule
-    let id = capsules.iter().max_by_key(|c| c.id).map_or(1, |max| max.id + 1);
+    let id = CAPSULES.next_id();
+    let capsule = insert_capsule(id, &new_capsule, checked, now);
+    duplicates::remember(&new_capsule, capsule.id, now);
+
+    Ok(WithDuplicateOf(Json(capsule), None))
+}
+
+// Adds a checked new capsule to the store and its owner
+fn insert_capsule(id: CapsuleId, new_capsule: &NewCapsule, checked: CheckedCapsule, now: DateTime<Utc>) -> Capsule {
+    let CheckedCapsule { timezone, time_open, time_open_local, delivery, signing, time_until_changed, publishing, tags, retention, custom_fields } = checked;
 
-    // Create the capsule with placeholder data
     let mut capsule = Capsule {
         id,
         name: new_capsule.name.clone(),  // Initial data from POST
         description: new_capsule.description.clone(),  // Initial data from POST
-        time_created: Utc::now(),
-        time_changed: None,
-        time_open: new_capsule.time_open,
-        time_until_changed: Utc::now() + chrono::Duration::weeks(1),
+        time_created: now,
+        time_changed: Some(now),
+        time_open,
+        time_until_changed,
+        contributions_close_at: new_capsule.contributions_close_at,
         contributor_id: new_capsule.contributor_id,
-        item_ids: None,
         version: 1,
+        timezone,
+        time_open_local,
+        delivery,
+        signing,
+        publishing,
+        reveal: None,
+        tags,
+        moderation: None,
+        co_owner_ids: Vec::new(),
+        retention,
+        time_items_deleted: None,
+        custom_fields,
+        opening: None,
     };
 
-    // Simulate a PUT operation by updating the newly created capsule immediately !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-    capsule.name = new_capsule.name.clone();
-    capsule.description = new_capsule.description.clone();
-    capsule.time_changed = Some(Utc::now());  // Update modification time
+    let contributor_name = CONTRIBUTORS.read(capsule.contributor_id, |contributor| contributor.name.clone()).unwrap_or_default();
+    templates::fill_at_creation(&mut capsule, &contributor_name);
 
-    // Add to the list of capsules
-    capsules.push(capsule.clone());
+    // Add to the list of capsules, stored together with its owner
+    database::atomically(|| {
+        CAPSULES.insert(capsule.clone());
+        INDEXES.write().unwrap().link_capsule(capsule.contributor_id, capsule.id);
+
+        // The contributor's capsule list changed with the index
+        CONTRIBUTORS.touch(new_capsule.contributor_id);
+    });
+    capsule
+}
 
-    // Update the contributor's list of capsule IDs
-    if let Some(contributor) = contributors.iter_mut().find(|c| c.id == new_capsule.contributor_id) {
-        if let Some(capsule_ids) = &mut contributor.capsule_ids {
-            capsule_ids.push(capsule.id);
-        } else {
-            contributor.capsule_ids = Some(vec![capsule.id]);
+// Creates a capsule under a UUID the client picked, so offline clients can name capsules
+// before they sync. A repeat of the same PUT returns the capsule it created.
+fn put_new_capsule(cid: &str, new_capsule: NewCapsule, now: DateTime<Utc>) -> PutOutcome {
+    let _claiming = CLAIMING.lock().unwrap();
+    if let Ok(cid) = cid.parse::<CapsuleId>() {
+        let capsule = CAPSULES.get(cid).ok_or(ApiError::CapsuleMissing)?;
+        if capsule.contributor_id != new_capsule.contributor_id {
+            return Err(ApiError::Other(Status::Conflict, format!("Capsule {} already exists with another owner", cid)));
         }
+        return Ok(Either::Left(Json(capsule)));
     }
+    let uuid = Uuid::parse_str(cid).ok().filter(|uuid| !uuid.is_nil())
+        .ok_or_else(|| status::Custom(Status::BadRequest, Json("Capsules created with PUT need a UUID as their id".to_string())))?;
+
+    let _contributor_guard = locks::lock_contributor(new_capsule.contributor_id);
+    let checked = check_new_capsule(&new_capsule, now).map_err(|mut errors| errors.remove(0))?;
+    let id = CAPSULES.next_id();
+    if !ids::claim_uuid(ids::Kind::Capsule, uuid, id.number()) {
+        return Err(ApiError::Other(Status::Conflict, format!("{} is already the id of another record", uuid)));
+    }
+    let capsule = insert_capsule(id, &new_capsule, checked, now);
+    Ok(Either::Right(status::Created::new(format!("/capsules/{}", capsule.id)).body(Json(capsule))))
+}
+
+// A list request: its URI, languages, time format, the data's revision and the time when it matters
+type ListKey = (String, Vec<String>, TimeFormat, u64, Option<i64>);
+
+// Renderings of capsule lists, see coalesce.rs
+static LIST_FLIGHTS: Lazy<Flights<ListKey, Result<RenderedPage, Status>>> = Lazy::new(Flights::new);
+
+// `q` searches the names and descriptions in every language, `contributor_id` limits the
+// list to one owner's capsules, which `sort=custom` puts in the owner's order. `state`
+// keeps the sealed, opening soon (within `soon_days`) or opened capsules.
+#[get("/capsules?<q>&<contributor_id>&<sort>&<state>&<soon_days>&<pagination..>")]
+#[allow(clippy::too_many_arguments)]
+pub fn list_capsules(q: Option<&str>, contributor_id: Option<ContributorId>, sort: Option<&str>, state: Option<&str>, soon_days: Option<i64>, pagination: Pagination, languages: AcceptLanguage, uri: &Origin<'_>, clock: &State<SharedClock>) -> Result<RenderedPage, ApiError> {
+    let custom = match sort {
+        None | Some("id") => false,
+        Some("custom") => true,
+        Some(other) => return Err(ApiError::Other(Status::BadRequest, format!("Unknown sort '{}', use id or custom", other))),
+    };
+    if custom && contributor_id.is_none() {
+        return Err(ApiError::Other(Status::BadRequest, "sort=custom needs a contributor_id".into()));
+    }
+    if let Some(state) = state.filter(|state| !capsule_groups::OPEN_STATES.contains(state)) {
+        return Err(ApiError::Other(Status::BadRequest, format!("Unknown state '{}', use sealed, opening_soon or opened", state)));
+    }
+    let soon = capsule_groups::soon(soon_days)?;
+    if contributor_id.is_some_and(|contributor_id| !CONTRIBUTORS.contains(contributor_id)) {
+        return Err(ApiError::ContributorNotFound);
+    }
+
+    // Identical requests against the same data and in the same time format share one
+    // rendering, `state` also depends on the time
+    let now = clock.now();
+    let key = (uri.to_string(), languages.0.clone(), time_format::current(), store::current_revision(), state.map(|_| now.timestamp()));
+    LIST_FLIGHTS.run(key, || {
+        let query = q.and_then(Query::parse);
+        if query.is_none() && contributor_id.is_none() && state.is_none() {
+            // Clone only the requested page
+            return Paginated::new(&pagination, Collection::Capsules, CAPSULES.len(), |start, per_page| CAPSULES.page(start, per_page))
+                .map(|capsule| capsule.localized(&languages.0))
+                .render();
+        }
 
-    Ok(Json(capsule))
+        let mut capsules = Vec::new();
+        match contributor_id {
+            Some(contributor_id) => {
+                let capsule_ids = INDEXES.read().unwrap().capsules_of(contributor_id);
+                capsules.extend(capsule_ids.into_iter().filter_map(|id| CAPSULES.get(id)));
+            },
+            None => CAPSULES.for_each(|capsule| capsules.push(capsule.clone())),
+        }
+        if let Some(query) = query {
+            capsules.retain(|capsule| query.matches(capsule.name.texts().into_iter().chain(capsule.description.texts())));
+        }
+        if let Some(state) = state {
+            capsules.retain(|capsule| capsule_groups::open_state(capsule, now, soon) == state);
+        }
+        if let Some(contributor_id) = contributor_id.filter(|_| custom) {
+            capsule_order::sort(contributor_id, &mut capsules);
+        }
+        Paginated::new(&pagination, Collection::Capsules, capsules.len(), |start, per_page| capsules.into_iter().skip(start).take(per_page).collect())
+            .map(|capsule| capsule.localized(&languages.0))
+            .render()
+    })
+        .map_err(ApiError::Unprocessable)
 }
 
+// A capsule's JSON, None if it couldn't be rendered
+type RenderedDetail = Option<Arc<String>>;
 
+// Renderings of capsules by their ETag, see coalesce.rs
+static DETAIL_FLIGHTS: Lazy<Flights<(CapsuleId, String), RenderedDetail>> = Lazy::new(Flights::new);
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CapsuleDetail {
+    #[serde(flatten)]
+    capsule: Capsule,
+    #[serde(flatten)]
+    lineage: MergeLineage,
+}
 
-#[get("/capsules?<pagination..>")]
-pub fn list_capsules(pagination: Pagination) -> Result<CustomResponder<Json<Vec<Capsule>>>, Status> {
-    let capsules = CAPSULES.lock().map_err(|_| Status::InternalServerError)?;
+#[get("/capsules/<cid>")]
+pub fn capsule_detail(cid: CapsuleId, languages: AcceptLanguage, caller: Caller, clock: &State<SharedClock>) -> Result<WithChainHash<CachedJson>, Option<Gone<CapsuleId>>> {
+    if caller.0.is_some() {
+        CAPSULES.read(cid, |capsule| reads::record(capsule, &caller, clock.now()));
+    }
 
-    let per_page = pagination.per_page.unwrap_or(10); // Default to 10 items per page if not specified
-    let page = pagination.page.unwrap_or(1); // Default to page 1 if not specified
-    let start = (page - 1) * per_page;
-    let end = start + per_page;
+    // Serve the cached rendering while the capsule is unchanged, the requested
+    // languages are part of the ETag since they change the rendering. Merges into the
+    // capsule change it, so its lineage is cached along.
+    let etag = cache::etag((CAPSULES.revision(cid).ok_or_else(|| tombstones::capsule(cid))?, &languages.0));
+    if let Some(body) = cache::lookup(CacheKind::Capsule, cid, &etag) {
+        return Ok(WithChainHash(CachedJson { body, etag }, hash_chain::head(cid)));
+    }
 
-    let paged_capsules = capsules[start..end.min(capsules.len())].to_vec(); // Safely slice the vector to the page size, handling cases where the range may exceed the vector bounds
+    // Requests arriving together for the same capsule and languages render it once
+    let (capsule, revision) = CAPSULES.get_with_revision(cid).ok_or_else(|| tombstones::capsule(cid))?;
+    let etag = cache::etag((revision, &languages.0));
+    let body = DETAIL_FLIGHTS.run((cid, etag.clone()), || {
+        // An id merged away earlier can be taken again by a PUT, which starts a new capsule
+        let lineage = MergeLineage { merged_into: None, ..merges::lineage(cid) };
+        let detail = CapsuleDetail { capsule: capsule.localized(&languages.0), lineage };
+        let body = serde_json::to_string(&detail).ok()?;
+        Some(cache::store(CacheKind::Capsule, cid, &etag, body))
+    });
+    Ok(WithChainHash(CachedJson { body: body.ok_or(None)?, etag }, hash_chain::head(cid)))
+}
 
-    Ok(CustomResponder {
-        inner: Json(paged_capsules),
-        total_items: capsules.len(),
-        page,
-        per_page,
-    })
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Countdown {
+    #[serde(serialize_with = "time_format::time")]
+    pub time_open: DateTime<Utc>,
+    pub timezone: Option<String>,
+    pub time_open_local: Option<NaiveDateTime>,
+    pub now_local: Option<NaiveDateTime>,
+    pub seconds_remaining: i64,
+    pub is_open: bool,
 }
-/*
-#[get("/capsules")]
-pub fn redirect_to_default() -> Redirect {
-    Redirect::to(uri!(list_capsules: Pagination { page: 1, per_page: 10 }))
-}*/
 
-#[get("/capsules/<cid>")]
-pub fn capsule_detail(cid: u32) -> Result<Option<Json<Capsule>>, Status> {
-    let capsules = CAPSULES.lock().map_err(|_| Status::InternalServerError)?;
-    Ok(capsules.iter().find(|c| c.id == cid).cloned().map(Json))
+#[get("/capsules/<cid>/countdown")]
+pub fn capsule_countdown(cid: CapsuleId, clock: &State<SharedClock>) -> Option<Json<Countdown>> {
+    let capsule = CAPSULES.get(cid)?;
+    let now = clock.now();
+    let tz = capsule.timezone.as_deref().and_then(|name| timezones::parse(name).ok());
+
+    // Stored open times predate the timezone, derive the local time when it's missing
+    let time_open_local = capsule.time_open_local.or_else(|| tz.map(|tz| timezones::to_local(capsule.time_open, tz)));
+
+    Some(Json(Countdown {
+        time_open: capsule.time_open,
+        timezone: capsule.timezone,
+        time_open_local,
+        now_local: tz.map(|tz| timezones::to_local(now, tz)),
+        seconds_remaining: (capsule.time_open - now).num_seconds().max(0),
+        is_open: now >= capsule.time_open,
+    }))
 }
 
+// Version from an If-Match header, as `3` or `"3"`. The cache's weak ETags never match.
+pub struct IfMatch(Option<u32>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let version = request.headers().get_one("If-Match")
+            .and_then(|value| value.trim().trim_matches('"').parse().ok());
+        request::Outcome::Success(IfMatch(version))
+    }
+}
+
+// Moves the capsule's version on in the database, when other instances share it
+fn claim_version(cid: CapsuleId, version: u32) -> Result<(), status::Custom<Json<String>>> {
+    match database::claim_version(cid, version) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(status::Custom(Status::PreconditionFailed, Json("Version mismatch, the capsule was changed on another instance. Please refresh your data.".into()))),
+        Err(e) => {
+            eprintln!("Failed to claim version {} of capsule {}: {}", version, cid, e);
+            Err(status::Custom(Status::ServiceUnavailable, Json("The database can't be reached, try again".into())))
+        },
+    }
+}
+
+// Replaces the editable fields of a capsule written against its current version, given
+// in If-Match or in the body. A new capsule's body creates it, see put_new_capsule.
 #[put("/capsules/<cid>", format = "json", data = "<capsule_data>")]
-pub fn update_capsule(cid: u32, capsule_data: Json<Capsule>) -> Result<Option<Json<Capsule>>, status::Custom<Json<String>>> {
-    let mut capsules = CAPSULES.lock().unwrap();
+pub fn update_capsule(cid: &str, if_match: IfMatch, capsule_data: Json<CapsulePut>, caller: Caller, clock: &State<SharedClock>) -> PutOutcome {
+    let update = match capsule_data.into_inner() {
+        CapsulePut::Update(update) => update,
+        CapsulePut::Create(new_capsule) => return put_new_capsule(cid, new_capsule, clock.now()),
+    };
+    let cid: CapsuleId = cid.parse().map_err(|_| ApiError::CapsuleMissing)?;
+    if if_match.0.is_some_and(|version| version != update.version) {
+        return Err(ApiError::Other(Status::BadRequest, "Conflicting versions provided. Please verify the If-Match header and JSON body version.".into()));
+    }
+    content_policy::check_capsule_name(&update.name)?;
+    content_policy::check_capsule_description(&update.description)?;
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
+
+    // Checked on a copy and claimed before the row is taken, the claim may wait on the
+    // database. The capsule lock keeps the version from moving on in between.
+    let capsule = CAPSULES.get(cid).ok_or(ApiError::CapsuleMissing)?;
+    ownership::check_editor(&capsule, &caller)?;
+    if signatures::is_sealed(&capsule, now) {
+        return Err(ApiError::ModificationExpired);
+    }
+    if capsule.version != update.version {
+        return Err(ApiError::VersionMismatchAt(capsule.version));
+    }
+    let timezone = update.timezone.clone().or_else(|| CONTRIBUTORS.read(capsule.contributor_id, |c| c.timezone.clone()).flatten());
+    let tz = timezone.as_deref().map(timezones::parse).transpose()?;
+    let (time_open, time_open_local) = resolve_time_open(update.time_open, update.time_open_local, tz)
+        .map_err(|e| status::Custom(Status::BadRequest, Json(e.into())))?;
+    if update.contributions_close_at.is_some_and(|close_at| close_at < capsule.time_until_changed) {
+        return Err(ApiError::Other(Status::BadRequest, format!("contributions_close_at can't be before the edit window closes at {}", capsule.time_until_changed.to_rfc3339())));
+    }
+    let retention = update.retention.map(retention::check).transpose()?;
+    let fields = update.custom_fields.clone().map(custom_fields::check).transpose()?;
+    claim_version(cid, capsule.version)?;
 
-    if let Some(capsule) = capsules.iter_mut().find(|c| c.id == cid) {
-        if Utc::now() > capsule.time_until_changed {
-            return Err(status::Custom(Status::BadRequest, Json("The modification period for this capsule has expired".to_string())));
+    let result = CAPSULES.try_update(cid, |capsule| {
+        if capsule.version != update.version {
+            return Err(ApiError::VersionMismatchAt(capsule.version));
         }
-        *capsule = capsule_data.into_inner();
-        capsule.time_changed = Some(Utc::now());
-        Ok(Some(Json(capsule.clone())))
-    } else {
-        Err(status::Custom(Status::NotFound, Json("Capsule not found".to_string())))
+        capsule.name = update.name.clone();
+        capsule.description = update.description.clone();
+        capsule.time_open = time_open;
+        capsule.time_open_local = time_open_local;
+        capsule.timezone = timezone;
+        capsule.contributions_close_at = update.contributions_close_at;
+        if let Some(tags) = &update.tags {
+            capsule.tags = clean_tags(tags);
+        }
+        if let Some(retention) = retention {
+            capsule.retention = retention;
+        }
+        if let Some(fields) = fields {
+            capsule.custom_fields = fields;
+        }
+        capsule.time_changed = Some(now);
+        capsule.version += 1;
+        field_history::forget(cid);  // A full replace may change anything, older patches can't be rebased
+        Ok(capsule.clone())
+    });
+
+    match result {
+        Some(Ok(capsule)) => Ok(Either::Left(Json(capsule))),
+        Some(Err(e)) => Err(e),
+        None => Err(ApiError::CapsuleMissing),
     }
 }
 
-#[patch("/capsules/<cid>?<etag>", format = "json", data = "<capsule_data>")]
-pub fn patch_capsule(cid: u32, etag: Option<u32>, capsule_data: Json<CapsulePatch>) -> Result<Json<Capsule>, status::Custom<Json<String>>> {
-    let mut capsules = CAPSULES.lock().unwrap();
+// With `?auto_merge=true` a patch made against an older version is applied on top of
+// the current one, as long as the fields it changes weren't changed since
+#[patch("/capsules/<cid>?<etag>&<auto_merge>", format = "json", data = "<capsule_data>")]
+pub fn patch_capsule(cid: CapsuleId, etag: Option<u32>, auto_merge: Option<bool>, capsule_data: Json<CapsulePatch>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<Capsule>, ApiError> {
+    capsule_data.name.as_ref().map_or(Ok(()), content_policy::check_capsule_name)?;
+    capsule_data.description.as_ref().map_or(Ok(()), content_policy::check_capsule_description)?;
+    let _guard = locks::lock_capsule(cid);
+    let time_now = clock.now();
+
+    // Checked on a copy and claimed before the row is taken, as in update_capsule
+    let capsule = CAPSULES.get(cid).ok_or(ApiError::CapsuleMissing)?;
+    ownership::check_editor(&capsule, &caller)?;
+    if signatures::is_sealed(&capsule, time_now) {
+        return Err(ApiError::ModificationExpired);
+    }
 
-    if let Some(capsule) = capsules.iter_mut().find(|c| c.id == cid) {
-        if Utc::now() > capsule.time_until_changed {
-            return Err(status::Custom(Status::BadRequest, Json("The modification period for this capsule has expired".into())));
-        }
+    // Determine the version to check against and handle conflicts if both are provided
+    match (etag, capsule_data.version) {
+        (Some(e), Some(v)) if e != v => {
+            return Err(ApiError::Other(Status::BadRequest, "Conflicting versions provided. Please verify the ETag and JSON body version.".into()));
+        },
+        _ => {}
+    }
 
-        // Determine the version to check against and handle conflicts if both are provided
-        match (etag, capsule_data.version) {
-            (Some(e), Some(v)) if e != v => {
-                return Err(status::Custom(Status::BadRequest, Json("Conflicting versions provided. Please verify the ETag and JSON body version.".into())));
-            },
-            _ => {}
-        }
+    let version_to_check = etag.or(capsule_data.version);
 
-        let version_to_check = etag.or(capsule_data.version);
+    if let Some(version) = version_to_check {
+        if capsule.version != version {
+            if !auto_merge.unwrap_or(false) {
+                return Err(ApiError::VersionMismatch);
+            }
 
-        if let Some(version) = version_to_check {
-            if capsule.version != version {
-                return Err(status::Custom(Status::Conflict, Json("Version mismatch. Please refresh your data.".into())));
+            // Fields that already hold the patched value don't conflict, so retrying a
+            // patch that went through is harmless
+            let differs = [
+                ("name", capsule_data.name.as_ref().is_some_and(|name| *name != capsule.name)),
+                ("description", capsule_data.description.as_ref().is_some_and(|description| *description != capsule.description)),
+            ];
+            let changed = field_history::changed_since(cid, version, capsule.version);
+            let conflicts: Vec<&str> = differs.iter()
+                .filter(|(field, differs)| *differs && changed.as_ref().is_none_or(|changed| changed.contains(field)))
+                .map(|(field, _)| *field)
+                .collect();
+            if !conflicts.is_empty() {
+                return Err(ApiError::Other(Status::Conflict, format!("Version mismatch, {} changed since version {}. Please refresh your data.", conflicts.join(" and "), version)));
+            }
+            if !differs.iter().any(|(_, differs)| *differs) {
+                return Ok(Json(capsule));
             }
-        } else {
-            return Err(status::Custom(Status::BadRequest, Json("Version number is required.".into())));
         }
+    } else {
+        return Err(ApiError::VersionRequired);
+    }
+
+    if capsule_data.name.is_none() && capsule_data.description.is_none() {
+        return Err(ApiError::Other(Status::BadRequest, "No valid fields provided for update.".into()));
+    }
+    claim_version(cid, capsule.version)?;
 
-        let time_now = Utc::now();
-        let mut updated = false;
+    CAPSULES.try_update(cid, |current| {
+        if current.version != capsule.version {
+            return Err(ApiError::VersionMismatch);
+        }
+        let mut updated = Vec::new();
 
         if let Some(ref name) = capsule_data.name {
-            capsule.name = name.clone();
-            updated = true;
+            current.name = name.clone();
+            updated.push("name");
         }
 
         if let Some(ref description) = capsule_data.description {
-            capsule.description = description.clone();
-            updated = true;
+            current.description = description.clone();
+            updated.push("description");
         }
 
-        if updated {
-            capsule.time_changed = Some(time_now);
-            capsule.version += 1; // Increment the version counter as the capsule has been updated.
-            Ok(Json(capsule.clone()))
-        } else {
-            Err(status::Custom(Status::BadRequest, Json("No valid fields provided for update.".into())))
-        }
-    } else {
-        Err(status::Custom(Status::NotFound, Json("Capsule not found.".into())))
-    }
+        current.time_changed = Some(time_now);
+        current.version += 1; // Increment the version counter as the capsule has been updated.
+        field_history::record(cid, current.version, updated);
+        Ok(Json(current.clone()))
+    }).unwrap_or(Err(ApiError::CapsuleMissing))
 }
 
+// With `?dry_run=true` nothing is removed, the response lists what would be.
+// `?items=detach` keeps the capsule's items, see orphans.rs
+#[delete("/capsules/<cid>?<dry_run>&<items>")]
+pub fn delete_capsule(cid: CapsuleId, dry_run: Option<bool>, items: Option<&str>, caller: Caller, clock: &State<SharedClock>) -> Result<Either<Status, Json<DeletionPlan>>, ApiError> {
+    let items_on_delete = orphans::parse(items)?;
+    let unsorted_capsule_id = match items_on_delete {
+        ItemsOnDelete::Delete => None,
+        ItemsOnDelete::Detach => orphans::unsorted_capsule(cid),
+    };
 
+    // The owner has to be locked before the capsules, see locks.rs
+    let contributor_id = match CAPSULES.read(cid, |c| c.contributor_id) {
+        Some(contributor_id) => contributor_id,
+        None => return Err(ApiError::CapsuleMissing),
+    };
+    let contributor_guard = locks::lock_contributor(contributor_id);
+    let capsule_guards = locks::lock_capsules(&[cid].into_iter().chain(unsorted_capsule_id).collect::<Vec<_>>());
 
-#[delete("/capsules/<cid>")]
-pub fn delete_capsule(cid: u32) -> Result<Status, status::Custom<Json<String>>> {
-    let mut capsules = CAPSULES.lock().unwrap();
-    let mut items = ITEMS.lock().unwrap(); // Lock the items data
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
-
-    if let Some(index) = capsules.iter().position(|c| c.id == cid) {
-        let contributor_id = capsules[index].contributor_id;
+    if let Some(Err(e)) = CAPSULES.read(cid, |capsule| ownership::check_editor(capsule, &caller)) {
+        return Err(e.into());
+    }
+    // Like other structural changes, deleting ends with the edit window
+    if CAPSULES.read(cid, |capsule| signatures::is_sealed(capsule, clock.now())) == Some(true) {
+        return Err(ApiError::ModificationExpired);
+    }
 
-        // Retrieve the item IDs before removing the capsule
-        let item_ids_to_remove = if let Some(item_ids) = &capsules[index].item_ids {
-            item_ids.clone()
-        } else {
-            Vec::new()
+    if dry_run.unwrap_or(false) {
+        return match CAPSULES.read(cid, |c| c.contributor_id) {
+            Some(owner_id) => {
+                let plan = DeletionPlan::capsules(owner_id, vec![cid]);
+                let plan = match items_on_delete {
+                    ItemsOnDelete::Delete => plan,
+                    ItemsOnDelete::Detach => plan.detaching_items(unsorted_capsule_id),
+                };
+                Ok(Either::Right(Json(plan)))
+            },
+            None => Err(ApiError::CapsuleMissing),
         };
+    }
 
-        // Remove the capsule
-        capsules.remove(index);
-
-        // Remove all items that belong to this capsule
-        items.retain(|item| !item_ids_to_remove.contains(&item.id));
-
-        // Update the contributor's list of capsule IDs
-        if let Some(contributor) = contributors.iter_mut().find(|c| c.id == contributor_id) {
-            if let Some(capsule_ids) = &mut contributor.capsule_ids {
-                let pos = capsule_ids.iter().position(|&x| x == cid);
-                if let Some(pos) = pos {
-                    capsule_ids.remove(pos);
+    // Remove the capsule, stored together with its items
+    database::atomically(|| if let Some(capsule) = CAPSULES.remove(cid) {
+        // Remove or keep all items that belong to this capsule
+        let item_ids = INDEXES.write().unwrap().drop_capsule(capsule.contributor_id, cid);
+        match items_on_delete {
+            ItemsOnDelete::Delete => {
+                for item_id in item_ids {
+                    ITEMS.remove(item_id);
                 }
-            }
+            },
+            ItemsOnDelete::Detach => orphans::detach(cid, item_ids, unsorted_capsule_id, clock.now()),
         }
 
-        Ok(Status::NoContent)
+        // The contributor's capsule list changed with the index
+        CONTRIBUTORS.touch(capsule.contributor_id);
+
+        drop(capsule_guards);
+        drop(contributor_guard);
+        CAPSULE_LOCKS.forget(cid);
+        field_history::forget(cid);
+        reads::forget(cid);
+
+        Ok(Either::Left(Status::NoContent))
     } else {
-        Err(status::Custom(Status::NotFound, Json("Capsule not found".to_string())))
+        Err(ApiError::CapsuleMissing)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_trimmed_without_blanks_or_repeats() {
+        let tags = [" family ", "", "2024", "family", "  "].map(String::from);
+        assert_eq!(clean_tags(&tags), ["family", "2024"]);
+    }
+
+    #[test]
+    fn edit_windows_last_up_to_a_year() {
+        assert!(matches!(check_edit_window(1), Ok(1)));
+        assert!(matches!(check_edit_window(MAX_EDIT_WINDOW_DAYS), Ok(MAX_EDIT_WINDOW_DAYS)));
+        assert!(check_edit_window(0).is_err());
+        assert!(check_edit_window(MAX_EDIT_WINDOW_DAYS + 1).is_err());
+    }
+
+    #[test]
+    fn opening_times_are_given_once() {
+        let warsaw: Tz = "Europe/Warsaw".parse().unwrap();
+        let time: DateTime<Utc> = "2030-06-01T10:00:00Z".parse().unwrap();
+        let local: NaiveDateTime = "2030-06-01T12:00:00".parse().unwrap();
+        assert_eq!(resolve_time_open(Some(time), None, Some(warsaw)), Ok((time, Some(local))));
+        assert_eq!(resolve_time_open(None, Some(local), Some(warsaw)), Ok((time, Some(local))));
+        assert_eq!(resolve_time_open(Some(time), None, None), Ok((time, None)));
+        assert!(resolve_time_open(None, Some(local), None).is_err());
+        assert!(resolve_time_open(Some(time), Some(local), Some(warsaw)).is_err());
+        assert!(resolve_time_open(None, None, None).is_err());
     }
 }
@@ -0,0 +1,71 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::http::uri::Origin;
+use rocket::response::Responder;
+use rocket::{Data, Request, Response};
+use rand::Rng;
+use std::time::Duration;
+
+use crate::config::{self, ChaosConfig};
+use crate::error_messages::ApiError;
+
+// Fault decided for a request in on_request and applied in on_response
+#[derive(Clone, Copy)]
+struct InjectedFault(Option<Status>);
+
+// Fairing injecting artificial latency, 500s and version conflicts on the configured routes
+pub struct Chaos;
+
+fn applies_to(chaos: &ChaosConfig, path: &str) -> bool {
+    chaos.enabled && (chaos.routes.is_empty() || chaos.routes.iter().any(|prefix| path.starts_with(prefix.as_str())))
+}
+
+fn roll(percent: f64) -> bool {
+    percent > 0.0 && rand::thread_rng().gen_range(0.0..100.0) < percent
+}
+
+#[rocket::async_trait]
+impl Fairing for Chaos {
+    fn info(&self) -> Info {
+        Info { name: "Chaos fault injection", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let chaos = &config::get().chaos;
+        if !applies_to(chaos, request.uri().path().as_str()) {
+            return;
+        }
+
+        if roll(chaos.latency_percent) {
+            rocket::tokio::time::sleep(Duration::from_millis(chaos.latency_ms)).await;
+        }
+
+        let fault = if roll(chaos.error_percent) {
+            Some(Status::InternalServerError)
+        } else if matches!(request.method(), Method::Put | Method::Patch) && roll(chaos.conflict_percent) {
+            Some(Status::Conflict)
+        } else {
+            None
+        };
+
+        if fault.is_some() {
+            // Route the request nowhere so no handler runs and no state is changed
+            request.set_uri(Origin::parse("/__chaos").unwrap());
+        }
+        request.local_cache(|| InjectedFault(fault));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let InjectedFault(Some(status)) = *request.local_cache(|| InjectedFault(None)) {
+            let error = if status == Status::Conflict {
+                ApiError::VersionMismatch
+            } else {
+                ApiError::Other(status, "Injected internal server error".into())
+            };
+            if let Ok(injected) = error.respond_to(request) {
+                response.merge(injected);
+            }
+            response.set_raw_header("X-Chaos-Injected", "true");
+        }
+    }
+}
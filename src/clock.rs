@@ -0,0 +1,149 @@
+// Source of the current time for the modification window and opening rules.
+//
+// Handlers take the clock from managed state instead of calling `Utc::now()` so those
+// rules can be exercised deterministically. Production uses the system clock; with
+// `adjustable_clock = true` the time can be moved or frozen through /admin/clock.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, RwLock};
+
+use crate::time_format;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+// Shared handle kept in Rocket's managed state
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Adjustment {
+    offset: Duration,                   // Added to the system time
+    frozen_at: Option<DateTime<Utc>>,   // Fixed time, the clock doesn't move while set
+}
+
+// Clock for development and tests that can be shifted or frozen at runtime
+#[derive(Default)]
+pub struct AdjustableClock {
+    adjustment: RwLock<Adjustment>,
+}
+
+impl Clock for AdjustableClock {
+    fn now(&self) -> DateTime<Utc> {
+        let adjustment = *self.adjustment.read().unwrap();
+        adjustment.frozen_at.unwrap_or_else(|| Utc::now() + adjustment.offset)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClockUpdate {
+    pub now: Option<DateTime<Utc>>,    // Jump to this time
+    pub advance_seconds: Option<i64>,  // Move the clock by this much, can be negative
+    #[serde(default)]
+    pub frozen: bool,                  // Stop the clock at the resulting time
+    #[serde(default)]
+    pub reset: bool,                   // Back to the system time, other fields are ignored
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClockState {
+    #[serde(serialize_with = "time_format::time")]
+    pub now: DateTime<Utc>,
+    pub offset_seconds: i64,
+    pub frozen: bool,
+}
+
+impl AdjustableClock {
+    fn state(&self) -> ClockState {
+        let adjustment = *self.adjustment.read().unwrap();
+        ClockState {
+            now: self.now(),
+            offset_seconds: (adjustment.frozen_at.map_or(adjustment.offset, |at| at - Utc::now()).num_milliseconds() as f64 / 1000.0).round() as i64,
+            frozen: adjustment.frozen_at.is_some(),
+        }
+    }
+
+    fn apply(&self, update: &ClockUpdate) {
+        if update.reset {
+            *self.adjustment.write().unwrap() = Adjustment::default();
+            return;
+        }
+        let mut target = update.now.unwrap_or_else(|| self.now());
+        if let Some(seconds) = update.advance_seconds {
+            target += Duration::seconds(seconds);
+        }
+        *self.adjustment.write().unwrap() = if update.frozen {
+            Adjustment { offset: Duration::zero(), frozen_at: Some(target) }
+        } else {
+            Adjustment { offset: target - Utc::now(), frozen_at: None }
+        };
+    }
+}
+
+// The adjustable clock behind /admin/clock, None when the system clock is used
+pub struct ClockControl(pub Option<Arc<AdjustableClock>>);
+
+fn not_adjustable() -> status::Custom<Json<String>> {
+    status::Custom(Status::NotFound, Json("The clock is not adjustable, start the server with adjustable_clock = true".into()))
+}
+
+#[get("/admin/clock")]
+pub fn get_clock(control: &State<ClockControl>) -> Result<Json<ClockState>, status::Custom<Json<String>>> {
+    let clock = control.0.as_ref().ok_or_else(not_adjustable)?;
+    Ok(Json(clock.state()))
+}
+
+#[post("/admin/clock", format = "json", data = "<update>")]
+pub fn set_clock(update: Json<ClockUpdate>, control: &State<ClockControl>) -> Result<Json<ClockState>, status::Custom<Json<String>>> {
+    let clock = control.0.as_ref().ok_or_else(not_adjustable)?;
+    clock.apply(&update);
+    Ok(Json(clock.state()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(now: Option<DateTime<Utc>>, advance_seconds: Option<i64>, frozen: bool) -> ClockUpdate {
+        ClockUpdate { now, advance_seconds, frozen, reset: false }
+    }
+
+    #[test]
+    fn the_clock_is_moved_and_frozen() {
+        let clock = AdjustableClock::default();
+        let start = "2030-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        clock.apply(&update(Some(start), None, true));
+        assert_eq!(clock.now(), start);
+        clock.apply(&update(None, Some(3600), true));
+        assert_eq!(clock.now(), start + Duration::hours(1));
+        assert!(clock.state().frozen);
+
+        // Unfrozen it runs on from there
+        clock.apply(&update(None, None, false));
+        let offset = clock.now() - (start + Duration::hours(1));
+        assert!(offset >= Duration::zero() && offset < Duration::seconds(5));
+    }
+
+    #[test]
+    fn reset_goes_back_to_the_system_time() {
+        let clock = AdjustableClock::default();
+        clock.apply(&update(None, Some(-86400), false));
+        assert_eq!(clock.state().offset_seconds, -86400);
+        clock.apply(&ClockUpdate { now: None, advance_seconds: None, frozen: false, reset: true });
+        let state = clock.state();
+        assert_eq!((state.offset_seconds, state.frozen), (0, false));
+    }
+}
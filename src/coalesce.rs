@@ -0,0 +1,77 @@
+// Single-flight for hot reads. Identical GETs arriving together, e.g. many clients polling
+// the same capsule or list page, would each read and render the same rows. Here they're
+// keyed by the route, its parameters and the version of the data they're rendered from:
+// the first one renders, the others wait for it and share its result. Nothing is kept once
+// the rendering is done, that's what cache.rs is for.
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+pub struct Flights<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+// Takes a rendering out of flight once it's done, also when it panicked
+struct Landing<'a, K: Eq + Hash, V> {
+    flights: &'a Flights<K, V>,
+    key: &'a K,
+    cell: &'a Arc<OnceCell<V>>,
+}
+
+impl<K: Eq + Hash, V> Drop for Landing<'_, K, V> {
+    fn drop(&mut self) {
+        let mut in_flight = self.flights.in_flight.lock().unwrap();
+        if in_flight.get(self.key).is_some_and(|cell| Arc::ptr_eq(cell, self.cell)) {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Flights<K, V> {
+    pub fn new() -> Self {
+        Flights { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    // The result of `render`, or of the identical rendering already in flight
+    pub fn run(&self, key: K, render: impl FnOnce() -> V) -> V {
+        let cell = self.in_flight.lock().unwrap().entry(key.clone()).or_default().clone();
+        let _landing = Landing { flights: self, key: &key, cell: &cell };
+        cell.get_or_init(render).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn identical_renderings_in_flight_are_shared() {
+        let flights = Flights::new();
+        let renders = AtomicU32::new(0);
+        let started = Barrier::new(2);
+        thread::scope(|scope| {
+            let first = scope.spawn(|| flights.run("page", || {
+                started.wait();
+                thread::sleep(Duration::from_millis(200));
+                renders.fetch_add(1, Ordering::SeqCst)
+            }));
+            started.wait();
+            let second = flights.run("page", || renders.fetch_add(1, Ordering::SeqCst));
+            assert_eq!((first.join().unwrap(), second), (0, 0));
+        });
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn finished_renderings_are_not_kept() {
+        let flights = Flights::new();
+        assert_eq!(flights.run(1, || "first"), "first");
+        assert_eq!(flights.run(1, || "second"), "second");
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+// Cold storage for capsules opened long ago. Archiving moves the content of every item
+// (description, metadata and its local file) to `cold_storage.dir` and leaves a stub in
+// the item store, so the hot store stays small over the years. Reading a stubbed item
+// starts a restore, which the scheduler completes once the cold tier has had
+// `restore_delay_secs` to bring the content back. Capsules of a contributor with a data
+// residency region are archived under that region's storage root.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::{self, status, Responder, Response};
+use rocket::{Request, State};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::capsules::CAPSULES;
+use crate::clock::{Clock, SharedClock};
+use crate::config;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, EntityId, ItemId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::locks;
+use crate::messages::Message;
+use crate::residency;
+use crate::store;
+use crate::time_format;
+
+// What is left of an item's content while it's in cold storage
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ColdStub {
+    #[serde(serialize_with = "time_format::time")]
+    pub archived_at: DateTime<Utc>,
+}
+
+// The content of an item in the cold tier, one file per item
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ColdRecord {
+    #[serde(serialize_with = "time_format::time")]
+    archived_at: DateTime<Utc>,
+    description: String,
+    metadata: Value,
+    file: Option<PathBuf>,  // Where the item's file went, if it had a local one
+    #[serde(default)]
+    message: Option<Message>,  // The text of a message item, which has no file
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ColdArchive {
+    pub capsule_id: CapsuleId,
+    pub archived_item_ids: Vec<ItemId>,
+    pub moved_files: usize,
+    pub failed: Vec<String>,  // Items left in the hot store, with the reason
+}
+
+// Answer to reading a stubbed item, with Retry-After until the restore is done
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Restoring {
+    pub item_id: ItemId,
+    pub capsule_id: CapsuleId,
+    #[serde(serialize_with = "time_format::time")]
+    pub archived_at: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::time")]
+    pub restore_ready_at: DateTime<Utc>,
+    #[serde(skip)]
+    retry_after: i64,
+}
+
+impl<'r> Responder<'r, 'static> for Restoring {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let retry_after = self.retry_after;
+        Response::build_from(status::Accepted(Json(self)).respond_to(request)?)
+            .raw_header("Retry-After", retry_after.to_string())
+            .ok()
+    }
+}
+
+// Restores requested so far, by the time the cold tier has them ready
+static RESTORES: Lazy<Mutex<BTreeMap<ItemId, DateTime<Utc>>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// Named by the stored number, the same with either id strategy, and not by capsule since
+// a stubbed item can still be merged or moved into another one
+fn cold_path(dir: &Path, item_id: ItemId, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", item_id.number(), extension))
+}
+
+// The record of an archived item, in whichever region it was archived
+fn find_record(item_id: ItemId) -> io::Result<PathBuf> {
+    residency::all_dirs(&config::get().cold_storage.dir).into_iter()
+        .map(|dir| cold_path(&dir, item_id, "json"))
+        .find(|path| path.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No cold storage record of item {}", item_id)))
+}
+
+fn is_local_file(path: &str) -> bool {
+    !path.starts_with("http://") && !path.starts_with("https://") && Path::new(path).is_file()
+}
+
+// Renames, or copies when the cold tier is on another file system
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn stub(item: &mut Item, archived_at: DateTime<Utc>) {
+    item.description.clear();
+    item.metadata = Value::Null;
+    item.message = None;
+    item.cold = Some(ColdStub { archived_at });
+}
+
+// Whether the capsule opened at least `cold_storage.after_days` ago
+pub fn is_eligible(time_open: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    now - time_open >= Duration::days(config::get().cold_storage.after_days.max(0))
+}
+
+// Moves one item's content out, returns whether a file was moved with it.
+// The caller holds the lock of the item's capsule.
+fn archive_item(item: &Item, now: DateTime<Utc>) -> io::Result<bool> {
+    let dir = residency::capsule_dir(&config::get().cold_storage.dir, item.id_capsule).map_err(io::Error::other)?;
+    let path = cold_path(&dir, item.id, "json");
+    let file = is_local_file(&item.path).then(|| cold_path(&dir, item.id, "data"));
+    if let Some(file) = &file {
+        move_file(Path::new(&item.path), file)?;
+    }
+    let record = ColdRecord { archived_at: now, description: item.description.clone(), metadata: item.metadata.clone(), file: file.clone(), message: item.message.clone() };
+    let written = fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&path, store::storing(|| serde_json::to_vec(&record)).unwrap_or_default()));
+    if let Err(e) = written {
+        // Put the file back, the item keeps its content
+        if let Some(file) = &file {
+            let _ = move_file(file, Path::new(&item.path));
+        }
+        return Err(e);
+    }
+    ITEMS.update(item.id, |item| stub(item, now));
+    Ok(file.is_some())
+}
+
+// Brings one item's content back from the cold tier
+fn restore_item(item_id: ItemId) -> io::Result<()> {
+    let Some(item) = ITEMS.get(item_id).filter(|item| item.cold.is_some()) else { return Ok(()) };
+    let _guard = locks::lock_capsule(item.id_capsule);
+    let path = find_record(item_id)?;
+    let record: ColdRecord = serde_json::from_slice(&fs::read(&path)?).map_err(io::Error::other)?;
+    if let Some(file) = &record.file {
+        move_file(file, Path::new(&item.path))?;
+    }
+    ITEMS.update(item_id, |item| {
+        item.description = record.description;
+        item.metadata = record.metadata;
+        item.message = record.message;
+        item.cold = None;
+    });
+    fs::remove_file(&path)
+}
+
+// Archives every item of a capsule still in the hot store
+fn archive_capsule(capsule_id: CapsuleId, now: DateTime<Utc>) -> ColdArchive {
+    let _guard = locks::lock_capsule(capsule_id);
+    let mut archive = ColdArchive { capsule_id, archived_item_ids: Vec::new(), moved_files: 0, failed: Vec::new() };
+    let item_ids = INDEXES.read().unwrap().items_of(capsule_id);
+    for item in item_ids.into_iter().filter_map(|id| ITEMS.get(id)).filter(|item| item.cold.is_none()) {
+        match archive_item(&item, now) {
+            Ok(moved_file) => {
+                archive.archived_item_ids.push(item.id);
+                archive.moved_files += moved_file as usize;
+            },
+            Err(e) => archive.failed.push(format!("Item {}: {}", item.id, e)),
+        }
+    }
+    archive
+}
+
+// Asks the cold tier for a stubbed item, once
+pub fn request_restore(item: &Item, now: DateTime<Utc>) -> Option<Restoring> {
+    let stub = item.cold.as_ref()?;
+    let ready_at = *RESTORES.lock().unwrap().entry(item.id)
+        .or_insert_with(|| now + Duration::seconds(config::get().cold_storage.restore_delay_secs.min(i64::MAX as u64) as i64));
+    Some(Restoring {
+        item_id: item.id,
+        capsule_id: item.id_capsule,
+        archived_at: stub.archived_at,
+        restore_ready_at: ready_at,
+        retry_after: (ready_at - now).num_seconds().max(1),
+    })
+}
+
+// Stubs the items archived before the last restart and drops the records of items
+// deleted since, once the items are loaded
+pub fn start() {
+    let entries = residency::all_dirs(&config::get().cold_storage.dir).into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten());
+    for entry in entries {
+        let path = entry.path();
+        let Some(item_id) = path.extension().filter(|ext| *ext == "json")
+            .and_then(|_| path.file_stem()?.to_str()?.parse().ok())
+            .map(ItemId::from_number) else { continue };
+        let record: ColdRecord = match fs::read(&path).map(|content| serde_json::from_slice(&content)) {
+            Ok(Ok(record)) => record,
+            _ => {
+                eprintln!("Skipping unreadable cold storage record {}", path.display());
+                continue;
+            },
+        };
+        if ITEMS.update(item_id, |item| stub(item, record.archived_at)).is_none() {
+            if let Some(file) = &record.file {
+                let _ = fs::remove_file(file);
+            }
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+// Completes the restores that are ready. Called by the scheduler on every tick.
+pub fn restore_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let ready: Vec<ItemId> = RESTORES.lock().unwrap().iter()
+        .filter(|(_, ready_at)| **ready_at <= now)
+        .map(|(&item_id, _)| item_id)
+        .collect();
+    for item_id in ready {
+        match restore_item(item_id) {
+            Ok(()) => {
+                RESTORES.lock().unwrap().remove(&item_id);
+            },
+            Err(e) => eprintln!("Failed to restore item {} from cold storage: {}", item_id, e),
+        }
+    }
+}
+
+// With `cold_storage.auto`, archives the capsules that have become eligible. Run by the
+// scheduler in the `cold_storage` maintenance window, see schedules.rs.
+pub fn archive_due(clock: &dyn Clock) {
+    if !config::get().cold_storage.auto {
+        return;
+    }
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if is_eligible(capsule.time_open, now) {
+            due.push(capsule.id);
+        }
+    });
+    for capsule_id in due {
+        let has_hot_items = INDEXES.read().unwrap().items_of(capsule_id).into_iter()
+            .any(|id| ITEMS.get(id).is_some_and(|item| item.cold.is_none()));
+        if has_hot_items {
+            for failure in archive_capsule(capsule_id, now).failed {
+                eprintln!("Cold storage of capsule {}: {}", capsule_id, failure);
+            }
+        }
+    }
+}
+
+#[post("/capsules/<cid>/cold-storage")]
+pub fn archive_to_cold_storage(cid: CapsuleId, clock: &State<SharedClock>) -> Result<Json<ColdArchive>, ApiError> {
+    let now = clock.now();
+    let time_open = CAPSULES.read(cid, |capsule| capsule.time_open)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    if !is_eligible(time_open, now) {
+        let from = time_open + Duration::days(config::get().cold_storage.after_days.max(0));
+        return Err(ApiError::Other(Status::Conflict, format!("Capsule {} can be moved to cold storage from {}", cid, from.to_rfc3339())));
+    }
+    Ok(Json(archive_capsule(cid, now)))
+}
@@ -0,0 +1,152 @@
+// Gzip and brotli compression of responses, picked from the client's Accept-Encoding.
+// Only bodies of a known size are compressed, streamed ones (JSON streams, zip
+// downloads) are passed through untouched.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::{Request, Response};
+use flate2::write::GzEncoder;
+use std::io::{Cursor, Write};
+
+use crate::config::{self, CompressionConfig};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+fn applies_to(compression: &CompressionConfig, path: &str) -> bool {
+    (compression.routes.is_empty() || compression.routes.iter().any(|prefix| path.starts_with(prefix.as_str())))
+        && !compression.exclude.iter().any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+// The accepted encoding with the highest q-value, brotli winning ties
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let (mut brotli, mut gzip, mut any) = (None, None, None);
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "br" => brotli = Some(q),
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "*" => any = Some(q),
+            _ => {},
+        }
+    }
+    for (encoding, q) in [(Encoding::Brotli, brotli.or(any)), (Encoding::Gzip, gzip.or(any))] {
+        if let Some(q) = q.filter(|&q| q > 0.0) {
+            if best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compress(encoding: Encoding, body: &[u8], compression: &CompressionConfig) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(compression.gzip_level.min(9)));
+            encoder.write_all(body)?;
+            encoder.finish()
+        },
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, compression.brotli_quality.min(11), 22);
+            encoder.write_all(body)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        },
+    }
+}
+
+// Fairing compressing large enough responses of the configured routes and content types
+pub struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info { name: "Response compression", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let compression = &config::get().compression;
+        if !compression.enabled
+            || [Status::NoContent, Status::NotModified].contains(&response.status())
+            || response.headers().contains("Content-Encoding")
+            || !applies_to(compression, request.uri().path().as_str())
+        {
+            return;
+        }
+        let compressible = response.content_type().is_some_and(|content_type| {
+            let media_type = format!("{}/{}", content_type.top(), content_type.sub()).to_ascii_lowercase();
+            compression.content_types.iter().any(|allowed| allowed.eq_ignore_ascii_case(&media_type))
+        });
+        let size = response.body().preset_size();
+        if !compressible || size.is_none_or(|size| size < compression.min_size) {
+            return;
+        }
+
+        // The body depends on Accept-Encoding from here on, also when it's sent as is
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        let Some(encoding) = request.headers().get("Accept-Encoding").find_map(negotiate) else { return };
+
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to read the response body of {} for compression: {}", request.uri(), e);
+                return;
+            },
+        };
+        match compress(encoding, &body, compression) {
+            Ok(compressed) if compressed.len() < body.len() => {
+                response.set_raw_header("Content-Encoding", encoding.name());
+                response.set_sized_body(compressed.len(), Cursor::new(compressed));
+            },
+            _ => response.set_sized_body(body.len(), Cursor::new(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(accept_encoding: &str) -> Option<&'static str> {
+        negotiate(accept_encoding).map(Encoding::name)
+    }
+
+    #[test]
+    fn the_preferred_encoding_is_picked() {
+        assert_eq!(name("gzip, br"), Some("br"));
+        assert_eq!(name("gzip;q=1.0, br;q=0.5"), Some("gzip"));
+        assert_eq!(name("x-gzip"), Some("gzip"));
+        assert_eq!(name("*"), Some("br"));
+        assert_eq!(name("br;q=0, *;q=0.1"), Some("gzip"));
+        assert_eq!(name("identity"), None);
+        assert_eq!(name("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn routes_are_picked_by_prefix() {
+        let all = CompressionConfig::default();
+        assert!(applies_to(&all, "/capsules"));
+        let some = CompressionConfig { routes: vec!["/capsules".into()], exclude: vec!["/capsules/1/widget".into()], ..all };
+        assert!(applies_to(&some, "/capsules/1"));
+        assert!(!applies_to(&some, "/capsules/1/widget.svg"));
+        assert!(!applies_to(&some, "/contributors"));
+    }
+}
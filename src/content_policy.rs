@@ -0,0 +1,119 @@
+// Rules for the text of capsules and items, checked when they are created or changed:
+// length limits per field and filters for banned content. Each filter is a
+// ContentFilter, so other checks (an external moderation service, say) can be added to
+// FILTERS later without touching the handlers. A broken rule answers 422 naming the
+// field and the rule.
+use rocket::serde::json::Json;
+use rocket::http::Status;
+use rocket::response::status;
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+
+use crate::config;
+use crate::i18n::LocalizedText;
+
+pub trait ContentFilter: Send + Sync {
+    // Name of the rule in error messages, as in the config
+    fn rule(&self) -> &'static str;
+    // What in the text breaks the rule, if anything
+    fn find(&self, text: &str) -> Option<String>;
+}
+
+// Whole words from `banned_words`, in any case
+struct BannedWords(Regex);
+
+impl ContentFilter for BannedWords {
+    fn rule(&self) -> &'static str {
+        "banned_words"
+    }
+
+    fn find(&self, text: &str) -> Option<String> {
+        self.0.find(text).map(|found| found.as_str().to_string())
+    }
+}
+
+struct BannedPatterns(Vec<Regex>);
+
+impl ContentFilter for BannedPatterns {
+    fn rule(&self) -> &'static str {
+        "banned_patterns"
+    }
+
+    fn find(&self, text: &str) -> Option<String> {
+        self.0.iter().find_map(|pattern| pattern.find(text)).map(|found| found.as_str().to_string())
+    }
+}
+
+static FILTERS: Lazy<Vec<Box<dyn ContentFilter>>> = Lazy::new(|| {
+    let policy = &config::get().content_policy;
+    let mut filters: Vec<Box<dyn ContentFilter>> = Vec::new();
+    let words: Vec<String> = policy.banned_words.iter()
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect();
+    if !words.is_empty() {
+        let words = RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|"))).case_insensitive(true).build()
+            .expect("Invalid content_policy.banned_words");
+        filters.push(Box::new(BannedWords(words)));
+    }
+    if !policy.banned_patterns.is_empty() {
+        let patterns = policy.banned_patterns.iter()
+            .map(|pattern| Regex::new(pattern).unwrap_or_else(|e| panic!("Invalid content_policy.banned_patterns entry '{}': {}", pattern, e)))
+            .collect();
+        filters.push(Box::new(BannedPatterns(patterns)));
+    }
+    filters
+});
+
+// Builds the filters at launch, so a bad pattern stops the server instead of a request
+pub fn start() {
+    Lazy::force(&FILTERS);
+}
+
+fn violation(field: &str, rule: &str, detail: String) -> status::Custom<Json<String>> {
+    status::Custom(Status::UnprocessableEntity, Json(format!("{} breaks the content rule {}: {}", field.replace('_', " "), rule, detail)))
+}
+
+// `field` is also the stem of its limits in the config, e.g. max_name_chars
+fn check_text(field: &str, text: &str, max_chars: Option<usize>, max_words: Option<usize>) -> Result<(), status::Custom<Json<String>>> {
+    let chars = text.chars().count();
+    if let Some(max) = max_chars.filter(|&max| chars > max) {
+        return Err(violation(field, &format!("max_{}_chars", field), format!("{} characters, at most {} allowed", chars, max)));
+    }
+    let words = text.split_whitespace().count();
+    if let Some(max) = max_words.filter(|&max| words > max) {
+        return Err(violation(field, &format!("max_{}_words", field), format!("{} words, at most {} allowed", words, max)));
+    }
+    for filter in FILTERS.iter() {
+        if let Some(found) = filter.find(text) {
+            return Err(violation(field, filter.rule(), format!("'{}' isn't allowed", found)));
+        }
+    }
+    Ok(())
+}
+
+// Every translation is checked on its own
+fn check_localized(field: &str, text: &LocalizedText, max_chars: Option<usize>, max_words: Option<usize>) -> Result<(), status::Custom<Json<String>>> {
+    text.texts().into_iter().try_for_each(|text| check_text(field, text, max_chars, max_words))
+}
+
+pub fn check_capsule_name(name: &LocalizedText) -> Result<(), status::Custom<Json<String>>> {
+    let policy = &config::get().content_policy;
+    check_localized("name", name, policy.max_name_chars, policy.max_name_words)
+}
+
+pub fn check_capsule_description(description: &LocalizedText) -> Result<(), status::Custom<Json<String>>> {
+    let policy = &config::get().content_policy;
+    check_localized("description", description, policy.max_description_chars, policy.max_description_words)
+}
+
+pub fn check_item_description(description: &str) -> Result<(), status::Custom<Json<String>>> {
+    let policy = &config::get().content_policy;
+    check_text("item_description", description, policy.max_item_description_chars, policy.max_item_description_words)
+}
+
+pub fn check_message(text: &str) -> Result<(), status::Custom<Json<String>>> {
+    let policy = &config::get().content_policy;
+    check_text("message", text, policy.max_message_chars, policy.max_message_words)
+}
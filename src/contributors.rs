@@ -1,24 +1,101 @@
-use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::http::{Status, Header};
-use rocket::response::status;
+use rocket::serde::{json::Json, Deserialize, Deserializer, Serialize, Serializer};
+use rocket::http::Status;
+use rocket::Either;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
-use rocket::response::{self, Responder, Response, Redirect};
-use rocket::Request;
 
 // Assume these are in a module named `capsules`
-use crate::capsules::{Capsule, CAPSULES};
+use crate::capsules::{self, Capsule, CAPSULES};
+use crate::database;
+use crate::error_messages::ApiError;
 use crate::items::ITEMS;
-
-
+use crate::indexes::INDEXES;
+use crate::store::{self, Entity, Table};
+use crate::locks::{self, CAPSULE_LOCKS, CONTRIBUTOR_LOCKS};
+use crate::timezones;
+use crate::tombstones::{self, Missing};
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::i18n::AcceptLanguage;
+use crate::dry_run::DeletionPlan;
+use crate::field_history;
+use crate::reads;
+use crate::tokens::{self, Caller};
+use crate::ids::{CapsuleId, ContributorId};
+use crate::publishing::Visibility;
+use crate::residency;
+use crate::open_notices::{self, Channel};
+
+
+// Like a capsule's `item_ids`, `capsule_ids` is derived from the capsules' owner through the
+// indexes when a contributor is written out
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(crate = "rocket::serde")]
+#[serde(crate = "rocket::serde", remote = "Self")]
 pub struct Contributor {
-    pub id: u32,
-    pub capsule_ids: Option<Vec<u32>>,
+    pub id: ContributorId,
     pub name: String,
     pub email: String,
-    
+    #[serde(default)]
+    pub timezone: Option<String>,  // Default IANA timezone for the contributor's capsules
+    #[serde(default)]
+    pub defaults: CapsuleDefaults,
+    #[serde(default)]
+    pub pinned_capsule_ids: Vec<CapsuleId>,  // Shown first on the owner's list, see capsule_order.rs
+    #[serde(default)]
+    pub capsule_order: Vec<CapsuleId>,  // Manual order of the rest
+    #[serde(default)]
+    pub region: Option<String>,  // Data residency region of the contributor's files, see residency.rs
+    #[serde(default)]
+    pub leaderboard: bool,  // Opted in to be listed on /public/stats with their name
+    #[serde(default = "open_notices::default_channels")]
+    pub open_channels: Vec<Channel>,  // How they're told their capsules opened, see open_notices.rs
+}
+
+// Settings for the contributor's new capsules, used where the request leaves them out
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleDefaults {
+    pub edit_window_days: Option<u32>,  // 7 when unset
+    pub visibility: Option<Visibility>,  // Private when unset
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Entity for Contributor {
+    type Id = ContributorId;
+
+    fn id(&self) -> ContributorId {
+        self.id
+    }
+}
+
+// The stored fields, as derived with `remote = "Self"`
+struct StoredContributor<'a>(&'a Contributor);
+
+impl Serialize for StoredContributor<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Contributor::serialize(self.0, serializer)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ContributorWithCapsules<'a> {
+    #[serde(flatten)]
+    contributor: StoredContributor<'a>,
+    capsule_ids: Vec<CapsuleId>,
+}
+
+impl Serialize for Contributor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let capsule_ids = INDEXES.read().unwrap().capsules_of(self.id);
+        ContributorWithCapsules { contributor: StoredContributor(self), capsule_ids }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Contributor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Contributor::deserialize(deserializer)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -26,6 +103,9 @@ pub struct Contributor {
 pub struct NewContributor {
     pub name: String,
     pub email: String,
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
     // No `id_capsule` since it might not be set at creation
 }
 
@@ -41,125 +121,105 @@ pub struct ContributorCapsules {
 pub struct ContributorUpdate {
     pub name: Option<String>,
     pub email: Option<String>,
+    pub timezone: Option<String>,
+    pub region: Option<String>,
+    pub leaderboard: Option<bool>,
+    pub open_channels: Option<Vec<Channel>>,
 }
 
 
-#[derive(FromForm, UriDisplayQuery)]
-pub struct Pagination {
-    page: Option<usize>,
-    per_page: Option<usize>,
-}
-
 
 
 // This would typically be stored in a database
-pub static CONTRIBUTORS: Lazy<Mutex<Vec<Contributor>>> = Lazy::new(|| {
-    Mutex::new(vec![])
-});
-
-// Custom responder to add headers
-pub struct CustomResponder<T> {
-    inner: T,
-    total_items: usize,
-    page: usize,
-    per_page: usize,
-}
-
-impl<'r, T: Responder<'r, 'static>> Responder<'r, 'static> for CustomResponder<T> {
-    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
-        let mut build = Response::build_from(self.inner.respond_to(request)?);
-        build.raw_header("X-Total-Count", self.total_items.to_string());
-        build.raw_header("X-Page", self.page.to_string());
-        build.raw_header("X-Per-Page", self.per_page.to_string());
-        build.ok()
-    }
-}
+pub static CONTRIBUTORS: Lazy<Table<Contributor>> = Lazy::new(|| Table::with_store(store::configured("contributors")));
+
+// Serializes the email uniqueness check with the write that relies on it
+pub static EMAIL_CHECK: Mutex<()> = Mutex::new(());
+
 
 
 
 #[post("/contributors", format = "json", data = "<contributor_data>")]
-pub fn create_contributor(contributor_data: Json<NewContributor>) -> Result<Json<Contributor>, status::Custom<Json<String>>> {
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
+pub fn create_contributor(contributor_data: Json<NewContributor>) -> Result<Json<Contributor>, ApiError> {
     let new_contributor = contributor_data.into_inner();
+    if let Some(ref timezone) = new_contributor.timezone {
+        timezones::parse(timezone)?;
+    }
+    if let Some(ref region) = new_contributor.region {
+        residency::check_region(region)?;
+    }
+    let _email_guard = EMAIL_CHECK.lock().unwrap();
 
     // Check if the email already exists
-    if contributors.iter().any(|c| c.email == new_contributor.email) {
-        return Err(status::Custom(Status::Conflict, Json("Email already in use".to_string())));
+    if CONTRIBUTORS.any(|c| c.email == new_contributor.email) {
+        return Err(ApiError::EmailInUse);
     }
 
-    let id = contributors.len() as u32 + 1;
+    let id = CONTRIBUTORS.next_id();
     let contributor = Contributor {
         id,
         name: new_contributor.name,
         email: new_contributor.email,
-        capsule_ids: None, 
+        timezone: new_contributor.timezone,
+        defaults: CapsuleDefaults::default(),
+        pinned_capsule_ids: Vec::new(),
+        capsule_order: Vec::new(),
+        region: new_contributor.region,
+        leaderboard: false,
+        open_channels: open_notices::default_channels(),
     };
-    contributors.push(contributor.clone());
+    CONTRIBUTORS.insert(contributor.clone());
     Ok(Json(contributor))
 }
 
 
 #[get("/contributors?<pagination..>")]
-pub fn list_contributors(pagination: Pagination) -> Result<CustomResponder<Json<Vec<Contributor>>>, Status> {
-    let contributors = CONTRIBUTORS.lock().map_err(|_| Status::InternalServerError)?;
-
-    let per_page = pagination.per_page.unwrap_or(10); // Default to 10 items per page if not specified
-    let page = pagination.page.unwrap_or(1); // Default to page 1 if not specified
-    let start = (page - 1) * per_page;
-    let end = start + per_page;
-
-    let paged_contributors = contributors[start..end.min(contributors.len())].to_vec(); // Safely slice the vector to the page size, handling cases where the range may exceed the vector bounds
-
-    Ok(CustomResponder {
-        inner: Json(paged_contributors),
-        total_items: contributors.len(),
-        page,
-        per_page,
-    })
+pub fn list_contributors(pagination: Pagination) -> Paginated<Contributor> {
+    // Clone only the requested page
+    Paginated::new(&pagination, Collection::Contributors, CONTRIBUTORS.len(), |start, per_page| CONTRIBUTORS.page(start, per_page))
 }
 
 #[get("/contributors/<contributor_id>")]
-pub fn get_contributor_with_capsules(contributor_id: u32) -> Result<Json<ContributorCapsules>, status::Custom<Json<String>>> {
-    let contributors = CONTRIBUTORS.lock().unwrap();
-    let capsules = CAPSULES.lock().unwrap();
-
-    if let Some(contributor) = contributors.iter().find(|c| c.id == contributor_id) {
-        if let Some(ref capsule_ids) = contributor.capsule_ids {
-            let contributor_capsules = capsule_ids.iter()
-                .filter_map(|id| capsules.iter().find(|c| c.id == *id))
-                .cloned()
-                .collect::<Vec<Capsule>>();
-
-            Ok(Json(ContributorCapsules {
-                contributor: contributor.clone(),
-                capsules: contributor_capsules
-            }))
-        } else {
-            // Handle the case where there are no capsule IDs
-            Ok(Json(ContributorCapsules {
-                contributor: contributor.clone(),
-                capsules: Vec::new() // No capsules associated
-            }))
-        }
+pub fn get_contributor_with_capsules(contributor_id: ContributorId, languages: AcceptLanguage) -> Result<Json<ContributorCapsules>, Missing<ContributorId>> {
+    if let Some(contributor) = CONTRIBUTORS.get(contributor_id) {
+        // Resolve the contributor's capsules through the reverse index
+        let capsule_ids = INDEXES.read().unwrap().capsules_of(contributor_id);
+        let contributor_capsules = capsule_ids.iter()
+            .filter_map(|id| CAPSULES.get(*id))
+            .map(|capsule| capsule.localized(&languages.0))
+            .collect::<Vec<Capsule>>();
+
+        Ok(Json(ContributorCapsules {
+            contributor,
+            capsules: contributor_capsules
+        }))
     } else {
-        Err(status::Custom(Status::NotFound, Json("Contributor not found".to_string())))
+        Err(tombstones::or_not_found(tombstones::contributor(contributor_id), ApiError::ContributorNotFound))
     }
 }
 
 #[patch("/contributors/<id>", format = "json", data = "<contributor_data>")]
-pub fn update_contributor(id: u32, contributor_data: Json<ContributorUpdate>) -> Result<Json<Contributor>, status::Custom<Json<String>>> {
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
+pub fn update_contributor(id: ContributorId, contributor_data: Json<ContributorUpdate>, caller: Caller) -> Result<Json<Contributor>, ApiError> {
+    caller.must_be(id)?;
+    if let Some(ref timezone) = contributor_data.timezone {
+        timezones::parse(timezone)?;
+    }
+    let _email_guard = EMAIL_CHECK.lock().unwrap();
+    let _guard = locks::lock_contributor(id);
+    if let Some(ref region) = contributor_data.region {
+        residency::check_region_change(id, region)?;
+    }
 
     // First, determine if the new email is provided and needs to be unique
     if let Some(ref new_email) = contributor_data.email {
         // Check for email uniqueness
-        if contributors.iter().any(|c| c.id != id && c.email == *new_email) {
-            return Err(status::Custom(Status::Conflict, Json("Email already in use".to_string())));
+        if CONTRIBUTORS.any(|c| c.id != id && c.email == *new_email) {
+            return Err(ApiError::EmailInUse);
         }
     }
 
     // Now proceed with finding and updating the contributor
-    if let Some(contributor) = contributors.iter_mut().find(|c| c.id == id) {
+    let updated = CONTRIBUTORS.update(id, |contributor| {
         // Update name if provided
         if let Some(ref name) = contributor_data.name {
             contributor.name = name.clone();
@@ -170,38 +230,90 @@ pub fn update_contributor(id: u32, contributor_data: Json<ContributorUpdate>) ->
             contributor.email = new_email.clone();  // Cloning the string here
         }
 
-        Ok(Json(contributor.clone()))
-    } else {
-        Err(status::Custom(Status::NotFound, Json("Contributor not found".to_string())))
+        if let Some(ref timezone) = contributor_data.timezone {
+            contributor.timezone = Some(timezone.clone());
+        }
+
+        if let Some(ref region) = contributor_data.region {
+            contributor.region = Some(region.clone());
+        }
+
+        if let Some(leaderboard) = contributor_data.leaderboard {
+            contributor.leaderboard = leaderboard;
+        }
+
+        if let Some(ref channels) = contributor_data.open_channels {
+            contributor.open_channels = channels.clone();
+            contributor.open_channels.dedup();
+        }
+
+        contributor.clone()
+    });
+
+    match updated {
+        Some(contributor) => Ok(Json(contributor)),
+        None => Err(ApiError::ContributorNotFound),
+    }
+}
+
+// Replaces the defaults applied to the contributor's new capsules
+#[put("/contributors/<id>/defaults", format = "json", data = "<defaults>")]
+pub fn update_capsule_defaults(id: ContributorId, defaults: Json<CapsuleDefaults>, caller: Caller) -> Result<Json<CapsuleDefaults>, ApiError> {
+    caller.must_be(id)?;
+    let mut defaults = defaults.into_inner();
+    if let Some(days) = defaults.edit_window_days {
+        capsules::check_edit_window(days)?;
     }
+    defaults.tags = capsules::clean_tags(&defaults.tags);
+
+    let _guard = locks::lock_contributor(id);
+    CONTRIBUTORS.update(id, |contributor| contributor.defaults = defaults.clone())
+        .map(|_| Json(defaults))
+        .ok_or(ApiError::ContributorNotFound)
 }
 
+// With `?dry_run=true` nothing is removed, the response lists what would be
+#[delete("/contributors/<contributor_id>?<dry_run>")]
+pub fn delete_contributor(contributor_id: ContributorId, dry_run: Option<bool>, caller: Caller) -> Result<Either<Status, Json<DeletionPlan>>, ApiError> {
+    caller.must_be(contributor_id)?;
+    let contributor_guard = locks::lock_contributor(contributor_id);
 
-#[delete("/contributors/<contributor_id>")]
-pub fn delete_contributor(contributor_id: u32) -> Result<Status, status::Custom<Json<String>>> {
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
-    let mut items = ITEMS.lock().unwrap();  // Lock the items data
+    if dry_run.unwrap_or(false) {
+        return if CONTRIBUTORS.contains(contributor_id) {
+            Ok(Either::Right(Json(DeletionPlan::contributor(contributor_id))))
+        } else {
+            Err(ApiError::ContributorNotFound)
+        };
+    }
 
-    // First, find if the contributor exists
-    if let Some(pos) = contributors.iter().position(|c| c.id == contributor_id) {
-        // Remove the contributor
-        contributors.remove(pos);
+    // Remove the contributor if it exists, stored together with its capsules and items
+    database::atomically(|| if CONTRIBUTORS.remove(contributor_id).is_some() {
+        // Now remove all capsules associated with this contributor, holding all of them
+        let capsule_ids = INDEXES.write().unwrap().drop_contributor(contributor_id);
+        let capsule_guards = locks::lock_capsules(&capsule_ids);
 
-        // Collect all capsule IDs associated with the contributor
-        let capsule_ids_to_remove: Vec<u32> = capsules.iter()
-            .filter(|capsule| capsule.contributor_id == contributor_id)
-            .map(|capsule| capsule.id)
-            .collect();
+        for capsule_id in capsule_ids.iter().copied() {
+            CAPSULES.remove(capsule_id);
 
-        // Now remove all capsules associated with this contributor
-        capsules.retain(|capsule| capsule.contributor_id != contributor_id);
+            // Remove all items that belong to the capsules of the deleted contributor
+            let item_ids = INDEXES.write().unwrap().drop_capsule(contributor_id, capsule_id);
+            for item_id in item_ids {
+                ITEMS.remove(item_id);
+            }
+        }
 
-        // Remove all items that belong to the capsules of the deleted contributor
-        items.retain(|item| !capsule_ids_to_remove.contains(&item.id_capsule));
+        drop(capsule_guards);
+        drop(contributor_guard);
+        for capsule_id in capsule_ids {
+            CAPSULE_LOCKS.forget(capsule_id);
+            field_history::forget(capsule_id);
+            reads::forget(capsule_id);
+        }
+        CONTRIBUTOR_LOCKS.forget(contributor_id);
+        tokens::forget_contributor(contributor_id);
 
-        Ok(Status::NoContent)
+        Ok(Either::Left(Status::NoContent))
     } else {
-        Err(status::Custom(Status::NotFound, Json("Contributor not found".to_string())))
-    }
+        Err(ApiError::ContributorNotFound)
+    })
 }
@@ -0,0 +1,126 @@
+// Custom fields of a capsule: named, typed fields that every item added to the capsule
+// fills in its metadata, for structured capsules like a yearbook whose photos all carry a
+// `student` and a `class`. The fields are set with the capsule and can be replaced while
+// the capsule can change; items already in it aren't checked again.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::{DateTime, NaiveDate};
+use reqwest::Url;
+use serde_json::Value;
+
+const MAX_FIELDS: usize = 50;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum FieldType {
+    Text,
+    Number,
+    Boolean,
+    Date,  // `2024-06-01` or an RFC 3339 time
+    Url,   // http or https
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomField {
+    pub name: String,  // Key in the item's metadata
+    #[serde(rename = "type")]
+    pub kind: FieldType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+pub fn check(fields: Vec<CustomField>) -> Result<Vec<CustomField>, status::Custom<Json<String>>> {
+    let bad_request = |message: String| status::Custom(Status::BadRequest, Json(message));
+    if fields.len() > MAX_FIELDS {
+        return Err(bad_request(format!("A capsule has at most {} custom fields", MAX_FIELDS)));
+    }
+    let mut checked: Vec<CustomField> = Vec::with_capacity(fields.len());
+    for field in fields {
+        let name = field.name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(bad_request(format!("Custom field names must have 1 to {} characters", MAX_NAME_LEN)));
+        }
+        if checked.iter().any(|other| other.name == name) {
+            return Err(bad_request(format!("Custom field '{}' is defined twice", name)));
+        }
+        checked.push(CustomField { name, ..field });
+    }
+    Ok(checked)
+}
+
+fn matches(kind: FieldType, value: &Value) -> bool {
+    match (kind, value) {
+        (FieldType::Text, Value::String(_)) | (FieldType::Number, Value::Number(_)) | (FieldType::Boolean, Value::Bool(_)) => true,
+        (FieldType::Date, Value::String(text)) => NaiveDate::parse_from_str(text, "%Y-%m-%d").is_ok() || DateTime::parse_from_rfc3339(text).is_ok(),
+        (FieldType::Url, Value::String(text)) => Url::parse(text).is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+        _ => false,
+    }
+}
+
+fn describe(kind: FieldType) -> &'static str {
+    match kind {
+        FieldType::Text => "a string",
+        FieldType::Number => "a number",
+        FieldType::Boolean => "true or false",
+        FieldType::Date => "a date",
+        FieldType::Url => "an http or https URL",
+    }
+}
+
+// Checks an item's metadata against the capsule's fields and reports every mismatch at
+// once. Keys that aren't fields are left alone.
+pub fn check_metadata(fields: &[CustomField], metadata: &Value) -> Result<(), status::Custom<Json<String>>> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let empty = serde_json::Map::new();
+    let values = match metadata {
+        Value::Object(values) => values,
+        Value::Null => &empty,
+        _ => return Err(status::Custom(Status::BadRequest, Json("metadata must be an object holding the capsule's custom fields".into()))),
+    };
+    let problems: Vec<String> = fields.iter()
+        .filter_map(|field| match values.get(&field.name).filter(|value| !value.is_null()) {
+            None if field.required => Some(format!("{} is required", field.name)),
+            Some(value) if !matches(field.kind, value) => Some(format!("{} must be {}", field.name, describe(field.kind))),
+            _ => None,
+        })
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(status::Custom(Status::BadRequest, Json(format!("The metadata doesn't fit the capsule's custom fields: {}", problems.join(", ")))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(name: &str, kind: FieldType, required: bool) -> CustomField {
+        CustomField { name: name.to_string(), kind, required }
+    }
+
+    #[test]
+    fn field_names_are_trimmed_and_unique() {
+        let checked = check(vec![field(" student ", FieldType::Text, true)]).ok().unwrap();
+        assert_eq!(checked[0].name, "student");
+        assert!(check(vec![field("class", FieldType::Text, false), field(" class", FieldType::Number, false)]).is_err());
+        assert!(check(vec![field("  ", FieldType::Text, false)]).is_err());
+    }
+
+    #[test]
+    fn metadata_has_to_fit_the_fields() {
+        let fields = [field("student", FieldType::Text, true), field("taken", FieldType::Date, false), field("site", FieldType::Url, false)];
+        assert!(check_metadata(&fields, &json!({ "student": "Ann", "taken": "2024-06-01", "site": "https://example.com", "other": 1 })).is_ok());
+        assert!(check_metadata(&fields, &json!({ "student": "Ann", "taken": null })).is_ok());
+
+        let error = check_metadata(&fields, &json!({ "taken": "June", "site": "ftp://example.com" })).err().unwrap();
+        assert_eq!(error.1.0, "The metadata doesn't fit the capsule's custom fields: student is required, taken must be a date, site must be an http or https URL");
+        assert!(check_metadata(&fields, &json!([])).is_err());
+        assert!(check_metadata(&[], &json!("anything")).is_ok());
+    }
+}
@@ -0,0 +1,524 @@
+// Database storage, with `storage.backend = "sqlite"` or `"postgres"`. Contributors,
+// capsules and items are kept in a database instead of the data files: they're loaded from
+// it at launch, and every change is written to it right after it's made. Reads are served
+// from memory like with the default backend, so the handlers don't wait on the database.
+//
+// The database is a rocket_db_pools sqlx pool, configured under `databases.capsules` like
+// any rocket_db_pools database, but opened before launch since the records are loaded then.
+// The schema is migrated on every start, see sqlite.rs and postgres.rs. A new database is
+// seeded from the data files once, unless `storage.seed_from_files` is off.
+//
+// Changes made together, like a capsule deleted with its items, are written in one
+// transaction when they're made inside `atomically`. A capsule's version is also kept in
+// its own column, moved on by `claim_version` before a versioned change. The entries of state.rs, like API keys
+// and webhooks, are kept in the `state` table the same way.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::tokio::runtime::Handle;
+use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::tokio::task;
+use rocket::tokio::time::{self, Duration};
+use rocket::Rocket;
+use rocket_db_pools::sqlx::{self, PgPool, Row, SqlitePool};
+use once_cell::sync::{Lazy, OnceCell};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::config::{self, StorageBackend};
+use crate::contributors::{Contributor, CONTRIBUTORS};
+use crate::ids::{CapsuleId, ContributorId, EntityId, ItemId};
+use crate::items::{Item, ITEMS};
+use crate::postgres;
+use crate::sqlite;
+use crate::state;
+use crate::store::{self, Entity, MemoryStore, Store};
+
+// Waited before writing rows again whose write failed
+const RETRY: Duration = Duration::from_secs(1);
+
+pub enum Pool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+// Runs the same code with either pool, each arm type-checked against its own driver
+macro_rules! with_pool {
+    ($pool:expr, $name:ident => $body:expr) => {
+        match $pool {
+            Pool::Sqlite($name) => $body,
+            Pool::Postgres($name) => $body,
+        }
+    };
+}
+
+static POOL: OnceCell<Pool> = OnceCell::new();
+
+// A changed row of a table or entry of a state collection, written as it is by then
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Changed {
+    Row(&'static str, u32),
+    Entry(&'static str, String),
+}
+
+enum Write {
+    Rows(Vec<Changed>),  // Changes made together, written in the same transaction
+    Flush(oneshot::Sender<()>),
+}
+
+// Changes wait here for the writer, see `start`
+struct Queue {
+    sender: mpsc::UnboundedSender<Write>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Write>>>,  // Taken by the writer
+}
+
+static QUEUE: Lazy<Queue> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    Queue { sender, receiver: Mutex::new(Some(receiver)) }
+});
+
+// Off while the records are loaded, they're already in the database
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Changes of the current `atomically`, sent when it ends
+    static GROUP: RefCell<Option<Vec<Changed>>> = const { RefCell::new(None) };
+    // Set while changes read from the database are applied, see `applying`
+    static APPLYING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Rows in memory, every change queued for writing
+pub struct DatabaseStore<T: Entity> {
+    rows: MemoryStore<T>,
+    table: &'static str,
+}
+
+impl<T: Entity> DatabaseStore<T> {
+    pub fn new(table: &'static str) -> Self {
+        DatabaseStore { rows: MemoryStore::new(), table }
+    }
+
+    fn changed(&self, id: T::Id) {
+        queue(Changed::Row(self.table, id.number()));
+    }
+}
+
+fn queue(changed: Changed) {
+    if !WRITING.load(Ordering::SeqCst) || APPLYING.with(Cell::get) {
+        return;
+    }
+    let mut changed = Some(changed);
+    GROUP.with(|group| {
+        if let Some(rows) = group.borrow_mut().as_mut() {
+            rows.extend(changed.take());
+        }
+    });
+    if let Some(changed) = changed {
+        let _ = QUEUE.sender.send(Write::Rows(vec![changed]));
+    }
+}
+
+// Queues an entry of a state collection for writing, see state.rs
+pub fn entry_changed(collection: &'static str, key: String) {
+    queue(Changed::Entry(collection, key));
+}
+
+// The next id of a state entry, from the database when instances share it
+pub fn next_entry_id() -> Option<u32> {
+    match POOL.get()? {
+        Pool::Postgres(pool) => Some(postgres::next_id(pool, "state")),
+        Pool::Sqlite(_) => None,
+    }
+}
+
+impl<T: Entity + Send + Sync> Store<T> for DatabaseStore<T> {
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn contains(&self, id: T::Id) -> bool {
+        self.rows.contains(id)
+    }
+
+    fn read(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> bool {
+        self.rows.read(id, f)
+    }
+
+    fn update(&self, id: T::Id, f: &mut dyn FnMut(&mut T, &mut u64)) -> bool {
+        let updated = self.rows.update(id, f);
+        if updated {
+            self.changed(id);
+        }
+        updated
+    }
+
+    fn insert(&self, row: T, revision: &mut dyn FnMut(&T, Option<u64>) -> u64) {
+        let id = row.id();
+        self.rows.insert(row, revision);
+        self.changed(id);
+    }
+
+    fn remove(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> Option<(T, u64)> {
+        let removed = self.rows.remove(id, f);
+        if removed.is_some() {
+            self.changed(id);
+        }
+        removed
+    }
+
+    fn ids(&self, start: usize, count: usize) -> Vec<T::Id> {
+        self.rows.ids(start, count)
+    }
+
+    fn last_id(&self) -> Option<T::Id> {
+        self.rows.last_id()
+    }
+
+    fn any(&self, predicate: &mut dyn FnMut(&T) -> bool) -> bool {
+        self.rows.any(predicate)
+    }
+
+    fn clear(&self) {
+        self.rows.clear()
+    }
+
+    fn next_id(&self) -> Option<T::Id> {
+        match POOL.get()? {
+            Pool::Postgres(pool) => Some(T::Id::from_number(postgres::next_id(pool, self.table))),
+            Pool::Sqlite(_) => None,
+        }
+    }
+}
+
+// Sends the changes made in `f` to be written in one transaction. Handlers run on one
+// thread without awaiting, so whatever `f` changes is part of it.
+pub fn atomically<R>(f: impl FnOnce() -> R) -> R {
+    struct Group(bool);
+    impl Drop for Group {
+        fn drop(&mut self) {
+            let rows = if self.0 { GROUP.with(|group| group.borrow_mut().take()) } else { None };
+            if let Some(rows) = rows.filter(|rows| !rows.is_empty()) {
+                let _ = QUEUE.sender.send(Write::Rows(rows));
+            }
+        }
+    }
+    // Nested calls join the outer group
+    let outer = GROUP.with(|group| {
+        let mut group = group.borrow_mut();
+        let outer = group.is_none();
+        if outer {
+            *group = Some(Vec::new());
+        }
+        outer
+    });
+    let _group = Group(outer);
+    f()
+}
+
+// Runs `f` without queueing its changes, for rows another instance already wrote
+pub fn applying<R>(f: impl FnOnce() -> R) -> R {
+    APPLYING.with(|applying| applying.set(true));
+    let result = f();
+    APPLYING.with(|applying| applying.set(false));
+    result
+}
+
+// rocket_db_pools' settings of the database, with its `url` unless one is set. The other
+// defaults are those rocket_db_pools gives a database it opens itself.
+pub fn figment(default_url: Option<String>) -> Figment {
+    let workers = rocket::Config::figment().extract_inner::<usize>(rocket::Config::WORKERS).unwrap_or_else(|_| rocket::Config::default().workers);
+    let figment = rocket::Config::figment().focus("databases.capsules");
+    let figment = match default_url {
+        Some(url) => figment.join(("url", url)),
+        None => figment,
+    };
+    figment.join(("max_connections", workers * 4)).join(("connect_timeout", 5))
+}
+
+// The records of the database, loaded at launch
+pub struct Loaded {
+    pub contributors: Vec<Contributor>,
+    pub capsules: Vec<Capsule>,
+    pub items: Vec<Item>,
+}
+
+fn read_file<T: for<'de> rocket::serde::Deserialize<'de>>(name: &str) -> Vec<T> {
+    config::data_file(name).map_or_else(Vec::new, |path| {
+        let json = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+        store::loading(|| serde_json::from_str(&json)).unwrap_or_else(|e| panic!("Invalid format in {}: {}", path.display(), e))
+    })
+}
+
+pub fn to_json<T: rocket::serde::Serialize>(row: &T) -> String {
+    store::storing(|| serde_json::to_string(row)).expect("Records serialize to JSON")
+}
+
+// The upsert of a row, with the id of its owner for capsules and items. A capsule isn't
+// written back over a newer version another instance claimed.
+fn upsert_sql(table: &str) -> &'static str {
+    match table {
+        "contributors" => "INSERT INTO contributors (id, data) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+        "capsules" => "INSERT INTO capsules (id, data, contributor_id, version) VALUES ($1, $2, $3, $4) ON CONFLICT (id) DO UPDATE SET data = excluded.data, contributor_id = excluded.contributor_id, version = excluded.version WHERE capsules.version <= excluded.version",
+        _ => "INSERT INTO items (id, data, capsule_id) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET data = excluded.data, capsule_id = excluded.capsule_id",
+    }
+}
+
+fn delete_sql(table: &str) -> &'static str {
+    match table {
+        "contributors" => "DELETE FROM contributors WHERE id = $1",
+        "capsules" => "DELETE FROM capsules WHERE id = $1",
+        _ => "DELETE FROM items WHERE id = $1",
+    }
+}
+
+// The values bound to a row's upsert after its id and JSON: the owner id of capsules and
+// items, and a capsule's version
+fn row_binds(table: &str, owner: u32, version: u32) -> Vec<i64> {
+    match table {
+        "contributors" => vec![],
+        "capsules" => vec![owner as i64, version as i64],
+        _ => vec![owner as i64],
+    }
+}
+
+// A row as it is now, (JSON, binds) or None once it's gone
+fn current_row(table: &str, id: u32) -> Option<(String, Vec<i64>)> {
+    match table {
+        "contributors" => CONTRIBUTORS.get(ContributorId::from_number(id)).map(|contributor| (to_json(&contributor), vec![])),
+        "capsules" => CAPSULES.get(CapsuleId::from_number(id)).map(|capsule| (to_json(&capsule), row_binds(table, capsule.contributor_id.number(), capsule.version))),
+        _ => ITEMS.get(ItemId::from_number(id)).map(|item| (to_json(&item), row_binds(table, item.id_capsule.number(), 0))),
+    }
+}
+
+// Imports the data files into a new database
+async fn seed(pool: &Pool) -> Result<(), sqlx::Error> {
+    let contributors: Vec<Contributor> = read_file("contributors.json");
+    let capsules: Vec<Capsule> = read_file("capsule.json");
+    let items: Vec<Item> = read_file("items.json");
+    let entries = state::read_files();
+    let mut rows: Vec<(&str, i64, String, Vec<i64>)> = Vec::new();
+    rows.extend(contributors.iter().map(|contributor| ("contributors", contributor.id.number() as i64, to_json(contributor), vec![])));
+    rows.extend(capsules.iter().map(|capsule| ("capsules", capsule.id.number() as i64, to_json(capsule), row_binds("capsules", capsule.contributor_id.number(), capsule.version))));
+    rows.extend(items.iter().map(|item| ("items", item.id.number() as i64, to_json(item), row_binds("items", item.id_capsule.number(), 0))));
+
+    let postgres = matches!(pool, Pool::Postgres(_));
+    with_pool!(pool, pool => {
+        let mut transaction = pool.begin().await?;
+        for (table, id, data, binds) in rows {
+            let query = binds.into_iter().fold(sqlx::query(upsert_sql(table)).bind(id).bind(data), |query, value| query.bind(value));
+            query.execute(&mut *transaction).await?;
+            if postgres {
+                sqlx::query(postgres::reserve_sql(table)).bind(id).execute(&mut *transaction).await?;
+            }
+        }
+        for (collection, key, entry) in &entries {
+            sqlx::query("INSERT INTO state (collection, key, data) VALUES ($1, $2, $3)")
+                .bind(*collection).bind(key.as_str()).bind(entry.to_string())
+                .execute(&mut *transaction).await?;
+            if let (true, Ok(id)) = (postgres, key.parse::<i64>()) {
+                sqlx::query(postgres::reserve_sql("state")).bind(id).execute(&mut *transaction).await?;
+            }
+        }
+        transaction.commit().await?;
+    });
+    println!("Seeded the database with {} contributors, {} capsules, {} items and {} state entries from the data files", contributors.len(), capsules.len(), items.len(), entries.len());
+    Ok(())
+}
+
+pub async fn load_table<T: for<'de> rocket::serde::Deserialize<'de>>(pool: &Pool, table: &str, id: Option<u32>) -> Result<Vec<T>, sqlx::Error> {
+    let sql = match id {
+        Some(_) => format!("SELECT id, data FROM {} WHERE id = $1", table),
+        None => format!("SELECT id, data FROM {} ORDER BY id", table),
+    };
+    let rows: Vec<(i64, String)> = with_pool!(pool, pool => {
+        let query = sqlx::query(&sql);
+        let query = match id {
+            Some(id) => query.bind(id as i64),
+            None => query,
+        };
+        query.fetch_all(pool).await?.iter()
+            .map(|row| Ok((row.try_get("id")?, row.try_get("data")?)))
+            .collect::<Result<_, sqlx::Error>>()?
+    });
+    rows.into_iter()
+        .map(|(id, data)| store::loading(|| serde_json::from_str(&data))
+            .map_err(|e| sqlx::Error::Decode(format!("Invalid row {} in {}: {}", id, table, e).into())))
+        .collect()
+}
+
+// Moves the stored version of a capsule on from `version`, which the change about to be
+// made was checked against. False if another instance changed the capsule since, then its
+// change reaches this one's memory shortly. A version behind `version` is this instance's
+// own write that's still queued. Blocks its thread like `next_id`, so it's called before
+// the capsule's row is taken, never while a shard is locked.
+pub fn claim_version(id: CapsuleId, version: u32) -> Result<bool, sqlx::Error> {
+    let Some(pool) = POOL.get() else { return Ok(true) };
+    let claim = async {
+        with_pool!(pool, pool => {
+            let claimed = sqlx::query("UPDATE capsules SET version = $1 WHERE id = $2 AND version <= $3")
+                .bind(version as i64 + 1).bind(id.number() as i64).bind(version as i64)
+                .execute(pool).await?.rows_affected() > 0;
+            if claimed {
+                return Ok(true);
+            }
+            // Not written yet, no other instance knows of it
+            let stored: Option<i64> = sqlx::query_scalar("SELECT version FROM capsules WHERE id = $1").bind(id.number() as i64).fetch_optional(pool).await?;
+            Ok(stored.is_none())
+        })
+    };
+    task::block_in_place(|| Handle::current().block_on(claim))
+}
+
+// An entry of a state collection, None if there's none
+pub async fn load_entry(pool: &Pool, collection: &str, key: &str) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let data: Option<String> = with_pool!(pool, pool => {
+        sqlx::query_scalar("SELECT data FROM state WHERE collection = $1 AND key = $2")
+            .bind(collection).bind(key).fetch_optional(pool).await?
+    });
+    data.map(|data| serde_json::from_str(&data)
+        .map_err(|e| sqlx::Error::Decode(format!("Invalid entry {} in {}: {}", key, collection, e).into())))
+        .transpose()
+}
+
+// Puts the stored entries of the state collections into memory
+async fn load_state(pool: &Pool) -> Result<(), sqlx::Error> {
+    let rows: Vec<(String, String, String)> = with_pool!(pool, pool => {
+        sqlx::query_as("SELECT collection, key, data FROM state ORDER BY collection, key").fetch_all(pool).await?
+    });
+    for (name, key, data) in rows {
+        let Some(collection) = state::collection(&name) else { continue };
+        serde_json::from_str(&data).and_then(|entry| (collection.put)(&key, Some(entry)))
+            .map_err(|e| sqlx::Error::Decode(format!("Invalid entry {} in {}: {}", key, name, e).into()))?;
+    }
+    Ok(())
+}
+
+// Opens and migrates the database, seeds a new one and reads its records
+pub async fn open(backend: StorageBackend) -> Loaded {
+    let opened = async {
+        let (pool, fresh) = match backend {
+            StorageBackend::Postgres => {
+                let (pool, fresh) = postgres::open().await?;
+                (Pool::Postgres(pool), fresh)
+            },
+            _ => {
+                let (pool, fresh) = sqlite::open().await?;
+                (Pool::Sqlite(pool), fresh)
+            },
+        };
+        if fresh && config::get().storage.seed_from_files {
+            seed(&pool).await?;
+        }
+        let loaded = Loaded {
+            contributors: load_table(&pool, "contributors", None).await?,
+            capsules: load_table(&pool, "capsules", None).await?,
+            items: load_table(&pool, "items", None).await?,
+        };
+        load_state(&pool).await?;
+        Ok::<_, sqlx::Error>((pool, loaded))
+    };
+    let (pool, loaded) = opened.await.unwrap_or_else(|e| panic!("Failed to load the database: {}", e));
+    let _ = POOL.set(pool);
+    loaded
+}
+
+// Writes the rows as they are now, deleting those that are gone
+async fn write_rows(pool: &Pool, rows: &BTreeSet<Changed>) -> Result<(), sqlx::Error> {
+    // Other instances hear of the rows once they're committed
+    let postgres = matches!(pool, Pool::Postgres(_));
+    with_pool!(pool, pool => {
+        let mut transaction = pool.begin().await?;
+        for changed in rows {
+            let (table, id) = match *changed {
+                Changed::Row(table, id) => (table, id),
+                Changed::Entry(collection, ref key) => {
+                    let entry = state::collection(collection).and_then(|state| (state.entry)(key));
+                    let query = match entry {
+                        Some(entry) => sqlx::query("INSERT INTO state (collection, key, data) VALUES ($1, $2, $3) ON CONFLICT (collection, key) DO UPDATE SET data = excluded.data")
+                            .bind(collection).bind(key.as_str()).bind(entry.to_string()),
+                        None => sqlx::query("DELETE FROM state WHERE collection = $1 AND key = $2").bind(collection).bind(key.as_str()),
+                    };
+                    query.execute(&mut *transaction).await?;
+                    if postgres {
+                        sqlx::query("SELECT pg_notify($1, $2)").bind(postgres::CHANNEL).bind(postgres::entry_payload(collection, key))
+                            .execute(&mut *transaction).await?;
+                    }
+                    continue;
+                },
+            };
+            let row = current_row(table, id);
+            let exists = row.is_some();
+            let query = match row {
+                Some((data, binds)) => binds.into_iter().fold(sqlx::query(upsert_sql(table)).bind(id as i64).bind(data), |query, value| query.bind(value)),
+                None => sqlx::query(delete_sql(table)).bind(id as i64),
+            };
+            query.execute(&mut *transaction).await?;
+            if postgres {
+                if exists {
+                    sqlx::query(postgres::reserve_sql(table)).bind(id as i64).execute(&mut *transaction).await?;
+                }
+                sqlx::query("SELECT pg_notify($1, $2)").bind(postgres::CHANNEL).bind(postgres::payload(table, id))
+                    .execute(&mut *transaction).await?;
+            }
+        }
+        transaction.commit().await
+    })
+}
+
+// Writes the queued changes, all that piled up in one transaction
+async fn write_queued(mut queue: mpsc::UnboundedReceiver<Write>) {
+    let Some(pool) = POOL.get() else { return };
+    let mut rows = BTreeSet::new();
+    let mut flushed = Vec::new();
+    while let Some(write) = queue.recv().await {
+        for write in std::iter::once(write).chain(std::iter::from_fn(|| queue.try_recv().ok())) {
+            match write {
+                Write::Rows(changed) => rows.extend(changed),
+                Write::Flush(done) => flushed.push(done),
+            }
+        }
+        while let Err(e) = write_rows(pool, &rows).await {
+            eprintln!("Failed to write {} rows to the database, trying again: {}", rows.len(), e);
+            time::sleep(RETRY).await;
+        }
+        rows.clear();
+        for done in flushed.drain(..) {
+            let _ = done.send(());
+        }
+    }
+}
+
+// Starts writing changes, once the records are loaded
+pub fn start() {
+    let (Some(pool), Some(queue)) = (POOL.get(), QUEUE.receiver.lock().unwrap().take()) else { return };
+    rocket::tokio::spawn(write_queued(queue));
+    if let Pool::Postgres(_) = pool {
+        rocket::tokio::spawn(postgres::follow(pool));
+    }
+    WRITING.store(true, Ordering::SeqCst);
+}
+
+// Waits for the queued changes to be written when the server shuts down
+pub struct Flush;
+
+#[rocket::async_trait]
+impl Fairing for Flush {
+    fn info(&self) -> Info {
+        Info { name: "Database writes", kind: Kind::Shutdown }
+    }
+
+    async fn on_shutdown(&self, _: &Rocket<rocket::Orbit>) {
+        flushed().await;
+    }
+}
+
+// Returns once the changes queued so far are written
+pub async fn flushed() {
+    let (done, written) = oneshot::channel();
+    if QUEUE.sender.send(Write::Flush(done)).is_ok() {
+        let _ = written.await;
+    }
+}
@@ -0,0 +1,114 @@
+// URLs given by clients that the server requests itself, like linked items and their
+// snapshots. A destination has to resolve to public addresses only, so nobody gets the
+// server to reach its own loopback, the private network or a cloud metadata endpoint at
+// 169.254.169.254. The request goes to the addresses that were checked, not to a second
+// lookup, and redirects are followed here so every hop is checked the same way.
+use reqwest::{header, Method, RequestBuilder, Response, Url};
+use reqwest::redirect::Policy;
+use rocket::tokio::net::lookup_host;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 5;
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local()
+        || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+        || a == 0                                 // "This network"
+        || (a == 100 && (64..128).contains(&b))  // Shared address space of carrier NATs
+        || (a == 192 && b == 0 && c == 0)         // Protocol assignments
+        || (a == 198 && (b == 18 || b == 19))    // Benchmarking
+        || a >= 240)                              // Reserved
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00             // Unique local
+        || (first & 0xffc0) == 0xfe80             // Link-local
+        || (first == 0x2001 && ip.segments()[1] == 0xdb8))  // Documentation
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+// The addresses an http(s) URL resolves to, if they're all public
+pub async fn check(url: &Url) -> Result<Vec<SocketAddr>, String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("{} isn't an http or https URL", url));
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str().ok_or_else(|| format!("{} has no host", url))?;
+    let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => lookup_host((host, port)).await
+            .map_err(|e| format!("{} can't be resolved: {}", host, e))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(format!("{} can't be resolved", url));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(format!("{} resolves to {}, which isn't a public address", host, addr.ip()));
+    }
+    Ok(addrs)
+}
+
+// Sends a request to a checked destination. GET and HEAD follow redirects to other checked
+// destinations, other methods get the redirect as the response.
+pub async fn send(method: Method, url: &Url, timeout: Option<Duration>, prepare: impl Fn(RequestBuilder) -> RequestBuilder) -> Result<Response, String> {
+    let follows = method == Method::GET || method == Method::HEAD;
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let addrs = check(&url).await?;
+        // Pinned to the checked addresses, and without proxies that would resolve the host again
+        let mut client = reqwest::Client::builder().redirect(Policy::none()).no_proxy();
+        if let Some(domain) = url.domain() {
+            client = client.resolve_to_addrs(domain, &addrs);
+        }
+        if let Some(timeout) = timeout {
+            client = client.timeout(timeout);
+        }
+        let client = client.build().map_err(|e| e.to_string())?;
+        let response = prepare(client.request(method.clone(), url.clone())).send().await.map_err(|e| e.to_string())?;
+
+        let location = response.headers().get(header::LOCATION).and_then(|value| value.to_str().ok()).map(str::to_string);
+        match location.filter(|_| follows && response.status().is_redirection()) {
+            Some(location) => url = url.join(&location).map_err(|e| format!("{} redirects to an invalid URL: {}", url, e))?,
+            None => return Ok(response),
+        }
+    }
+    Err(format!("{} redirects more than {} times", url, MAX_REDIRECTS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{} counts as public", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} counts as internal", ip);
+        }
+    }
+
+    #[test]
+    fn internal_destinations_are_refused() {
+        let runtime = rocket::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        for url in ["http://127.0.0.1:8000/", "http://169.254.169.254/latest/meta-data/", "http://[::1]/", "http://localhost/", "ftp://example.com/"] {
+            let url = Url::parse(url).unwrap();
+            assert!(runtime.block_on(check(&url)).is_err(), "{} was allowed", url);
+        }
+    }
+}
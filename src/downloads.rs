@@ -0,0 +1,145 @@
+// ZIP download of all item files of an opened capsule. The archive is written into a
+// pipe while it is being sent, one file at a time, so memory use stays flat no matter
+// how large the capsule is.
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder, Response};
+use rocket::futures::AsyncWriteExt;
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{self, AsyncReadExt, AsyncWrite, DuplexStream};
+use rocket::{Request, State};
+use async_zip::error::ZipError;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use reqwest::{Method, Url};
+
+use crate::archives;
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::destinations;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::owner_only;
+use crate::reveals;
+use crate::ids::CapsuleId;
+use crate::tokens::Caller;
+
+// Data buffered between the ZIP writer and the response
+const PIPE_BUFFER: usize = 64 * 1024;
+
+pub struct ZipDownload {
+    file_name: String,
+    body: DuplexStream,
+}
+
+impl<'r> Responder<'r, 'static> for ZipDownload {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(ContentType::ZIP)
+            .header(Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", self.file_name)))
+            .streamed_body(self.body)
+            .ok()
+    }
+}
+
+// Where an item's file is read from, opened before its entry is started
+enum Source {
+    File(File),
+    Remote(reqwest::Response),
+}
+
+impl Source {
+    // Linked files only from public addresses, see destinations.rs
+    async fn open(path: &str) -> Option<Source> {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            let url = Url::parse(path).ok()?;
+            let response = destinations::send(Method::GET, &url, None, |request| request).await.ok()?.error_for_status().ok()?;
+            Some(Source::Remote(response))
+        } else {
+            File::open(path).await.ok().map(Source::File)
+        }
+    }
+
+    async fn copy_to(self, out: &mut (impl AsyncWriteExt + Unpin)) -> Result<(), String> {
+        match self {
+            Source::File(mut file) => {
+                let mut buffer = vec![0; PIPE_BUFFER];
+                loop {
+                    let read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    out.write_all(&buffer[..read]).await.map_err(|e| e.to_string())?;
+                }
+            },
+            Source::Remote(mut response) => {
+                while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+                    out.write_all(&chunk).await.map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// Writes the items' files into the archive, calling `on_item` after each one. Shared with
+// the export jobs, see exports.rs
+pub async fn write_zip<W: AsyncWrite + Unpin>(mut zip: ZipFileWriter<W>, items: Vec<Item>, mut on_item: impl FnMut()) -> Result<W, ZipError> {
+    let mut missing = Vec::new();
+
+    // Photos and videos are compressed already, so entries are stored as they are
+    for item in items {
+        // Messages have no file, their text is the entry
+        if let Some(message) = &item.message {
+            let entry_builder = ZipEntryBuilder::new(archives::file_name(&item).into(), Compression::Stored)
+                .last_modification_date(ZipDateTime::from_chrono(&item.time_added));
+            zip.write_entry_whole(entry_builder, message.text.as_bytes()).await?;
+            on_item();
+            continue;
+        }
+        let Some(source) = Source::open(&item.path).await else {
+            missing.push(item.path);
+            on_item();
+            continue;
+        };
+        let entry_builder = ZipEntryBuilder::new(archives::file_name(&item).into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&item.time_added));
+        let mut entry = zip.write_entry_stream(entry_builder).await?;
+        if let Err(e) = source.copy_to(&mut entry).await {
+            missing.push(format!("{} (incomplete: {})", item.path, e));
+        }
+        entry.close().await?;
+        on_item();
+    }
+
+    if !missing.is_empty() {
+        let listing = format!("These item files could not be read:\n{}\n", missing.join("\n"));
+        zip.write_entry_whole(ZipEntryBuilder::new("MISSING.txt".into(), Compression::Stored), listing.as_bytes()).await?;
+    }
+    Ok(zip.close().await?.into_inner())
+}
+
+// Ranked after `/capsules/<cid>/items/<item_id>`, which passes on non-numeric ids
+#[get("/capsules/<cid>/items/download", rank = 1)]
+pub fn download_items(cid: CapsuleId, caller: Caller, clock: &State<SharedClock>) -> Result<ZipDownload, ApiError> {
+    let now = clock.now();
+    let capsule = CAPSULES.get(cid)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    if capsule.time_open > now {
+        return Err(ApiError::CapsuleNotOpen(cid, capsule.time_open));
+    }
+
+    let item_ids = reveals::visible(cid, INDEXES.read().unwrap().items_of(cid));
+    let item_ids = owner_only::visible(cid, item_ids, &caller, now);
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+
+    let (writer, body) = io::duplex(PIPE_BUFFER);
+    rocket::tokio::spawn(async move {
+        // Fails when the client goes away, which just ends the download
+        if let Err(e) = write_zip(ZipFileWriter::with_tokio(writer), items, || ()).await {
+            eprintln!("ZIP download of capsule {} stopped: {}", cid, e);
+        }
+    });
+
+    Ok(ZipDownload { file_name: format!("capsule-{}-items.zip", cid), body })
+}
@@ -0,0 +1,83 @@
+// Previews for destructive endpoints called with `?dry_run=true`: everything the
+// request would remove, computed under the same locks but without changing anything.
+use rocket::serde::Serialize;
+
+use crate::indexes::INDEXES;
+use crate::merges::CapsuleDetails;
+use crate::ids::{CapsuleId, ContributorId, ItemId};
+
+// A capsule id that would be dropped from a contributor's `capsule_ids`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ContributorLink {
+    pub contributor_id: ContributorId,
+    pub capsule_id: CapsuleId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeletionPlan {
+    pub dry_run: bool,
+    pub contributors: Vec<ContributorId>,  // Contributors that would be deleted
+    pub capsules: Vec<CapsuleId>,      // Capsules that would be deleted
+    pub items: Vec<ItemId>,         // Items that would be deleted with their capsules
+    pub item_count: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub detached_items: Vec<ItemId>,  // Items that would be kept, see orphans.rs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsorted_capsule_id: Option<CapsuleId>,  // Where the detached items would move
+    pub contributor_links: Vec<ContributorLink>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MergePlan {
+    pub dry_run: bool,
+    pub kept_capsule_id: CapsuleId,
+    pub removed_capsule_id: CapsuleId,
+    pub moved_item_ids: Vec<ItemId>,  // Items that would move into the kept capsule
+    pub item_count: usize,
+    pub contributor_links: Vec<ContributorLink>,
+    pub merged_capsule: CapsuleDetails,  // The capsule as the merge would return it
+}
+
+impl DeletionPlan {
+    // Deleting the given capsules of one contributor, with all of their items
+    pub fn capsules(contributor_id: ContributorId, capsule_ids: Vec<CapsuleId>) -> Self {
+        let indexes = INDEXES.read().unwrap();
+        let items: Vec<ItemId> = capsule_ids.iter().flat_map(|&id| indexes.items_of(id)).collect();
+        let contributor_links = capsule_ids.iter()
+            .map(|&capsule_id| ContributorLink { contributor_id, capsule_id })
+            .collect();
+        DeletionPlan {
+            dry_run: true,
+            contributors: Vec::new(),
+            capsules: capsule_ids,
+            item_count: items.len(),
+            items,
+            detached_items: Vec::new(),
+            unsorted_capsule_id: None,
+            contributor_links,
+        }
+    }
+
+    // The same deletion keeping the items instead of deleting them
+    pub fn detaching_items(self, unsorted_capsule_id: Option<CapsuleId>) -> Self {
+        DeletionPlan {
+            detached_items: self.items,
+            items: Vec::new(),
+            item_count: 0,
+            unsorted_capsule_id,
+            ..self
+        }
+    }
+
+    // Deleting a contributor along with every capsule it owns
+    pub fn contributor(contributor_id: ContributorId) -> Self {
+        let capsule_ids = INDEXES.read().unwrap().capsules_of(contributor_id);
+        DeletionPlan {
+            contributors: vec![contributor_id],
+            ..DeletionPlan::capsules(contributor_id, capsule_ids)
+        }
+    }
+}
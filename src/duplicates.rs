@@ -0,0 +1,76 @@
+// Double-submission guard for capsule creation. A contributor posting the exact same
+// capsule again within `duplicates.window_secs` (a double click, a retried request)
+// gets the capsule created the first time, marked with an X-Duplicate-Of header,
+// instead of a twin with a new id.
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::capsules::{Capsule, NewCapsule, CAPSULES};
+use crate::config;
+use crate::ids::CapsuleId;
+
+#[derive(Clone, Copy)]
+struct Recent {
+    capsule_id: CapsuleId,
+    created: DateTime<Utc>,
+}
+
+// Recently created capsules by hash of their payload
+static RECENT: Lazy<Mutex<HashMap<String, Recent>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn window() -> Option<Duration> {
+    let secs = config::get().duplicates.window_secs;
+    (secs > 0).then(|| Duration::seconds(secs.min(i64::MAX as u64) as i64))
+}
+
+// The payload includes the contributor id, so identical capsules of different contributors don't collide
+fn payload_hash(new_capsule: &NewCapsule) -> String {
+    let payload = serde_json::to_string(new_capsule).unwrap_or_default();
+    format!("{:x}", Sha256::digest(payload.as_bytes()))
+}
+
+// The capsule an identical recent request created, if it still exists. Called under the
+// contributor's lock, so a duplicate can't slip in between this check and `remember`.
+pub fn find(new_capsule: &NewCapsule, now: DateTime<Utc>) -> Option<Capsule> {
+    let window = window()?;
+    let recent = *RECENT.lock().unwrap().get(&payload_hash(new_capsule))?;
+    if now - recent.created > window || now < recent.created {
+        return None;
+    }
+    CAPSULES.get(recent.capsule_id)
+}
+
+pub fn remember(new_capsule: &NewCapsule, capsule_id: CapsuleId, now: DateTime<Utc>) {
+    let Some(window) = window() else { return };
+    let mut recent = RECENT.lock().unwrap();
+    recent.retain(|_, entry| now - entry.created <= window && entry.created <= now);
+    recent.insert(payload_hash(new_capsule), Recent { capsule_id, created: now });
+}
+
+// Forgets submissions that are past the window, for contributors who don't post again.
+// Run by the scheduler's `gc` task.
+pub fn sweep(now: DateTime<Utc>) {
+    let mut recent = RECENT.lock().unwrap();
+    match window() {
+        Some(window) => recent.retain(|_, entry| now - entry.created <= window && entry.created <= now),
+        None => recent.clear(),
+    }
+}
+
+// A response marked as the repeat of an earlier request with X-Duplicate-Of
+pub struct WithDuplicateOf<R>(pub R, pub Option<CapsuleId>);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for WithDuplicateOf<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.0.respond_to(request)?);
+        if let Some(capsule_id) = self.1 {
+            response.raw_header("X-Duplicate-Of", capsule_id.to_string());
+        }
+        response.ok()
+    }
+}
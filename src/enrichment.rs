@@ -0,0 +1,117 @@
+// Generated descriptions for new items, e.g. from a captioning or transcription
+// service. Enrichers run in the background after the item is stored, so uploads never
+// wait for them; the result goes into the item's `generated_description` metadata and
+// the progress into `enrichment`.
+use rocket::serde::Deserialize;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::time::Duration;
+
+use crate::config;
+use crate::items::{Item, ITEMS};
+
+// Something that can describe an item. To add one, implement this and add it to `ENRICHERS`.
+#[rocket::async_trait]
+pub trait Enricher: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Whether this enricher handles the item, the first one that does is used
+    fn accepts(&self, item: &Item) -> bool;
+
+    async fn describe(&self, item: &Item) -> Result<String, String>;
+}
+
+// Posts the item as JSON to `enrichment.url` and expects `{"description": "..."}` back
+pub struct WebhookEnricher {
+    url: String,
+    types: Vec<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WebhookResponse {
+    description: String,
+}
+
+#[rocket::async_trait]
+impl Enricher for WebhookEnricher {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn accepts(&self, item: &Item) -> bool {
+        self.types.is_empty() || self.types.contains(&item.type_c)
+    }
+
+    async fn describe(&self, item: &Item) -> Result<String, String> {
+        let response = self.client.post(&self.url).json(item).send().await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let body: WebhookResponse = response.json().await.map_err(|e| format!("Unexpected response: {}", e))?;
+        Ok(body.description)
+    }
+}
+
+static ENRICHERS: Lazy<Vec<Box<dyn Enricher>>> = Lazy::new(|| {
+    let settings = &config::get().enrichment;
+    let mut enrichers: Vec<Box<dyn Enricher>> = Vec::new();
+    if let Some(url) = &settings.url {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()
+            .expect("Failed to build the enrichment HTTP client");
+        enrichers.push(Box::new(WebhookEnricher { url: url.clone(), types: settings.types.clone(), client }));
+    }
+    enrichers
+});
+
+fn enricher_for(item: &Item) -> Option<&'static dyn Enricher> {
+    ENRICHERS.iter().find(|enricher| enricher.accepts(item)).map(|enricher| enricher.as_ref())
+}
+
+fn set_status(item: &mut Item, enricher: &str, status: &str, error: Option<String>) {
+    if let Some(metadata) = item.metadata.as_object_mut() {
+        metadata.insert("enrichment".into(), json!({
+            "enricher": enricher,
+            "status": status,
+            "error": error,
+            "time_updated": Utc::now(),
+        }));
+    }
+}
+
+// Marks a new item as waiting for a description, before it is stored. Returns whether
+// `start` should be called once it is; items whose metadata isn't an object are skipped.
+pub fn mark_pending(item: &mut Item) -> bool {
+    let Some(enricher) = enricher_for(item) else { return false };
+    if item.metadata.is_null() {
+        item.metadata = json!({});
+    }
+    if !item.metadata.is_object() {
+        return false;
+    }
+    set_status(item, enricher.name(), "pending", None);
+    true
+}
+
+// Describes a stored item in the background
+pub fn start(item: Item) {
+    let Some(enricher) = enricher_for(&item) else { return };
+    rocket::tokio::spawn(async move {
+        ITEMS.update(item.id, |stored| set_status(stored, enricher.name(), "running", None));
+        let result = enricher.describe(&item).await;
+
+        // The item may have been deleted meanwhile, then there's nothing to update
+        ITEMS.update(item.id, |stored| match result {
+            Ok(description) => {
+                if let Some(metadata) = stored.metadata.as_object_mut() {
+                    metadata.insert("generated_description".into(), json!(description));
+                }
+                set_status(stored, enricher.name(), "completed", None);
+            },
+            Err(e) => set_status(stored, enricher.name(), "failed", Some(e)),
+        });
+    });
+}
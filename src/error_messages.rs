@@ -0,0 +1,221 @@
+// Errors of the API with their messages in the client's language. Handlers answer the
+// common errors with an `ApiError`, which names its message by a key and carries the
+// values that go into it, like ids. When it's sent, the message is written in the first
+// language of Accept-Language that has a bundle, English otherwise; values are carried
+// over as they are. Other errors are answered in English with `ApiError::Other`, which
+// anything answered as `status::Custom<Json<String>>` turns into.
+use rocket::serde::json::Json;
+use rocket::http::{Header, Status};
+use rocket::response::{self, status, Responder};
+use rocket::Request;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+use crate::i18n;
+use crate::ids::{CapsuleId, ContributorId, ItemId};
+
+// Languages with a bundle, English being the one the messages are written in
+const LANGUAGES: [&str; 3] = ["en", "uk", "pl"];
+
+struct Translation {
+    key: &'static str,
+    en: &'static str,
+    uk: &'static str,
+    pl: &'static str,
+}
+
+// Values go into the translations in the order of the English template
+const BUNDLE: &[Translation] = &[
+    Translation { key: "capsule_not_found", en: "No capsule found with ID {}", uk: "Капсулу з ID {} не знайдено", pl: "Nie znaleziono kapsuły o ID {}" },
+    Translation { key: "capsule_missing", en: "Capsule not found", uk: "Капсулу не знайдено", pl: "Nie znaleziono kapsuły" },
+    Translation { key: "contributor_not_found", en: "Contributor not found", uk: "Учасника не знайдено", pl: "Nie znaleziono uczestnika" },
+    Translation { key: "item_not_found", en: "Item with ID {} not found", uk: "Елемент з ID {} не знайдено", pl: "Nie znaleziono elementu o ID {}" },
+    Translation { key: "item_not_in_capsule", en: "Item not found in the specified capsule", uk: "Елемент не знайдено у вказаній капсулі", pl: "Nie znaleziono elementu we wskazanej kapsule" },
+    Translation { key: "message_not_in_capsule", en: "Message not found in the specified capsule", uk: "Повідомлення не знайдено у вказаній капсулі", pl: "Nie znaleziono wiadomości we wskazanej kapsule" },
+    Translation { key: "contributions_ended", en: "The contribution period for this capsule has ended", uk: "Період додавання до цієї капсули завершився", pl: "Okres dodawania do tej kapsuły się zakończył" },
+    Translation { key: "modification_expired", en: "The modification period for this capsule has expired", uk: "Період змін цієї капсули минув", pl: "Okres wprowadzania zmian w tej kapsule minął" },
+    Translation { key: "capsule_opened", en: "Capsule {} has already opened", uk: "Капсула {} вже відкрилася", pl: "Kapsuła {} została już otwarta" },
+    Translation { key: "capsule_not_open", en: "Capsule {} opens on {} and its items can only be downloaded after that", uk: "Капсула {} відкривається {}, її елементи можна завантажити лише після цього", pl: "Kapsuła {} otwiera się {} i jej elementy można pobrać dopiero wtedy" },
+    Translation { key: "item_limit", en: "Capsule {} already holds the maximum of {} items", uk: "Капсула {} уже містить максимальні {} елементів", pl: "Kapsuła {} zawiera już maksymalnie {} elementów" },
+    Translation { key: "size_limit", en: "The item is {} bytes, capsule {} has {} bytes left", uk: "Елемент має {} байтів, у капсулі {} залишилося {} байтів", pl: "Element ma {} bajtów, w kapsule {} zostało {} bajtów" },
+    Translation { key: "version_required", en: "Version number is required.", uk: "Потрібен номер версії.", pl: "Wymagany jest numer wersji." },
+    Translation { key: "version_mismatch", en: "Version mismatch. Please refresh your data.", uk: "Версії не збігаються. Оновіть дані.", pl: "Niezgodność wersji. Odśwież dane." },
+    Translation { key: "version_mismatch_at", en: "Version mismatch, the capsule is at version {}. Please refresh your data.", uk: "Версії не збігаються, капсула має версію {}. Оновіть дані.", pl: "Niezgodność wersji, kapsuła ma wersję {}. Odśwież dane." },
+    Translation { key: "email_in_use", en: "Email already in use", uk: "Ця електронна адреса вже використовується", pl: "Ten adres e-mail jest już używany" },
+    Translation { key: "name_required", en: "name is required", uk: "Поле name обов'язкове", pl: "Pole name jest wymagane" },
+    Translation { key: "invalid_time", en: "{} must be a date or an RFC 3339 time", uk: "{} має бути датою або часом у форматі RFC 3339", pl: "{} musi być datą lub czasem w formacie RFC 3339" },
+    Translation { key: "invalid_group_by", en: "group_by must be day, week, month or year", uk: "group_by має бути day, week, month або year", pl: "group_by musi mieć wartość day, week, month lub year" },
+    Translation { key: "unknown_timezone", en: "Unknown timezone '{}', expected an IANA name like Europe/Warsaw", uk: "Невідомий часовий пояс '{}', очікується назва IANA, наприклад Europe/Warsaw", pl: "Nieznana strefa czasowa '{}', oczekiwano nazwy IANA, np. Europe/Warsaw" },
+    Translation { key: "not_invited_signer", en: "This contributor is not invited to sign the capsule", uk: "Цього учасника не запрошено підписати капсулу", pl: "Ten uczestnik nie został zaproszony do podpisania kapsuły" },
+    Translation { key: "already_signed", en: "This contributor has already signed the capsule", uk: "Цей учасник уже підписав капсулу", pl: "Ten uczestnik już podpisał kapsułę" },
+    Translation { key: "invalid_url", en: "url must be an http or https URL", uk: "url має бути адресою http або https", pl: "url musi być adresem http lub https" },
+    Translation { key: "not_own_account", en: "Only the contributor's own API key can change them", uk: "Змінювати учасника можна лише його власним ключем API", pl: "Uczestnika można zmieniać tylko jego własnym kluczem API" },
+    Translation { key: "not_webhook_owner", en: "Webhooks can only be managed by their own contributor", uk: "Вебхуками може керувати лише їхній власник", pl: "Webhookami może zarządzać tylko ich właściciel" },
+    Translation { key: "no_webhook", en: "Contributor {} has no webhook {}", uk: "Учасник {} не має вебхука {}", pl: "Uczestnik {} nie ma webhooka {}" },
+    Translation { key: "webhook_paused", en: "Webhook {} is paused", uk: "Вебхук {} призупинено", pl: "Webhook {} jest wstrzymany" },
+    Translation { key: "empty_message", en: "The message has no text", uk: "Повідомлення не містить тексту", pl: "Wiadomość nie zawiera tekstu" },
+    Translation { key: "unprocessable", en: "The request couldn't be processed", uk: "Не вдалося обробити запит", pl: "Nie udało się przetworzyć żądania" },
+];
+
+impl Translation {
+    fn text(&self, language: &str) -> &'static str {
+        match language {
+            "uk" => self.uk,
+            "pl" => self.pl,
+            _ => self.en,
+        }
+    }
+}
+
+pub enum ApiError {
+    CapsuleNotFound(CapsuleId),
+    CapsuleMissing,  // Without an id to name
+    ContributorNotFound,
+    UnknownContributor,  // Named in the body, so it's the request that's wrong
+    ItemNotFound(ItemId),
+    ItemNotInCapsule,
+    MessageNotInCapsule,
+    ContributionsEnded,
+    ModificationExpired,
+    CapsuleOpened(CapsuleId),
+    CapsuleNotOpen(CapsuleId, DateTime<Utc>),
+    ItemLimit(CapsuleId, usize),
+    SizeLimit { size: u64, capsule_id: CapsuleId, left: u64 },
+    VersionRequired,
+    VersionMismatch,
+    VersionMismatchAt(u32),
+    EmailInUse,
+    NameRequired,
+    InvalidTime(String),  // The parameter
+    InvalidGroupBy,
+    UnknownTimezone(String),
+    NotInvitedSigner,
+    AlreadySigned,
+    InvalidUrl,
+    NotOwnAccount,
+    NotWebhookOwner,
+    NoWebhook(ContributorId, u32),
+    WebhookPaused(u32),
+    EmptyMessage,
+    Unprocessable(Status),
+    Other(Status, String),  // Without a translation
+}
+
+impl ApiError {
+    // The status, the key of the message and its values
+    fn parts(&self) -> (Status, &'static str, Vec<String>) {
+        match self {
+            ApiError::CapsuleNotFound(id) => (Status::NotFound, "capsule_not_found", vec![id.to_string()]),
+            ApiError::CapsuleMissing => (Status::NotFound, "capsule_missing", vec![]),
+            ApiError::ContributorNotFound => (Status::NotFound, "contributor_not_found", vec![]),
+            ApiError::UnknownContributor => (Status::BadRequest, "contributor_not_found", vec![]),
+            ApiError::ItemNotFound(id) => (Status::NotFound, "item_not_found", vec![id.to_string()]),
+            ApiError::ItemNotInCapsule => (Status::NotFound, "item_not_in_capsule", vec![]),
+            ApiError::MessageNotInCapsule => (Status::NotFound, "message_not_in_capsule", vec![]),
+            ApiError::ContributionsEnded => (Status::BadRequest, "contributions_ended", vec![]),
+            ApiError::ModificationExpired => (Status::BadRequest, "modification_expired", vec![]),
+            ApiError::CapsuleOpened(id) => (Status::Conflict, "capsule_opened", vec![id.to_string()]),
+            ApiError::CapsuleNotOpen(id, time_open) => (Status::Conflict, "capsule_not_open", vec![id.to_string(), time_open.to_string()]),
+            ApiError::ItemLimit(id, items) => (Status::Forbidden, "item_limit", vec![id.to_string(), items.to_string()]),
+            ApiError::SizeLimit { size, capsule_id, left } => (Status::Forbidden, "size_limit", vec![size.to_string(), capsule_id.to_string(), left.to_string()]),
+            ApiError::VersionRequired => (Status::BadRequest, "version_required", vec![]),
+            ApiError::VersionMismatch => (Status::Conflict, "version_mismatch", vec![]),
+            ApiError::VersionMismatchAt(version) => (Status::PreconditionFailed, "version_mismatch_at", vec![version.to_string()]),
+            ApiError::EmailInUse => (Status::Conflict, "email_in_use", vec![]),
+            ApiError::NameRequired => (Status::BadRequest, "name_required", vec![]),
+            ApiError::InvalidTime(name) => (Status::BadRequest, "invalid_time", vec![name.to_string()]),
+            ApiError::InvalidGroupBy => (Status::BadRequest, "invalid_group_by", vec![]),
+            ApiError::UnknownTimezone(name) => (Status::BadRequest, "unknown_timezone", vec![name.clone()]),
+            ApiError::NotInvitedSigner => (Status::Forbidden, "not_invited_signer", vec![]),
+            ApiError::AlreadySigned => (Status::Conflict, "already_signed", vec![]),
+            ApiError::InvalidUrl => (Status::BadRequest, "invalid_url", vec![]),
+            ApiError::NotOwnAccount => (Status::Forbidden, "not_own_account", vec![]),
+            ApiError::NotWebhookOwner => (Status::Forbidden, "not_webhook_owner", vec![]),
+            ApiError::NoWebhook(id, webhook_id) => (Status::NotFound, "no_webhook", vec![id.to_string(), webhook_id.to_string()]),
+            ApiError::WebhookPaused(id) => (Status::Conflict, "webhook_paused", vec![id.to_string()]),
+            ApiError::EmptyMessage => (Status::BadRequest, "empty_message", vec![]),
+            ApiError::Unprocessable(status) => (*status, "unprocessable", vec![]),
+            ApiError::Other(status, _) => (*status, "", vec![]),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.parts().0
+    }
+
+    // The message in the language, English without a translation
+    pub fn message(&self, language: &str) -> String {
+        if let ApiError::Other(_, message) = self {
+            return message.clone();
+        }
+        let (_, key, values) = self.parts();
+        let translation = BUNDLE.iter().find(|translation| translation.key == key).expect("Every key has a translation");
+        fill(translation.text(language), &values)
+    }
+}
+
+// In English, for places without a request, like job errors
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message(LANGUAGES[0]))
+    }
+}
+
+impl From<status::Custom<Json<String>>> for ApiError {
+    fn from(error: status::Custom<Json<String>>) -> Self {
+        ApiError::Other(error.0, error.1.0)
+    }
+}
+
+fn fill(template: &str, values: &[String]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    for (index, part) in template.split("{}").enumerate() {
+        if index > 0 {
+            filled.push_str(values.get(index - 1).map(String::as_str).unwrap_or_default());
+        }
+        filled.push_str(part);
+    }
+    filled
+}
+
+// The first requested language with a bundle, by its primary subtag ("pl-PL" -> "pl")
+pub fn language_of(languages: &[String]) -> &'static str {
+    languages.iter()
+        .find_map(|tag| {
+            let primary = tag.split('-').next().unwrap_or_default();
+            LANGUAGES.into_iter().find(|language| language.eq_ignore_ascii_case(primary))
+        })
+        .unwrap_or(LANGUAGES[0])
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let language = language_of(&i18n::accepted_languages(request));
+        let translated = language != LANGUAGES[0] && !matches!(self, ApiError::Other(..));
+        let mut response = status::Custom(self.status(), Json(self.message(language))).respond_to(request)?;
+        response.adjoin_header(Header::new("Vary", "Accept-Language"));
+        if translated {
+            response.set_header(Header::new("Content-Language", language));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_fill_the_templates_in_order() {
+        assert_eq!(fill("Contributor {} has no webhook {}", &["1".into(), "2".into()]), "Contributor 1 has no webhook 2");
+        assert_eq!(fill("Capsule {} has already opened", &[]), "Capsule  has already opened");
+    }
+
+    #[test]
+    fn the_first_language_with_a_bundle_is_used() {
+        assert_eq!(language_of(&["de".into(), "PL-pl".into(), "uk".into()]), "pl");
+        assert_eq!(language_of(&["uk-UA".into()]), "uk");
+        assert_eq!(language_of(&["de".into()]), "en");
+        assert_eq!(language_of(&[]), "en");
+    }
+}
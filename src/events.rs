@@ -0,0 +1,213 @@
+// Event-sourced storage of capsules, enabled with `events.enabled = true`.
+//
+// Every change to a capsule is appended to a log as an immutable event: `created`
+// with the full capsule, `updated` with just the fields that changed, `deleted`.
+// On startup the capsules are a projection of that log, replayed in order, instead
+// of the data files; those are only used to seed the log on the very first start.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::config;
+use crate::ids::CapsuleId;
+use crate::store;
+use crate::time_format;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleEvent {
+    pub seq: u64,  // Position in the log, across all capsules
+    pub capsule_id: CapsuleId,
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub kind: EventKind,
+    pub data: Value,  // The capsule when created, the changed fields when updated
+}
+
+#[derive(Default)]
+struct EventLog {
+    events: HashMap<CapsuleId, Vec<CapsuleEvent>>,
+    projection: BTreeMap<CapsuleId, Value>,  // Current state per capsule, as folded from the events
+    next_seq: u64,
+    file: Option<File>,
+}
+
+static LOG: Lazy<Mutex<EventLog>> = Lazy::new(|| Mutex::new(EventLog { next_seq: 1, ..Default::default() }));
+
+// Folds one event into the projection
+fn apply(projection: &mut BTreeMap<CapsuleId, Value>, event: &CapsuleEvent) {
+    match event.kind {
+        EventKind::Created => {
+            projection.insert(event.capsule_id, event.data.clone());
+        },
+        EventKind::Updated => {
+            if let (Some(Value::Object(state)), Value::Object(changes)) = (projection.get_mut(&event.capsule_id), &event.data) {
+                for (field, value) in changes {
+                    state.insert(field.clone(), value.clone());
+                }
+            }
+        },
+        EventKind::Deleted => {
+            projection.remove(&event.capsule_id);
+        },
+    }
+}
+
+// Top-level fields of `new` that differ from `old`, removed fields become null
+fn changed_fields(old: &Value, new: &Value) -> Map<String, Value> {
+    let empty = Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let mut changes: Map<String, Value> = new.iter()
+        .filter(|(field, value)| old.get(*field) != Some(value))
+        .map(|(field, value)| (field.clone(), value.clone()))
+        .collect();
+    for field in old.keys().filter(|field| !new.contains_key(*field)) {
+        changes.insert(field.clone(), Value::Null);
+    }
+    changes
+}
+
+impl EventLog {
+    fn append(&mut self, capsule_id: CapsuleId, kind: EventKind, data: Value) {
+        let event = CapsuleEvent { seq: self.next_seq, capsule_id, time: Utc::now(), kind, data };
+        self.next_seq += 1;
+        if let Some(file) = &mut self.file {
+            let line = store::storing(|| serde_json::to_string(&event)).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("Failed to write event {} to the event log: {}", event.seq, e);
+            }
+        }
+        apply(&mut self.projection, &event);
+        self.events.entry(capsule_id).or_default().push(event);
+    }
+
+    // Turns a change seen by the table into an event, changes that leave the capsule as it was are skipped
+    fn record(&mut self, capsule_id: CapsuleId, capsule: Option<&Capsule>) {
+        let state = capsule.and_then(|capsule| store::storing(|| serde_json::to_value(capsule)).ok());
+        match (self.projection.get(&capsule_id), state) {
+            (None, Some(state)) => self.append(capsule_id, EventKind::Created, state),
+            (Some(previous), Some(state)) => {
+                let changes = changed_fields(previous, &state);
+                if !changes.is_empty() {
+                    self.append(capsule_id, EventKind::Updated, Value::Object(changes));
+                }
+            },
+            (Some(_), None) => self.append(capsule_id, EventKind::Deleted, Value::Null),
+            (None, None) => {},
+        }
+    }
+}
+
+// Replays the event log into the capsules it describes, None when there is no log yet
+pub fn replay() -> Option<Vec<Capsule>> {
+    let path = &config::get().events.file;
+    let file = File::open(path).ok()?;
+    let mut log = LOG.lock().unwrap();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: CapsuleEvent = store::loading(|| serde_json::from_str(&line))
+            .unwrap_or_else(|e| panic!("Invalid event on line {} of {}: {}", number + 1, path, e));
+        log.next_seq = log.next_seq.max(event.seq + 1);
+        apply(&mut log.projection, &event);
+        log.events.entry(event.capsule_id).or_default().push(event);
+    }
+    if log.events.is_empty() {
+        return None;
+    }
+
+    let capsules = log.projection.iter()
+        .map(|(id, state)| store::loading(|| serde_json::from_value(state.clone()))
+            .unwrap_or_else(|e| panic!("Capsule {} can't be rebuilt from {}: {}", id, path, e)))
+        .collect();
+    Some(capsules)
+}
+
+// Starts recording every change to the capsules. Capsules the log doesn't know yet, all
+// of them on the first start, get a `created` event first.
+pub fn start() {
+    let path = Path::new(&config::get().events.file);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Failed to create {}: {}", dir.display(), e));
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .unwrap_or_else(|e| panic!("Failed to open {}: {}", path.display(), e));
+
+    {
+        let mut log = LOG.lock().unwrap();
+        log.file = Some(file);
+        // Ids of deleted capsules stay taken, so their history never mixes with a new capsule
+        if let Some(&last_id) = log.events.keys().max() {
+            CAPSULES.reserve_through(last_id);
+        }
+        CAPSULES.for_each(|capsule| log.record(capsule.id, Some(capsule)));
+    }
+    CAPSULES.observe(|capsule_id, capsule| LOG.lock().unwrap().record(capsule_id, capsule));
+}
+
+// Signatures are stored with the capsule, but only shown through /signatures
+fn without_signatures(mut event: CapsuleEvent) -> CapsuleEvent {
+    if let Some(Value::Object(signing)) = event.data.get_mut("signing") {
+        signing.remove("signatures");
+    }
+    event
+}
+
+#[get("/capsules/<cid>/events")]
+pub fn capsule_events(cid: CapsuleId) -> Result<Json<Vec<CapsuleEvent>>, status::Custom<Json<String>>> {
+    if !config::get().events.enabled {
+        return Err(status::Custom(Status::NotFound, Json("Event sourcing is not enabled, start the server with events.enabled = true".into())));
+    }
+    LOG.lock().unwrap().events.get(&cid)
+        .map(|events| Json(events.iter().cloned().map(without_signatures).collect()))
+        .ok_or_else(|| status::Custom(Status::NotFound, Json(format!("No events for capsule {}", cid))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::EntityId;
+    use serde_json::json;
+
+    fn event(seq: u64, kind: EventKind, data: Value) -> CapsuleEvent {
+        CapsuleEvent { seq, capsule_id: CapsuleId::from_number(1), time: Utc::now(), kind, data }
+    }
+
+    #[test]
+    fn updates_hold_only_the_changed_fields() {
+        let old = json!({ "name": "Old", "description": "Same", "tags": ["a"] });
+        let new = json!({ "name": "New", "description": "Same" });
+        assert_eq!(Value::Object(changed_fields(&old, &new)), json!({ "name": "New", "tags": null }));
+    }
+
+    #[test]
+    fn events_fold_into_the_current_capsule() {
+        let mut projection = BTreeMap::new();
+        apply(&mut projection, &event(1, EventKind::Created, json!({ "name": "Old", "description": "Same" })));
+        apply(&mut projection, &event(2, EventKind::Updated, json!({ "name": "New" })));
+        assert_eq!(projection[&CapsuleId::from_number(1)], json!({ "name": "New", "description": "Same" }));
+
+        apply(&mut projection, &event(3, EventKind::Deleted, Value::Null));
+        assert!(projection.is_empty());
+    }
+}
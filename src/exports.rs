@@ -0,0 +1,209 @@
+// Large exports as background jobs: a full backup (the same JSON as GET /export) or the
+// ZIP of an opened capsule's item files is written into `exports.dir` while the client
+// polls GET /exports/<id>, then downloaded from GET /exports/<id>/download. Exports run
+// on the job queue and failed writes are retried. Every attempt writes its own `.part`
+// file and only renames it once complete, so a download never sees a half-written
+// artifact. Capsule ZIPs of a contributor with a data residency region are written under
+// that region's storage root.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::fs::NamedFile;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use rocket::tokio::{fs, io::AsyncWriteExt, task};
+use async_zip::tokio::write::ZipFileWriter;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::downloads;
+use crate::error_messages::ApiError;
+use crate::ids::CapsuleId;
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::jobs::{Job, JobContext, JobError, JobKind, JobStatus, SharedJobs};
+use crate::residency;
+use crate::reveals;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "rocket::serde", tag = "kind", rename_all = "snake_case")]
+pub enum ExportKind {
+    Backup,                                // Contributors, capsules and items
+    CapsuleZip { capsule_id: CapsuleId },  // Item files of an opened capsule
+}
+
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportJob {
+    #[serde(flatten)]
+    pub job: Job,  // total and done count records or files
+    #[serde(flatten)]
+    pub kind: ExportKind,
+    pub size: Option<u64>,
+    pub download_url: Option<String>,  // Set once completed
+}
+
+// What an export job writes, kept by job id next to the job itself
+#[derive(Clone, Copy)]
+struct Export {
+    kind: ExportKind,
+    size: Option<u64>,  // Of the finished file
+}
+
+static EXPORTS: Lazy<RwLock<HashMap<u32, Export>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn export_job(job: Job) -> Option<ExportJob> {
+    let Export { kind, size } = *EXPORTS.read().unwrap().get(&job.id)?;
+    let download_url = (job.status == JobStatus::Completed).then(|| format!("/exports/{}/download", job.id));
+    Some(ExportJob { job, kind, size, download_url })
+}
+
+fn file_name(job_id: u32, kind: ExportKind) -> String {
+    match kind {
+        ExportKind::Backup => format!("export-{}-backup.json", job_id),
+        ExportKind::CapsuleZip { capsule_id } => format!("export-{}-capsule-{}.zip", job_id, capsule_id),
+    }
+}
+
+fn artifact_dir(kind: ExportKind) -> Result<PathBuf, String> {
+    let dir = &config::get().exports.dir;
+    match kind {
+        ExportKind::Backup => Ok(PathBuf::from(dir)),
+        ExportKind::CapsuleZip { capsule_id } => residency::capsule_dir(dir, capsule_id),
+    }
+}
+
+// The finished file, in whichever region it was written
+fn find_artifact(job_id: u32, kind: ExportKind) -> Option<PathBuf> {
+    residency::all_dirs(&config::get().exports.dir).into_iter()
+        .map(|dir| dir.join(file_name(job_id, kind)))
+        .find(|path| path.is_file())
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    path.with_extension("part")
+}
+
+// A JSON array of the records that still exist, written one at a time
+fn write_array<K, T: Serialize>(out: &mut impl Write, keys: Vec<K>, fetch: impl Fn(K) -> Option<T>, ctx: &JobContext) -> std::io::Result<()> {
+    out.write_all(b"[")?;
+    let mut first = true;
+    for key in keys {
+        if let Some(record) = fetch(key) {
+            if !first {
+                out.write_all(b",")?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *out, &record).map_err(std::io::Error::other)?;
+        }
+        ctx.advance();
+    }
+    out.write_all(b"]")
+}
+
+fn write_backup(ctx: &JobContext, path: &Path) -> std::io::Result<()> {
+    let (contributor_ids, capsule_ids, item_ids) = (CONTRIBUTORS.ids(), CAPSULES.ids(), ITEMS.ids());
+    ctx.set_total(contributor_ids.len() + capsule_ids.len() + item_ids.len());
+
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    out.write_all(b"{\"contributors\":")?;
+    write_array(&mut out, contributor_ids, |id| CONTRIBUTORS.get(id), ctx)?;
+    out.write_all(b",\"capsules\":")?;
+    write_array(&mut out, capsule_ids, |id| CAPSULES.get(id), ctx)?;
+    out.write_all(b",\"items\":")?;
+    write_array(&mut out, item_ids, |id| ITEMS.get(id), ctx)?;
+    out.write_all(b"}")?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+async fn write_capsule_zip(ctx: &JobContext, capsule_id: CapsuleId, path: &Path) -> Result<(), String> {
+    let item_ids = reveals::visible(capsule_id, INDEXES.read().unwrap().items_of(capsule_id));
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+    ctx.set_total(items.len());
+
+    let file = fs::File::create(path).await.map_err(|e| e.to_string())?;
+    let mut file = downloads::write_zip(ZipFileWriter::with_tokio(file), items, || ctx.advance()).await
+        .map_err(|e| e.to_string())?;
+    file.flush().await.map_err(|e| e.to_string())?;
+    file.sync_all().await.map_err(|e| e.to_string())
+}
+
+// One attempt of an export job. Writing is retried, the file is simply written again.
+async fn run_export(ctx: JobContext, kind: ExportKind) -> Result<(), JobError> {
+    let job_id = ctx.job_id;
+    let dir = artifact_dir(kind).map_err(JobError::permanent)?;
+    let path = dir.join(file_name(job_id, kind));
+    let part = part_path(&path);
+    let written = match fs::create_dir_all(&dir).await {
+        Err(e) => Err(e.to_string()),
+        Ok(()) => match kind {
+            ExportKind::Backup => {
+                let (ctx, part) = (ctx.clone(), part.clone());
+                task::spawn_blocking(move || write_backup(&ctx, &part)).await
+                    .map_err(|e| e.to_string())
+                    .and_then(|written| written.map_err(|e| e.to_string()))
+            },
+            ExportKind::CapsuleZip { capsule_id } => write_capsule_zip(&ctx, capsule_id, &part).await,
+        },
+    };
+    let written = match written {
+        Ok(()) => fs::rename(&part, &path).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let _ = fs::remove_file(&part).await;
+        return Err(JobError::temporary(e));
+    }
+
+    let size = fs::metadata(&path).await.ok().map(|metadata| metadata.len());
+    if let Some(export) = EXPORTS.write().unwrap().get_mut(&job_id) {
+        export.size = size;
+    }
+    Ok(())
+}
+
+#[post("/exports", format = "json", data = "<kind>")]
+pub fn start_export(kind: Json<ExportKind>, clock: &State<SharedClock>, jobs: &State<SharedJobs>) -> Result<status::Accepted<Json<ExportJob>>, ApiError> {
+    let kind = kind.into_inner();
+    if let ExportKind::CapsuleZip { capsule_id } = kind {
+        let time_open = CAPSULES.read(capsule_id, |capsule| capsule.time_open)
+            .ok_or(ApiError::CapsuleNotFound(capsule_id))?;
+        if time_open > clock.now() {
+            return Err(ApiError::Other(Status::Conflict, format!("Capsule {} opens on {} and its items can only be exported after that", capsule_id, time_open)));
+        }
+    }
+
+    // Held while submitting, so the export is known before a worker can pick the job up
+    let mut exports = EXPORTS.write().unwrap();
+    let job = jobs.submit(JobKind::Export, Box::new(move |ctx| Box::pin(run_export(ctx, kind))));
+    exports.insert(job.id, Export { kind, size: None });
+    drop(exports);
+
+    Ok(status::Accepted(Json(ExportJob { job, kind, size: None, download_url: None })))
+}
+
+fn find_export(id: u32, jobs: &SharedJobs) -> Option<ExportJob> {
+    jobs.get(id).filter(|job| job.kind == JobKind::Export).and_then(export_job)
+}
+
+#[get("/exports/<id>")]
+pub fn get_export(id: u32, jobs: &State<SharedJobs>) -> Option<Json<ExportJob>> {
+    find_export(id, jobs).map(Json)
+}
+
+#[get("/exports/<id>/download")]
+pub async fn download_export(id: u32, jobs: &State<SharedJobs>) -> Result<NamedFile, status::Custom<Json<String>>> {
+    let job = find_export(id, jobs)
+        .ok_or_else(|| status::Custom(Status::NotFound, Json(format!("No export job with ID {}", id))))?;
+    if job.job.status != JobStatus::Completed {
+        return Err(status::Custom(Status::Conflict, Json(format!("Export job {} isn't completed yet", id))));
+    }
+    let removed = || status::Custom(Status::Gone, Json(format!("The file of export job {} was removed", id)));
+    NamedFile::open(find_artifact(id, job.kind).ok_or_else(removed)?).await.map_err(|_| removed())
+}
@@ -0,0 +1,46 @@
+// Which fields each version of a capsule changed, so a PATCH made against an older
+// version can be rebased when it doesn't touch anything that changed since. Only the
+// recent versions are kept; older ones, and anything before a full PUT, count as unknown.
+use once_cell::sync::Lazy;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::RwLock;
+use crate::ids::CapsuleId;
+
+const MAX_VERSIONS: usize = 50;
+
+// Versions of one capsule with the fields each of them changed, oldest first
+type Versions = VecDeque<(u32, Vec<&'static str>)>;
+
+pub static CAPSULE_HISTORY: Lazy<RwLock<HashMap<CapsuleId, Versions>>> = Lazy::new(|| {
+    RwLock::new(HashMap::new())
+});
+
+// Notes the fields changed by a version, called whenever a capsule's version is bumped
+pub fn record(capsule_id: CapsuleId, version: u32, fields: Vec<&'static str>) {
+    let mut history = CAPSULE_HISTORY.write().unwrap();
+    let versions = history.entry(capsule_id).or_default();
+    versions.push_back((version, fields));
+    if versions.len() > MAX_VERSIONS {
+        versions.pop_front();
+    }
+}
+
+// Fields changed after `version` up to `current`, None if any of those versions is unknown
+pub fn changed_since(capsule_id: CapsuleId, version: u32, current: u32) -> Option<BTreeSet<&'static str>> {
+    if version > current {
+        return None;
+    }
+    let history = CAPSULE_HISTORY.read().unwrap();
+    let versions = history.get(&capsule_id);
+    let mut changed = BTreeSet::new();
+    for missed in version + 1..=current {
+        let (_, fields) = versions?.iter().find(|(v, _)| *v == missed)?;
+        changed.extend(fields.iter().copied());
+    }
+    Some(changed)
+}
+
+// Drops the history of a removed or fully replaced capsule
+pub fn forget(capsule_id: CapsuleId) {
+    CAPSULE_HISTORY.write().unwrap().remove(&capsule_id);
+}
@@ -0,0 +1,68 @@
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use std::sync::RwLock;
+use once_cell::sync::Lazy;
+
+use crate::state;
+
+// Optional behaviors that can be switched off at runtime without redeploying
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct FeatureFlags {
+    pub public_feed_enabled: bool,
+    pub uploads_enabled: bool,
+    pub merges_enabled: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        FeatureFlags {
+            public_feed_enabled: true,
+            uploads_enabled: true,
+            merges_enabled: true,
+        }
+    }
+}
+
+// Global in-memory storage for the feature flags
+pub static FLAGS: Lazy<RwLock<FeatureFlags>> = Lazy::new(|| {
+    RwLock::new(FeatureFlags::default())
+});
+
+// Snapshot of the current flags, used by the handlers that are guarded by them
+pub fn current() -> FeatureFlags {
+    FLAGS.read().unwrap().clone()
+}
+
+// Shared rejection for handlers whose feature has been switched off
+pub fn disabled(feature: &str) -> status::Custom<Json<String>> {
+    status::Custom(Status::ServiceUnavailable, Json(format!("{} is currently disabled", feature)))
+}
+
+#[get("/admin/flags")]
+pub fn get_flags() -> Json<FeatureFlags> {
+    Json(current())
+}
+
+#[put("/admin/flags", format = "json", data = "<flags_data>")]
+pub fn update_flags(flags_data: Json<FeatureFlags>) -> Json<FeatureFlags> {
+    let mut flags = FLAGS.write().unwrap();
+    *flags = flags_data.into_inner();
+    state::changed("flags", "flags");
+    Json(flags.clone())
+}
+
+// The flags as the single state entry "flags", see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    vec![("flags".to_string(), state::to_entry(&current()))]
+}
+
+pub fn entry(_: &str) -> Option<serde_json::Value> {
+    Some(state::to_entry(&current()))
+}
+
+pub fn put(_: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    *FLAGS.write().unwrap() = entry.map(state::from_entry).transpose()?.unwrap_or_default();
+    Ok(())
+}
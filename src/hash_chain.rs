@@ -0,0 +1,225 @@
+// Tamper-evident history of every capsule: each revision of its content is hashed and
+// linked to the previous one, hash = sha256(prev_hash + state_hash). Once the capsule
+// opens, recipients can walk the chain and see exactly when its content changed, and
+// check that what they see now is the head of that chain. The chains are kept as state
+// (see state.rs) and checked when they're loaded: a content change the server didn't
+// make, while it wasn't running, is linked as an `offline` revision instead of starting
+// the chain over.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::error_messages::ApiError;
+use crate::items::ITEMS;
+use crate::indexes::INDEXES;
+use crate::ids::{CapsuleId, EntityId};
+use crate::state;
+use crate::time_format;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Item metadata filled in by the server rather than the contributors, see enrichment.rs
+const GENERATED_METADATA: [&str; 2] = ["enrichment", "generated_description"];
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ChainLink {
+    pub seq: u32,
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub version: u32,  // Capsule version at this revision
+    pub state_hash: String,
+    pub prev_hash: String,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offline: bool,  // Found when the chain was loaded, the server didn't make this change
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HashChain {
+    pub capsule_id: CapsuleId,
+    pub head: String,
+    pub current_state_hash: String,
+    pub verified: bool,  // Links are intact, none is offline and the current content is the head's
+    pub links: Vec<ChainLink>,
+}
+
+static CHAINS: Lazy<Mutex<HashMap<CapsuleId, Vec<ChainLink>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn hex_sha256(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// What the chain vouches for: the capsule's content and its items, as JSON with sorted
+// keys. Bookkeeping such as edit times, publishing or reveal progress is left out.
+fn content(capsule: &Capsule) -> Value {
+    let items: Vec<Value> = INDEXES.read().unwrap().items_of(capsule.id).into_iter()
+        .filter_map(|id| ITEMS.get(id))
+        .map(|item| {
+            let mut metadata = item.metadata;
+            if let Some(fields) = metadata.as_object_mut() {
+                fields.retain(|field, _| !GENERATED_METADATA.contains(&field.as_str()));
+            }
+            json!({
+                "id": item.id,
+                "type_c": item.type_c,
+                "time_added": item.time_added,
+                "description": item.description,
+                "size": item.size,
+                "path": item.path,
+                "metadata": metadata,
+            })
+        })
+        .collect();
+    json!({
+        "id": capsule.id,
+        "contributor_id": capsule.contributor_id,
+        "name": capsule.name,
+        "description": capsule.description,
+        "time_created": capsule.time_created,
+        "time_open": capsule.time_open,
+        "time_until_changed": capsule.time_until_changed,
+        "items": items,
+    })
+}
+
+pub fn state_hash(capsule: &Capsule) -> String {
+    hex_sha256(content(capsule).to_string().as_bytes())
+}
+
+fn link_hash(prev_hash: &str, state_hash: &str) -> String {
+    hex_sha256(format!("{}{}", prev_hash, state_hash).as_bytes())
+}
+
+// Whether every link follows from the one before it, starting from the genesis hash
+fn intact(links: &[ChainLink]) -> bool {
+    let mut prev_hash = GENESIS_HASH;
+    for (i, link) in links.iter().enumerate() {
+        if link.seq != i as u32 + 1 || link.prev_hash != prev_hash || link.hash != link_hash(&link.prev_hash, &link.state_hash) {
+            return false;
+        }
+        prev_hash = &link.hash;
+    }
+    true
+}
+
+// Adds a link when the capsule's content changed, drops the chain once it's removed
+fn record(chains: &mut HashMap<CapsuleId, Vec<ChainLink>>, capsule_id: CapsuleId, capsule: Option<&Capsule>, time: DateTime<Utc>, offline: bool) {
+    let Some(capsule) = capsule else {
+        if chains.remove(&capsule_id).is_some() {
+            state::changed("hash_chains", capsule_id.number());
+        }
+        return;
+    };
+    let state_hash = state_hash(capsule);
+    let links = chains.entry(capsule_id).or_default();
+    if links.last().is_some_and(|last| last.state_hash == state_hash) {
+        return;
+    }
+    let prev_hash = links.last().map_or(GENESIS_HASH.to_string(), |last| last.hash.clone());
+    links.push(ChainLink {
+        seq: links.len() as u32 + 1,
+        time,
+        version: capsule.version,
+        hash: link_hash(&prev_hash, &state_hash),
+        state_hash,
+        prev_hash,
+        offline,
+    });
+    state::changed("hash_chains", capsule_id.number());
+}
+
+// Checks the loaded chains against the loaded capsules and starts the missing ones, called
+// once their items are loaded too. Only changes that went through reach the observer.
+pub fn start() {
+    {
+        let mut chains = CHAINS.lock().unwrap();
+        let gone: Vec<CapsuleId> = chains.keys().copied().filter(|&capsule_id| !CAPSULES.contains(capsule_id)).collect();
+        for capsule_id in gone {
+            record(&mut chains, capsule_id, None, Utc::now(), false);
+        }
+        CAPSULES.for_each(|capsule| {
+            let time = capsule.time_changed.unwrap_or(capsule.time_created);
+            let offline = match chains.get(&capsule.id) {
+                // The last change before the chain existed, nothing earlier is known
+                None => false,
+                Some(links) => {
+                    if !intact(links) {
+                        eprintln!("The hash chain of capsule {} is broken, it was changed outside the server", capsule.id);
+                    }
+                    true
+                },
+            };
+            record(&mut chains, capsule.id, Some(capsule), time, offline);
+        });
+    }
+    CAPSULES.observe(|capsule_id, capsule| record(&mut CHAINS.lock().unwrap(), capsule_id, capsule, Utc::now(), false));
+}
+
+// Hash of the latest revision, sent with the capsule
+pub fn head(capsule_id: CapsuleId) -> Option<String> {
+    CHAINS.lock().unwrap().get(&capsule_id)?.last().map(|link| link.hash.clone())
+}
+
+// A response with the capsule's chain head in an X-Capsule-Hash header
+pub struct WithChainHash<R>(pub R, pub Option<String>);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for WithChainHash<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.0.respond_to(request)?);
+        if let Some(hash) = self.1 {
+            response.raw_header("X-Capsule-Hash", hash);
+        }
+        response.ok()
+    }
+}
+
+#[get("/capsules/<cid>/hash-chain")]
+pub fn capsule_hash_chain(cid: CapsuleId) -> Result<Json<HashChain>, ApiError> {
+    let current_state_hash = CAPSULES.read(cid, state_hash)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    let links = CHAINS.lock().unwrap().get(&cid).cloned().unwrap_or_default();
+
+    let head = links.last().map_or(GENESIS_HASH.to_string(), |last| last.hash.clone());
+    let verified = intact(&links)
+        && !links.iter().any(|link| link.offline)
+        && links.last().is_some_and(|last| last.state_hash == current_state_hash);
+
+    Ok(Json(HashChain {
+        capsule_id: cid,
+        head,
+        current_state_hash,
+        verified,
+        links,
+    }))
+}
+
+// The chains as state entries by capsule id, see state.rs
+pub fn entries() -> Vec<(String, Value)> {
+    CHAINS.lock().unwrap().iter().map(|(capsule_id, links)| (capsule_id.number().to_string(), state::to_entry(links))).collect()
+}
+
+pub fn entry(key: &str) -> Option<Value> {
+    let capsule_id = CapsuleId::from_number(key.parse().ok()?);
+    CHAINS.lock().unwrap().get(&capsule_id).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<Value>) -> Result<(), serde_json::Error> {
+    let Ok(number) = key.parse() else { return Ok(()) };
+    let capsule_id = CapsuleId::from_number(number);
+    let links: Option<Vec<ChainLink>> = entry.map(state::from_entry).transpose()?;
+    let mut chains = CHAINS.lock().unwrap();
+    match links {
+        Some(links) => chains.insert(capsule_id, links),
+        None => chains.remove(&capsule_id),
+    };
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use rocket::serde::{Deserialize, Serialize};
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+use std::collections::BTreeMap;
+
+// Language used when none of the requested ones is available
+const DEFAULT_LANGUAGE: &str = "en";
+
+// Text field that is either a plain string or a map of language tag to translation,
+// e.g. {"en": "Diary", "uk": "Щоденник"}. Plain strings keep older data and clients working.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum LocalizedText {
+    Plain(String),
+    Localized(BTreeMap<String, String>),
+}
+
+impl From<String> for LocalizedText {
+    fn from(text: String) -> Self {
+        LocalizedText::Plain(text)
+    }
+}
+
+impl LocalizedText {
+    // Picks the best translation: each requested language in order, first exactly and then
+    // by its primary subtag ("uk-UA" -> "uk"), then the default language, then any
+    pub fn resolve(&self, languages: &[String]) -> &str {
+        let translations = match self {
+            LocalizedText::Plain(text) => return text,
+            LocalizedText::Localized(translations) => translations,
+        };
+        let find = |tag: &str| translations.iter()
+            .find(|(language, _)| language.eq_ignore_ascii_case(tag))
+            .map(|(_, text)| text.as_str());
+
+        languages.iter()
+            .find_map(|tag| find(tag).or_else(|| find(tag.split('-').next().unwrap_or_default())))
+            .or_else(|| find(DEFAULT_LANGUAGE))
+            .or_else(|| translations.values().next().map(String::as_str))
+            .unwrap_or_default()
+    }
+
+    // Text in the default language, for places without a request to take preferences from
+    // Every translation, for searching
+    pub fn texts(&self) -> Vec<&str> {
+        match self {
+            LocalizedText::Plain(text) => vec![text.as_str()],
+            LocalizedText::Localized(translations) => translations.values().map(String::as_str).collect(),
+        }
+    }
+
+    pub fn default_text(&self) -> &str {
+        self.resolve(&[])
+    }
+
+    // The same text with every translation rewritten
+    pub fn map(&self, f: impl Fn(&str) -> String) -> LocalizedText {
+        match self {
+            LocalizedText::Plain(text) => LocalizedText::Plain(f(text)),
+            LocalizedText::Localized(translations) => LocalizedText::Localized(translations.iter()
+                .map(|(language, text)| (language.clone(), f(text)))
+                .collect()),
+        }
+    }
+
+    // The same text reduced to a plain string in the best matching language
+    pub fn localized(&self, languages: &[String]) -> LocalizedText {
+        LocalizedText::Plain(self.resolve(languages).to_string())
+    }
+}
+
+// Languages from the Accept-Language header, most preferred first
+pub struct AcceptLanguage(pub Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptLanguage {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(AcceptLanguage(accepted_languages(request)))
+    }
+}
+
+// Languages from the Accept-Language header, most preferred first
+pub fn accepted_languages(request: &Request<'_>) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = request.headers().get("Accept-Language")
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then(|| (tag.to_string(), quality))
+        })
+        .collect();
+    // Stable, so equally weighted languages keep the client's order
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages.into_iter().map(|(tag, _)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_best_translation_is_picked() {
+        let text = LocalizedText::Localized(BTreeMap::from([
+            ("en".to_string(), "Hello".to_string()),
+            ("uk".to_string(), "Привіт".to_string()),
+            ("pl-PL".to_string(), "Cześć".to_string()),
+        ]));
+        assert_eq!(text.resolve(&["uk-UA".into()]), "Привіт");
+        assert_eq!(text.resolve(&["PL-pl".into()]), "Cześć");
+        assert_eq!(text.resolve(&["de".into()]), "Hello");
+        assert_eq!(LocalizedText::Localized(BTreeMap::from([("pl".to_string(), "Cześć".to_string())])).default_text(), "Cześć");
+        assert_eq!(LocalizedText::from("Hello".to_string()).resolve(&["uk".into()]), "Hello");
+    }
+}
@@ -0,0 +1,274 @@
+// Typed entity ids. Every capsule, item and contributor is stored under a sequential
+// number, but with `ids.strategy = "uuid"` the API shows a random UUID in its place, so
+// ids can't be guessed or enumerated. A UUID is given out when a record is stored, loaded
+// data sets with plain numbers included, and kept in `ids.file` so it stays the same
+// across restarts. Clients may also pick the UUID of a new record themselves, see
+// claim_uuid. Request bodies then have to use UUIDs, only stored data is read with numbers.
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::request::FromParam;
+use rocket::serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+use crate::config::{self, IdStrategy};
+use crate::store;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Kind {
+    Capsule,
+    Item,
+    Contributor,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Capsule => "capsule",
+            Kind::Item => "item",
+            Kind::Contributor => "contributor",
+        }
+    }
+}
+
+// A line of `ids.file`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Assignment {
+    kind: Kind,
+    id: u32,
+    uuid: String,
+}
+
+#[derive(Default)]
+struct Registry {
+    uuids: HashMap<(Kind, u32), Uuid>,
+    ids: HashMap<Uuid, (Kind, u32)>,
+    file: Option<File>,
+}
+
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(|| RwLock::new(Registry::load(&config::get().ids.file)));
+
+impl Registry {
+    fn load(path: &str) -> Registry {
+        let mut registry = Registry::default();
+        if let Ok(file) = File::open(path) {
+            for (number, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let assignment: Assignment = serde_json::from_str(&line)
+                    .unwrap_or_else(|e| panic!("Invalid id on line {} of {}: {}", number + 1, path, e));
+                let uuid = Uuid::parse_str(&assignment.uuid)
+                    .unwrap_or_else(|e| panic!("Invalid UUID on line {} of {}: {}", number + 1, path, e));
+                registry.uuids.insert((assignment.kind, assignment.id), uuid);
+                registry.ids.insert(uuid, (assignment.kind, assignment.id));
+            }
+        }
+        registry
+    }
+
+    fn assign(&mut self, kind: Kind, id: u32) -> Uuid {
+        if let Some(&uuid) = self.uuids.get(&(kind, id)) {
+            return uuid;
+        }
+        let uuid = Uuid::new_v4();
+        self.record(kind, id, uuid);
+        uuid
+    }
+
+    fn record(&mut self, kind: Kind, id: u32, uuid: Uuid) {
+        self.uuids.insert((kind, id), uuid);
+        self.ids.insert(uuid, (kind, id));
+
+        // Written right away, the UUID may end up in stored data before the next restart
+        if self.file.is_none() {
+            let path = Path::new(&config::get().ids.file);
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                let _ = fs::create_dir_all(dir);
+            }
+            self.file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| eprintln!("Failed to open {}: {}", path.display(), e))
+                .ok();
+        }
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&Assignment { kind, id, uuid: uuid.to_string() }).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("Failed to record the UUID of {:?} {}: {}", kind, id, e);
+            }
+        }
+    }
+}
+
+fn uuids() -> bool {
+    config::get().ids.strategy == IdStrategy::Uuid
+}
+
+// Gives a stored record its UUID unless it has one, with the uuid strategy
+pub fn assign<I: EntityId>(id: I) {
+    if !uuids() || REGISTRY.read().unwrap().uuids.contains_key(&(I::KIND, id.number())) {
+        return;
+    }
+    REGISTRY.write().unwrap().assign(I::KIND, id.number());
+}
+
+// The UUID shown for an id. Every stored record has one; an id that was never stored,
+// like one taken from a failed request, shows as the nil UUID instead of getting one.
+pub fn public_uuid(kind: Kind, id: u32) -> Uuid {
+    REGISTRY.read().unwrap().uuids.get(&(kind, id)).copied().unwrap_or(Uuid::nil())
+}
+
+// Gives a new id the UUID a client chose for it. False if the UUID is already taken.
+pub fn claim_uuid(kind: Kind, uuid: Uuid, id: u32) -> bool {
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.ids.contains_key(&uuid) || registry.uuids.contains_key(&(kind, id)) {
+        return false;
+    }
+    registry.record(kind, id, uuid);
+    true
+}
+
+fn resolve(kind: Kind, uuid: Uuid) -> Option<u32> {
+    match REGISTRY.read().unwrap().ids.get(&uuid) {
+        Some(&(found, id)) if found == kind => Some(id),
+        _ => None,
+    }
+}
+
+// An id as written in a path or query: a UUID with the uuid strategy, a number otherwise.
+// UUIDs chosen by clients are known with either strategy.
+fn parse_public(kind: Kind, value: &str) -> Option<u32> {
+    match Uuid::parse_str(value.trim()) {
+        Ok(uuid) => resolve(kind, uuid),
+        Err(_) if !uuids() => value.trim().parse().ok(),
+        Err(_) => None,
+    }
+}
+
+// Stored data accepts plain numbers with either strategy, so existing data sets keep
+// loading, request bodies only without the uuid strategy; strings have to be a known UUID
+struct IdVisitor(Kind);
+
+impl IdVisitor {
+    fn number<E: de::Error>(self, value: impl TryInto<u32> + fmt::Display + Copy) -> Result<u32, E> {
+        if uuids() && !store::is_loading() {
+            return Err(E::custom(format!("{} ids are UUIDs, not numbers like {}", self.0.name(), value)));
+        }
+        value.try_into().map_err(|_| E::custom(format!("id {} is out of range", value)))
+    }
+}
+
+impl<'de> de::Visitor<'de> for IdVisitor {
+    type Value = u32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a numeric id or a UUID")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u32, E> {
+        self.number(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u32, E> {
+        self.number(value)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u32, E> {
+        Uuid::parse_str(value).ok()
+            .and_then(|uuid| resolve(self.0, uuid))
+            .ok_or_else(|| E::custom(format!("unknown {} id {}", self.0.name(), value)))
+    }
+}
+
+// What the store needs from an id
+pub trait EntityId: Copy + Eq + Ord + Hash + Send + Sync + fmt::Display + 'static {
+    const KIND: Kind;
+
+    fn from_number(number: u32) -> Self;
+    fn number(self) -> u32;
+}
+
+// One type per kind of record, so passing a capsule id where an item id belongs doesn't
+// compile. The number inside is private, ids come from the store, paths and bodies.
+macro_rules! entity_id {
+    ($name:ident, $kind:expr) => {
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+        pub struct $name(u32);
+
+        impl EntityId for $name {
+            const KIND: Kind = $kind;
+
+            fn from_number(number: u32) -> Self {
+                $name(number)
+            }
+
+            // The stored number, never shown with the uuid strategy
+            fn number(self) -> u32 {
+                self.0
+            }
+        }
+
+        // The public form, for messages and links
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                if uuids() {
+                    write!(f, "{}", public_uuid($kind, self.0))
+                } else {
+                    write!(f, "{}", self.0)
+                }
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if uuids() {
+                    serializer.serialize_str(&public_uuid($kind, self.0).to_string())
+                } else {
+                    serializer.serialize_u32(self.0)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(IdVisitor($kind)).map($name)
+            }
+        }
+
+        impl<'a> FromParam<'a> for $name {
+            type Error = &'a str;
+
+            fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+                parse_public($kind, param).map($name).ok_or(param)
+            }
+        }
+
+        #[rocket::async_trait]
+        impl<'v> FromFormField<'v> for $name {
+            fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+                parse_public($kind, field.value).map($name)
+                    .ok_or_else(|| form::Error::validation("not a valid id").into())
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = ();
+
+            fn from_str(value: &str) -> Result<Self, ()> {
+                parse_public($kind, value).map($name).ok_or(())
+            }
+        }
+    };
+}
+
+entity_id!(CapsuleId, Kind::Capsule);
+entity_id!(ItemId, Kind::Item);
+entity_id!(ContributorId, Kind::Contributor);
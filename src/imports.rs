@@ -0,0 +1,347 @@
+// Bulk import of remote files into a capsule.
+//
+// An import runs on the job queue: every file is downloaded into the imports directory,
+// hashed on the way, and added to the capsule as an item. Progress is polled through
+// GET /imports/<job_id>. A source that can't be listed is retried, files that fail are
+// reported instead.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use rocket::tokio::{fs, io::AsyncWriteExt};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::capsules::CAPSULES;
+use crate::clock::{Clock, SharedClock};
+use crate::config;
+use crate::destinations;
+use crate::error_messages::ApiError;
+use crate::flags;
+use crate::items::{self, NewItem, Origin};
+use crate::ids::{CapsuleId, EntityId, ItemId};
+use crate::jobs::{Job, JobContext, JobError, JobKind, SharedJobs};
+use crate::residency;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ImportSource {
+    UrlList,
+    Dropbox,
+    GooglePhotos,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportRequest {
+    pub source: ImportSource,
+    #[serde(default)]
+    pub urls: Vec<String>,             // url_list: direct links, dropbox: shared links
+    pub access_token: Option<String>,  // google_photos: OAuth token with photoslibrary.readonly
+    pub album_id: Option<String>,      // google_photos: album to import
+}
+
+// What an import did so far, kept by job id next to the job itself
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportResult {
+    pub capsule_id: CapsuleId,
+    pub imported: usize,
+    pub failed: usize,
+    pub item_ids: Vec<ItemId>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportJob {
+    #[serde(flatten)]
+    pub job: Job,
+    #[serde(flatten)]
+    pub result: ImportResult,
+}
+
+static IMPORT_RESULTS: Lazy<RwLock<HashMap<u32, ImportResult>>> = Lazy::new(|| {
+    RwLock::new(HashMap::new())
+});
+
+// A remote file to import, with whatever the source already told us about it
+struct RemoteFile {
+    url: String,
+    name: Option<String>,
+    metadata: serde_json::Value,
+}
+
+fn update_result<R>(job_id: u32, capsule_id: CapsuleId, f: impl FnOnce(&mut ImportResult) -> R) -> R {
+    let mut results = IMPORT_RESULTS.write().unwrap();
+    f(results.entry(job_id).or_insert_with(|| ImportResult {
+        capsule_id,
+        imported: 0,
+        failed: 0,
+        item_ids: Vec::new(),
+        errors: Vec::new(),
+    }))
+}
+
+// Dropbox shared links point at a preview page unless `dl=1` is set
+fn dropbox_download_url(url: &str) -> String {
+    let url = url.replace("?dl=0", "?dl=1").replace("&dl=0", "&dl=1");
+    if url.contains("dl=1") || url.contains("raw=1") {
+        url
+    } else if url.contains('?') {
+        format!("{}&dl=1", url)
+    } else {
+        format!("{}?dl=1", url)
+    }
+}
+
+// Lists the media items of a Google Photos album through the Library API
+async fn google_photos_files(client: &reqwest::Client, access_token: &str, album_id: &str) -> Result<Vec<RemoteFile>, String> {
+    let mut files = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut body = serde_json::json!({ "albumId": album_id, "pageSize": 100 });
+        if let Some(token) = &page_token {
+            body["pageToken"] = serde_json::Value::String(token.clone());
+        }
+        let response: serde_json::Value = client
+            .post("https://photoslibrary.googleapis.com/v1/mediaItems:search")
+            .bearer_auth(access_token)
+            .json(&body)
+            .send().await.map_err(|e| e.to_string())?
+            .error_for_status().map_err(|e| e.to_string())?
+            .json().await.map_err(|e| e.to_string())?;
+
+        for media in response["mediaItems"].as_array().into_iter().flatten() {
+            let base_url = media["baseUrl"].as_str().unwrap_or_default();
+            // `=d` downloads the original photo, `=dv` the video
+            let suffix = if media["mimeType"].as_str().unwrap_or_default().starts_with("video/") { "=dv" } else { "=d" };
+            files.push(RemoteFile {
+                url: format!("{}{}", base_url, suffix),
+                name: media["filename"].as_str().map(str::to_string),
+                metadata: serde_json::json!({
+                    "google_photos_id": media["id"],
+                    "creation_time": media["mediaMetadata"]["creationTime"],
+                    "width": media["mediaMetadata"]["width"],
+                    "height": media["mediaMetadata"]["height"],
+                }),
+            });
+        }
+
+        page_token = response["nextPageToken"].as_str().map(str::to_string);
+        if page_token.is_none() {
+            return Ok(files);
+        }
+    }
+}
+
+pub fn item_type(content_type: &str) -> &'static str {
+    match content_type.split('/').next().unwrap_or_default() {
+        "image" => "photo",
+        "video" => "video",
+        "audio" => "audio",
+        "text" => "letter",
+        _ => "file",
+    }
+}
+
+// Same style as the sizes already stored on items, e.g. "2MB"
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{}MB", (b + 512 * 1024) / (1024 * 1024)),
+        b if b >= 1024 => format!("{}KB", (b + 512) / 1024),
+        b => format!("{}B", b),
+    }
+}
+
+// The given name, or the last segment of the URL
+pub fn file_name(name: Option<&str>, url: &str) -> String {
+    let name = name.map(str::to_string).unwrap_or_else(|| {
+        url.split(['?', '#']).next().unwrap_or_default()
+            .rsplit('/').next().unwrap_or_default().to_string()
+    });
+    // Keep the name safe to use as a path component
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || ".-_".contains(c) { c } else { '_' }).collect();
+    if name.trim_matches('.').is_empty() { "file".to_string() } else { name }
+}
+
+// A remote file saved locally
+pub struct Download {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+}
+
+// Streams a file to `path`, hashing it on the way so it's only read once. Nothing is
+// left behind when it fails or is larger than `max_mb`. Shared with linked items, the
+// file has to be at a public address, see destinations.rs.
+pub async fn download(url: &Url, timeout: Option<Duration>, path: PathBuf, max_mb: u64) -> Result<Download, String> {
+    let mut response = destinations::send(Method::GET, url, timeout, |request| request).await
+        .and_then(|r| r.error_for_status().map_err(|e| e.to_string()))
+        .map_err(|e| format!("{}: {}", url, e))?;
+    let content_type = response.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+    }
+    let mut out = fs::File::create(&path).await.map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    let limit = max_mb * 1024 * 1024;
+    let written: Result<(), String> = async {
+        while let Some(chunk) = response.chunk().await.map_err(|e| format!("{}: {}", url, e))? {
+            size += chunk.len() as u64;
+            if size > limit {
+                return Err(format!("{}: larger than {}MB", url, max_mb));
+            }
+            hasher.update(&chunk);
+            out.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        }
+        out.flush().await.map_err(|e| e.to_string())
+    }.await;
+    if let Err(e) = written {
+        let _ = fs::remove_file(&path).await;
+        return Err(e);
+    }
+    Ok(Download { path, size, sha256: format!("{:x}", hasher.finalize()), content_type })
+}
+
+// Where a downloaded file of a capsule is kept, unique within `dir` or the same directory
+// of the capsule's residency region
+pub fn download_path(dir: &str, capsule_id: CapsuleId, index: usize, name: &str) -> Result<PathBuf, String> {
+    let dir = residency::capsule_dir(dir, capsule_id)?;
+    Ok(dir.join(capsule_id.number().to_string()).join(format!("{}-{}-{}", Utc::now().timestamp_millis(), index, name)))
+}
+
+// Downloads one file into the imports directory and adds it to the capsule
+async fn import_file(clock: &dyn Clock, job_id: u32, source: ImportSource, capsule_id: CapsuleId, index: usize, file: RemoteFile) -> Result<ItemId, String> {
+    let settings = &config::get().imports;
+    let name = file_name(file.name.as_deref(), &file.url);
+    let url = Url::parse(&file.url).map_err(|e| format!("{}: {}", file.url, e))?;
+    let Download { path, size, sha256, content_type } =
+        download(&url, None, download_path(&settings.dir, capsule_id, index, &name)?, settings.max_file_mb).await?;
+
+    let mut metadata = serde_json::json!({
+        "source_url": file.url,
+        "original_name": name,
+        "content_type": content_type,
+        "sha256": sha256,
+    });
+    if let (Some(extra), Some(target)) = (file.metadata.as_object(), metadata.as_object_mut()) {
+        target.extend(extra.clone());
+    }
+
+    let new_item = NewItem {
+        type_c: item_type(&content_type).to_string(),
+        description: name.clone(),
+        size: format_size(size),
+        path: path.to_string_lossy().into_owned(),
+        metadata,
+        owner_only: false,
+        message: None,
+    };
+    let origin = Origin::Import { job_id, source, url: file.url.clone() };
+    match items::create_item(capsule_id, &new_item, origin, clock) {
+        Ok(item) => Ok(item.id),
+        Err(error) => {
+            let _ = fs::remove_file(&path).await;
+            Err(format!("{}: {}", file.url, error))
+        }
+    }
+}
+
+// One attempt of an import job
+async fn run_import(ctx: JobContext, capsule_id: CapsuleId, request: ImportRequest, clock: SharedClock) -> Result<(), JobError> {
+    let job_id = ctx.job_id;
+    let client = reqwest::Client::new();
+    let source = request.source;
+
+    let files = match request.source {
+        ImportSource::UrlList => Ok(request.urls.into_iter().map(|url| RemoteFile { url, name: None, metadata: serde_json::json!({}) }).collect()),
+        ImportSource::Dropbox => Ok(request.urls.iter().map(|url| RemoteFile {
+            url: dropbox_download_url(url),
+            name: None,
+            metadata: serde_json::json!({ "dropbox_link": url }),
+        }).collect()),
+        ImportSource::GooglePhotos => {
+            let access_token = request.access_token.unwrap_or_default();
+            let album_id = request.album_id.unwrap_or_default();
+            google_photos_files(&client, &access_token, &album_id).await
+        }
+    };
+
+    // Nothing was imported yet, so listing the source again is safe
+    let files: Vec<RemoteFile> = files.map_err(|e| JobError::temporary(format!("Listing the files failed: {}", e)))?;
+
+    ctx.set_total(files.len());
+    for (index, file) in files.into_iter().enumerate() {
+        let result = import_file(clock.as_ref(), job_id, source, capsule_id, index, file).await;
+        update_result(job_id, capsule_id, |imported| match result {
+            Ok(item_id) => {
+                imported.imported += 1;
+                imported.item_ids.push(item_id);
+            },
+            Err(e) => {
+                imported.failed += 1;
+                imported.errors.push(e);
+            }
+        });
+        ctx.advance();
+    }
+
+    let result = IMPORT_RESULTS.read().unwrap().get(&job_id).cloned();
+    match result {
+        Some(result) if result.imported == 0 && result.failed > 0 => Err(JobError::permanent("None of the files could be imported")),
+        _ => Ok(()),
+    }
+}
+
+#[post("/capsules/<cid>/import", format = "json", data = "<import_request>")]
+pub async fn start_import(cid: CapsuleId, import_request: Json<ImportRequest>, clock: &State<SharedClock>, jobs: &State<SharedJobs>) -> Result<status::Accepted<Json<ImportJob>>, ApiError> {
+    if !flags::current().uploads_enabled {
+        return Err(flags::disabled("Uploading items").into());
+    }
+    if !CAPSULES.contains(cid) {
+        return Err(ApiError::CapsuleNotFound(cid));
+    }
+
+    let request = import_request.into_inner();
+    match request.source {
+        ImportSource::UrlList | ImportSource::Dropbox if request.urls.is_empty() => {
+            return Err(ApiError::Other(Status::BadRequest, "urls must list at least one file".into()));
+        },
+        ImportSource::GooglePhotos if request.access_token.is_none() || request.album_id.is_none() => {
+            return Err(ApiError::Other(Status::BadRequest, "google_photos imports need access_token and album_id".into()));
+        },
+        _ => {}
+    }
+    // Checked again when each file is downloaded, a host may resolve differently by then
+    for url in &request.urls {
+        let parsed = Url::parse(url.trim()).map_err(|_| ApiError::InvalidUrl)?;
+        destinations::check(&parsed).await.map_err(|e| ApiError::Other(Status::BadRequest, e))?;
+    }
+
+    let clock = clock.inner().clone();
+    let job = jobs.submit(JobKind::Import, Box::new(move |ctx| {
+        Box::pin(run_import(ctx, cid, request.clone(), clock.clone()))
+    }));
+    let result = update_result(job.id, cid, |imported| imported.clone());
+    Ok(status::Accepted(Json(ImportJob { job, result })))
+}
+
+#[get("/imports/<job_id>")]
+pub fn get_import(job_id: u32, jobs: &State<SharedJobs>) -> Option<Json<ImportJob>> {
+    let job = jobs.get(job_id).filter(|job| job.kind == JobKind::Import)?;
+    let result = IMPORT_RESULTS.read().unwrap().get(&job_id).cloned()?;
+    Some(Json(ImportJob { job, result }))
+}
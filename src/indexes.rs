@@ -0,0 +1,144 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+use once_cell::sync::Lazy;
+
+use crate::capsules::Capsule;
+use crate::ids::{CapsuleId, ContributorId, ItemId};
+use crate::item_store::ItemStore;
+use crate::store::Table;
+
+// Reverse indexes derived from the owning side of each relation
+// (item.id_capsule and capsule.contributor_id), kept up to date by every mutation. They are
+// the only copy of a capsule's item_ids and a contributor's capsule_ids, which responses
+// still show.
+#[derive(Default)]
+pub struct Indexes {
+    items_by_capsule: HashMap<CapsuleId, BTreeSet<ItemId>>,
+    capsules_by_contributor: HashMap<ContributorId, BTreeSet<CapsuleId>>,
+}
+
+pub static INDEXES: Lazy<RwLock<Indexes>> = Lazy::new(|| {
+    RwLock::new(Indexes::default())
+});
+
+impl Indexes {
+    // Builds the indexes from scratch from the authoritative records
+    pub fn rebuild(capsules: &Table<Capsule>, items: &ItemStore) -> Self {
+        let mut indexes = Indexes::default();
+        capsules.for_each(|capsule| indexes.link_capsule(capsule.contributor_id, capsule.id));
+        items.for_each_owner(|item_id, capsule_id| indexes.link_item(capsule_id, item_id));
+        indexes
+    }
+
+    pub fn items_of(&self, capsule_id: CapsuleId) -> Vec<ItemId> {
+        self.items_by_capsule.get(&capsule_id).map(|ids| ids.iter().copied().collect()).unwrap_or_default()
+    }
+
+    pub fn has_item(&self, capsule_id: CapsuleId, item_id: ItemId) -> bool {
+        self.items_by_capsule.get(&capsule_id).is_some_and(|ids| ids.contains(&item_id))
+    }
+
+    pub fn capsules_of(&self, contributor_id: ContributorId) -> Vec<CapsuleId> {
+        self.capsules_by_contributor.get(&contributor_id).map(|ids| ids.iter().copied().collect()).unwrap_or_default()
+    }
+
+    pub fn link_item(&mut self, capsule_id: CapsuleId, item_id: ItemId) {
+        self.items_by_capsule.entry(capsule_id).or_default().insert(item_id);
+    }
+
+    pub fn unlink_item(&mut self, capsule_id: CapsuleId, item_id: ItemId) {
+        if let Some(ids) = self.items_by_capsule.get_mut(&capsule_id) {
+            ids.remove(&item_id);
+        }
+    }
+
+    pub fn link_capsule(&mut self, contributor_id: ContributorId, capsule_id: CapsuleId) {
+        self.capsules_by_contributor.entry(contributor_id).or_default().insert(capsule_id);
+    }
+
+    pub fn unlink_capsule(&mut self, contributor_id: ContributorId, capsule_id: CapsuleId) {
+        if let Some(ids) = self.capsules_by_contributor.get_mut(&contributor_id) {
+            ids.remove(&capsule_id);
+        }
+    }
+
+    // Forgets a removed capsule and returns the items that were attached to it
+    pub fn drop_capsule(&mut self, contributor_id: ContributorId, capsule_id: CapsuleId) -> Vec<ItemId> {
+        self.unlink_capsule(contributor_id, capsule_id);
+        self.items_by_capsule.remove(&capsule_id).map(|ids| ids.into_iter().collect()).unwrap_or_default()
+    }
+
+    // Whether both hold the same links, ignoring sets left empty by unlinking
+    pub fn same_links(&self, other: &Indexes) -> bool {
+        fn non_empty<K: Eq + std::hash::Hash + Copy, V: Ord>(map: &HashMap<K, BTreeSet<V>>) -> HashMap<K, &BTreeSet<V>> {
+            map.iter().filter(|(_, ids)| !ids.is_empty()).map(|(&key, ids)| (key, ids)).collect()
+        }
+        non_empty(&self.items_by_capsule) == non_empty(&other.items_by_capsule)
+            && non_empty(&self.capsules_by_contributor) == non_empty(&other.capsules_by_contributor)
+    }
+
+    // Forgets a removed contributor and returns the capsules that were attached to it
+    pub fn drop_contributor(&mut self, contributor_id: ContributorId) -> Vec<CapsuleId> {
+        self.capsules_by_contributor.remove(&contributor_id).map(|ids| ids.into_iter().collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::EntityId;
+
+    fn ids() -> (ContributorId, CapsuleId, CapsuleId, ItemId, ItemId) {
+        (ContributorId::from_number(1), CapsuleId::from_number(1), CapsuleId::from_number(2), ItemId::from_number(1), ItemId::from_number(2))
+    }
+
+    #[test]
+    fn links_are_found_from_the_owning_side() {
+        let (contributor, capsule, other, item, second) = ids();
+        let mut indexes = Indexes::default();
+        indexes.link_capsule(contributor, capsule);
+        indexes.link_capsule(contributor, other);
+        indexes.link_item(capsule, second);
+        indexes.link_item(capsule, item);
+
+        assert_eq!(indexes.capsules_of(contributor), vec![capsule, other]);
+        assert_eq!(indexes.items_of(capsule), vec![item, second]);
+        assert!(indexes.has_item(capsule, item) && !indexes.has_item(other, item));
+
+        // Moved to the other capsule
+        indexes.unlink_item(capsule, item);
+        indexes.link_item(other, item);
+        assert_eq!(indexes.items_of(capsule), vec![second]);
+        assert!(indexes.has_item(other, item));
+    }
+
+    #[test]
+    fn dropping_a_capsule_hands_back_its_items() {
+        let (contributor, capsule, other, item, second) = ids();
+        let mut indexes = Indexes::default();
+        indexes.link_capsule(contributor, capsule);
+        indexes.link_capsule(contributor, other);
+        indexes.link_item(capsule, item);
+        indexes.link_item(capsule, second);
+
+        assert_eq!(indexes.drop_capsule(contributor, capsule), vec![item, second]);
+        assert!(indexes.items_of(capsule).is_empty());
+        assert_eq!(indexes.drop_contributor(contributor), vec![other]);
+        assert!(indexes.capsules_of(contributor).is_empty());
+    }
+
+    #[test]
+    fn emptied_sets_are_the_same_links_as_none() {
+        let (contributor, capsule, _, item, _) = ids();
+        let mut indexes = Indexes::default();
+        indexes.link_capsule(contributor, capsule);
+        indexes.link_item(capsule, item);
+        indexes.unlink_item(capsule, item);
+
+        let mut rebuilt = Indexes::default();
+        rebuilt.link_capsule(contributor, capsule);
+        assert!(indexes.same_links(&rebuilt));
+        rebuilt.link_item(capsule, item);
+        assert!(!indexes.same_links(&rebuilt));
+    }
+}
@@ -0,0 +1,417 @@
+// Storage behind ITEMS.
+//
+// By default every item is resident in a Table like contributors and capsules. In lazy
+// mode (`items.lazy = true`) items.json is split per capsule into spill files at boot and
+// only the items of recently used capsules are kept in memory; the least recently used
+// capsule is written back to its spill file when the cache is full. Only the light
+// item -> capsule mapping stays resident for every item.
+//
+// Every write also updates the metadata index, which stays resident in both modes.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use chrono::{DateTime, Utc};
+
+use rocket::serde::de::{Deserializer, SeqAccess, Visitor};
+
+use crate::config::ItemsConfig;
+use crate::ids::{CapsuleId, EntityId, ItemId};
+use crate::items::Item;
+use crate::metadata_index::MetadataIndex;
+use crate::store::{self, ChangeLog, Table};
+
+pub struct ItemStore {
+    rows: Table<Item>,
+    lazy: Option<LazyItems>,
+    metadata: RwLock<MetadataIndex>,
+}
+
+struct LazyItems {
+    spill_dir: PathBuf,
+    cache_capsules: usize,
+    // Taken before any shard of `rows`, and held for the whole item operation so a
+    // capsule can't be evicted halfway through it
+    state: Mutex<LazyState>,
+}
+
+#[derive(Default)]
+struct LazyState {
+    owners: BTreeMap<ItemId, CapsuleId>,               // Every item id -> its capsule, resident or not
+    by_capsule: HashMap<CapsuleId, BTreeSet<ItemId>>,  // Every capsule id -> its item ids
+    loaded: HashMap<CapsuleId, u64>,                   // Resident capsules -> last use
+    tick: u64,
+}
+
+impl ItemStore {
+    pub fn new(config: &ItemsConfig) -> Self {
+        let lazy = config.lazy.then(|| LazyItems {
+            spill_dir: config.spill_dir.clone().map(PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join("capsule-items")),
+            cache_capsules: config.cache_capsules.max(2),
+            state: Mutex::new(LazyState::default()),
+        });
+        ItemStore { rows: Table::with_store(store::configured("items")), lazy, metadata: RwLock::new(MetadataIndex::default()) }
+    }
+
+    // Loads items read from somewhere else than items.json, see sqlite.rs. Not in lazy mode
+    pub fn load_rows(&self, items: Vec<Item>) {
+        let mut metadata = MetadataIndex::default();
+        for item in &items {
+            metadata.index(item.id, &item.metadata);
+        }
+        self.rows.replace_all(items);
+        *self.metadata.write().unwrap() = metadata;
+    }
+
+    // Loads items.json, either fully or split into spill files in lazy mode. Without a file
+    // the store starts empty.
+    pub fn load(&self, path: Option<&Path>) {
+        let mut metadata = MetadataIndex::default();
+        match &self.lazy {
+            None => {
+                let Some(path) = path else { return self.rows.replace_all(Vec::new()) };
+                let items_json = fs::read_to_string(path).expect("Failed to read items.json");
+                let items_data: Vec<Item> = store::loading(|| serde_json::from_str(&items_json)).expect("Invalid format in items.json");
+                for item in &items_data {
+                    metadata.index(item.id, &item.metadata);
+                }
+                self.rows.replace_all(items_data);
+            }
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                *state = LazyState::default();
+                let _ = fs::remove_dir_all(&lazy.spill_dir);
+                fs::create_dir_all(&lazy.spill_dir).expect("Failed to create the items spill directory");
+
+                self.rows.replace_all(Vec::new());
+                let Some(path) = path else { return };
+
+                // Items are streamed one by one, items.json is never held in memory as a whole
+                let file = File::open(path).expect("Failed to read items.json");
+                let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+                let max_id = store::loading(|| deserializer
+                    .deserialize_seq(SpillVisitor { lazy, state: &mut state, rows: &self.rows, metadata: &mut metadata }))
+                    .expect("Invalid format in items.json");
+
+                self.rows.reserve_through(max_id);
+            }
+        }
+        *self.metadata.write().unwrap() = metadata;
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.lazy {
+            None => self.rows.len(),
+            Some(lazy) => lazy.state.lock().unwrap().owners.len(),
+        }
+    }
+
+    pub fn ids(&self) -> Vec<ItemId> {
+        match &self.lazy {
+            None => self.rows.ids(),
+            Some(lazy) => lazy.state.lock().unwrap().owners.keys().copied().collect(),
+        }
+    }
+
+    pub fn page(&self, start: usize, count: usize) -> Vec<Item> {
+        match &self.lazy {
+            None => self.rows.page(start, count),
+            Some(lazy) => {
+                let ids: Vec<ItemId> = lazy.state.lock().unwrap().owners.keys().skip(start).take(count).copied().collect();
+                ids.into_iter().filter_map(|id| self.get(id)).collect()
+            }
+        }
+    }
+
+    // Calls `f(item_id, capsule_id)` for every item without loading any of them
+    pub fn for_each_owner(&self, mut f: impl FnMut(ItemId, CapsuleId)) {
+        match &self.lazy {
+            None => self.rows.for_each(|item| f(item.id, item.id_capsule)),
+            Some(lazy) => {
+                for (&item_id, &capsule_id) in &lazy.state.lock().unwrap().owners {
+                    f(item_id, capsule_id);
+                }
+            }
+        }
+    }
+
+    pub fn next_id(&self) -> ItemId {
+        self.rows.next_id()
+    }
+
+    // Items whose metadata has every one of the (path, value) pairs, see metadata_index.rs
+    pub fn find_by_metadata(&self, conditions: &[(String, String)]) -> BTreeSet<ItemId> {
+        self.metadata.read().unwrap().find(conditions)
+    }
+
+    // Indexes the metadata of every item again, spilled ones read from their files without
+    // loading them, and returns how many items were indexed. The caller keeps items from
+    // changing meanwhile by holding every capsule lock.
+    pub fn rebuild_metadata(&self) -> usize {
+        let mut metadata = MetadataIndex::default();
+        let mut count = 0;
+        let mut index = |item: &Item| {
+            metadata.index(item.id, &item.metadata);
+            count += 1;
+        };
+        match &self.lazy {
+            None => self.rows.for_each(&mut index),
+            Some(lazy) => {
+                let state = lazy.state.lock().unwrap();
+                for (&capsule_id, item_ids) in &state.by_capsule {
+                    if state.loaded.contains_key(&capsule_id) {
+                        item_ids.iter().for_each(|&id| { self.rows.read(id, &mut index); });
+                    } else {
+                        read_spill(&lazy.spill_path(capsule_id)).iter().for_each(|(_, item)| index(item));
+                    }
+                }
+            }
+        }
+        *self.metadata.write().unwrap() = metadata;
+        count
+    }
+
+    pub fn reserve_stored_ids(&self) {
+        match &self.lazy {
+            None => self.rows.reserve_stored_ids(),
+            Some(lazy) => {
+                if let Some(&max) = lazy.state.lock().unwrap().owners.keys().next_back() {
+                    self.rows.reserve_through(max);
+                }
+            }
+        }
+    }
+
+    fn index_metadata(&self, id: ItemId, metadata: &serde_json::Value) {
+        self.metadata.write().unwrap().index(id, metadata);
+    }
+
+    pub fn change_log(&self) -> RwLockReadGuard<'_, ChangeLog<ItemId>> {
+        self.rows.change_log()
+    }
+
+    pub fn get(&self, id: ItemId) -> Option<Item> {
+        match &self.lazy {
+            None => self.rows.get(id),
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                let capsule_id = *state.owners.get(&id)?;
+                lazy.fault_in(&mut state, &self.rows, capsule_id);
+                self.rows.get(id)
+            }
+        }
+    }
+
+    pub fn removed_at(&self, id: ItemId) -> Option<DateTime<Utc>> {
+        self.rows.removed_at(id)
+    }
+
+    pub fn revision(&self, id: ItemId) -> Option<u64> {
+        match &self.lazy {
+            None => self.rows.revision(id),
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                let capsule_id = *state.owners.get(&id)?;
+                lazy.fault_in(&mut state, &self.rows, capsule_id);
+                self.rows.revision(id)
+            }
+        }
+    }
+
+    pub fn update<R>(&self, id: ItemId, f: impl FnOnce(&mut Item) -> R) -> Option<R> {
+        self.try_update(id, |item| Ok::<R, Infallible>(f(item))).map(|result| match result {
+            Ok(result) => result,
+            Err(never) => match never {},
+        })
+    }
+
+    // Like `update`, an Err leaves the item as it was and isn't recorded, see Table::try_update
+    pub fn try_update<R, E>(&self, id: ItemId, f: impl FnOnce(&mut Item) -> Result<R, E>) -> Option<Result<R, E>> {
+        match &self.lazy {
+            None => {
+                let (result, metadata) = match self.rows.try_update(id, |item| f(item).map(|result| (result, item.metadata.clone())))? {
+                    Ok(updated) => updated,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.index_metadata(id, &metadata);
+                Some(Ok(result))
+            }
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                let capsule_id = *state.owners.get(&id)?;
+                lazy.fault_in(&mut state, &self.rows, capsule_id);
+                let (result, new_capsule_id, metadata) = match self.rows.try_update(id, |item| f(item).map(|result| (result, item.id_capsule, item.metadata.clone())))? {
+                    Ok(updated) => updated,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.index_metadata(id, &metadata);
+
+                // The item was moved to another capsule (merges), it now belongs to that capsule's spill file
+                if new_capsule_id != capsule_id {
+                    state.unassign(id, capsule_id);
+                    state.assign(id, new_capsule_id);
+                    lazy.fault_in(&mut state, &self.rows, new_capsule_id);
+                }
+                Some(Ok(result))
+            }
+        }
+    }
+
+    pub fn insert(&self, item: Item) {
+        self.index_metadata(item.id, &item.metadata);
+        match &self.lazy {
+            None => self.rows.insert(item),
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                if let Some(old_capsule_id) = state.owners.get(&item.id).copied() {
+                    lazy.fault_in(&mut state, &self.rows, old_capsule_id);
+                    state.unassign(item.id, old_capsule_id);
+                }
+                lazy.fault_in(&mut state, &self.rows, item.id_capsule);
+                state.assign(item.id, item.id_capsule);
+                self.rows.insert(item);
+            }
+        }
+    }
+
+    pub fn remove(&self, id: ItemId) -> Option<Item> {
+        let removed = match &self.lazy {
+            None => self.rows.remove(id),
+            Some(lazy) => {
+                let mut state = lazy.state.lock().unwrap();
+                let capsule_id = *state.owners.get(&id)?;
+                lazy.fault_in(&mut state, &self.rows, capsule_id);
+                state.unassign(id, capsule_id);
+                self.rows.remove(id)
+            }
+        };
+        self.metadata.write().unwrap().unindex(id);
+        removed
+    }
+}
+
+impl LazyState {
+    fn assign(&mut self, item_id: ItemId, capsule_id: CapsuleId) {
+        self.owners.insert(item_id, capsule_id);
+        self.by_capsule.entry(capsule_id).or_default().insert(item_id);
+    }
+
+    fn unassign(&mut self, item_id: ItemId, capsule_id: CapsuleId) {
+        self.owners.remove(&item_id);
+        if let Some(ids) = self.by_capsule.get_mut(&capsule_id) {
+            ids.remove(&item_id);
+        }
+    }
+}
+
+impl LazyItems {
+    fn spill_path(&self, capsule_id: CapsuleId) -> PathBuf {
+        self.spill_dir.join(format!("{}.jsonl", capsule_id.number()))
+    }
+
+    // Makes the capsule's items resident, evicting the least recently used capsules if needed
+    fn fault_in(&self, state: &mut LazyState, rows: &Table<Item>, capsule_id: CapsuleId) {
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(last_used) = state.loaded.get_mut(&capsule_id) {
+            *last_used = tick;
+            return;
+        }
+
+        for (revision, item) in read_spill(&self.spill_path(capsule_id)) {
+            rows.load(item, revision);
+        }
+        state.loaded.insert(capsule_id, tick);
+
+        while state.loaded.len() > self.cache_capsules {
+            let victim = state.loaded.iter()
+                .filter(|(&id, _)| id != capsule_id)
+                .min_by_key(|(_, &last_used)| last_used)
+                .map(|(&id, _)| id);
+            match victim {
+                Some(victim) => self.evict(state, rows, victim),
+                None => break,
+            }
+        }
+    }
+
+    // Writes the capsule's current items back to its spill file and drops them from memory
+    fn evict(&self, state: &mut LazyState, rows: &Table<Item>, capsule_id: CapsuleId) {
+        state.loaded.remove(&capsule_id);
+        let item_ids = state.by_capsule.get(&capsule_id).cloned().unwrap_or_default();
+        let items: Vec<(u64, Item)> = item_ids.iter()
+            .filter_map(|&id| rows.unload(id))
+            .map(|(item, revision)| (revision, item))
+            .collect();
+        write_spill(&self.spill_path(capsule_id), &items);
+    }
+}
+
+// Spill files hold one `[revision, item]` pair per line, so a reloaded item keeps its revision
+fn read_spill(path: &Path) -> Vec<(u64, Item)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(), // Capsule without any spilled items
+    };
+    BufReader::new(file)
+        .lines()
+        .map(|line| {
+            let line = line.expect("Failed to read an items spill file");
+            store::loading(|| serde_json::from_str(&line)).expect("Invalid item in an items spill file")
+        })
+        .collect()
+}
+
+fn write_spill(path: &Path, items: &[(u64, Item)]) {
+    if items.is_empty() {
+        let _ = fs::remove_file(path);
+        return;
+    }
+    // Write to a temporary file first so a crash never leaves a half written spill file
+    let tmp_path = path.with_extension("jsonl.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path).expect("Failed to write an items spill file"));
+    for entry in items {
+        store::storing(|| serde_json::to_writer(&mut writer, entry)).expect("Failed to write an items spill file");
+        writer.write_all(b"\n").expect("Failed to write an items spill file");
+    }
+    writer.flush().expect("Failed to write an items spill file");
+    drop(writer);
+    fs::rename(&tmp_path, path).expect("Failed to write an items spill file");
+}
+
+// Appends every item of the items.json array to its capsule's spill file and returns the highest id
+struct SpillVisitor<'a> {
+    lazy: &'a LazyItems,
+    state: &'a mut LazyState,
+    rows: &'a Table<Item>,
+    metadata: &'a mut MetadataIndex,
+}
+
+impl<'de, 'a> Visitor<'de> for SpillVisitor<'a> {
+    type Value = ItemId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of items")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ItemId, A::Error> {
+        let mut max_id = ItemId::default();
+        while let Some(item) = seq.next_element::<Item>()? {
+            let path = self.lazy.spill_path(item.id_capsule);
+            let mut file = OpenOptions::new().create(true).append(true).open(path)
+                .expect("Failed to write an items spill file");
+            let revision = self.rows.record_external(item.id);
+            store::storing(|| serde_json::to_writer(&mut file, &(revision, &item))).expect("Failed to write an items spill file");
+            file.write_all(b"\n").expect("Failed to write an items spill file");
+
+            max_id = max_id.max(item.id);
+            self.state.assign(item.id, item.id_capsule);
+            self.metadata.index(item.id, &item.metadata);
+        }
+        Ok(max_id)
+    }
+}
@@ -1,23 +1,52 @@
 use rocket::serde::{Serialize, Deserialize, json::Json};
 use chrono::{DateTime, Utc};
-use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use rocket::response::status;
-use rocket::http::{Status, ContentType};
-use rocket::response::status::Custom;
-use rocket::response::{Response, Responder, self};
-use std::collections::HashMap;
-use rocket::Request;
+use rocket::http::Status;
+use rocket::State;
 
+use crate::bus::{self, DomainEvent};
 use crate::capsules::{ CAPSULES};
-use sha2::{Sha256, Digest};
+use crate::database;
+use crate::error_messages::ApiError;
+use crate::flags;
+use crate::indexes::INDEXES;
+use crate::store::Entity;
+use crate::item_store::ItemStore;
+use crate::config;
+use crate::locks;
+use crate::streaming::{self, JsonStream};
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::cache::{self, CacheKind, CachedJson};
+use crate::clock::{Clock, SharedClock};
+use crate::signatures;
+use crate::quotas::{self, WithLimits};
+use crate::reveals;
+use crate::owner_only;
+use crate::messages::Message;
+use crate::payload::Payload;
+use crate::time_format;
+use crate::tokens::Caller;
+use crate::enrichment;
+use crate::search::Query;
+use crate::imports::ImportSource;
+use crate::ids::{CapsuleId, ItemId};
+use crate::cold_storage::{self, ColdStub, Restoring};
+use crate::content_policy;
+use crate::metadata_index::MetaFilter;
+use crate::residency;
+use crate::custom_fields;
+use crate::tombstones::{self, Missing};
+use rocket::Either;
+use rocket::futures::stream::Stream;
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct Item {
-    pub id: u32,  // Now public, allowing access from other modules
-    pub id_capsule: u32,
+    pub id: ItemId,  // Now public, allowing access from other modules
+    pub id_capsule: CapsuleId,
     pub type_c: String,
+    #[serde(serialize_with = "time_format::time")]
     pub time_added: DateTime<Utc>,
     pub description: String,
     pub size: String,
@@ -25,6 +54,43 @@ pub struct Item {
     pub metadata: serde_json::Value,
  //   pub idempotency_key: String,
     pub version: u32,
+    #[serde(default)]
+    pub provenance: Vec<ProvenanceStep>,  // Where the item came from, oldest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cold: Option<ColdStub>,  // Set while the content is in cold storage, see cold_storage.rs
+    #[serde(default)]
+    pub owner_only: bool,  // Hidden from everyone but the capsule's owner until it opens, see owner_only.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,  // Text of items composed in the API instead of a file, see messages.rs
+}
+
+// How an item got into a capsule
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde", tag = "origin", rename_all = "snake_case")]
+pub enum Origin {
+    Upload,
+    Import { job_id: u32, source: ImportSource, url: String },
+    Link { url: String },  // Added by reference, see links.rs
+    Merge { from_capsule: CapsuleId },
+    Move { from_capsule: CapsuleId },  // Detached from a deleted capsule, see orphans.rs
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ProvenanceStep {
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub capsule_id: CapsuleId,  // Capsule the item ended up in
+    #[serde(flatten)]
+    pub origin: Origin,
+}
+
+impl Entity for Item {
+    type Id = ItemId;
+
+    fn id(&self) -> ItemId {
+        self.id
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,6 +101,10 @@ pub struct NewItem {
     pub size: String,
     pub path: String,
     pub metadata: serde_json::Value,
+    #[serde(default)]
+    pub owner_only: bool,
+    #[serde(skip)]
+    pub message: Option<Message>,  // Only set by POST /capsules/<cid>/messages
 }
 
 #[derive(Deserialize)]
@@ -45,17 +115,9 @@ pub struct NewItemUpdate {
 }
 
 
-#[derive(FromForm, UriDisplayQuery)]
-pub struct Pagination {
-    page: Option<usize>,
-    per_page: Option<usize>,
-}
-
 
 // Global in-memory storage for items
-pub static ITEMS: Lazy<Mutex<Vec<Item>>> = Lazy::new(|| {
-    Mutex::new(vec![])
-});
+pub static ITEMS: Lazy<ItemStore> = Lazy::new(|| ItemStore::new(&config::get().items));
 
 //static IDEMPOTENCY_RECORDS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
@@ -71,86 +133,124 @@ fn generate_idempotency_key(item: &NewItem) -> String {
 }*/
 
 
-// Define a custom responder that includes pagination information
-// Custom responder to add headers
-pub struct CustomResponder<T> {
-    inner: T,
-    total_items: usize,
-    page: usize,
-    per_page: usize,
-}
-
-impl<'r, T: Responder<'r, 'static>> Responder<'r, 'static> for CustomResponder<T> {
-    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
-        let mut build = Response::build_from(self.inner.respond_to(request)?);
-        build.raw_header("X-Total-Count", self.total_items.to_string());
-        build.raw_header("X-Page", self.page.to_string());
-        build.raw_header("X-Per-Page", self.per_page.to_string());
-        build.ok()
-    }
-}
-
 
 
-#[get("/items?<pagination..>")]
-pub fn get_all_items(pagination: Pagination) ->  Result<CustomResponder<Json<Vec<Item>>>, Status> {
-    let items = ITEMS.lock().map_err(|_| Status::InternalServerError)?;
 
-    let per_page = pagination.per_page.unwrap_or(10); // Default to 10 items per page if not specified
-    let page = pagination.page.unwrap_or(1); // Default to page 1 if not specified
-    let start = (page - 1) * per_page;
-    let end = start + per_page;
+// `q` searches the descriptions and types, `meta.<key>=<value>` parameters look items up
+// in the metadata index
+#[get("/items?<q>&<pagination..>")]
+pub fn get_all_items(q: Option<&str>, pagination: Pagination, meta: MetaFilter, caller: Caller, clock: &State<SharedClock>) -> Result<Paginated<Item>, status::Custom<Json<String>>> {
+    let mut hidden = reveals::all_hidden();
+    hidden.extend(owner_only::all_hidden(&caller, clock.now()));
+    let query = q.and_then(Query::parse);
+    if query.is_some() || !meta.is_empty() {
+        let item_ids = if meta.is_empty() { ITEMS.ids() } else { ITEMS.find_by_metadata(meta.conditions()?).into_iter().collect() };
+        let items: Vec<Item> = item_ids.into_iter()
+            .filter(|id| !hidden.contains(id))
+            .filter_map(|id| ITEMS.get(id))
+            .filter(|item| query.as_ref().is_none_or(|query| query.matches([item.description.as_str(), item.type_c.as_str()])))
+            .collect();
+        return Ok(Paginated::new(&pagination, Collection::Items, items.len(), |start, per_page| items.into_iter().skip(start).take(per_page).collect()));
+    }
 
-    let paged_items = items[start..end.min(items.len())].to_vec(); // Safely slice the vector to the page size
+    // Items still waiting for their reveal or kept by the owner are left out, which means paging over the ids
+    if !hidden.is_empty() {
+        let item_ids: Vec<ItemId> = ITEMS.ids().into_iter().filter(|id| !hidden.contains(id)).collect();
+        return Ok(Paginated::new(&pagination, Collection::Items, item_ids.len(), |start, per_page| {
+            item_ids.iter().skip(start).take(per_page).filter_map(|&id| ITEMS.get(id)).collect()
+        }));
+    }
 
-    Ok(CustomResponder {
-        inner: Json(paged_items),
-        total_items: items.len(),
-        page,
-        per_page,
-    })
+    // Clone only the requested page
+    Ok(Paginated::new(&pagination, Collection::Items, ITEMS.len(), |start, per_page| ITEMS.page(start, per_page)))
 }
 
 
+// A stubbed item is restored from cold storage, 202 until it's back
 #[get("/items/<item_id>")]
-pub fn get_item(item_id: u32) -> Result<Json<Item>, status::Custom<Json<String>>> {
-    let items = ITEMS.lock().unwrap();
-
-    match items.iter().find(|item| item.id == item_id) {
-        Some(item) => Ok(Json(item.clone())),
-        None => Err(status::Custom(Status::NotFound, Json(format!("Item with ID {} not found", item_id))))
+pub fn get_item(item_id: ItemId, caller: Caller, clock: &State<SharedClock>) -> Result<Either<Json<Item>, Restoring>, Missing<ItemId>> {
+    let now = clock.now();
+    let item = ITEMS.get(item_id)
+        .filter(|item| !reveals::is_hidden(item.id_capsule, item.id) && !owner_only::is_hidden(item.id_capsule, item.id, &caller, now));
+    match item {
+        Some(item) => Ok(cold_storage::request_restore(&item, now).map_or(Either::Left(Json(item)), Either::Right)),
+        None => Err(tombstones::or_not_found(tombstones::item(item_id), ApiError::ItemNotFound(item_id)))
     }
 }
 
 
-#[get("/capsules/<cid>/items")]
-pub fn get_capsule_items(cid: u32) -> Result<Json<Vec<Item>>, status::Custom<Json<String>>> {
-    let capsules = CAPSULES.lock().unwrap();
-    let items = ITEMS.lock().unwrap();
+// Item lists longer than this are streamed instead of being rendered into the cache
+const MAX_CACHED_ITEMS: usize = 500;
 
+#[get("/capsules/<cid>/items")]
+pub fn get_capsule_items(cid: CapsuleId, caller: Caller, clock: &State<SharedClock>) -> Result<Either<CachedJson, JsonStream<impl Stream<Item = String>>>, ApiError> {
     // Find the capsule by ID and retrieve associated items
-    if let Some(capsule) = capsules.iter().find(|&c| c.id == cid) {
-        if let Some(item_ids) = &capsule.item_ids {
-            let capsule_items: Vec<Item> = item_ids
-                .iter()
-                .filter_map(|id| items.iter().find(|&item| item.id == *id))
-                .cloned()
-                .collect();
-            
-            Ok(Json(capsule_items))
-        } else {
-            // Handle case where capsule has no items
-            Ok(Json(vec![]))
+    if CAPSULES.contains(cid) {
+        // Resolve the capsule's items through the reverse index
+        let item_ids = reveals::visible(cid, INDEXES.read().unwrap().items_of(cid));
+        // The ids go into the ETag, so callers seeing fewer items don't get the others' rendering
+        let item_ids = owner_only::visible(cid, item_ids, &caller, clock.now());
+        if item_ids.len() > MAX_CACHED_ITEMS {
+            return Ok(Either::Right(streaming::json_array(item_ids, |id| ITEMS.get(id))));
         }
+
+        // Serve the cached rendering while none of the items changed
+        let revisions: Vec<(ItemId, Option<u64>)> = item_ids.iter().map(|&id| (id, ITEMS.revision(id))).collect();
+        let etag = cache::etag(&revisions);
+        let body = match cache::lookup(CacheKind::CapsuleItems, cid, &etag) {
+            Some(body) => body,
+            None => {
+                let capsule_items: Vec<Item> = item_ids.iter().filter_map(|&id| ITEMS.get(id)).collect();
+                let rendered = serde_json::to_string(&capsule_items)
+                    .map_err(|e| status::Custom(Status::InternalServerError, Json(e.to_string())))?;
+                cache::store(CacheKind::CapsuleItems, cid, &etag, rendered)
+            }
+        };
+        Ok(Either::Left(CachedJson { body, etag }))
     } else {
-        Err(status::Custom(Status::NotFound, Json(format!("No capsule found with ID {}", cid))))
+        Err(ApiError::CapsuleNotFound(cid))
     }
 }
 
 #[post("/capsules/<cid>/items", format = "json", data = "<item_data>")]
-pub fn add_item_to_capsule(cid: u32, item_data: Json<NewItem>) -> Result<Json<Item>, Custom<Json<String>>> {
-    let mut items = ITEMS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
+pub fn add_item_to_capsule(cid: CapsuleId, item_data: Payload<NewItem>, caller: Caller, clock: &State<SharedClock>) -> Result<WithLimits<Json<Item>>, ApiError> {
+    if !flags::current().uploads_enabled {
+        return Err(flags::disabled("Uploading items").into());
+    }
+    if item_data.owner_only {
+        if let Some(result) = CAPSULES.read(cid, |capsule| owner_only::check_can_mark(capsule, &caller, clock.now())) {
+            result?;
+        }
+    }
+
+    let item = create_item(cid, &item_data, Origin::Upload, clock.as_ref())?;
+    Ok(WithLimits(Json(item), quotas::limits(cid)))
+}
+
+// Why the item can't be added to the capsule now, empty if it can. Shared with
+// POST /capsules/<cid>/items/validate, see validation.rs
+pub fn check_new_item(cid: CapsuleId, item_data: &NewItem, now: DateTime<Utc>) -> Vec<ApiError> {
+    let Some(closed) = CAPSULES.read(cid, |capsule| signatures::contributions_closed(capsule, now)) else {
+        return vec![ApiError::CapsuleNotFound(cid)];
+    };
+    let mut errors = Vec::new();
+    errors.extend(content_policy::check_item_description(&item_data.description).err().map(ApiError::from));
+    // Check if the capsule still takes items
+    if closed {
+        errors.push(ApiError::ContributionsEnded);
+    }
+    if let Err(error) = quotas::check(cid, &item_data.size) {
+        errors.push(error);
+    }
+    errors.extend(residency::check_file(&item_data.path, cid).err().map(ApiError::from));
+    errors.extend(CAPSULES.read(cid, |capsule| custom_fields::check_metadata(&capsule.custom_fields, &item_data.metadata)).and_then(Result::err).map(ApiError::from));
+    errors
+}
+
+// Adds a new item to a capsule that can still be changed, shared with the importer
+pub fn create_item(cid: CapsuleId, item_data: &NewItem, origin: Origin, clock: &dyn Clock) -> Result<Item, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
    // let mut idempotency_records = IDEMPOTENCY_RECORDS.lock().unwrap();
 
     // Generate the idempotency key
@@ -162,17 +262,16 @@ pub fn add_item_to_capsule(cid: u32, item_data: Json<NewItem>) -> Result<Json<It
     //}
 
     // Find the corresponding capsule
-    if let Some(capsule) = capsules.iter_mut().find(|cap| cap.id == cid) {
-        // Check if the capsule modification period has expired
-        if Utc::now() > capsule.time_until_changed {
-            return Err(Custom(Status::BadRequest, Json("The modification period for this capsule has expired".into())));
+    if CAPSULES.contains(cid) {
+        if let Some(error) = check_new_item(cid, item_data, now).into_iter().next() {
+            return Err(error);
         }
 
         // Generate a new ID for the item
-        let new_id = items.iter().max_by_key(|item| item.id).map_or(1, |max_item| max_item.id + 1);
+        let new_id = ITEMS.next_id();
 
         // Create new item with new ID and current timestamp
-        let new_item = Item {
+        let mut new_item = Item {
             id: new_id,
             id_capsule: cid,
             type_c: item_data.type_c.clone(),
@@ -180,105 +279,135 @@ pub fn add_item_to_capsule(cid: u32, item_data: Json<NewItem>) -> Result<Json<It
             size: item_data.size.clone(),
             path: item_data.path.clone(),
             metadata: item_data.metadata.clone(),
-            time_added: Utc::now(),
+            time_added: now,
             //idempotency_key: idempotency_key.clone(),
-            version: 1
+            version: 1,
+            provenance: vec![ProvenanceStep { time: now, capsule_id: cid, origin }],
+            cold: None,
+            owner_only: item_data.owner_only,
+            message: item_data.message.clone(),
         };
 
-        // Update the capsule's item list and modification time
-        capsule.item_ids.get_or_insert_with(Vec::new).push(new_id);
-        capsule.time_changed = Some(Utc::now());
+        // Add the new item to the global list, its description is generated afterwards.
+        // The item and its capsule are stored together.
+        let enrich = enrichment::mark_pending(&mut new_item);
+        database::atomically(|| {
+            ITEMS.insert(new_item.clone());
+            INDEXES.write().unwrap().link_item(cid, new_id);
+            bus::publish(DomainEvent::ItemAdded { capsule_id: cid, item_id: new_id }, now);
+            if enrich {
+                enrichment::start(new_item.clone());
+            }
 
-        // Add the new item to the global list
-        items.push(new_item.clone());
+            // The capsule's item list changed with the index, update its modification time
+            CAPSULES.update(cid, |capsule| capsule.time_changed = Some(now));
+        });
 
         // Record the successful operation to handle future idempotency
       //  idempotency_records.insert(idempotency_key, serde_json::to_string(&new_item).unwrap());
 
-        Ok(Json(new_item))
+        Ok(new_item)
     } else {
-        Err(Custom(Status::NotFound, Json(format!("Capsule with ID {} not found", cid))))
+        Err(ApiError::CapsuleNotFound(cid))
     }
 }
 
 
 
 #[get("/capsules/<capsule_id>/items/<item_id>")]
-pub fn get_capsule_item(capsule_id: u32, item_id: u32) -> Result<Json<Item>, status::Custom<Json<String>>> {
-    let capsules = CAPSULES.lock().unwrap();
-    let items = ITEMS.lock().unwrap();
-
-    if let Some(capsule) = capsules.iter().find(|&c| c.id == capsule_id) {
-        if capsule.item_ids.as_ref().map_or(false, |ids| ids.contains(&item_id)) {
-            if let Some(item) = items.iter().find(|&item| item.id == item_id) {
-                return Ok(Json(item.clone()));
-            }
+pub fn get_capsule_item(capsule_id: CapsuleId, item_id: ItemId, caller: Caller, clock: &State<SharedClock>) -> Result<Either<Json<Item>, Restoring>, ApiError> {
+    let now = clock.now();
+    let in_capsule = INDEXES.read().unwrap().has_item(capsule_id, item_id);
+
+    if in_capsule && !reveals::is_hidden(capsule_id, item_id) && !owner_only::is_hidden(capsule_id, item_id, &caller, now) {
+        if let Some(item) = ITEMS.get(item_id) {
+            return Ok(cold_storage::request_restore(&item, now).map_or(Either::Left(Json(item)), Either::Right));
         }
     }
-    Err(status::Custom(Status::NotFound, Json("Item not found in the specified capsule".to_string())))
+    Err(ApiError::ItemNotInCapsule)
 }
 
 
 #[patch("/capsules/<capsule_id>/items/<item_id>?<etag>", format = "json", data = "<item_update>")]
 pub fn patch_capsule_item_description(
-    capsule_id: u32, 
-    item_id: u32, 
+    capsule_id: CapsuleId, 
+    item_id: ItemId, 
     etag: Option<u32>, 
-    item_update: Json<NewItemUpdate>
-) -> Result<Json<Item>, status::Custom<Json<String>>> {
-    let mut items = ITEMS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
+    item_update: Json<NewItemUpdate>,
+    clock: &State<SharedClock>
+) -> Result<Json<Item>, ApiError> {
+    content_policy::check_item_description(&item_update.description)?;
+    let _guard = locks::lock_capsule(capsule_id);
+    let now = clock.now();
 
     // Verify the capsule contains the item and can still be changed
-    if let Some(capsule) = capsules.iter_mut().find(|c| c.id == capsule_id && c.item_ids.as_ref().map_or(false, |ids| ids.contains(&item_id))) {
-        if Utc::now() > capsule.time_until_changed {
-            return Err(status::Custom(Status::BadRequest, Json("The modification period for this capsule has expired".into())));
-        }
+    let in_capsule = INDEXES.read().unwrap().has_item(capsule_id, item_id);
+    let sealed = CAPSULES.read(capsule_id, |c| in_capsule.then(|| signatures::contributions_closed(c, now))).flatten();
 
-        if let Some(item) = items.iter_mut().find(|item| item.id == item_id) {
-            // Resolve version to check from ETag or the update body
-            let version_to_check = etag.or(item_update.version);
+    if let Some(sealed) = sealed {
+        if sealed {
+            return Err(ApiError::ContributionsEnded);
+        }
 
-            // Check version matches
-            if version_to_check.is_none() || version_to_check != Some(item.version) {
-                return Err(status::Custom(Status::Conflict, Json("Version mismatch. Please refresh your data.".into())));
+        // The item and the capsule's modification time are written together
+        let updated = database::atomically(|| {
+            let result = ITEMS.try_update(item_id, |item| {
+                // The stub would be overwritten by the restored description
+                if item.cold.is_some() {
+                    return Err(ApiError::Other(Status::Conflict, format!("Item {} is in cold storage, read it to restore it first", item_id)));
+                }
+
+                // Resolve version to check from ETag or the update body
+                let version_to_check = etag.or(item_update.version);
+
+                // Check version matches
+                if version_to_check.is_none() || version_to_check != Some(item.version) {
+                    return Err(ApiError::VersionMismatch);
+                }
+
+                // Proceed with the update
+                item.description = item_update.description.clone();
+                item.version += 1;  // Increment the version to signify an update
+                Ok(item.clone())
+            })?;
+            if result.is_ok() {
+                CAPSULES.update(capsule_id, |capsule| capsule.time_changed = Some(now));  // Update the capsule's last modified time
             }
+            Some(result)
+        });
 
-            // Proceed with the update
-            item.description = item_update.description.clone();
-            item.version += 1;  // Increment the version to signify an update
-            capsule.time_changed = Some(Utc::now());  // Update the capsule's last modified time
-
-            return Ok(Json(item.clone()));
+        if let Some(result) = updated {
+            return result.map(Json);
         }
     }
 
-    Err(status::Custom(Status::NotFound, Json(format!("No item with ID {} found in capsule {}", item_id, capsule_id))))
+    Err(ApiError::Other(Status::NotFound, format!("No item with ID {} found in capsule {}", item_id, capsule_id)))
 }
 
 
 
 
 #[delete("/capsules/<capsule_id>/items/<item_id>")]
-pub fn delete_capsule_item(capsule_id: u32, item_id: u32) -> Result<Status, status::Custom<Json<String>>> {
-    let mut items = ITEMS.lock().unwrap();
-    let mut capsules = CAPSULES.lock().unwrap();
+pub fn delete_capsule_item(capsule_id: CapsuleId, item_id: ItemId, clock: &State<SharedClock>) -> Result<Status, ApiError> {
+    let _guard = locks::lock_capsule(capsule_id);
+    let now = clock.now();
 
     // Verify the capsule can still be changed and contains the specified item
-    if let Some(capsule) = capsules.iter_mut().find(|cap| cap.id == capsule_id) {
-        if Utc::now() > capsule.time_until_changed {
-            return Err(status::Custom(Status::BadRequest, Json("The modification period for this capsule has expired".into())));
-        }
-
-        if let Some(pos) = capsule.item_ids.as_mut().unwrap().iter().position(|&id| id == item_id) {
-            // Remove the item ID from the capsule's item_ids list
-            capsule.item_ids.as_mut().unwrap().remove(pos);
-            // Remove the item from the ITEMS list
-            items.retain(|item| item.id != item_id);
-            capsule.time_changed = Some(Utc::now());  // Update the time_changed to now
-
-            return Ok(Status::NoContent);
-        }
+    let closed = CAPSULES.read(capsule_id, |capsule| signatures::contributions_closed(capsule, now));
+    let in_capsule = INDEXES.read().unwrap().has_item(capsule_id, item_id);
+
+    match closed {
+        Some(true) => Err(ApiError::ContributionsEnded),
+        Some(false) if in_capsule => {
+            // Remove the item from the ITEMS list, the capsule's list follows the index
+            database::atomically(|| {
+                ITEMS.remove(item_id);
+                INDEXES.write().unwrap().unlink_item(capsule_id, item_id);
+                CAPSULES.update(capsule_id, |capsule| capsule.time_changed = Some(now));  // Update the time_changed to now
+            });
+            bus::publish(DomainEvent::ItemRemoved { capsule_id, item_id }, now);
+            Ok(Status::NoContent)
+        },
+        _ => Err(ApiError::Other(Status::NotFound, format!("Item with ID {} not found in capsule {}", item_id, capsule_id))),
     }
-    Err(status::Custom(Status::NotFound, Json(format!("Item with ID {} not found in capsule {}", item_id, capsule_id))))
-}
\ No newline at end of file
+}
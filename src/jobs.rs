@@ -0,0 +1,268 @@
+// Background jobs: work that takes longer than a request (imports, exports) is submitted
+// to the job queue kept in managed state and run by a fixed number of tokio workers,
+// started on liftoff. A job whose attempt fails with a temporary error is queued again
+// after a backoff that doubles with every attempt, until `jobs.max_attempts`.
+//
+// Features keep their own results (imported items, the export's file) by job id and
+// report progress through the JobContext handed to each attempt.
+use rocket::serde::{json::Json, Serialize};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::futures::future::BoxFuture;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::tokio::sync::{mpsc, Mutex as AsyncMutex};
+use rocket::tokio::time::{self, Duration};
+use rocket::{Rocket, State};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::config;
+use crate::time_format;
+
+// Longest wait between two attempts
+const MAX_BACKOFF_SECS: u64 = 3600;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum JobKind {
+    Import,
+    Export,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Retrying,  // Failed temporarily, runs again at `retry_at`
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Job {
+    pub id: u32,
+    #[serde(rename = "type")]
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub total: usize,  // Units of work of the current attempt, e.g. files
+    pub done: usize,
+    pub error: Option<String>,  // Last failure
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub retry_at: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_created: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_started: Option<DateTime<Utc>>,  // Of the last attempt
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_finished: Option<DateTime<Utc>>,
+}
+
+pub struct JobError {
+    pub message: String,
+    pub retryable: bool,
+}
+
+impl JobError {
+    pub fn temporary(message: impl Into<String>) -> Self {
+        JobError { message: message.into(), retryable: true }
+    }
+
+    pub fn permanent(message: impl Into<String>) -> Self {
+        JobError { message: message.into(), retryable: false }
+    }
+}
+
+// One attempt of a job, called again for every retry
+pub type Work = Box<dyn Fn(JobContext) -> BoxFuture<'static, Result<(), JobError>> + Send + Sync>;
+
+pub struct JobQueue {
+    jobs: RwLock<BTreeMap<u32, Job>>,
+    work: Mutex<HashMap<u32, Arc<Work>>>,  // Dropped once a job is finished
+    next_id: AtomicU32,
+    sender: mpsc::UnboundedSender<u32>,
+    receiver: Arc<AsyncMutex<mpsc::UnboundedReceiver<u32>>>,
+}
+
+// Shared handle kept in Rocket's managed state
+pub type SharedJobs = Arc<JobQueue>;
+
+// Progress reporting for the attempt being run
+#[derive(Clone)]
+pub struct JobContext {
+    pub job_id: u32,
+    queue: SharedJobs,
+}
+
+impl JobContext {
+    pub fn set_total(&self, total: usize) {
+        self.queue.update(self.job_id, |job| job.total = total);
+    }
+
+    pub fn advance(&self) {
+        self.queue.update(self.job_id, |job| job.done += 1);
+    }
+}
+
+impl JobQueue {
+    pub fn new() -> SharedJobs {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Arc::new(JobQueue {
+            jobs: RwLock::new(BTreeMap::new()),
+            work: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            sender,
+            receiver: Arc::new(AsyncMutex::new(receiver)),
+        })
+    }
+
+    fn update(&self, job_id: u32, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.write().unwrap().get_mut(&job_id) {
+            f(job);
+        }
+    }
+
+    pub fn get(&self, job_id: u32) -> Option<Job> {
+        self.jobs.read().unwrap().get(&job_id).cloned()
+    }
+
+    // Queues a job, it starts as soon as a worker is free
+    pub fn submit(&self, kind: JobKind, work: Work) -> Job {
+        let job = Job {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            kind,
+            status: JobStatus::Queued,
+            attempts: 0,
+            max_attempts: config::get().jobs.max_attempts.max(1),
+            total: 0,
+            done: 0,
+            error: None,
+            retry_at: None,
+            time_created: Utc::now(),
+            time_started: None,
+            time_finished: None,
+        };
+        self.jobs.write().unwrap().insert(job.id, job.clone());
+        self.work.lock().unwrap().insert(job.id, Arc::new(work));
+        let _ = self.sender.send(job.id);
+        job
+    }
+
+    async fn run(self: &Arc<Self>, job_id: u32) {
+        let Some(work) = self.work.lock().unwrap().get(&job_id).cloned() else { return };
+        self.update(job_id, |job| {
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+            job.total = 0;
+            job.done = 0;
+            job.retry_at = None;
+            job.time_started = Some(Utc::now());
+        });
+
+        let result = work(JobContext { job_id, queue: self.clone() }).await;
+        let mut retry_in = None;
+        self.update(job_id, |job| match result {
+            Ok(()) => {
+                job.status = JobStatus::Completed;
+                job.error = None;
+                job.time_finished = Some(Utc::now());
+            },
+            Err(e) if e.retryable && job.attempts < job.max_attempts => {
+                let backoff = config::get().jobs.retry_base_secs.saturating_mul(1 << (job.attempts - 1).min(20)).min(MAX_BACKOFF_SECS);
+                job.status = JobStatus::Retrying;
+                job.error = Some(e.message);
+                job.retry_at = Some(Utc::now() + chrono::Duration::seconds(backoff as i64));
+                retry_in = Some(backoff);
+            },
+            Err(e) => {
+                eprintln!("Job {} failed: {}", job_id, e.message);
+                job.status = JobStatus::Failed;
+                job.error = Some(e.message);
+                job.time_finished = Some(Utc::now());
+            },
+        });
+
+        match retry_in {
+            Some(backoff) => {
+                let sender = self.sender.clone();
+                rocket::tokio::spawn(async move {
+                    time::sleep(Duration::from_secs(backoff)).await;
+                    let _ = sender.send(job_id);
+                });
+            },
+            None => {
+                self.work.lock().unwrap().remove(&job_id);
+            },
+        }
+    }
+}
+
+// Starts the workers once the server is up
+pub struct Workers;
+
+#[rocket::async_trait]
+impl Fairing for Workers {
+    fn info(&self) -> Info {
+        Info { name: "Job workers", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<rocket::Orbit>) {
+        let queue = rocket.state::<SharedJobs>().expect("Job queue is managed before launch").clone();
+        for _ in 0..config::get().jobs.workers.max(1) {
+            let queue = queue.clone();
+            rocket::tokio::spawn(async move {
+                loop {
+                    // Only one idle worker waits on the channel at a time
+                    let next = queue.receiver.lock().await.recv().await;
+                    match next {
+                        Some(job_id) => queue.run(job_id).await,
+                        None => return,
+                    }
+                }
+            });
+        }
+    }
+}
+
+fn parse_status(status: &str) -> Option<JobStatus> {
+    match status {
+        "queued" => Some(JobStatus::Queued),
+        "running" => Some(JobStatus::Running),
+        "retrying" => Some(JobStatus::Retrying),
+        "completed" => Some(JobStatus::Completed),
+        "failed" => Some(JobStatus::Failed),
+        _ => None,
+    }
+}
+
+fn parse_kind(kind: &str) -> Option<JobKind> {
+    match kind {
+        "import" => Some(JobKind::Import),
+        "export" => Some(JobKind::Export),
+        _ => None,
+    }
+}
+
+// All jobs, newest first, optionally of one type or status
+#[get("/jobs?<type>&<status>")]
+pub fn list_jobs(r#type: Option<&str>, status: Option<&str>, jobs: &State<SharedJobs>) -> Result<Json<Vec<Job>>, status::Custom<Json<String>>> {
+    let kind = r#type.map(|kind| parse_kind(kind)
+        .ok_or_else(|| status::Custom(Status::BadRequest, Json(format!("Unknown type '{}', use import or export", kind))))).transpose()?;
+    let job_status = status.map(|status| parse_status(status)
+        .ok_or_else(|| status::Custom(Status::BadRequest, Json(format!("Unknown status '{}', use queued, running, retrying, completed or failed", status))))).transpose()?;
+    let listed = jobs.jobs.read().unwrap().values().rev()
+        .filter(|job| kind.is_none_or(|kind| job.kind == kind) && job_status.is_none_or(|status| job.status == status))
+        .cloned()
+        .collect();
+    Ok(Json(listed))
+}
+
+#[get("/jobs/<id>")]
+pub fn get_job(id: u32, jobs: &State<SharedJobs>) -> Option<Json<Job>> {
+    jobs.get(id).map(Json)
+}
@@ -0,0 +1,243 @@
+// "Letter to your future self": capsules that are emailed to a list of recipients
+// when they open. The scheduler calls `deliver_due` on every tick, the outcome for
+// each recipient is kept on the capsule. Recipients don't need an account, they're
+// given with the new capsule or managed later under /capsules/<cid>/recipients.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::Clock;
+use crate::config;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::reveals;
+use crate::notifications::{self, Email};
+use crate::shares;
+use crate::ids::CapsuleId;
+use crate::locks;
+use crate::ownership;
+use crate::time_format;
+use crate::tokens::Caller;
+
+// Attempts per recipient before a temporary failure is given up
+const MAX_ATTEMPTS: u32 = 5;
+const MAX_RECIPIENTS: usize = 20;
+const MAX_NAME_LEN: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Recipient {
+    #[serde(default)]
+    pub name: Option<String>,  // Used to greet the recipient, if given
+    pub email: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_sent: Option<DateTime<Utc>>,
+    pub error: Option<String>,  // Last failure
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Delivery {
+    pub recipients: Vec<Recipient>,
+    pub share_token: Option<String>,  // Share link included in the email, issued on the first attempt
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewRecipient {
+    name: String,
+    email: String,
+}
+
+fn check_email(email: &str) -> Result<(), status::Custom<Json<String>>> {
+    match email.parse::<lettre::Address>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(status::Custom(Status::BadRequest, Json(format!("'{}' is not a valid email address", email)))),
+    }
+}
+
+fn too_many_recipients() -> status::Custom<Json<String>> {
+    status::Custom(Status::BadRequest, Json(format!("A capsule can be delivered to at most {} recipients", MAX_RECIPIENTS)))
+}
+
+// Delivery settings for a new capsule, None when there is nobody to email
+pub fn new_delivery(addresses: &[String]) -> Result<Option<Delivery>, status::Custom<Json<String>>> {
+    let mut emails: Vec<String> = addresses.iter().map(|address| address.trim().to_lowercase()).collect();
+    emails.sort();
+    emails.dedup();
+    if emails.is_empty() {
+        return Ok(None);
+    }
+    if emails.len() > MAX_RECIPIENTS {
+        return Err(too_many_recipients());
+    }
+    emails.iter().try_for_each(|email| check_email(email))?;
+
+    let recipients = emails.into_iter()
+        .map(|email| Recipient { name: None, email, status: DeliveryStatus::Pending, attempts: 0, time_sent: None, error: None })
+        .collect();
+    Ok(Some(Delivery { recipients, share_token: None }))
+}
+
+// Subject and body of the email, the items being those the recipients can see
+pub fn render_email(capsule: &Capsule, items: &[Item], share_url: Option<&str>) -> (String, String) {
+    let name = capsule.name.default_text();
+    let subject = format!("Your time capsule \"{}\" has opened", name);
+
+    let mut body = format!(
+        "The time capsule \"{}\" was sealed on {} and opened on {}.\n\n{}\n",
+        name, capsule.time_created.format("%B %-d, %Y"), capsule.time_open.format("%B %-d, %Y"), capsule.description.default_text(),
+    );
+    let items: Vec<String> = items.iter()
+        .map(|item| format!("- {}: {}", item.type_c, item.description))
+        .collect();
+    if !items.is_empty() {
+        let noun = if items.len() == 1 { "item" } else { "items" };
+        body.push_str(&format!("\nInside ({} {}):\n{}\n", items.len(), noun, items.join("\n")));
+    }
+    if let Some(url) = share_url {
+        body.push_str(&format!("\nOpen it here: {}\n", url));
+    }
+    (subject, body)
+}
+
+// The link in the email, only with `public_url` set since it needs an absolute URL
+pub fn share_url(token: &str) -> Option<String> {
+    config::get().public_url.as_ref().map(|base| format!("{}/shared/{}/preview", base.trim_end_matches('/'), token))
+}
+
+pub fn greet(name: Option<&str>, body: &str) -> String {
+    match name {
+        Some(name) => format!("Hello {},\n\n{}", name, body),
+        None => body.to_string(),
+    }
+}
+
+async fn deliver(capsule_id: CapsuleId, now: DateTime<Utc>) {
+    let Some(capsule) = CAPSULES.get(capsule_id) else { return };
+    let Some(delivery) = &capsule.delivery else { return };
+    let pending: Vec<(String, Option<String>)> = delivery.recipients.iter()
+        .filter(|recipient| recipient.status == DeliveryStatus::Pending)
+        .map(|recipient| (recipient.email.clone(), recipient.name.clone()))
+        .collect();
+
+    // Links need an absolute URL, so they're only included with `public_url` set
+    let share_token = delivery.share_token.clone()
+        .or_else(|| config::get().public_url.as_ref().map(|_| shares::new_share(capsule_id).token));
+    let share_url = share_token.as_deref().and_then(share_url);
+    let item_ids = reveals::visible(capsule_id, INDEXES.read().unwrap().items_of(capsule_id));
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+    let (subject, body) = render_email(&capsule, &items, share_url.as_deref());
+
+    let mut results = Vec::new();
+    for (email, name) in pending {
+        let body = greet(name.as_deref(), &body);
+        let result = notifications::send_email(Email { to: email.clone(), subject: subject.clone(), body }).await;
+        results.push((email, result));
+    }
+
+    CAPSULES.update(capsule_id, |capsule| {
+        let Some(delivery) = &mut capsule.delivery else { return };
+        delivery.share_token = share_token;
+        for (email, result) in results {
+            let Some(recipient) = delivery.recipients.iter_mut().find(|r| r.email == email) else { continue };
+            recipient.attempts += 1;
+            match result {
+                Ok(()) => {
+                    recipient.status = DeliveryStatus::Sent;
+                    recipient.time_sent = Some(now);
+                    recipient.error = None;
+                },
+                Err(e) => {
+                    eprintln!("Delivery of capsule {} to {} failed: {}", capsule_id, email, e.message);
+                    recipient.error = Some(e.message);
+                    if e.permanent || recipient.attempts >= MAX_ATTEMPTS {
+                        recipient.status = DeliveryStatus::Failed;
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Emails every opened capsule that still has recipients waiting
+pub async fn deliver_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        let waiting = capsule.delivery.as_ref()
+            .is_some_and(|delivery| delivery.recipients.iter().any(|r| r.status == DeliveryStatus::Pending));
+        if waiting && capsule.time_open <= now {
+            due.push(capsule.id);
+        }
+    });
+
+    for capsule_id in due {
+        deliver(capsule_id, now).await;
+    }
+}
+
+// Adds someone to email when the capsule opens. A capsule that has already opened
+// emails them on the next scheduler tick. Recipients are managed by the capsule's
+// owner and co-owners.
+#[post("/capsules/<cid>/recipients", format = "json", data = "<recipient>")]
+pub fn add_recipient(cid: CapsuleId, recipient: Json<NewRecipient>, caller: Caller) -> Result<status::Created<Json<Recipient>>, ApiError> {
+    let name = recipient.name.trim().to_string();
+    let email = recipient.email.trim().to_lowercase();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ApiError::Other(Status::BadRequest, format!("name is required and limited to {} characters", MAX_NAME_LEN)));
+    }
+    check_email(&email)?;
+
+    let _guard = locks::lock_capsule(cid);
+    let added = CAPSULES.try_update(cid, |capsule| {
+        ownership::check_editor(capsule, &caller)?;
+        let delivery = capsule.delivery.get_or_insert_with(|| Delivery { recipients: Vec::new(), share_token: None });
+        if delivery.recipients.iter().any(|r| r.email == email) {
+            return Err(status::Custom(Status::Conflict, Json(format!("{} is already a recipient of capsule {}", email, cid))));
+        }
+        if delivery.recipients.len() >= MAX_RECIPIENTS {
+            return Err(too_many_recipients());
+        }
+        let recipient = Recipient { name: Some(name.clone()), email: email.clone(), status: DeliveryStatus::Pending, attempts: 0, time_sent: None, error: None };
+        delivery.recipients.push(recipient.clone());
+        Ok(recipient)
+    });
+    match added {
+        Some(Ok(recipient)) => Ok(status::Created::new(format!("/capsules/{}", cid)).body(Json(recipient))),
+        Some(Err(e)) => Err(e.into()),
+        None => Err(ApiError::CapsuleNotFound(cid)),
+    }
+}
+
+// Stops emailing a recipient. A share link they were already sent keeps working.
+#[delete("/capsules/<cid>/recipients?<email>")]
+pub fn remove_recipient(cid: CapsuleId, email: &str, caller: Caller) -> Result<Status, ApiError> {
+    let email = email.trim().to_lowercase();
+    let _guard = locks::lock_capsule(cid);
+    let removed = CAPSULES.try_update(cid, |capsule| {
+        ownership::check_editor(capsule, &caller)?;
+        let delivery = capsule.delivery.as_mut()
+            .filter(|delivery| delivery.recipients.iter().any(|r| r.email == email))
+            .ok_or_else(|| ApiError::Other(Status::NotFound, format!("{} is not a recipient of capsule {}", email, cid)))?;
+        delivery.recipients.retain(|r| r.email != email);
+        if delivery.recipients.is_empty() && delivery.share_token.is_none() {
+            capsule.delivery = None;
+        }
+        Ok(Status::NoContent)
+    });
+    removed.unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
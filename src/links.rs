@@ -0,0 +1,137 @@
+// Items that point at a file elsewhere instead of being uploaded. The server checks the
+// URL with a HEAD request, records what it answers (content type and size) and keeps the
+// URL as the item's path. With `snapshot` the file is also downloaded into
+// `links.snapshot_dir`, so the capsule keeps it even if the link is gone by the time the
+// capsule opens. Links that lead to internal addresses are refused, see destinations.rs.
+use rocket::serde::{json::Json, Deserialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::tokio::fs;
+use rocket::State;
+use reqwest::{header, Method, StatusCode, Url};
+use std::time::Duration;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::config;
+use crate::destinations;
+use crate::error_messages::ApiError;
+use crate::flags;
+use crate::ids::CapsuleId;
+use crate::imports::{self, Download};
+use crate::items::{self, Item, NewItem, Origin};
+use crate::ownership;
+use crate::quotas::{self, WithLimits};
+use crate::tokens::Caller;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewLinkedItem {
+    url: String,
+    type_c: Option<String>,       // Defaults to the type of the content
+    description: Option<String>,  // Defaults to the file name in the URL, or its host
+    #[serde(default)]
+    snapshot: bool,               // Keep a copy of the content
+}
+
+fn timeout() -> Option<Duration> {
+    Some(Duration::from_secs(config::get().links.timeout_secs))
+}
+
+// What the server of a link tells about it
+struct Head {
+    content_type: Option<String>,
+    size: Option<u64>,
+    final_url: Url,  // After redirects
+}
+
+// Servers that don't answer HEAD get a GET whose body is never read
+async fn probe(url: &Url) -> Result<Head, String> {
+    let mut response = destinations::send(Method::HEAD, url, timeout(), |request| request).await?;
+    if matches!(response.status(), StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED) {
+        response = destinations::send(Method::GET, url, timeout(), |request| request).await?;
+    }
+    let response = response.error_for_status().map_err(|e| e.to_string())?;
+    let value = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+    Ok(Head {
+        content_type: value(header::CONTENT_TYPE).map(|value| value.split(';').next().unwrap_or_default().trim().to_string()),
+        size: value(header::CONTENT_LENGTH).and_then(|value| value.parse().ok()),
+        final_url: response.url().clone(),
+    })
+}
+
+// Web pages are kept as links, files get the type an import would give them
+fn item_type(content_type: &str) -> &'static str {
+    match content_type {
+        "text/html" | "application/xhtml+xml" => "link",
+        _ => imports::item_type(content_type),
+    }
+}
+
+// Only the capsule's owner and co-owners link items, since the server fetches them
+#[post("/capsules/<cid>/items/link", format = "json", data = "<link>")]
+pub async fn link_item(cid: CapsuleId, link: Json<NewLinkedItem>, caller: Caller, clock: &State<SharedClock>) -> Result<WithLimits<Json<Item>>, ApiError> {
+    if !flags::current().uploads_enabled {
+        return Err(flags::disabled("Uploading items").into());
+    }
+    let link = link.into_inner();
+    let url = Url::parse(link.url.trim()).ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or(ApiError::InvalidUrl)?;
+    CAPSULES.read(cid, |capsule| ownership::check_editor(capsule, &caller))
+        .ok_or(ApiError::CapsuleNotFound(cid))??;
+
+    let head = probe(&url).await
+        .map_err(|e| status::Custom(Status::UnprocessableEntity, Json(format!("{} isn't reachable: {}", url, e))))?;
+    let content_type = head.content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    let mut metadata = serde_json::json!({
+        "source_url": url.as_str(),
+        "content_type": content_type,
+        "content_length": head.size,
+        "time_checked": clock.now(),
+    });
+    if head.final_url != url {
+        metadata["final_url"] = serde_json::Value::String(head.final_url.to_string());
+    }
+    let name = imports::file_name(None, url.as_str());
+    let description = link.description.unwrap_or_else(|| {
+        url.path_segments().and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+            .or(url.host_str())
+            .unwrap_or(url.as_str())
+            .to_string()
+    });
+    let mut new_item = NewItem {
+        type_c: link.type_c.unwrap_or_else(|| item_type(&content_type).to_string()),
+        description,
+        size: imports::format_size(head.size.unwrap_or(0)),
+        path: url.to_string(),
+        metadata,
+        owner_only: false,
+        message: None,
+    };
+
+    // Nothing is downloaded for an item that would be refused anyway
+    if let Some(error) = items::check_new_item(cid, &new_item, clock.now()).into_iter().next() {
+        return Err(error);
+    }
+    if link.snapshot {
+        let settings = &config::get().links;
+        let path = imports::download_path(&settings.snapshot_dir, cid, 0, &name)
+            .map_err(|e| status::Custom(Status::InternalServerError, Json(format!("Snapshot failed: {}", e))))?;
+        let Download { path, size, sha256, .. } = imports::download(&url, timeout(), path, settings.max_snapshot_mb).await
+            .map_err(|e| status::Custom(Status::UnprocessableEntity, Json(format!("Snapshot failed: {}", e))))?;
+        new_item.size = imports::format_size(size);
+        new_item.path = path.to_string_lossy().into_owned();
+        new_item.metadata["snapshot"] = serde_json::json!({ "size": size, "sha256": sha256, "time_taken": clock.now() });
+    }
+
+    match items::create_item(cid, &new_item, Origin::Link { url: url.to_string() }, clock.as_ref()) {
+        Ok(item) => Ok(WithLimits(Json(item), quotas::limits(cid))),
+        Err(e) => {
+            if link.snapshot {
+                let _ = fs::remove_file(&new_item.path).await;
+            }
+            Err(e)
+        },
+    }
+}
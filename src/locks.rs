@@ -0,0 +1,77 @@
+// Per-entity operation locks.
+//
+// The tables in store.rs protect individual rows, but most handlers are multi-step
+// (check a capsule, create an item, link it, ...). Those steps are serialized per
+// capsule or per contributor here, so operations on unrelated capsules never wait
+// on each other.
+//
+// Lock ordering, always acquired top to bottom and released in reverse:
+//   0. the email uniqueness lock in contributors.rs
+//   1. contributor locks (CONTRIBUTOR_LOCKS), several at once only through
+//      `lock_contributors`, which takes them in ascending id order
+//   2. capsule locks (CAPSULE_LOCKS), several at once only through `lock_capsules`,
+//      which takes them in ascending id order
+//   3. table shards and the order index (store.rs), held only for a single access;
+//      in lazy item mode the item store's state lock comes right before the item shards,
+//      and a table's change log is only ever taken after its shard (or on its own; sync
+//      holds the contributor, capsule and item change logs at once, in that order)
+//   4. INDEXES and other global RwLocks, held only for a single access
+use parking_lot::{Mutex, RawMutex};
+use parking_lot::lock_api::ArcMutexGuard;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::ids::{CapsuleId, ContributorId, EntityId};
+
+pub type EntityGuard = ArcMutexGuard<RawMutex, ()>;
+
+pub struct LockTable<I> {
+    locks: Mutex<HashMap<I, Arc<Mutex<()>>>>,
+}
+
+impl<I: EntityId> LockTable<I> {
+    fn new() -> Self {
+        LockTable { locks: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn lock(&self, id: I) -> EntityGuard {
+        let entry = self.locks.lock().entry(id).or_default().clone();
+        entry.lock_arc()
+    }
+
+    // Drops the lock of a deleted entity once nobody else is waiting on it
+    pub fn forget(&self, id: I) {
+        let mut locks = self.locks.lock();
+        if locks.get(&id).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&id);
+        }
+    }
+}
+
+pub static CONTRIBUTOR_LOCKS: Lazy<LockTable<ContributorId>> = Lazy::new(LockTable::new);
+pub static CAPSULE_LOCKS: Lazy<LockTable<CapsuleId>> = Lazy::new(LockTable::new);
+
+pub fn lock_contributor(id: ContributorId) -> EntityGuard {
+    CONTRIBUTOR_LOCKS.lock(id)
+}
+
+pub fn lock_capsule(id: CapsuleId) -> EntityGuard {
+    CAPSULE_LOCKS.lock(id)
+}
+
+// Locks several contributors in ascending id order, like `lock_capsules`
+pub fn lock_contributors(ids: &[ContributorId]) -> Vec<EntityGuard> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    ids.into_iter().map(lock_contributor).collect()
+}
+
+// Locks several capsules in ascending id order so two callers can never deadlock
+pub fn lock_capsules(ids: &[CapsuleId]) -> Vec<EntityGuard> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    ids.into_iter().map(lock_capsule).collect()
+}
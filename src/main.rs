@@ -1,13 +1,12 @@
 #[macro_use] extern crate rocket;
 use std::fs;
-use serde_json; // Ensure serde_json is added to your dependencies
 
 mod capsules;
-use capsules::{create_and_update_capsule, list_capsules, capsule_detail, update_capsule, patch_capsule, delete_capsule};
+use capsules::{create_and_update_capsule, list_capsules, capsule_detail, capsule_countdown, update_capsule, patch_capsule, delete_capsule};
 
 mod contributors;
 use contributors::{create_contributor, list_contributors, get_contributor_with_capsules, delete_contributor,
-    update_contributor};
+    update_contributor, update_capsule_defaults};
 
 mod items;
 use items::{get_all_items, get_item, get_capsule_items, add_item_to_capsule, get_capsule_item,
@@ -16,27 +15,251 @@ use items::{get_all_items, get_item, get_capsule_items, add_item_to_capsule, get
 mod merges;
 use merges::{merge_capsules, get_merge_records };
 
+mod flags;
+use flags::{get_flags, update_flags};
+
+mod admin;
+use admin::{reassign_capsules, rebuild_derived_data};
+mod schedules;
+mod templates;
+mod owner_only;
+mod payload;
+mod messages;
+mod error_messages;
+mod persistence;
+mod coalesce;
+mod database;
+mod sqlite;
+mod postgres;
+use owner_only::set_owner_only;
+use messages::{add_message, get_message};
+use schedules::{get_schedules, update_schedules};
+mod anonymize;
+use anonymize::anonymize_data;
+
+mod store;
+mod state;
+mod ids;
+mod item_store;
+mod locks;
+mod streaming;
+mod pagination;
+mod cache;
+mod sync;
+mod capsule_view;
+mod reports;
+mod timezones;
+mod i18n;
+mod widgets;
+use widgets::{capsule_widget_svg, capsule_widget_html};
+mod shares;
+use shares::{create_share, share_preview};
+mod imports;
+use imports::{start_import, get_import};
+mod archives;
+use archives::{export_archive, download_archive};
+use reports::{openings_report, upcoming_report};
+use capsule_view::get_full_capsule;
+use sync::sync_changes;
+use streaming::export_all;
+
+mod indexes;
+
+mod config;
+mod notifications;
+mod letters;
+mod open_notices;
+use letters::{add_recipient, remove_recipient};
+mod scheduler;
+mod clock;
+mod dry_run;
+mod signatures;
+mod audit;
+mod publishing;
+mod downloads;
+mod quotas;
+mod field_history;
+mod events;
+mod bulk_contributors;
+mod reveals;
+mod metrics;
+mod usage;
+mod reads;
+mod enrichment;
+mod hash_chain;
+mod tokens;
+mod sessions;
+mod search;
+mod capsule_groups;
+mod orphans;
+mod duplicates;
+mod validation;
+mod cold_storage;
+mod bus;
+mod capsule_order;
+mod bulk_capsules;
+mod moderation;
+mod content_policy;
+mod ownership;
+mod retention;
+mod exports;
+use exports::{start_export, get_export, download_export};
+mod jobs;
+use jobs::{list_jobs, get_job};
+mod links;
+mod destinations;
+use links::link_item;
+mod webhooks;
+mod metadata_index;
+mod residency;
+mod custom_fields;
+mod tombstones;
+mod time_format;
+mod simulation;
+use simulation::simulate_open;
+mod stats;
+use stats::{growth_report, public_stats};
+use webhooks::{create_webhook, list_webhooks, get_webhook, patch_webhook, delete_webhook, rotate_webhook_secret, list_webhook_deliveries, replay_webhook};
+use ownership::{request_ownership, list_ownership_requests, approve_ownership_request, reject_ownership_request, remove_co_owner};
+use orphans::{orphaned_items, attach_item};
+use validation::{validate_capsule, validate_item};
+use cold_storage::archive_to_cold_storage;
+use bus::event_stream;
+use capsule_order::{set_capsule_order, pin_capsule, unpin_capsule};
+use bulk_capsules::patch_capsules;
+use moderation::{report_capsule, list_reports, resolve_report, restore_capsule};
+use capsule_groups::grouped_capsules;
+use tokens::{create_token, send_code, list_tokens, revoke_token};
+use sessions::{list_sessions, end_session};
+use hash_chain::capsule_hash_chain;
+use reads::capsule_reads;
+use usage::contributor_usage;
+use reveals::{schedule_reveal, cancel_reveal, get_reveal};
+use bulk_contributors::{import_contributors_json, import_contributors_csv};
+use events::capsule_events;
+use quotas::capsule_limits;
+use downloads::download_items;
+use audit::{get_audit_log, contributor_changes};
+use publishing::{schedule_publishing, cancel_publishing, get_publishing, public_feed};
+use signatures::{sign_capsule, get_signatures};
+use clock::{get_clock, set_clock};
+mod chaos;
+mod compression;
+mod reporting;
+#[cfg(test)]
+mod tests;
+
 #[launch]
-fn rocket() -> _ {
-    let contributors_json = fs::read_to_string("C:/Users/РЕГИНА/Desktop/studia/RUST/rest-capsules/src/data/contributors.json").expect("Failed to read contributors.json");
-    let capsules_json = fs::read_to_string("C:/Users/РЕГИНА/Desktop/studia/RUST/rest-capsules/src/data/capsule.json").expect("Failed to read capsules.json");
-    let items_json = fs::read_to_string("C:/Users/РЕГИНА/Desktop/studia/RUST/rest-capsules/src/data/items.json").expect("Failed to read items.json");
-
-    let contributors_data: Vec<contributors::Contributor> = serde_json::from_str(&contributors_json).expect("Invalid format in contributors.json");
-    let capsules_data: Vec<capsules::Capsule> = serde_json::from_str(&capsules_json).expect("Invalid format in capsules.json");
-    let items_data: Vec<items::Item> = serde_json::from_str(&items_json).expect("Invalid format in items.json");
-
-    // Fill the global state with data loaded from files
-    *contributors::CONTRIBUTORS.lock().unwrap() = contributors_data;
-    *capsules::CAPSULES.lock().unwrap() = capsules_data;
-    *items::ITEMS.lock().unwrap() = items_data;
-
-    rocket::build()
-        .mount("/", routes![
-            create_and_update_capsule, list_capsules, capsule_detail, update_capsule, patch_capsule, delete_capsule,
-            create_contributor, list_contributors, get_contributor_with_capsules, delete_contributor, update_contributor,
-            get_all_items, get_item, get_capsule_items, add_item_to_capsule, get_capsule_item,
-            patch_capsule_item_description, delete_capsule_item,
-            merge_capsules, get_merge_records
-        ])
+async fn rocket() -> _ {
+    let app_config = config::get();
+
+    let in_database = app_config.storage.backend != config::StorageBackend::Memory;
+    if in_database {
+        // The database is the record, nothing else may replace or re-derive its rows
+        assert!(!app_config.anonymize, "anonymize can't be used with a database storage.backend");
+        assert!(!app_config.persistence.enabled, "persistence.enabled can't be used with a database storage.backend");
+        assert!(!app_config.events.enabled, "events.enabled can't be used with a database storage.backend");
+        assert!(!app_config.items.lazy, "items.lazy can't be used with a database storage.backend");
+    }
+    let loaded = if in_database { Some(database::open(app_config.storage.backend).await) } else { None };
+
+    // Data files come from `data_dir`, a missing one is empty with `empty_if_missing`
+    let contributors_json = config::data_file("contributors.json")
+        .map_or_else(|| "[]".to_string(), |path| fs::read_to_string(path).expect("Failed to read contributors.json"));
+    let capsules_json = config::data_file("capsule.json")
+        .map_or_else(|| "[]".to_string(), |path| fs::read_to_string(path).expect("Failed to read capsules.json"));
+
+    let contributors_data: Vec<contributors::Contributor> = store::loading(|| serde_json::from_str(&contributors_json)).expect("Invalid format in contributors.json");
+    let capsules_data: Vec<capsules::Capsule> = store::loading(|| serde_json::from_str(&capsules_json)).expect("Invalid format in capsules.json");
+
+    // In event-sourced mode capsules are rebuilt from their event log once it exists
+    let capsules_data = match app_config.events.enabled.then(events::replay).flatten() {
+        Some(replayed) => replayed,
+        None => capsules_data,
+    };
+
+    // Fill the global state with data loaded from files, or from the database
+    match loaded {
+        Some(loaded) => {
+            contributors::CONTRIBUTORS.replace_all(loaded.contributors);
+            capsules::CAPSULES.replace_all(loaded.capsules);
+            items::ITEMS.load_rows(loaded.items);
+        },
+        None => {
+            contributors::CONTRIBUTORS.replace_all(contributors_data);
+            capsules::CAPSULES.replace_all(capsules_data);
+            if app_config.events.enabled {
+                events::start();
+            }
+            items::ITEMS.load(config::data_file("items.json").as_deref());
+            state::load_files();
+        },
+    }
+
+    // Derive the reverse indexes from the loaded records, id lists in the files are ignored
+    *indexes::INDEXES.write().unwrap() = indexes::Indexes::rebuild(&capsules::CAPSULES, &items::ITEMS);
+
+    cold_storage::start();
+    content_policy::start();
+
+    if app_config.persistence.enabled {
+        // Fake values would be written over the real data
+        assert!(!app_config.anonymize, "anonymize and persistence.enabled can't be used together");
+        let dir = config::data_path("");
+        persistence::check_writable(&dir).unwrap_or_else(|e| panic!("Can't write the data to {}: {}", dir.display(), e));
+    }
+
+    if app_config.anonymize {
+        let result = anonymize::anonymize_all();
+        println!("Anonymized {} contributors and {} items", result.contributors, result.items);
+    }
+    bus::start();
+    database::start();
+    hash_chain::start();  // Once the items are loaded and final, and new links are written
+
+    let mut rocket = rocket::build().attach(tokens::TokenGate).attach(metrics::Metrics).attach(audit::ApiChanges);
+    if app_config.chaos.enabled {
+        rocket = rocket.attach(chaos::Chaos);
+    }
+    if app_config.reporting.is_configured() {
+        reporting::install_panic_hook();
+        rocket = rocket.attach(reporting::ErrorReporter);
+    }
+
+    // Time source for the modification window and opening rules, see clock.rs
+    let adjustable = app_config.adjustable_clock.then(|| std::sync::Arc::new(clock::AdjustableClock::default()));
+    let clock: clock::SharedClock = match &adjustable {
+        Some(adjustable) => adjustable.clone(),
+        None => std::sync::Arc::new(clock::SystemClock),
+    };
+    rocket = rocket.manage(clock).manage(clock::ClockControl(adjustable)).attach(bus::Subscribers).attach(scheduler::Scheduler);
+    rocket = rocket.manage(jobs::JobQueue::new()).attach(jobs::Workers).attach(time_format::TimeFormatting);
+    if app_config.persistence.enabled {
+        rocket = rocket.attach(persistence::Persistence);
+    }
+    if in_database {
+        rocket = rocket.attach(database::Flush);
+    }
+    if app_config.compression.enabled {
+        // Last, so it sees the bodies other fairings may have replaced
+        rocket = rocket.attach(compression::Compression);
+    }
+
+    rocket
+        .mount("/", reporting::reported(time_format::formatted(routes![
+            create_and_update_capsule, validate_capsule, list_capsules, grouped_capsules, capsule_detail, capsule_countdown, update_capsule, patch_capsule, patch_capsules, delete_capsule, archive_to_cold_storage,
+            create_contributor, list_contributors, get_contributor_with_capsules, delete_contributor, update_contributor, update_capsule_defaults, set_capsule_order, pin_capsule, unpin_capsule,
+            request_ownership, list_ownership_requests, approve_ownership_request, reject_ownership_request, remove_co_owner,
+            get_all_items, orphaned_items, attach_item, get_item, get_capsule_items, add_item_to_capsule, link_item, validate_item, get_capsule_item,
+            patch_capsule_item_description, delete_capsule_item, set_owner_only, add_message, get_message,
+            merge_capsules, get_merge_records,
+            get_flags, update_flags, reassign_capsules, rebuild_derived_data, get_schedules, update_schedules, anonymize_data, get_clock, set_clock,
+            export_all, start_export, get_export, download_export, list_jobs, get_job, create_webhook, list_webhooks, get_webhook, patch_webhook, delete_webhook, rotate_webhook_secret, list_webhook_deliveries, replay_webhook, sync_changes, get_full_capsule,
+            openings_report, upcoming_report, growth_report, public_stats, capsule_widget_svg, capsule_widget_html,
+            create_share, share_preview, add_recipient, remove_recipient, sign_capsule, get_signatures, get_publishing, schedule_publishing, cancel_publishing, public_feed, report_capsule, list_reports, resolve_report, restore_capsule, get_audit_log, contributor_changes, start_import, get_import,
+            export_archive, download_archive, download_items, capsule_limits, capsule_events, import_contributors_json, import_contributors_csv,
+            schedule_reveal, cancel_reveal, get_reveal, simulate_open, contributor_usage, capsule_reads, capsule_hash_chain,
+            create_token, send_code, list_tokens, revoke_token, list_sessions, end_session, event_stream
+        ])))
+        .register("/", catchers![payload::unprocessable_payload])
 }
@@ -1,23 +1,44 @@
 use rocket::serde::{Serialize, Deserialize, json::Json};
 use rocket::http::{Status};
-use rocket::response::status::Custom;
-use std::sync::Mutex;
+use rocket::response::status::{self, Custom};
+use rocket::{Either, State};
+use std::sync::RwLock;
 use once_cell::sync::Lazy;
 use chrono::{DateTime, Utc};
 
+use crate::bus::{self, DomainEvent};
 use crate::capsules::{Capsule, CAPSULES};
 use crate::contributors::CONTRIBUTORS;
-use crate::items::ITEMS;
+use crate::database;
+use crate::items::{Origin, ProvenanceStep, ITEMS};
+use crate::clock::SharedClock;
+use crate::dry_run::{ContributorLink, MergePlan};
+use crate::flags;
+use crate::i18n::LocalizedText;
+use crate::indexes::INDEXES;
+use crate::locks;
+use crate::ownership;
+use crate::signatures;
+use crate::state;
+use crate::field_history;
+use crate::reads;
+use crate::streaming::{self, JsonStream};
+use crate::time_format;
+use crate::tokens::Caller;
+use crate::ids::{CapsuleId, ContributorId, EntityId, ItemId};
+use rocket::futures::stream::Stream;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CapsuleDetails {
-    pub id: u32,
-    pub contributor_id: u32,
+    pub id: CapsuleId,
+    pub contributor_id: ContributorId,
+    #[serde(serialize_with = "time_format::time")]
     pub time_created: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::time")]
     pub time_changed: DateTime<Utc>,
-    pub description: String,
-    pub name: String,
-    pub item_ids: Option<Vec<u32>>, // Assuming item IDs are relevant for the capsule details
+    pub description: LocalizedText,
+    pub name: LocalizedText,
+    pub item_ids: Option<Vec<ItemId>>, // Assuming item IDs are relevant for the capsule details
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -27,7 +48,23 @@ pub struct MergeRecord {
     pub new_merged_capsule: CapsuleDetails,
 }
 
-pub static MERGE_RECORDS: Lazy<Mutex<Vec<MergeRecord>>> = Lazy::new(|| Mutex::new(vec![]));
+pub static MERGE_RECORDS: Lazy<RwLock<Vec<MergeRecord>>> = Lazy::new(|| RwLock::new(vec![]));
+
+// Where a capsule came from and went to through merges, shown with the capsule so clients
+// can tell a merged capsule from a deleted one
+#[derive(Serialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct MergeLineage {
+    pub merged_from: Vec<CapsuleId>,      // Capsules merged into this one, oldest first
+    pub merged_into: Option<CapsuleId>,   // The capsule this one was merged into, once it's gone
+}
+
+pub fn lineage(cid: CapsuleId) -> MergeLineage {
+    let records = MERGE_RECORDS.read().unwrap();
+    let merged_from = records.iter().filter(|r| r.new_merged_capsule.id == cid).map(|r| r.old_capsule2.id).collect();
+    let merged_into = records.iter().rev().find(|r| r.old_capsule2.id == cid).map(|r| r.new_merged_capsule.id);
+    MergeLineage { merged_from, merged_into }
+}
 
 impl From<Capsule> for CapsuleDetails {
     fn from(capsule: Capsule) -> Self {
@@ -35,99 +72,171 @@ impl From<Capsule> for CapsuleDetails {
             id: capsule.id,
             contributor_id: capsule.contributor_id,
             time_created: capsule.time_created,
-            time_changed: capsule.time_changed.expect("Time changed should be set"),
+            time_changed: capsule.time_changed.unwrap_or(capsule.time_created),
             description: capsule.description,
             name: capsule.name,
-            item_ids: capsule.item_ids,
+            item_ids: Some(INDEXES.read().unwrap().items_of(capsule.id)),
         }
     }
 }
 
 #[derive(Deserialize)]
-struct MergeRequest {
-    capsule_id1: u32,
-    capsule_id2: u32,
+pub struct MergeRequest {
+    capsule_id1: CapsuleId,
+    capsule_id2: CapsuleId,
 }
 
-#[post("/merges", format = "json", data = "<merge_request>")]
-pub fn merge_capsules(merge_request: Json<MergeRequest>) -> Result<Json<CapsuleDetails>, Custom<String>> {
-    let mut capsules = CAPSULES.lock().unwrap();
-    let mut items = ITEMS.lock().unwrap();
-    let mut contributors = CONTRIBUTORS.lock().unwrap();
+// With `?dry_run=true` the capsules are checked and locked as usual, but the response
+// only describes the merge instead of performing it
+#[post("/merges?<dry_run>", format = "json", data = "<merge_request>")]
+pub fn merge_capsules(merge_request: Json<MergeRequest>, dry_run: Option<bool>, caller: Caller, clock: &State<SharedClock>) -> Result<Either<Json<CapsuleDetails>, Json<MergePlan>>, Custom<String>> {
+    if !flags::current().merges_enabled {
+        return Err(Custom(Status::ServiceUnavailable, "Merging capsules is currently disabled".into()));
+    }
 
-    let idx1 = capsules.iter().position(|c| c.id == merge_request.capsule_id1);
-    let idx2 = capsules.iter().position(|c| c.id == merge_request.capsule_id2);
+    let id1 = merge_request.capsule_id1;
+    let id2 = merge_request.capsule_id2;
 
-    if idx1.is_none() || idx2.is_none() {
-        return Err(Custom(Status::BadRequest, "One or both capsules not found.".into()));
+    if id1 == id2 {
+        return Err(Custom(Status::BadRequest, "A capsule cannot be merged with itself.".into()));
     }
 
-    let idx1 = idx1.unwrap();
-    let idx2 = idx2.unwrap();
+    // Take the owner's lock before both capsule locks, see locks.rs
+    let contributor_id = CAPSULES.read(id1, |c| c.contributor_id);
+    let contributor_guard = contributor_id.map(locks::lock_contributor);
+    let capsule_guards = locks::lock_capsules(&[id1, id2]);
 
-    if capsules[idx1].contributor_id != capsules[idx2].contributor_id {
+    let (capsule1, capsule2) = match (CAPSULES.get(id1), CAPSULES.get(id2)) {
+        (Some(c1), Some(c2)) => (c1, c2),
+        _ => return Err(Custom(Status::BadRequest, "One or both capsules not found.".into())),
+    };
+
+    if capsule1.contributor_id != capsule2.contributor_id {
         return Err(Custom(Status::Forbidden, "Capsules have different contributors.".into()));
     }
+    // The second capsule goes away, so the caller has to be able to edit both
+    for capsule in [&capsule1, &capsule2] {
+        ownership::check_editor(capsule, &caller).map_err(|status::Custom(status, Json(message))| Custom(status, message))?;
+    }
 
-    let time_now = Utc::now();
-    if capsules[idx1].time_changed.unwrap() > time_now || capsules[idx2].time_changed.unwrap() > time_now {
+    let time_now = clock.now();
+    let (Some(changed1), Some(changed2)) = (capsule1.time_changed, capsule2.time_changed) else {
+        return Err(Custom(Status::Conflict, "Capsules without a modification time can't be merged.".into()));
+    };
+    if changed1 > time_now || changed2 > time_now {
         return Err(Custom(Status::Forbidden, "Capsule modification not allowed at this time.".into()));
     }
-
-    // Clone the old capsules for the record before any modification
-    let old_capsule1 = capsules[idx1].clone().into();
-    let old_capsule2 = capsules[idx2].clone().into();
-
-    // Transfer all items from the second capsule to the first capsule
-    let item_ids_from_capsule2 = capsules[idx2].item_ids.take().unwrap_or_default();
-    for item_id in item_ids_from_capsule2.iter() {
-        if let Some(item) = items.iter_mut().find(|i| i.id == *item_id) {
-            item.id_capsule = capsules[idx1].id; // Update the capsule ID of the item
-        }
+    // Merging is a structural change, it ends with the edit window even if items are still accepted
+    if signatures::is_sealed(&capsule1, time_now) || signatures::is_sealed(&capsule2, time_now) {
+        return Err(Custom(Status::BadRequest, "The modification period for one or both capsules has expired.".into()));
     }
 
-    if let Some(ref mut item_ids_from_capsule1) = capsules[idx1].item_ids {
-        item_ids_from_capsule1.extend(item_ids_from_capsule2);
-    } else {
-        capsules[idx1].item_ids = Some(item_ids_from_capsule2);
+    if dry_run.unwrap_or(false) {
+        let moved_item_ids = INDEXES.read().unwrap().items_of(id2);
+        let mut item_ids = INDEXES.read().unwrap().items_of(id1);
+        item_ids.extend(&moved_item_ids);
+        let merged_capsule = CapsuleDetails {
+            id: id1,
+            contributor_id: capsule1.contributor_id,
+            time_created: capsule1.time_created,
+            time_changed: time_now,
+            description: format!("Updated by merging with Capsule {}", id2).into(),
+            name: capsule1.name.clone(),
+            item_ids: Some(item_ids),
+        };
+        return Ok(Either::Right(Json(MergePlan {
+            dry_run: true,
+            kept_capsule_id: id1,
+            removed_capsule_id: id2,
+            item_count: moved_item_ids.len(),
+            moved_item_ids,
+            contributor_links: vec![ContributorLink { contributor_id: capsule2.contributor_id, capsule_id: id2 }],
+            merged_capsule,
+        })));
     }
 
-      // Update contributor's capsule list by removing the second capsule
-      if let Some(contributor) = contributors.iter_mut().find(|c| c.id == capsules[idx1].contributor_id) {
-        if let Some(capsule_ids) = &mut contributor.capsule_ids {
-            capsule_ids.retain(|&id| id != capsules[idx2].id);
+    // Keep the old capsules for the record before any modification
+    let old_capsule1 = capsule1.into();
+    let old_capsule2 = capsule2.into();
+
+    // Remove the second capsule and transfer all of its items to the first capsule, written
+    // together with the merge record
+    let (updated_capsule, moved_item_ids) = database::atomically(|| {
+        let capsule2 = CAPSULES.remove(id2).unwrap();
+        let moved_item_ids = INDEXES.write().unwrap().drop_capsule(capsule2.contributor_id, id2);
+        for item_id in moved_item_ids.iter().copied() {
+            ITEMS.update(item_id, |item| {
+                item.id_capsule = id1;  // Update the capsule ID of the item
+                item.provenance.push(ProvenanceStep { time: time_now, capsule_id: id1, origin: Origin::Merge { from_capsule: id2 } });
+            });
+        }
+        {
+            let mut indexes = INDEXES.write().unwrap();
+            for item_id in moved_item_ids.iter().copied() {
+                indexes.link_item(id1, item_id);
+            }
         }
-    }
-
 
-    // Remove the second capsule
-    capsules.remove(idx2);
+        // Both lists changed with the index, the first capsule's items and the contributor's capsules
+        let capsule1 = CAPSULES.update(id1, |capsule1| capsule1.clone()).unwrap();
+        CONTRIBUTORS.touch(capsule1.contributor_id);
+
+        // Create updated capsule details to return
+        let updated_capsule = CapsuleDetails {
+            id: capsule1.id,
+            contributor_id: capsule1.contributor_id,
+            time_created: capsule1.time_created,
+            time_changed: time_now,
+            description: format!("Updated by merging with Capsule {}", id2).into(),
+            name: capsule1.name.clone(),
+            item_ids: Some(INDEXES.read().unwrap().items_of(id1)),
+        };
+
+        // Store the merge record
+        let merge_record = MergeRecord {
+            old_capsule1,
+            old_capsule2,
+            new_merged_capsule: updated_capsule.clone(),
+        };
+        MERGE_RECORDS.write().unwrap().push(merge_record);
+        state::changed("merges", id2.number());
+        (updated_capsule, moved_item_ids)
+    });
+    bus::publish(DomainEvent::CapsuleMerged { capsule_id: id1, removed_capsule_id: id2, moved_item_ids }, time_now);
+
+    drop(capsule_guards);
+    drop(contributor_guard);
+    locks::CAPSULE_LOCKS.forget(id2);
+    field_history::forget(id2);
+    reads::forget(id2);
+
+    Ok(Either::Left(Json(updated_capsule)))
+}
 
-    // Create updated capsule details to return
-    let updated_capsule = CapsuleDetails {
-        id: capsules[idx1].id,
-        contributor_id: capsules[idx1].contributor_id,
-        time_created: capsules[idx1].time_created,
-        time_changed: Utc::now(),
-        description: format!("Updated by merging with Capsule {}", capsules[idx2].id),
-        name: capsules[idx1].name.clone(),
-        item_ids: capsules[idx1].item_ids.clone(),
-    };
 
-    // Store the merge record
-    let merge_record = MergeRecord {
-        old_capsule1: old_capsule1,
-        old_capsule2: old_capsule2,
-        new_merged_capsule: updated_capsule.clone(),
-    };
-    MERGE_RECORDS.lock().unwrap().push(merge_record);
+// The records as state entries by the id of the removed capsule, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    MERGE_RECORDS.read().unwrap().iter().map(|record| (record.old_capsule2.id.number().to_string(), state::to_entry(record))).collect()
+}
 
-    Ok(Json(updated_capsule))
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    MERGE_RECORDS.read().unwrap().iter().find(|record| record.old_capsule2.id.number().to_string() == key).map(state::to_entry)
 }
 
+// Records stay in the order of their merges
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let record: Option<MergeRecord> = entry.map(state::from_entry).transpose()?;
+    let mut records = MERGE_RECORDS.write().unwrap();
+    records.retain(|old| old.old_capsule2.id.number().to_string() != key);
+    if let Some(record) = record {
+        let at = records.partition_point(|old| old.new_merged_capsule.time_changed <= record.new_merged_capsule.time_changed);
+        records.insert(at, record);
+    }
+    Ok(())
+}
 
 #[get("/merges")]
-pub fn get_merge_records() -> Json<Vec<MergeRecord>> {
-    let merge_records = MERGE_RECORDS.lock().unwrap();
-    Json(merge_records.clone())
+pub fn get_merge_records() -> JsonStream<impl Stream<Item = String>> {
+    let count = MERGE_RECORDS.read().unwrap().len();
+    streaming::json_array((0..count).collect(), |index| MERGE_RECORDS.read().unwrap().get(index).cloned())
 }
@@ -0,0 +1,160 @@
+// Letters to the future. A message is an item whose content is text written straight into
+// the request instead of a file somewhere, stored with the item. Markdown messages are
+// kept as written and can be read back rendered as HTML; the HTML is cleaned on the
+// server, so scripts and event handlers in the text never reach a reader.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::{ContentType, Status};
+use rocket::{Either, State};
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::cold_storage::{self, Restoring};
+use crate::content_policy;
+use crate::error_messages::ApiError;
+use crate::flags;
+use crate::imports;
+use crate::indexes::INDEXES;
+use crate::items::{self, Item, NewItem, Origin, ITEMS};
+use crate::owner_only;
+use crate::payload::Payload;
+use crate::quotas::{self, WithLimits};
+use crate::reveals;
+use crate::ids::{CapsuleId, ItemId};
+use crate::tokens::Caller;
+
+const TYPE: &str = "message";
+
+// Characters of the first line used as the description of a message without a title
+const TITLE_CHARS: usize = 80;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl MessageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            MessageFormat::Plain => "txt",
+            MessageFormat::Markdown => "md",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            MessageFormat::Plain => ContentType::Plain,
+            MessageFormat::Markdown => ContentType::new("text", "markdown").with_params(("charset", "utf-8")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Message {
+    pub text: String,
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewMessage {
+    pub title: Option<String>,  // The item's description, the start of the first line without one
+    pub text: String,
+    #[serde(default)]
+    pub format: MessageFormat,
+    #[serde(default)]
+    pub owner_only: bool,
+}
+
+fn title_of(text: &str) -> String {
+    let line = text.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+    let line = line.trim_start_matches('#').trim();
+    match line.char_indices().nth(TITLE_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+// The message as sanitized HTML. Plain text is escaped, blank lines separate paragraphs.
+fn render_html(message: &Message) -> String {
+    let html = match message.format {
+        MessageFormat::Markdown => {
+            let mut html = String::new();
+            let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+            html::push_html(&mut html, Parser::new_ext(&message.text, options));
+            html
+        },
+        MessageFormat::Plain => message.text.split("\n\n")
+            .filter(|paragraph| !paragraph.trim().is_empty())
+            .map(|paragraph| format!("<p>{}</p>\n", ammonia::clean_text(paragraph.trim()).replace("&#10;", "<br>")))
+            .collect(),
+    };
+    ammonia::clean(&html)
+}
+
+#[post("/capsules/<cid>/messages", format = "json", data = "<message_data>")]
+pub fn add_message(cid: CapsuleId, message_data: Payload<NewMessage>, caller: Caller, clock: &State<SharedClock>) -> Result<WithLimits<Json<Item>>, ApiError> {
+    if !flags::current().uploads_enabled {
+        return Err(flags::disabled("Uploading items").into());
+    }
+    let message_data = message_data.into_inner();
+    if message_data.text.trim().is_empty() {
+        return Err(ApiError::EmptyMessage);
+    }
+    content_policy::check_message(&message_data.text)?;
+    if message_data.owner_only {
+        if let Some(result) = CAPSULES.read(cid, |capsule| owner_only::check_can_mark(capsule, &caller, clock.now())) {
+            result?;
+        }
+    }
+
+    let description = message_data.title.filter(|title| !title.trim().is_empty())
+        .unwrap_or_else(|| title_of(&message_data.text));
+    let new_item = NewItem {
+        type_c: TYPE.to_string(),
+        description,
+        size: imports::format_size(message_data.text.len() as u64),
+        path: String::new(),
+        metadata: serde_json::json!({}),
+        owner_only: message_data.owner_only,
+        message: Some(Message { text: message_data.text, format: message_data.format }),
+    };
+    let item = items::create_item(cid, &new_item, Origin::Upload, clock.as_ref())?;
+    Ok(WithLimits(Json(item), quotas::limits(cid)))
+}
+
+// A message's text with its content type
+type MessageBody = (ContentType, String);
+
+// The text of a message item, as written or with `render=html` as sanitized HTML. Hidden
+// and cold items are answered like `GET /capsules/<cid>/items/<iid>`.
+#[get("/capsules/<cid>/items/<iid>/message?<render>")]
+pub fn get_message(cid: CapsuleId, iid: ItemId, render: Option<&str>, caller: Caller, clock: &State<SharedClock>) -> Result<Either<MessageBody, Restoring>, ApiError> {
+    let html = match render {
+        None => false,
+        Some("html") => true,
+        Some(other) => return Err(ApiError::Other(Status::BadRequest, format!("Unknown render '{}', use html", other))),
+    };
+    let now = clock.now();
+    let in_capsule = INDEXES.read().unwrap().has_item(cid, iid);
+    let item = ITEMS.get(iid)
+        .filter(|item| in_capsule && item.type_c == TYPE)
+        .filter(|_| !reveals::is_hidden(cid, iid) && !owner_only::is_hidden(cid, iid, &caller, now))
+        .ok_or(ApiError::MessageNotInCapsule)?;
+    if let Some(restoring) = cold_storage::request_restore(&item, now) {
+        return Ok(Either::Right(restoring));
+    }
+    let message = item.message
+        .ok_or(ApiError::MessageNotInCapsule)?;
+
+    if html {
+        Ok(Either::Left((ContentType::HTML, render_html(&message))))
+    } else {
+        Ok(Either::Left((message.format.content_type(), message.text)))
+    }
+}
@@ -0,0 +1,130 @@
+// Inverted index of item metadata behind `GET /items?meta.<key>=<value>`. Every scalar in
+// an item's metadata is indexed under its dotted path (`exif.camera`), array elements under
+// the path of their array. Values are compared as trimmed lowercase text, so `meta.year=2020`
+// finds the number 2020 and `meta.camera=iphone` finds "iPhone". The ItemStore updates the
+// index on every item write, spilled items in lazy mode included.
+use rocket::serde::json::Json;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ids::ItemId;
+
+const PREFIX: &str = "meta.";
+// Longer values, usually free text, aren't worth an index entry
+const MAX_VALUE_LEN: usize = 200;
+
+#[derive(Default)]
+pub struct MetadataIndex {
+    postings: HashMap<String, HashMap<String, BTreeSet<ItemId>>>,  // Path -> value -> items
+    entries: HashMap<ItemId, Vec<(String, String)>>,               // What each item is indexed under
+}
+
+fn normalize(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+fn collect(path: &str, value: &Value, entries: &mut Vec<(String, String)>) {
+    let text = match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                collect(&path, value, entries);
+            }
+            return;
+        },
+        Value::Array(values) => {
+            for value in values {
+                collect(path, value, entries);
+            }
+            return;
+        },
+        Value::Null => return,
+        Value::String(text) => normalize(text),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Number(number) => number.to_string(),
+    };
+    if !path.is_empty() && text.len() <= MAX_VALUE_LEN {
+        entries.push((path.to_string(), text));
+    }
+}
+
+impl MetadataIndex {
+    // (Re)indexes an item under its current metadata
+    pub fn index(&mut self, item_id: ItemId, metadata: &Value) {
+        self.unindex(item_id);
+        let mut entries = Vec::new();
+        collect("", metadata, &mut entries);
+        entries.sort();
+        entries.dedup();
+        if entries.is_empty() {
+            return;
+        }
+        for (path, value) in &entries {
+            self.postings.entry(path.clone()).or_default().entry(value.clone()).or_default().insert(item_id);
+        }
+        self.entries.insert(item_id, entries);
+    }
+
+    pub fn unindex(&mut self, item_id: ItemId) {
+        let Some(entries) = self.entries.remove(&item_id) else { return };
+        for (path, value) in entries {
+            let Some(values) = self.postings.get_mut(&path) else { continue };
+            if let Some(ids) = values.get_mut(&value) {
+                ids.remove(&item_id);
+                if ids.is_empty() {
+                    values.remove(&value);
+                }
+            }
+            if values.is_empty() {
+                self.postings.remove(&path);
+            }
+        }
+    }
+
+    // Items having every one of the (path, value) pairs, rarest pair first
+    pub fn find(&self, conditions: &[(String, String)]) -> BTreeSet<ItemId> {
+        let mut sets: Vec<&BTreeSet<ItemId>> = Vec::new();
+        for (path, value) in conditions {
+            match self.postings.get(path).and_then(|values| values.get(value)) {
+                Some(ids) => sets.push(ids),
+                None => return BTreeSet::new(),
+            }
+        }
+        sets.sort_by_key(|ids| ids.len());
+        let Some((first, rest)) = sets.split_first() else { return BTreeSet::new() };
+        first.iter().copied().filter(|id| rest.iter().all(|ids| ids.contains(id))).collect()
+    }
+}
+
+// The `meta.<key>=<value>` parameters of a request, normalized like the index
+pub struct MetaFilter(Vec<(String, String)>);
+
+impl MetaFilter {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn conditions(&self) -> Result<&[(String, String)], status::Custom<Json<String>>> {
+        if self.0.iter().any(|(path, _)| path.is_empty() || path.split('.').any(str::is_empty)) {
+            return Err(status::Custom(Status::BadRequest, Json("Metadata filters need a key, as in meta.camera=iPhone".into())));
+        }
+        Ok(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetaFilter {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let conditions = request.uri().query()
+            .map(|query| query.segments()
+                .filter_map(|(name, value)| name.strip_prefix(PREFIX).map(|path| (path.to_string(), normalize(value))))
+                .collect())
+            .unwrap_or_default();
+        request::Outcome::Success(MetaFilter(conditions))
+    }
+}
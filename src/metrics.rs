@@ -0,0 +1,70 @@
+// Request counts per contributor and day, kept in memory. Requests are attributed by
+// their path: `/contributors/<id>/...` to that contributor, `/capsules/<cid>/...` to
+// the capsule's owner; anything else isn't metered.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::ids::{CapsuleId, ContributorId};
+
+#[derive(Clone, Copy, Default)]
+pub struct RequestCounts {
+    pub requests: u64,
+    pub failed: u64,  // Answered with a 4xx or 5xx status
+}
+
+pub static REQUEST_COUNTS: Lazy<RwLock<HashMap<ContributorId, BTreeMap<NaiveDate, RequestCounts>>>> = Lazy::new(|| {
+    RwLock::new(HashMap::new())
+});
+
+// Contributor a request is metered against, resolved before the request runs so a
+// capsule that gets deleted is still attributed to its owner
+#[derive(Clone, Copy)]
+struct MeteredContributor(Option<ContributorId>);
+
+fn contributor_of(request: &Request<'_>) -> Option<ContributorId> {
+    let mut segments = request.uri().path().segments();
+    let collection = segments.next()?;
+    let id = segments.next()?;
+    match collection {
+        "contributors" => id.parse().ok(),
+        "capsules" => CAPSULES.read(id.parse::<CapsuleId>().ok()?, |capsule| capsule.contributor_id),
+        _ => None,
+    }
+}
+
+// Request counts of a contributor, by day
+pub fn daily_counts(contributor_id: ContributorId) -> BTreeMap<NaiveDate, RequestCounts> {
+    REQUEST_COUNTS.read().unwrap().get(&contributor_id).cloned().unwrap_or_default()
+}
+
+pub struct Metrics;
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info { name: "Request metering", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let contributor_id = contributor_of(request);
+        request.local_cache(|| MeteredContributor(contributor_id));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let MeteredContributor(Some(contributor_id)) = *request.local_cache(|| MeteredContributor(None)) else { return };
+        let now = request.rocket().state::<SharedClock>().map_or_else(Utc::now, |clock| clock.now());
+
+        let mut counts = REQUEST_COUNTS.write().unwrap();
+        let day = counts.entry(contributor_id).or_default().entry(now.date_naive()).or_default();
+        day.requests += 1;
+        if response.status().code >= 400 {
+            day.failed += 1;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// Abuse reports on public capsules and the moderation queue behind them. Anyone can
+// report a capsule listed in the public feed; moderators work through the open reports
+// and either dismiss them or take the capsule out of public view:
+//   unlist  the capsule leaves the feed, share links keep working
+//   hide    the capsule leaves the feed and its share links stop working
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+
+use crate::bus::{self, DomainEvent};
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::error_messages::ApiError;
+use crate::ids::CapsuleId;
+use crate::locks;
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::publishing::Visibility;
+use crate::state;
+use crate::time_format;
+
+const MAX_REASON_LEN: usize = 2000;
+const MAX_CONTACT_LEN: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ModerationState {
+    Unlisted,
+    Hidden,
+}
+
+// Set on a capsule taken out of public view
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Moderation {
+    pub state: ModerationState,
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub report_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ReportStatus {
+    Open,
+    Actioned,   // The capsule was unlisted or hidden
+    Dismissed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AbuseReport {
+    pub id: u32,
+    pub capsule_id: CapsuleId,
+    pub reason: String,
+    pub contact: Option<String>,  // How to reach the reporter, only shown to moderators
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,
+    pub status: ReportStatus,
+    pub action: Option<ModerationState>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_resolved: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewReport {
+    reason: String,
+    contact: Option<String>,
+}
+
+// What the reporter gets back
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReportReceipt {
+    pub id: u32,
+    pub capsule_id: CapsuleId,
+    pub status: ReportStatus,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ModerationAction {
+    Dismiss,
+    Unlist,
+    Hide,
+}
+
+impl ModerationAction {
+    fn name(self) -> &'static str {
+        match self {
+            ModerationAction::Dismiss => "dismiss",
+            ModerationAction::Unlist => "unlist",
+            ModerationAction::Hide => "hide",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Resolution {
+    action: ModerationAction,
+}
+
+struct ReportStore {
+    reports: Vec<AbuseReport>,
+    next_id: u32,
+}
+
+static REPORTS: Lazy<RwLock<ReportStore>> = Lazy::new(|| RwLock::new(ReportStore { reports: Vec::new(), next_id: 1 }));
+
+// Whether the capsule is in the public feed
+pub fn is_listed(capsule: &Capsule) -> bool {
+    capsule.publishing.visibility == Visibility::Public && capsule.moderation.is_none()
+}
+
+pub fn is_hidden(capsule: &Capsule) -> bool {
+    capsule.moderation.as_ref().is_some_and(|moderation| moderation.state == ModerationState::Hidden)
+}
+
+#[post("/public/capsules/<cid>/report", format = "json", data = "<report>")]
+pub fn report_capsule(cid: CapsuleId, report: Json<NewReport>, clock: &State<SharedClock>) -> Result<status::Created<Json<ReportReceipt>>, status::Custom<Json<String>>> {
+    let report = report.into_inner();
+    let reason = report.reason.trim().to_string();
+    let contact = report.contact.map(|contact| contact.trim().to_string()).filter(|contact| !contact.is_empty());
+    if reason.is_empty() {
+        return Err(status::Custom(Status::BadRequest, Json("reason is required".into())));
+    }
+    if reason.chars().count() > MAX_REASON_LEN || contact.as_ref().is_some_and(|contact| contact.chars().count() > MAX_CONTACT_LEN) {
+        return Err(status::Custom(Status::BadRequest, Json(format!("reason is limited to {} and contact to {} characters", MAX_REASON_LEN, MAX_CONTACT_LEN))));
+    }
+    // Private capsules answer like missing ones, so reports can't be used to probe for them
+    if CAPSULES.read(cid, is_listed) != Some(true) {
+        return Err(status::Custom(Status::NotFound, Json(format!("No public capsule found with ID {}", cid))));
+    }
+
+    let mut store = REPORTS.write().unwrap();
+    let id = state::next_id(&mut store.next_id);
+    store.reports.push(AbuseReport {
+        id,
+        capsule_id: cid,
+        reason,
+        contact,
+        time: clock.now(),
+        status: ReportStatus::Open,
+        action: None,
+        time_resolved: None,
+    });
+    state::changed("reports", id);
+    Ok(status::Created::new(format!("/admin/reports/{}", id)).body(Json(ReportReceipt { id, capsule_id: cid, status: ReportStatus::Open })))
+}
+
+// The moderation queue, oldest open reports first by default
+#[get("/admin/reports?<status>&<pagination..>")]
+pub fn list_reports(status: Option<&str>, pagination: Pagination) -> Result<Paginated<AbuseReport>, status::Custom<Json<String>>> {
+    let status = match status.unwrap_or("open") {
+        "open" => Some(ReportStatus::Open),
+        "actioned" => Some(ReportStatus::Actioned),
+        "dismissed" => Some(ReportStatus::Dismissed),
+        "all" => None,
+        other => return Err(status::Custom(Status::BadRequest, Json(format!("Unknown status '{}', use open, actioned, dismissed or all", other)))),
+    };
+    let reports: Vec<AbuseReport> = REPORTS.read().unwrap().reports.iter()
+        .filter(|report| status.is_none_or(|status| report.status == status))
+        .cloned()
+        .collect();
+    Ok(Paginated::new(&pagination, Collection::Reports, reports.len(), |start, per_page| reports.into_iter().skip(start).take(per_page).collect()))
+}
+
+// Dismisses a report, or unlists or hides its capsule, which resolves the capsule's
+// other open reports along with it
+#[post("/admin/reports/<id>/resolve", format = "json", data = "<resolution>")]
+pub fn resolve_report(id: u32, resolution: Json<Resolution>, clock: &State<SharedClock>) -> Result<Json<AbuseReport>, status::Custom<Json<String>>> {
+    let now = clock.now();
+    let not_found = || status::Custom(Status::NotFound, Json(format!("No report found with ID {}", id)));
+    let capsule_id = REPORTS.read().unwrap().reports.iter().find(|report| report.id == id).ok_or_else(not_found)?.capsule_id;
+    let state = match resolution.action {
+        ModerationAction::Dismiss => None,
+        ModerationAction::Unlist => Some(ModerationState::Unlisted),
+        ModerationAction::Hide => Some(ModerationState::Hidden),
+    };
+
+    let _guard = locks::lock_capsule(capsule_id);
+    if let Some(state) = state {
+        let moderated = CAPSULES.update(capsule_id, |capsule| {
+            capsule.moderation = Some(Moderation { state, time: now, report_id: id });
+        });
+        if moderated.is_none() {
+            return Err(status::Custom(Status::NotFound, Json(format!("Capsule {} no longer exists, dismiss the report instead", capsule_id))));
+        }
+        bus::publish(DomainEvent::CapsuleModerated { capsule_id, action: resolution.action.name() }, now);
+    }
+
+    let mut store = REPORTS.write().unwrap();
+    for report in store.reports.iter_mut() {
+        let resolves = report.id == id || (state.is_some() && report.capsule_id == capsule_id && report.status == ReportStatus::Open);
+        if resolves {
+            report.status = if state.is_some() { ReportStatus::Actioned } else { ReportStatus::Dismissed };
+            report.action = state;
+            report.time_resolved = Some(now);
+            state::changed("reports", report.id);
+        }
+    }
+    store.reports.iter().find(|report| report.id == id).cloned().map(Json).ok_or_else(not_found)
+}
+
+// Puts an unlisted or hidden capsule back in public view
+#[delete("/admin/capsules/<cid>/moderation")]
+pub fn restore_capsule(cid: CapsuleId, clock: &State<SharedClock>) -> Result<Status, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    CAPSULES.try_update(cid, |capsule| match capsule.moderation.take() {
+        Some(_) => Ok(()),
+        None => Err(ApiError::Other(Status::NotFound, format!("Capsule {} isn't unlisted or hidden", cid))),
+    }).ok_or(ApiError::CapsuleNotFound(cid))??;
+    bus::publish(DomainEvent::CapsuleModerated { capsule_id: cid, action: "restore" }, clock.now());
+    Ok(Status::NoContent)
+}
+
+// The reports as state entries by id, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    REPORTS.read().unwrap().reports.iter().map(|report| (report.id.to_string(), state::to_entry(report))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    REPORTS.read().unwrap().reports.iter().find(|report| report.id.to_string() == key).map(state::to_entry)
+}
+
+// Reports stay in the order of their ids, the queue's order
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let report: Option<AbuseReport> = entry.map(state::from_entry).transpose()?;
+    let mut store = REPORTS.write().unwrap();
+    store.reports.retain(|old| old.id.to_string() != key);
+    if let Some(report) = report {
+        store.next_id = store.next_id.max(report.id + 1);
+        let at = store.reports.partition_point(|old| old.id < report.id);
+        store.reports.insert(at, report);
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+// Outgoing email, sent over SMTP as configured in `[default.mail]`
+use lettre::message::header::ContentType;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::config;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,  // Plain text
+}
+
+pub struct SendError {
+    pub message: String,
+    pub permanent: bool,  // Retrying won't help, e.g. the server rejected the recipient
+}
+
+impl SendError {
+    fn temporary(message: String) -> Self {
+        SendError { message, permanent: false }
+    }
+}
+
+pub async fn send_email(email: Email) -> Result<(), SendError> {
+    let settings = &config::get().mail;
+    let smtp_url = settings.smtp_url.as_deref()
+        .ok_or_else(|| SendError::temporary("mail.smtp_url is not configured".into()))?;
+
+    let message = Message::builder()
+        .from(settings.from.parse().map_err(|e| SendError::temporary(format!("Invalid sender address: {}", e)))?)
+        .to(email.to.parse().map_err(|e| SendError { message: format!("Invalid recipient address: {}", e), permanent: true })?)
+        .subject(email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body)
+        .map_err(|e| SendError::temporary(e.to_string()))?;
+
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)
+        .map_err(|e| SendError::temporary(format!("Invalid mail.smtp_url: {}", e)))?
+        .build();
+    transport.send(message).await
+        .map(|_| ())
+        .map_err(|e| SendError { permanent: e.is_permanent(), message: e.to_string() })
+}
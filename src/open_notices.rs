@@ -0,0 +1,175 @@
+// Telling the owner and co-owners that their capsule has opened. When the scheduler sees a
+// capsule open it publishes `capsule.opened` and notifies each of them through the channels
+// they picked in `open_channels`: an email, their webhooks or the live event stream. External
+// recipients are emailed by letters.rs as before. Every notice is kept on the capsule with
+// its status; emails are tried again on the next ticks, webhooks retry on their own.
+use rocket::serde::{Deserialize, Serialize};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::bus::{self, DomainEvent, Published};
+use crate::capsules::CAPSULES;
+use crate::clock::Clock;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::letters::{self, DeliveryStatus};
+use crate::notifications::{self, Email};
+use crate::reveals;
+use crate::time_format;
+use crate::webhooks;
+
+// Attempts per email before a temporary failure is given up, as for letters
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Channel {
+    Email,
+    Webhook,  // The contributor's webhooks that take `capsule.opened`
+    Sse,      // The event on /events
+}
+
+// Every channel, for contributors who haven't picked any
+pub fn default_channels() -> Vec<Channel> {
+    vec![Channel::Email, Channel::Webhook, Channel::Sse]
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenNotice {
+    pub contributor_id: ContributorId,
+    pub channel: Channel,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_sent: Option<DateTime<Utc>>,
+    pub error: Option<String>,  // Last failure
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenNotices {
+    #[serde(serialize_with = "time_format::time")]
+    pub time: DateTime<Utc>,  // When the opening was announced
+    pub seq: u64,             // Of the `capsule.opened` event
+    pub notices: Vec<OpenNotice>,
+}
+
+// Capsules whose webhooks are being sent to, so a tick doesn't start them again
+static SENDING: Lazy<Mutex<HashSet<(CapsuleId, ContributorId)>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+fn record(capsule_id: CapsuleId, contributor_id: ContributorId, channel: Channel, attempts: u32, error: Option<String>, permanent: bool, now: DateTime<Utc>) {
+    CAPSULES.update(capsule_id, |capsule| {
+        let Some(opening) = &mut capsule.opening else { return };
+        let Some(notice) = opening.notices.iter_mut().find(|notice| notice.contributor_id == contributor_id && notice.channel == channel) else { return };
+        notice.attempts = attempts;
+        match error {
+            None => {
+                notice.status = DeliveryStatus::Sent;
+                notice.time_sent = Some(now);
+                notice.error = None;
+            },
+            Some(error) => {
+                eprintln!("Notifying contributor {} of capsule {} opening by {:?} failed: {}", contributor_id, capsule_id, channel, error);
+                notice.error = Some(error);
+                if permanent || channel == Channel::Webhook || attempts >= MAX_ATTEMPTS {
+                    notice.status = DeliveryStatus::Failed;
+                }
+            },
+        }
+    });
+}
+
+// Publishes the opening and keeps a pending notice for every channel of the owner and co-owners
+fn announce(capsule_id: CapsuleId, now: DateTime<Utc>) {
+    let Some(capsule) = CAPSULES.get(capsule_id) else { return };
+    let mut notices = Vec::new();
+    for contributor_id in std::iter::once(capsule.contributor_id).chain(capsule.co_owner_ids.iter().copied()) {
+        let channels = CONTRIBUTORS.read(contributor_id, |contributor| contributor.open_channels.clone()).unwrap_or_default();
+        for channel in channels {
+            // Only contributors with a webhook for it get a webhook notice
+            if channel == Channel::Webhook && !webhooks::takes_opened(&capsule, contributor_id) {
+                continue;
+            }
+            notices.push(OpenNotice { contributor_id, channel, status: DeliveryStatus::Pending, attempts: 0, time_sent: None, error: None });
+        }
+    }
+    let published = bus::publish(DomainEvent::CapsuleOpened { capsule_id }, now);
+    // The event is all there is to the live stream
+    for notice in notices.iter_mut().filter(|notice| notice.channel == Channel::Sse) {
+        notice.status = DeliveryStatus::Sent;
+        notice.attempts = 1;
+        notice.time_sent = Some(now);
+    }
+    CAPSULES.update(capsule_id, |capsule| {
+        capsule.opening.get_or_insert(OpenNotices { time: now, seq: published.seq, notices });
+    });
+}
+
+async fn send_pending(capsule_id: CapsuleId, now: DateTime<Utc>) {
+    let Some(capsule) = CAPSULES.get(capsule_id) else { return };
+    let Some(opening) = &capsule.opening else { return };
+    let pending: Vec<&OpenNotice> = opening.notices.iter().filter(|notice| notice.status == DeliveryStatus::Pending).collect();
+    let published = Published { seq: opening.seq, time: opening.time, event: DomainEvent::CapsuleOpened { capsule_id } };
+
+    for notice in pending.iter().filter(|notice| notice.channel == Channel::Webhook) {
+        let contributor_id = notice.contributor_id;
+        if !SENDING.lock().unwrap().insert((capsule_id, contributor_id)) {
+            continue;
+        }
+        let published = published.clone();
+        rocket::tokio::spawn(async move {
+            let (attempts, error) = webhooks::deliver_opened(contributor_id, published).await;
+            record(capsule_id, contributor_id, Channel::Webhook, attempts, error, false, Utc::now());
+            SENDING.lock().unwrap().remove(&(capsule_id, contributor_id));
+        });
+    }
+
+    let emails: Vec<&OpenNotice> = pending.into_iter().filter(|notice| notice.channel == Channel::Email).collect();
+    if emails.is_empty() {
+        return;
+    }
+    let item_ids = reveals::visible(capsule_id, INDEXES.read().unwrap().items_of(capsule_id));
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+    let (subject, body) = letters::render_email(&capsule, &items, None);
+    for notice in emails {
+        let Some((to, name)) = CONTRIBUTORS.read(notice.contributor_id, |contributor| (contributor.email.clone(), contributor.name.clone())) else {
+            record(capsule_id, notice.contributor_id, Channel::Email, notice.attempts + 1, Some("The contributor was deleted".into()), true, now);
+            continue;
+        };
+        let body = letters::greet(Some(&name), &body);
+        let result = notifications::send_email(Email { to, subject: subject.clone(), body }).await;
+        let (error, permanent) = match result {
+            Ok(()) => (None, false),
+            Err(e) => (Some(e.message), e.permanent),
+        };
+        record(capsule_id, notice.contributor_id, Channel::Email, notice.attempts + 1, error, permanent, now);
+    }
+}
+
+// Announces the capsules that opened since the last tick and sends the notices still
+// waiting. Capsules that opened longer than `open_notices.max_age_hours` ago when they're
+// first seen, e.g. while the server was down, aren't announced anymore.
+pub async fn notify_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let oldest = now - Duration::hours(config::get().open_notices.max_age_hours as i64);
+    let mut opened = Vec::new();
+    let mut waiting = Vec::new();
+    CAPSULES.for_each(|capsule| match &capsule.opening {
+        None if capsule.time_open <= now && capsule.time_open > oldest => opened.push(capsule.id),
+        Some(opening) if opening.notices.iter().any(|notice| notice.status == DeliveryStatus::Pending) => waiting.push(capsule.id),
+        _ => {},
+    });
+
+    for &capsule_id in &opened {
+        announce(capsule_id, now);
+    }
+    for capsule_id in opened.into_iter().chain(waiting) {
+        send_pending(capsule_id, now).await;
+    }
+}
@@ -0,0 +1,138 @@
+// Items that outlive their capsule. Deleting a capsule with `?items=detach` keeps its
+// items: they move to the unsorted capsule when `deletion.unsorted_capsule_id` is set,
+// otherwise they stay behind as orphans, still pointing at the deleted capsule, until
+// they are attached to another one.
+use rocket::serde::{json::Json, Deserialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::config::{self, ItemsOnDelete};
+use crate::database;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::{Item, Origin, ProvenanceStep, ITEMS};
+use crate::locks;
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::quotas;
+use crate::residency;
+use crate::signatures;
+use crate::ids::{CapsuleId, EntityId, ItemId};
+
+// The `?items=` of a capsule deletion, `deletion.items` when it's missing
+pub fn parse(value: Option<&str>) -> Result<ItemsOnDelete, status::Custom<Json<String>>> {
+    match value {
+        None => Ok(config::get().deletion.items),
+        Some("delete") => Ok(ItemsOnDelete::Delete),
+        Some("detach") => Ok(ItemsOnDelete::Detach),
+        Some(_) => Err(status::Custom(Status::BadRequest, Json("items must be delete or detach".into()))),
+    }
+}
+
+// Where the items of a deleted capsule go, None leaves them as orphans
+pub fn unsorted_capsule(deleted_capsule_id: CapsuleId) -> Option<CapsuleId> {
+    config::get().deletion.unsorted_capsule_id
+        .map(CapsuleId::from_number)
+        .filter(|&id| id != deleted_capsule_id && CAPSULES.contains(id))
+}
+
+pub fn is_orphan(item: &Item) -> bool {
+    !CAPSULES.contains(item.id_capsule)
+}
+
+// Attaches an item to a capsule it wasn't in, unless it has left `from_capsule` meanwhile.
+// The caller holds the lock of `to_capsule`.
+fn move_item(item_id: ItemId, from_capsule: CapsuleId, to_capsule: CapsuleId, now: DateTime<Utc>) -> bool {
+    database::atomically(|| {
+        let moved = ITEMS.update(item_id, |item| {
+            if item.id_capsule != from_capsule {
+                return false;
+            }
+            item.id_capsule = to_capsule;
+            item.provenance.push(ProvenanceStep { time: now, capsule_id: to_capsule, origin: Origin::Move { from_capsule } });
+            true
+        });
+        if moved != Some(true) {
+            return false;
+        }
+        {
+            let mut indexes = INDEXES.write().unwrap();
+            indexes.unlink_item(from_capsule, item_id);
+            indexes.link_item(to_capsule, item_id);
+        }
+        CAPSULES.update(to_capsule, |capsule| capsule.time_changed = Some(now));
+        true
+    })
+}
+
+// Keeps the items of a deleted capsule, moving them to the unsorted capsule if there is
+// one. Items whose file is kept in another residency region stay orphans. The caller
+// holds the locks of both capsules.
+pub fn detach(from_capsule: CapsuleId, item_ids: Vec<ItemId>, unsorted_capsule_id: Option<CapsuleId>, now: DateTime<Utc>) {
+    // Checked again under the lock, the unsorted capsule may have been deleted meanwhile
+    let Some(to_capsule) = unsorted_capsule_id.filter(|&id| CAPSULES.contains(id)) else { return };
+    for item_id in item_ids {
+        if ITEMS.get(item_id).is_some_and(|item| residency::check_file(&item.path, to_capsule).is_ok()) {
+            move_item(item_id, from_capsule, to_capsule, now);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AttachRequest {
+    capsule_id: CapsuleId,
+}
+
+// Items whose capsule was deleted, waiting to be attached again
+#[get("/items/orphans?<pagination..>")]
+pub fn orphaned_items(pagination: Pagination) -> Paginated<Item> {
+    let items: Vec<Item> = ITEMS.ids().into_iter()
+        .filter_map(|id| ITEMS.get(id))
+        .filter(is_orphan)
+        .collect();
+    Paginated::new(&pagination, Collection::Items, items.len(), |start, per_page| items.into_iter().skip(start).take(per_page).collect())
+}
+
+// Puts an orphaned item into a capsule, under the same rules as adding a new one
+#[post("/items/<item_id>/attach", format = "json", data = "<request>")]
+pub fn attach_item(item_id: ItemId, request: Json<AttachRequest>, clock: &State<SharedClock>) -> Result<Json<Item>, ApiError> {
+    let cid = request.capsule_id;
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
+
+    let closed = CAPSULES.read(cid, |capsule| signatures::contributions_closed(capsule, now))
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    if closed {
+        return Err(ApiError::ContributionsEnded);
+    }
+    let item = ITEMS.get(item_id)
+        .ok_or(ApiError::ItemNotFound(item_id))?;
+    if !is_orphan(&item) {
+        return Err(ApiError::Other(Status::Conflict, format!("Item {} belongs to capsule {}", item_id, item.id_capsule)));
+    }
+    quotas::check(cid, &item.size)?;
+    residency::check_file(&item.path, cid)?;
+
+    if !move_item(item_id, item.id_capsule, cid, now) {
+        return Err(ApiError::Other(Status::Conflict, format!("Item {} was attached elsewhere meanwhile", item_id)));
+    }
+    ITEMS.get(item_id).map(Json)
+        .ok_or(ApiError::ItemNotFound(item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_of_deleted_capsules_are_deleted_or_detached() {
+        assert!(matches!(parse(Some("delete")), Ok(ItemsOnDelete::Delete)));
+        assert!(matches!(parse(Some("detach")), Ok(ItemsOnDelete::Detach)));
+        assert!(parse(None).is_ok_and(|items| items == config::get().deletion.items));
+        assert!(parse(Some("keep")).is_err_and(|error| error.0 == Status::BadRequest));
+    }
+}
@@ -0,0 +1,88 @@
+// Items the capsule's owner keeps to themselves until the capsule opens, such as a
+// surprise in a capsule shared with co-owners. Until then the item routes leave them out
+// for everyone else, as if they weren't there. Who is asking comes from the request's
+// API key; requests without one are treated as someone else, never as the owner.
+use rocket::serde::{json::Json, Deserialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ItemId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::locks;
+use crate::ownership;
+use crate::tokens::Caller;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OwnerOnlyUpdate {
+    pub owner_only: bool,
+}
+
+// Whether the caller is kept from the capsule's owner-only items right now
+fn kept_out(capsule: &Capsule, caller: &Caller, now: DateTime<Utc>) -> bool {
+    caller.0 != Some(capsule.contributor_id) && capsule.time_open > now
+}
+
+fn is_owner_only(item_id: ItemId) -> bool {
+    ITEMS.get(item_id).is_some_and(|item| item.owner_only)
+}
+
+// The given items of a capsule the caller may see
+pub fn visible(capsule_id: CapsuleId, item_ids: Vec<ItemId>, caller: &Caller, now: DateTime<Utc>) -> Vec<ItemId> {
+    if !CAPSULES.read(capsule_id, |capsule| kept_out(capsule, caller, now)).unwrap_or(false) {
+        return item_ids;
+    }
+    item_ids.into_iter().filter(|&id| !is_owner_only(id)).collect()
+}
+
+pub fn is_hidden(capsule_id: CapsuleId, item_id: ItemId, caller: &Caller, now: DateTime<Utc>) -> bool {
+    CAPSULES.read(capsule_id, |capsule| kept_out(capsule, caller, now)).unwrap_or(false) && is_owner_only(item_id)
+}
+
+// Owner-only items hidden from the caller, for listings across capsules
+pub fn all_hidden(caller: &Caller, now: DateTime<Utc>) -> HashSet<ItemId> {
+    let mut sealed = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if kept_out(capsule, caller, now) {
+            sealed.push(capsule.id);
+        }
+    });
+    sealed.into_iter()
+        .flat_map(|capsule_id| INDEXES.read().unwrap().items_of(capsule_id))
+        .filter(|&id| is_owner_only(id))
+        .collect()
+}
+
+// Only the owner marks items, and only before the capsule opens
+pub fn check_can_mark(capsule: &Capsule, caller: &Caller, now: DateTime<Utc>) -> Result<(), status::Custom<Json<String>>> {
+    ownership::check_owner(capsule, caller)?;
+    if capsule.time_open <= now {
+        return Err(status::Custom(Status::Conflict, Json(format!("Capsule {} has already opened, its items are shown to everyone", capsule.id))));
+    }
+    Ok(())
+}
+
+#[put("/capsules/<cid>/items/<iid>/owner-only", format = "json", data = "<update>")]
+pub fn set_owner_only(cid: CapsuleId, iid: ItemId, update: Json<OwnerOnlyUpdate>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<Item>, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
+    CAPSULES.read(cid, |capsule| check_can_mark(capsule, &caller, now))
+        .ok_or(ApiError::CapsuleNotFound(cid))??;
+    if !INDEXES.read().unwrap().has_item(cid, iid) {
+        return Err(ApiError::ItemNotInCapsule);
+    }
+
+    ITEMS.update(iid, |item| {
+        item.owner_only = update.owner_only;
+        item.clone()
+    })
+        .map(Json)
+        .ok_or(ApiError::ItemNotInCapsule)
+}
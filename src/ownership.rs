@@ -0,0 +1,221 @@
+// Co-ownership of capsules. A collaborator asks to become a co-owner, the owner approves
+// or rejects the request, and approved co-owners may make the same structural edits as
+// the owner (replacing, patching and deleting the capsule). Who is asking comes from
+// the request's API key; requests without one are refused.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::database;
+use crate::locks;
+use crate::state;
+use crate::time_format;
+use crate::tokens::Caller;
+
+const MAX_MESSAGE_LEN: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum RequestStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OwnershipRequest {
+    pub id: u32,
+    pub capsule_id: CapsuleId,
+    pub contributor_id: ContributorId,
+    pub message: Option<String>,  // Shown to the owner
+    pub status: RequestStatus,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_requested: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_decided: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewOwnershipRequest {
+    contributor_id: ContributorId,
+    message: Option<String>,
+}
+
+struct RequestStore {
+    requests: Vec<OwnershipRequest>,
+    next_id: u32,
+}
+
+static REQUESTS: Lazy<RwLock<RequestStore>> = Lazy::new(|| RwLock::new(RequestStore { requests: Vec::new(), next_id: 1 }));
+
+fn forbidden(caller: ContributorId, cid: CapsuleId, what: &str) -> status::Custom<Json<String>> {
+    status::Custom(Status::Forbidden, Json(format!("Contributor {} isn't {} of capsule {}", caller, what, cid)))
+}
+
+pub fn is_editor(capsule: &Capsule, contributor_id: ContributorId) -> bool {
+    capsule.contributor_id == contributor_id || capsule.co_owner_ids.contains(&contributor_id)
+}
+
+// Whether the caller may make structural edits to the capsule: its owner or a co-owner
+pub fn check_editor(capsule: &Capsule, caller: &Caller) -> Result<(), status::Custom<Json<String>>> {
+    let contributor_id = caller.required()?;
+    if !is_editor(capsule, contributor_id) {
+        return Err(forbidden(contributor_id, capsule.id, "an owner or co-owner"));
+    }
+    Ok(())
+}
+
+// Only the owner decides on requests and removes co-owners
+pub fn check_owner(capsule: &Capsule, caller: &Caller) -> Result<(), status::Custom<Json<String>>> {
+    let contributor_id = caller.required()?;
+    if capsule.contributor_id != contributor_id {
+        return Err(forbidden(contributor_id, capsule.id, "the owner"));
+    }
+    Ok(())
+}
+
+fn capsule_not_found(cid: CapsuleId) -> ApiError {
+    ApiError::CapsuleNotFound(cid)
+}
+
+#[post("/capsules/<cid>/ownership-requests", format = "json", data = "<request>")]
+pub fn request_ownership(cid: CapsuleId, request: Json<NewOwnershipRequest>, caller: Caller, clock: &State<SharedClock>) -> Result<status::Created<Json<OwnershipRequest>>, ApiError> {
+    let request = request.into_inner();
+    let contributor_id = request.contributor_id;
+    if caller.required()? != contributor_id {
+        return Err(ApiError::Other(Status::Forbidden, "Co-ownership can only be requested for yourself".into()));
+    }
+    let message = request.message.map(|message| message.trim().to_string()).filter(|message| !message.is_empty());
+    if message.as_ref().is_some_and(|message| message.chars().count() > MAX_MESSAGE_LEN) {
+        return Err(ApiError::Other(Status::BadRequest, format!("message is limited to {} characters", MAX_MESSAGE_LEN)));
+    }
+    if !CONTRIBUTORS.contains(contributor_id) {
+        return Err(ApiError::UnknownContributor);
+    }
+
+    let _guard = locks::lock_capsule(cid);
+    let already = CAPSULES.read(cid, |capsule| is_editor(capsule, contributor_id)).ok_or_else(|| capsule_not_found(cid))?;
+    if already {
+        return Err(ApiError::Other(Status::Conflict, format!("Contributor {} already owns capsule {}", contributor_id, cid)));
+    }
+    let mut store = REQUESTS.write().unwrap();
+    if store.requests.iter().any(|r| r.capsule_id == cid && r.contributor_id == contributor_id && r.status == RequestStatus::Pending) {
+        return Err(ApiError::Other(Status::Conflict, format!("Contributor {} already has a pending request for capsule {}", contributor_id, cid)));
+    }
+    let ownership_request = OwnershipRequest {
+        id: state::next_id(&mut store.next_id),
+        capsule_id: cid,
+        contributor_id,
+        message,
+        status: RequestStatus::Pending,
+        time_requested: clock.now(),
+        time_decided: None,
+    };
+    store.requests.push(ownership_request.clone());
+    state::changed("ownership_requests", ownership_request.id);
+    Ok(status::Created::new(format!("/capsules/{}/ownership-requests/{}", cid, ownership_request.id)).body(Json(ownership_request)))
+}
+
+// Requests for a capsule, pending ones first
+#[get("/capsules/<cid>/ownership-requests")]
+pub fn list_ownership_requests(cid: CapsuleId) -> Result<Json<Vec<OwnershipRequest>>, ApiError> {
+    if !CAPSULES.contains(cid) {
+        return Err(capsule_not_found(cid));
+    }
+    let mut requests: Vec<OwnershipRequest> = REQUESTS.read().unwrap().requests.iter()
+        .filter(|request| request.capsule_id == cid)
+        .cloned()
+        .collect();
+    requests.sort_by_key(|request| (request.status != RequestStatus::Pending, request.id));
+    Ok(Json(requests))
+}
+
+#[post("/capsules/<cid>/ownership-requests/<id>/approve")]
+pub fn approve_ownership_request(cid: CapsuleId, id: u32, caller: Caller, clock: &State<SharedClock>) -> Result<Json<OwnershipRequest>, ApiError> {
+    decide(cid, id, RequestStatus::Approved, &caller, clock.now())
+}
+
+#[post("/capsules/<cid>/ownership-requests/<id>/reject")]
+pub fn reject_ownership_request(cid: CapsuleId, id: u32, caller: Caller, clock: &State<SharedClock>) -> Result<Json<OwnershipRequest>, ApiError> {
+    decide(cid, id, RequestStatus::Rejected, &caller, clock.now())
+}
+
+fn decide(cid: CapsuleId, id: u32, decision: RequestStatus, caller: &Caller, now: DateTime<Utc>) -> Result<Json<OwnershipRequest>, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    CAPSULES.read(cid, |capsule| check_owner(capsule, caller)).ok_or_else(|| capsule_not_found(cid))??;
+
+    let mut store = REQUESTS.write().unwrap();
+    let request = store.requests.iter_mut()
+        .find(|request| request.id == id && request.capsule_id == cid)
+        .ok_or_else(|| status::Custom(Status::NotFound, Json(format!("No ownership request {} for capsule {}", id, cid))))?;
+    if request.status != RequestStatus::Pending {
+        return Err(ApiError::Other(Status::Conflict, format!("Ownership request {} was already decided", id)));
+    }
+    let contributor_id = request.contributor_id;
+    if decision == RequestStatus::Approved && !CONTRIBUTORS.contains(contributor_id) {
+        return Err(ApiError::Other(Status::Conflict, format!("Contributor {} no longer exists, reject the request instead", contributor_id)));
+    }
+    database::atomically(|| {
+        if decision == RequestStatus::Approved {
+            CAPSULES.update(cid, |capsule| {
+                if !is_editor(capsule, contributor_id) {
+                    capsule.co_owner_ids.push(contributor_id);
+                }
+            });
+        }
+        request.status = decision;
+        request.time_decided = Some(now);
+        state::changed("ownership_requests", id);
+    });
+    Ok(Json(request.clone()))
+}
+
+// The requests as state entries by id, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    REQUESTS.read().unwrap().requests.iter().map(|request| (request.id.to_string(), state::to_entry(request))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    REQUESTS.read().unwrap().requests.iter().find(|request| request.id.to_string() == key).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let request: Option<OwnershipRequest> = entry.map(state::from_entry).transpose()?;
+    let mut store = REQUESTS.write().unwrap();
+    store.requests.retain(|old| old.id.to_string() != key);
+    if let Some(request) = request {
+        store.next_id = store.next_id.max(request.id + 1);
+        let at = store.requests.partition_point(|old| old.id < request.id);
+        store.requests.insert(at, request);
+    }
+    Ok(())
+}
+
+// Takes co-ownership away again, or gives it up when a co-owner calls it for themselves
+#[delete("/capsules/<cid>/co-owners/<contributor_id>")]
+pub fn remove_co_owner(cid: CapsuleId, contributor_id: ContributorId, caller: Caller) -> Result<Status, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    let removed = CAPSULES.update(cid, |capsule| {
+        if caller.0 != Some(contributor_id) {
+            check_owner(capsule, &caller)?;
+        }
+        let before = capsule.co_owner_ids.len();
+        capsule.co_owner_ids.retain(|&id| id != contributor_id);
+        Ok::<_, ApiError>(capsule.co_owner_ids.len() < before)
+    }).ok_or_else(|| capsule_not_found(cid))??;
+    if !removed {
+        return Err(ApiError::Other(Status::NotFound, format!("Contributor {} isn't a co-owner of capsule {}", contributor_id, cid)));
+    }
+    Ok(Status::NoContent)
+}
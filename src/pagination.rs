@@ -0,0 +1,181 @@
+use rocket::serde::Serialize;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::cache;
+use crate::config::{self, PageSizeConfig};
+
+// The paginated lists, each with its own page sizes
+#[derive(Clone, Copy)]
+pub enum Collection {
+    Capsules,
+    Contributors,
+    Items,
+    Feed,
+    Audit,
+    Reports,
+}
+
+impl Collection {
+    // Default and maximum `per_page`, from the config or the built-in values
+    fn page_sizes(self) -> (usize, usize) {
+        let pagination = &config::get().pagination;
+        let (configured, default, max): (&PageSizeConfig, usize, usize) = match self {
+            Collection::Capsules => (&pagination.capsules, 10, 100),
+            Collection::Contributors => (&pagination.contributors, 10, 100),
+            Collection::Items => (&pagination.items, 25, 200),
+            Collection::Feed => (&pagination.feed, 10, 100),
+            Collection::Audit => (&pagination.audit, 10, 100),
+            Collection::Reports => (&pagination.reports, 10, 100),
+        };
+        let max = configured.max_per_page.unwrap_or(max).max(1);
+        (configured.default_per_page.unwrap_or(default).clamp(1, max), max)
+    }
+}
+
+#[derive(FromForm)]
+pub struct Pagination {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+impl Pagination {
+    // 1-based, page 0 is treated as 1
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    // Capped to the collection's maximum instead of being refused
+    pub fn per_page(&self, collection: Collection) -> usize {
+        let (default, max) = collection.page_sizes();
+        self.per_page.unwrap_or(default).clamp(1, max)
+    }
+
+    // Index of the first record on the page, out of range pages are simply empty
+    pub fn start(&self, collection: Collection) -> usize {
+        (self.page() - 1).saturating_mul(self.per_page(collection))
+    }
+}
+
+// One page of a list, sent as a JSON array with the paging details in headers:
+// X-Total-Count, X-Total-Pages, X-Page, X-Per-Page and an RFC 8288 Link header
+// with the first, prev, next and last pages. The page carries a weak ETag and is
+// answered with 304 when the client already has it.
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total_items: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl<T> Paginated<T> {
+    // Fetches the requested page with `fetch(start, per_page)`
+    pub fn new(pagination: &Pagination, collection: Collection, total_items: usize, fetch: impl FnOnce(usize, usize) -> Vec<T>) -> Self {
+        let per_page = pagination.per_page(collection);
+        Paginated {
+            items: fetch(pagination.start(collection), per_page),
+            total_items,
+            page: pagination.page(),
+            per_page,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Paginated<U> {
+        Paginated {
+            items: self.items.into_iter().map(f).collect(),
+            total_items: self.total_items,
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+}
+
+// Link header for the pages around the current one, keeping the other query parameters
+fn link_header(request: &Request<'_>, page: usize, per_page: usize, total_pages: usize) -> String {
+    let path = request.uri().path();
+    let other_params: Vec<String> = request.uri().query()
+        .map(|query| query.raw_segments()
+            .filter(|segment| {
+                let name = segment.as_str().split('=').next().unwrap_or_default();
+                name != "page" && name != "per_page"
+            })
+            .map(|segment| segment.as_str().to_string())
+            .collect())
+        .unwrap_or_default();
+    let link = |page: usize, rel: &str| {
+        let mut params = other_params.clone();
+        params.push(format!("page={}", page));
+        params.push(format!("per_page={}", per_page));
+        format!("<{}?{}>; rel=\"{}\"", path, params.join("&"), rel)
+    };
+
+    let mut links = vec![link(1, "first")];
+    if page > 1 {
+        links.push(link((page - 1).min(total_pages), "prev"));
+    }
+    if page < total_pages {
+        links.push(link(page + 1, "next"));
+    }
+    links.push(link(total_pages, "last"));
+    links.join(", ")
+}
+
+// A page rendered to JSON, which identical requests arriving together can share, see
+// coalesce.rs. Sent like the `Paginated` it was rendered from.
+#[derive(Clone)]
+pub struct RenderedPage {
+    body: Arc<String>,
+    etag: String,
+    total_items: usize,
+    page: usize,
+    per_page: usize,
+}
+
+impl<T: Serialize> Paginated<T> {
+    pub fn render(self) -> Result<RenderedPage, Status> {
+        // The ETag comes from the rendered page rather than the ids and versions on it,
+        // which also covers translations picked by Accept-Language and records that
+        // have no version. The total is part of it, since it changes the headers.
+        let body = serde_json::to_string(&self.items).map_err(|_| Status::InternalServerError)?;
+        let etag = cache::etag((&body, self.total_items));
+        Ok(RenderedPage { body: Arc::new(body), etag, total_items: self.total_items, page: self.page, per_page: self.per_page })
+    }
+}
+
+impl RenderedPage {
+    fn total_pages(&self) -> usize {
+        self.total_items.div_ceil(self.per_page).max(1)
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Paginated<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        self.render()?.respond_to(request)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RenderedPage {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let total_pages = self.total_pages();
+        let link = link_header(request, self.page, self.per_page, total_pages);
+        let etag = self.etag;
+
+        let mut build = Response::build();
+        if cache::not_modified(request, &etag) {
+            build.status(Status::NotModified);
+        } else {
+            let len = self.body.len();
+            build.header(ContentType::JSON).sized_body(len, Cursor::new(cache::SharedBody(self.body)));
+        }
+        build.raw_header("ETag", etag)
+            .raw_header("X-Total-Count", self.total_items.to_string())
+            .raw_header("X-Total-Pages", total_pages.to_string())
+            .raw_header("X-Page", self.page.to_string())
+            .raw_header("X-Per-Page", self.per_page.to_string())
+            .raw_header("Link", link)
+            .ok()
+    }
+}
@@ -0,0 +1,131 @@
+// Request bodies that don't fit their type. Rocket's Json guard answers those with a bare
+// 422; `Payload` reads the body the same way but keeps where deserializing failed, and the
+// 422 catcher answers with an `application/problem+json` naming the field, what was
+// wrong with it and what was expected there.
+use rocket::serde::{DeserializeOwned, Serialize};
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::io::Cursor;
+use std::ops::Deref;
+
+use crate::error_messages::ApiError;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: Option<String>,     // Path like `delivery.recipients[0].email`, None for the body as a whole
+    pub message: String,
+    pub expected: Option<String>,  // When serde says what would have fit
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    fn new(status: Status, detail: String, errors: Vec<FieldError>) -> Problem {
+        Problem { kind: "about:blank", title: "Invalid request payload", status: status.code, detail, errors }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_string(&self).unwrap_or_default();
+        Response::build()
+            .status(Status::from_code(self.status).unwrap_or(Status::UnprocessableEntity))
+            .header(ContentType::new("application", "problem+json"))
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+// Splits serde's "invalid type: string \"x\", expected u32" into what was wrong and what was expected
+fn field_error(path: String, error: &serde_json::Error) -> FieldError {
+    let text = error.to_string();
+    let location = format!(" at line {} column {}", error.line(), error.column());
+    let text = text.strip_suffix(&location).unwrap_or(&text);
+
+    let mut field = (path != ".").then_some(path);
+    // The path of a missing field is the object it's missing from
+    if let Some(name) = text.strip_prefix("missing field `").and_then(|rest| rest.strip_suffix('`')) {
+        field = Some(field.map_or_else(|| name.to_string(), |parent| format!("{}.{}", parent, name)));
+    }
+    if error.is_syntax() || error.is_eof() {
+        field = None;
+    }
+    let (message, expected) = match text.split_once(", expected ") {
+        Some((message, expected)) => (message.to_string(), Some(expected.to_string())),
+        None => (text.to_string(), None),
+    };
+    FieldError { field, message, expected }
+}
+
+// Deserializes a JSON body, the problem says which field didn't fit. Shared with the
+// dry runs, see validation.rs
+pub fn parse<T: DeserializeOwned>(body: &str) -> Result<T, Problem> {
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(body)).map_err(|e| {
+        let error = field_error(e.path().to_string(), e.inner());
+        let detail = match &error.field {
+            _ if e.inner().is_data() && error.message.starts_with("missing field") => error.message.clone(),
+            Some(field) => format!("{} at {}", error.message, field),
+            None => format!("{} at line {} column {}", error.message, e.inner().line(), e.inner().column()),
+        };
+        Problem::new(Status::UnprocessableEntity, detail, vec![error])
+    })
+}
+
+// JSON request body like `Json<T>`, with the details of why it didn't deserialize
+pub struct Payload<T>(pub T);
+
+impl<T> Payload<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for Payload<T> {
+    type Error = Problem;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let failed = |status: Status, detail: String| Outcome::Error((status, Problem::new(status, detail, Vec::new())));
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => return failed(Status::PayloadTooLarge, format!("The body is larger than {}", limit)),
+            Err(e) => return failed(Status::BadRequest, format!("The body couldn't be read: {}", e)),
+        };
+        match parse(&body) {
+            Ok(value) => Outcome::Success(Payload(value)),
+            Err(problem) => {
+                request.local_cache(|| Some(problem.clone()));
+                Outcome::Error((Status::UnprocessableEntity, problem))
+            },
+        }
+    }
+}
+
+// The problem of a payload that didn't deserialize, a plain message for other 422s
+#[catch(422)]
+pub fn unprocessable_payload(request: &Request<'_>) -> Result<Problem, ApiError> {
+    request.local_cache(|| None::<Problem>).clone()
+        .ok_or(ApiError::Unprocessable(Status::UnprocessableEntity))
+}
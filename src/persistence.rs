@@ -0,0 +1,218 @@
+// Writing the data back. With `persistence.enabled` contributors, capsules and items are
+// written to their files in `data_dir` after they change, so they survive a restart, and
+// so are the collections of state.rs, like API keys and webhooks. A file is written next
+// to the old one and renamed over it, so a crash mid-write leaves the previous version.
+// Rapid changes are batched: a write waits until nothing has changed for
+// `persistence.debounce_ms`. A failed write is tried again, waiting longer each time.
+// Pending changes are written when the server shuts down.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::Serialize;
+use rocket::tokio::task;
+use rocket::tokio::time::{self, Duration, Instant};
+use rocket::Rocket;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::capsules::CAPSULES;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::items::ITEMS;
+use crate::state;
+use crate::store;
+
+// How often the tables are checked for changes
+const POLL: Duration = Duration::from_millis(100);
+
+// Under constant changes a write still happens after this many debounce periods
+const MAX_DEBOUNCES: u32 = 10;
+
+// Items read at a time while writing items.json, so lazily loaded items aren't all resident
+const ITEM_PAGE: usize = 500;
+
+// Waited before the first retry of a failed write, doubled for each further one up to the most
+const RETRY_FIRST: Duration = Duration::from_secs(1);
+const RETRY_MOST: Duration = Duration::from_secs(60);
+
+// Tries at shutdown, with the same growing waits
+const SHUTDOWN_TRIES: u32 = 3;
+
+// The revisions of the tables and of state.rs the files were last written at, every
+// change below them is on disk
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+static WRITTEN_STATE: AtomicU64 = AtomicU64::new(0);
+
+// Held while writing, so the loop and the shutdown don't write at the same time
+static WRITING: Mutex<()> = Mutex::new(());
+
+// Writes into a temporary file and renames it over the file
+fn replace_file(name: &str, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let path = config::data_path(name);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    let mut out = BufWriter::new(File::create(&temporary)?);
+    write(&mut out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&temporary, &path)
+}
+
+// Writes the rows as a JSON array
+fn write_file<T: Serialize>(name: &str, mut rows: impl FnMut(&mut dyn FnMut(&T) -> io::Result<()>) -> io::Result<()>) -> io::Result<()> {
+    replace_file(name, |out| {
+        out.write_all(b"[")?;
+        let mut first = true;
+        rows(&mut |row| {
+            out.write_all(if first { b"\n  " } else { b",\n  " })?;
+            first = false;
+            store::storing(|| serde_json::to_writer(&mut *out, row)).map_err(io::Error::other)
+        })?;
+        out.write_all(b"\n]\n")
+    })
+}
+
+// Writes the files of the state collections if any entry changed since the last write
+fn write_state() -> io::Result<()> {
+    let revision = state::current_revision();
+    if revision == WRITTEN_STATE.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    for collection in &state::COLLECTIONS {
+        let entries: serde_json::Map<String, serde_json::Value> = (collection.entries)().into_iter().collect();
+        replace_file(&format!("{}.json", collection.name), |out| {
+            serde_json::to_writer_pretty(&mut *out, &entries).map_err(io::Error::other)?;
+            out.write_all(b"\n")
+        })?;
+    }
+    WRITTEN_STATE.store(revision, Ordering::SeqCst);
+    Ok(())
+}
+
+// Writes the three data files if anything changed since the last write, and the state
+fn write_changes() -> io::Result<()> {
+    let _writing = WRITING.lock().unwrap();
+    write_state()?;
+    // Taken before reading, changes made while writing are written next time
+    let revision = store::current_revision();
+    if revision == WRITTEN.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    write_file("contributors.json", |write| {
+        let mut result = Ok(());
+        CONTRIBUTORS.for_each(|contributor| if result.is_ok() { result = write(contributor) });
+        result
+    })?;
+    write_file("capsule.json", |write| {
+        let mut result = Ok(());
+        CAPSULES.for_each(|capsule| if result.is_ok() { result = write(capsule) });
+        result
+    })?;
+    write_file("items.json", |write| {
+        let mut start = 0;
+        loop {
+            let page = ITEMS.page(start, ITEM_PAGE);
+            for item in &page {
+                write(item)?;
+            }
+            if page.len() < ITEM_PAGE {
+                return Ok(());
+            }
+            start += page.len();
+        }
+    })?;
+    WRITTEN.store(revision, Ordering::SeqCst);
+    Ok(())
+}
+
+// Whether everything was written
+async fn write_in_background() -> bool {
+    match task::spawn_blocking(write_changes).await {
+        Ok(Err(e)) => eprintln!("Failed to write the data to {}: {}", config::data_path("").display(), e),
+        Err(e) => eprintln!("Writing the data stopped: {}", e),
+        Ok(Ok(())) => return true,
+    }
+    false
+}
+
+fn revisions() -> (u64, u64) {
+    (store::current_revision(), state::current_revision())
+}
+
+// Starts watching for changes, once the loaded data is what's on disk
+pub struct Persistence;
+
+#[rocket::async_trait]
+impl Fairing for Persistence {
+    fn info(&self) -> Info {
+        Info { name: "Persistence", kind: Kind::Liftoff | Kind::Shutdown }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<rocket::Orbit>) {
+        let written = revisions();
+        WRITTEN.store(written.0, Ordering::SeqCst);
+        WRITTEN_STATE.store(written.1, Ordering::SeqCst);
+        let debounce = Duration::from_millis(config::get().persistence.debounce_ms);
+
+        rocket::tokio::spawn(async move {
+            let mut interval = time::interval(POLL);
+            // The revisions last seen, when they were first seen and when the first unwritten change was.
+            // Changes made before the task first runs are seen as new.
+            let mut seen = written;
+            let mut seen_at = Instant::now();
+            let mut pending_since: Option<Instant> = None;
+            // When a failed write is tried again, and the wait after that
+            let mut retry_at: Option<Instant> = None;
+            let mut retry_wait = RETRY_FIRST;
+            loop {
+                interval.tick().await;
+                let current = revisions();
+                if current != seen {
+                    seen = current;
+                    seen_at = Instant::now();
+                    pending_since.get_or_insert(seen_at);
+                }
+                let Some(since) = pending_since else { continue };
+                let due = match retry_at {
+                    Some(at) => Instant::now() >= at,
+                    None => seen_at.elapsed() >= debounce || since.elapsed() >= debounce * MAX_DEBOUNCES,
+                };
+                if !due {
+                    continue;
+                }
+                if write_in_background().await {
+                    pending_since = None;
+                    retry_at = None;
+                    retry_wait = RETRY_FIRST;
+                } else {
+                    eprintln!("Trying to write the data again in {:?}", retry_wait);
+                    retry_at = Some(Instant::now() + retry_wait);
+                    retry_wait = (retry_wait * 2).min(RETRY_MOST);
+                }
+            }
+        });
+    }
+
+    async fn on_shutdown(&self, _: &Rocket<rocket::Orbit>) {
+        let mut wait = RETRY_FIRST;
+        for _ in 1..SHUTDOWN_TRIES {
+            if write_in_background().await {
+                return;
+            }
+            time::sleep(wait).await;
+            wait *= 2;
+        }
+        write_in_background().await;
+    }
+}
+
+// Whether the data files can be written, checked at launch
+pub fn check_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".write-test");
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
@@ -0,0 +1,219 @@
+// PostgreSQL, with `storage.backend = "postgres"`: one database shared by several instances
+// of the server. Writing and loading are the same for every database, see database.rs; on
+// top of that the instances keep each other up to date. Every written row is announced with
+// NOTIFY once its transaction commits, and the other instances read it back into memory,
+// entries of state.rs included. New ids come from sequences in the database, so two
+// instances never hand out the same id.
+//
+// Changes reach the other instances within moments, not at once: a read right after a
+// write on another instance can still see the old row. Versioned changes of a capsule,
+// PUT and PATCH, first move its version on in the database, so of two instances changing
+// it against the same version one gets `412 Precondition Failed`, see
+// database::claim_version. Other changes of the same row at the same time are settled by
+// the last write.
+use rocket::tokio::runtime::Handle;
+use rocket::tokio::task;
+use rocket::tokio::time::{self, Duration};
+use rocket_db_pools::sqlx::{self, postgres::PgListener, Executor, PgPool};
+use rocket_db_pools::Pool as _;
+use once_cell::sync::Lazy;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::contributors::{Contributor, CONTRIBUTORS};
+use crate::database::{self, Pool};
+use crate::ids::{CapsuleId, ContributorId, EntityId, ItemId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::locks;
+use crate::state;
+
+// Where rows are announced, as "<instance>:<table>:<id>", and state entries as
+// "<instance>:state:<collection>:<key>"
+pub const CHANNEL: &str = "capsule_rows";
+
+// Waited before listening again after the connection was lost
+const RECONNECT: Duration = Duration::from_secs(1);
+
+// Key of the advisory lock that lets one instance at a time migrate
+const MIGRATION_LOCK: i64 = 0x6361_7073;
+
+// Applied in order, each once, the number applied is kept in schema_version
+const MIGRATIONS: &[&str] = &[
+    // 1: Rows as JSON, with the ids they're looked up by, and the id sequences
+    "CREATE TABLE contributors (id BIGINT PRIMARY KEY, data TEXT NOT NULL);
+     CREATE TABLE capsules (id BIGINT PRIMARY KEY, contributor_id BIGINT NOT NULL, data TEXT NOT NULL);
+     CREATE INDEX capsules_contributor ON capsules (contributor_id);
+     CREATE TABLE items (id BIGINT PRIMARY KEY, capsule_id BIGINT NOT NULL, data TEXT NOT NULL);
+     CREATE INDEX items_capsule ON items (capsule_id);
+     CREATE SEQUENCE contributors_ids;
+     CREATE SEQUENCE capsules_ids;
+     CREATE SEQUENCE items_ids;",
+    // 2: Entries of the state collections, and the ids they hand out
+    "CREATE TABLE state (collection TEXT NOT NULL, key TEXT NOT NULL, data TEXT NOT NULL, PRIMARY KEY (collection, key));
+     CREATE SEQUENCE state_ids;",
+    // 3: Capsule versions, compared and moved on in the database, see database::claim_version
+    "ALTER TABLE capsules ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
+     UPDATE capsules SET version = COALESCE((data::json->>'version')::bigint, 0);",
+];
+
+// Tells this instance's announcements apart from the others'
+static INSTANCE: Lazy<String> = Lazy::new(|| uuid::Uuid::new_v4().simple().to_string());
+
+pub fn payload(table: &str, id: u32) -> String {
+    format!("{}:{}:{}", *INSTANCE, table, id)
+}
+
+pub fn entry_payload(collection: &str, key: &str) -> String {
+    format!("{}:state:{}:{}", *INSTANCE, collection, key)
+}
+
+// Moves the table's id sequence past `$1`, for rows written with an id it didn't hand out
+pub fn reserve_sql(table: &str) -> &'static str {
+    match table {
+        "contributors" => "SELECT setval('contributors_ids', $1) FROM contributors_ids WHERE last_value < $1 OR NOT is_called",
+        "capsules" => "SELECT setval('capsules_ids', $1) FROM capsules_ids WHERE last_value < $1 OR NOT is_called",
+        "state" => "SELECT setval('state_ids', $1) FROM state_ids WHERE last_value < $1 OR NOT is_called",
+        _ => "SELECT setval('items_ids', $1) FROM items_ids WHERE last_value < $1 OR NOT is_called",
+    }
+}
+
+// Takes the next id of the table from its sequence. Ids are handed out while a handler
+// runs, which doesn't wait on anything else, so this one blocks its thread.
+pub fn next_id(pool: &PgPool, table: &str) -> u32 {
+    let sql = match table {
+        "contributors" => "SELECT nextval('contributors_ids')",
+        "capsules" => "SELECT nextval('capsules_ids')",
+        "state" => "SELECT nextval('state_ids')",
+        _ => "SELECT nextval('items_ids')",
+    };
+    let id: Result<i64, _> = task::block_in_place(|| Handle::current().block_on(sqlx::query_scalar(sql).fetch_one(pool)));
+    let id = id.unwrap_or_else(|e| panic!("Failed to take a new id for {} from the database: {}", table, e));
+    id as u32
+}
+
+// Migrates the database, returns whether it was new. Instances starting together wait
+// for each other.
+async fn migrate(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)").bind(MIGRATION_LOCK).execute(&mut *transaction).await?;
+    transaction.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)").await?;
+    let version: Option<i32> = sqlx::query_scalar("SELECT version FROM schema_version").fetch_optional(&mut *transaction).await?;
+    let version = version.unwrap_or(0);
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        transaction.execute(*migration).await?;
+    }
+    transaction.execute("DELETE FROM schema_version").await?;
+    sqlx::query("INSERT INTO schema_version (version) VALUES ($1)").bind(MIGRATIONS.len() as i32).execute(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(version == 0)
+}
+
+// Opens and migrates the database, returns whether it was new. There's no default `url`.
+pub async fn open() -> Result<(PgPool, bool), sqlx::Error> {
+    let pool = PgPool::init(&database::figment(None)).await
+        .unwrap_or_else(|e| panic!("Failed to open the database, set databases.capsules.url: {:?}", e));
+    let fresh = migrate(&pool).await?;
+    Ok((pool, fresh))
+}
+
+// Puts a row written by another instance into memory, None if it was removed. The
+// capsule is locked like a handler would, so a request on it never sees half of it.
+async fn apply(pool: &Pool, table: &str, id: u32) -> Result<(), sqlx::Error> {
+    match table {
+        "contributors" => {
+            let row: Option<Contributor> = database::load_table(pool, table, Some(id)).await?.pop();
+            let id = ContributorId::from_number(id);
+            let _guard = locks::lock_contributor(id);
+            database::applying(|| match row {
+                Some(contributor) => CONTRIBUTORS.insert(contributor),
+                None => {
+                    CONTRIBUTORS.remove(id);
+                },
+            });
+        },
+        "capsules" => {
+            let row: Option<Capsule> = database::load_table(pool, table, Some(id)).await?.pop();
+            let id = CapsuleId::from_number(id);
+            let _guard = locks::lock_capsule(id);
+            let owner = CAPSULES.read(id, |capsule| capsule.contributor_id);
+            let new_owner = row.as_ref().map(|capsule| capsule.contributor_id);
+            database::applying(|| match row {
+                Some(capsule) => CAPSULES.insert(capsule),
+                None => {
+                    CAPSULES.remove(id);
+                },
+            });
+            let mut indexes = INDEXES.write().unwrap();
+            if let Some(owner) = owner {
+                indexes.unlink_capsule(owner, id);
+            }
+            if let Some(owner) = new_owner {
+                indexes.link_capsule(owner, id);
+            }
+        },
+        _ => {
+            let row: Option<Item> = database::load_table(pool, table, Some(id)).await?.pop();
+            let id = ItemId::from_number(id);
+            let capsule_id = ITEMS.get(id).map(|item| item.id_capsule);
+            let new_capsule_id = row.as_ref().map(|item| item.id_capsule);
+            let _guard = capsule_id.or(new_capsule_id).map(locks::lock_capsule);
+            database::applying(|| match row {
+                Some(item) => ITEMS.insert(item),
+                None => {
+                    ITEMS.remove(id);
+                },
+            });
+            let mut indexes = INDEXES.write().unwrap();
+            if let Some(capsule_id) = capsule_id {
+                indexes.unlink_item(capsule_id, id);
+            }
+            if let Some(capsule_id) = new_capsule_id {
+                indexes.link_item(capsule_id, id);
+            }
+        },
+    }
+    Ok(())
+}
+
+// Puts a state entry written by another instance into memory, None if it was removed
+async fn apply_entry(pool: &Pool, collection: &str, key: &str) -> Result<(), sqlx::Error> {
+    let Some(state) = state::collection(collection) else { return Ok(()) };
+    let entry = database::load_entry(pool, collection, key).await?;
+    (state.put)(key, entry).map_err(|e| sqlx::Error::Decode(format!("Invalid entry {} in {}: {}", key, collection, e).into()))
+}
+
+// Follows the rows the other instances write, for as long as the server runs. Rows
+// announced while the connection is down are missed until they change again.
+pub async fn follow(pool: &'static Pool) {
+    let Pool::Postgres(postgres) = pool else { return };
+    loop {
+        let listening = async {
+            let mut listener = PgListener::connect_with(postgres).await?;
+            listener.listen(CHANNEL).await?;
+            loop {
+                let notification = listener.recv().await?;
+                let mut parts = notification.payload().splitn(3, ':');
+                let (Some(instance), Some(table), Some(id)) = (parts.next(), parts.next(), parts.next()) else { continue };
+                if instance == INSTANCE.as_str() {
+                    continue;
+                }
+                if table == "state" {
+                    let Some((collection, key)) = id.split_once(':') else { continue };
+                    if let Err(e) = apply_entry(pool, collection, key).await {
+                        eprintln!("Failed to read {} {} written by another instance: {}", collection, key, e);
+                    }
+                    continue;
+                }
+                let Ok(id) = id.parse::<u32>() else { continue };
+                if let Err(e) = apply(pool, table, id).await {
+                    eprintln!("Failed to read {} {} written by another instance: {}", table, id, e);
+                }
+            }
+        };
+        let result: Result<(), sqlx::Error> = listening.await;
+        if let Err(e) = result {
+            eprintln!("Lost the connection following the other instances, reconnecting: {}", e);
+        }
+        time::sleep(RECONNECT).await;
+    }
+}
@@ -0,0 +1,122 @@
+// Capsules are private until they are published to the public feed. Owners schedule
+// the switch ahead of time, at the open time or any other moment, and the scheduler
+// flips the visibility once it's due.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::{DateTime, Utc};
+
+use crate::bus::{self, DomainEvent};
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::Clock;
+use crate::error_messages::ApiError;
+use crate::flags;
+use crate::i18n::AcceptLanguage;
+use crate::locks;
+use crate::moderation;
+use crate::ownership;
+use crate::pagination::{Collection, Pagination, Paginated};
+use crate::ids::CapsuleId;
+use crate::time_format;
+use crate::tokens::Caller;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Private,
+    Public,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct Publishing {
+    pub visibility: Visibility,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub publish_at: Option<DateTime<Utc>>,  // When the scheduler makes the capsule public
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_published: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PublishRequest {
+    publish_at: Option<DateTime<Utc>>,  // Defaults to the capsule's open time
+}
+
+// Schedules a private capsule to become public, for its owner or co-owners
+#[put("/capsules/<cid>/publishing", format = "json", data = "<request>")]
+pub fn schedule_publishing(cid: CapsuleId, request: Json<PublishRequest>, caller: Caller) -> Result<Json<Publishing>, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+
+    CAPSULES.try_update(cid, |capsule| {
+        ownership::check_editor(capsule, &caller)?;
+        if capsule.publishing.visibility == Visibility::Public {
+            return Err(ApiError::Other(Status::Conflict, "The capsule is already public".into()));
+        }
+        capsule.publishing.publish_at = Some(request.publish_at.unwrap_or(capsule.time_open));
+        Ok(Json(capsule.publishing.clone()))
+    }).unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
+
+// Cancels a scheduled publication
+#[delete("/capsules/<cid>/publishing")]
+pub fn cancel_publishing(cid: CapsuleId, caller: Caller) -> Result<Json<Publishing>, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+
+    CAPSULES.try_update(cid, |capsule| {
+        ownership::check_editor(capsule, &caller)?;
+        capsule.publishing.publish_at = None;
+        Ok(Json(capsule.publishing.clone()))
+    }).unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
+
+// Makes every capsule whose publication is due public, called by the scheduler
+pub fn publish_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if capsule.publishing.publish_at.is_some_and(|publish_at| publish_at <= now) {
+            due.push(capsule.id);
+        }
+    });
+
+    for capsule_id in due {
+        let _guard = locks::lock_capsule(capsule_id);
+        // Checked again under the lock, the schedule may have been cancelled meanwhile
+        let published = CAPSULES.update(capsule_id, |capsule| {
+            let publish_at = capsule.publishing.publish_at.filter(|&publish_at| publish_at <= now)?;
+            capsule.publishing = Publishing { visibility: Visibility::Public, publish_at: None, time_published: Some(now) };
+            Some(publish_at)
+        }).flatten();
+
+        if let Some(publish_at) = published {
+            bus::publish(DomainEvent::CapsulePublished { capsule_id, publish_at }, now);
+        }
+    }
+}
+
+// Public capsules, most recently published first
+#[get("/feed?<pagination..>")]
+pub fn public_feed(pagination: Pagination, languages: AcceptLanguage) -> Result<Paginated<Capsule>, status::Custom<Json<String>>> {
+    if !flags::current().public_feed_enabled {
+        return Err(flags::disabled("The public feed"));
+    }
+
+    let mut capsules = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if moderation::is_listed(capsule) {
+            capsules.push(capsule.clone());
+        }
+    });
+    capsules.sort_by(|a, b| b.publishing.time_published.cmp(&a.publishing.time_published).then(a.id.cmp(&b.id)));
+
+    Ok(Paginated::new(&pagination, Collection::Feed, capsules.len(), |start, per_page| capsules.into_iter().skip(start).take(per_page).collect())
+        .map(|capsule| capsule.localized(&languages.0)))
+}
+
+// Current publishing state of a capsule
+#[get("/capsules/<cid>/publishing")]
+pub fn get_publishing(cid: CapsuleId) -> Option<Json<Publishing>> {
+    CAPSULES.read(cid, |capsule| Json(capsule.publishing.clone()))
+}
@@ -0,0 +1,118 @@
+// Per-capsule limits on the number and total size of items, set in `[default.quotas]`.
+// Responses to item uploads carry the remaining allowance in headers, so clients can
+// warn before an upload is refused.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::{self, status, Responder, Response};
+use rocket::Request;
+
+use crate::capsules::CAPSULES;
+use crate::config;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::ITEMS;
+use crate::ids::CapsuleId;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleLimits {
+    pub capsule_id: CapsuleId,
+    pub items: usize,
+    pub bytes: u64,
+    pub max_items: Option<usize>,  // None when unlimited
+    pub max_bytes: Option<u64>,
+    pub items_remaining: Option<usize>,
+    pub bytes_remaining: Option<u64>,
+}
+
+// Item sizes are stored as text like "2MB", in binary units
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier as f64).round() as u64)
+}
+
+pub fn limits(capsule_id: CapsuleId) -> CapsuleLimits {
+    let item_ids = INDEXES.read().unwrap().items_of(capsule_id);
+    let bytes = item_ids.iter()
+        .filter_map(|&id| ITEMS.get(id))
+        .filter_map(|item| parse_size(&item.size))
+        .sum::<u64>();
+    let quotas = &config::get().quotas;
+    CapsuleLimits {
+        capsule_id,
+        items: item_ids.len(),
+        bytes,
+        max_items: quotas.max_items_per_capsule,
+        max_bytes: quotas.max_bytes_per_capsule,
+        items_remaining: quotas.max_items_per_capsule.map(|max| max.saturating_sub(item_ids.len())),
+        bytes_remaining: quotas.max_bytes_per_capsule.map(|max| max.saturating_sub(bytes)),
+    }
+}
+
+// Refuses an item that would take the capsule over its quota, called under the capsule lock
+pub fn check(capsule_id: CapsuleId, size: &str) -> Result<(), ApiError> {
+    let limits = limits(capsule_id);
+    if limits.items_remaining == Some(0) {
+        return Err(ApiError::ItemLimit(capsule_id, limits.items));
+    }
+    if let Some(bytes_remaining) = limits.bytes_remaining {
+        let size = parse_size(size)
+            .ok_or_else(|| status::Custom(Status::BadRequest, Json(format!("Item size '{}' should look like 512KB or 2MB", size))))?;
+        if size > bytes_remaining {
+            return Err(ApiError::SizeLimit { size, capsule_id, left: bytes_remaining });
+        }
+    }
+    Ok(())
+}
+
+// A response with the capsule's remaining allowance in headers, for the limits that are set
+pub struct WithLimits<R>(pub R, pub CapsuleLimits);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for WithLimits<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.0.respond_to(request)?);
+        if let Some(items_remaining) = self.1.items_remaining {
+            response.raw_header("X-Capsule-Items-Remaining", items_remaining.to_string());
+        }
+        if let Some(bytes_remaining) = self.1.bytes_remaining {
+            response.raw_header("X-Capsule-Bytes-Remaining", bytes_remaining.to_string());
+        }
+        response.ok()
+    }
+}
+
+#[get("/capsules/<cid>/limits")]
+pub fn capsule_limits(cid: CapsuleId) -> Option<Json<CapsuleLimits>> {
+    CAPSULES.contains(cid).then(|| Json(limits(cid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_read_in_binary_units() {
+        assert_eq!(parse_size("2MB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size(" 1.5kb "), Some(1536));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512 B"), Some(512));
+        assert_eq!(parse_size("1GB"), Some(1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn unknown_sizes_are_rejected() {
+        assert_eq!(parse_size("2TB"), None);
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size(""), None);
+    }
+}
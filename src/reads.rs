@@ -0,0 +1,127 @@
+// Read receipts: once a capsule has opened, every contributor other than the owner who
+// fetches it is recorded, so the owner can tell whether the people it was meant for
+// actually saw it. Viewers are known by the API key they send.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId, EntityId};
+use crate::state;
+use crate::time_format;
+use crate::tokens::Caller;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ReadReceipt {
+    pub contributor_id: ContributorId,
+    #[serde(serialize_with = "time_format::time")]
+    pub first_read: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::time")]
+    pub last_read: DateTime<Utc>,
+    pub count: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CapsuleReads {
+    pub capsule_id: CapsuleId,
+    pub reads: Vec<ReadReceipt>,
+    pub unread_signers: Vec<ContributorId>,  // Invited co-signers who haven't looked at it yet
+}
+
+// Receipts by capsule and contributor id, kept apart from the capsule so reading it doesn't change it
+pub static READS: Lazy<RwLock<HashMap<CapsuleId, BTreeMap<ContributorId, ReadReceipt>>>> = Lazy::new(|| {
+    RwLock::new(HashMap::new())
+});
+
+// Records a view of an opened capsule, called by the capsule detail route
+pub fn record(capsule: &Capsule, caller: &Caller, now: DateTime<Utc>) {
+    let Some(contributor_id) = caller.0 else { return };
+    if contributor_id == capsule.contributor_id || now < capsule.time_open {
+        return;
+    }
+    let mut reads = READS.write().unwrap();
+    reads.entry(capsule.id).or_default().entry(contributor_id)
+        .and_modify(|receipt| {
+            receipt.last_read = now;
+            receipt.count += 1;
+        })
+        .or_insert(ReadReceipt { contributor_id, first_read: now, last_read: now, count: 1 });
+    state::changed("reads", key(capsule.id, contributor_id));
+}
+
+// Drops the receipts of a removed capsule
+pub fn forget(capsule_id: CapsuleId) {
+    let removed = READS.write().unwrap().remove(&capsule_id);
+    for contributor_id in removed.into_iter().flat_map(|receipts| receipts.into_keys()) {
+        state::changed("reads", key(capsule_id, contributor_id));
+    }
+}
+
+// Receipts are state entries by "<capsule id>:<contributor id>", see state.rs
+fn key(capsule_id: CapsuleId, contributor_id: ContributorId) -> String {
+    format!("{}:{}", capsule_id.number(), contributor_id.number())
+}
+
+fn parse_key(key: &str) -> Option<(CapsuleId, ContributorId)> {
+    let (capsule_id, contributor_id) = key.split_once(':')?;
+    Some((CapsuleId::from_number(capsule_id.parse().ok()?), ContributorId::from_number(contributor_id.parse().ok()?)))
+}
+
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    READS.read().unwrap().iter()
+        .flat_map(|(&capsule_id, receipts)| receipts.values().map(move |receipt| (key(capsule_id, receipt.contributor_id), state::to_entry(receipt))))
+        .collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    let (capsule_id, contributor_id) = parse_key(key)?;
+    READS.read().unwrap().get(&capsule_id)?.get(&contributor_id).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let Some((capsule_id, contributor_id)) = parse_key(key) else { return Ok(()) };
+    let receipt: Option<ReadReceipt> = entry.map(state::from_entry).transpose()?;
+    let mut reads = READS.write().unwrap();
+    match receipt {
+        Some(receipt) => {
+            reads.entry(capsule_id).or_default().insert(contributor_id, receipt);
+        },
+        None => {
+            if let Some(receipts) = reads.get_mut(&capsule_id) {
+                receipts.remove(&contributor_id);
+                if receipts.is_empty() {
+                    reads.remove(&capsule_id);
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+// Who has read the capsule, only for its owner
+#[get("/capsules/<cid>/reads")]
+pub fn capsule_reads(cid: CapsuleId, caller: Caller) -> Result<Json<CapsuleReads>, ApiError> {
+    let capsule = CAPSULES.get(cid)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    match caller.0 {
+        None => return Err(ApiError::Other(Status::Unauthorized, "Send the owner's API key".into())),
+        Some(id) if id != capsule.contributor_id => {
+            return Err(ApiError::Other(Status::Forbidden, "Only the capsule's owner can see who read it".into()));
+        },
+        Some(_) => {},
+    }
+
+    let reads: Vec<ReadReceipt> = READS.read().unwrap().get(&cid)
+        .map(|receipts| receipts.values().cloned().collect())
+        .unwrap_or_default();
+    let unread_signers = capsule.signing.map(|signing| signing.signers).unwrap_or_default().into_iter()
+        .filter(|&id| id != capsule.contributor_id && !reads.iter().any(|receipt| receipt.contributor_id == id))
+        .collect();
+    Ok(Json(CapsuleReads { capsule_id: cid, reads, unread_signers }))
+}
@@ -0,0 +1,238 @@
+use rocket::data::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::route::{self, Handler, Route};
+use rocket::serde::Serialize;
+use rocket::{Request, Response};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::config::{self, ReportingConfig};
+use crate::time_format;
+
+// A panic or 5xx response forwarded to the configured sink
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorReport {
+    pub kind: String,   // "panic" or "server_error"
+    pub message: String,
+    #[serde(serialize_with = "time_format::time")]
+    pub timestamp: DateTime<Utc>,
+    pub location: Option<String>,
+    pub method: Option<String>,
+    pub uri: Option<String>,    // The path only, queries can hold emails and share tokens
+    pub route: Option<String>,  // The route that matched, e.g. /capsules/<cid>/items
+    pub status: Option<u16>,
+    pub user_agent: Option<String>,
+}
+
+// Sentry DSNs look like https://<public_key>@<host>/<project_id>
+fn sentry_store_url(dsn: &str) -> Option<(String, String)> {
+    let (scheme, rest) = dsn.split_once("://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let (host, project_id) = rest.rsplit_once('/')?;
+    Some((format!("{}://{}/api/{}/store/", scheme, host, project_id), key.to_string()))
+}
+
+fn sentry_event(report: &ErrorReport, environment: &Option<String>) -> serde_json::Value {
+    let event_id: String = (0..32).map(|_| format!("{:x}", rand::thread_rng().gen_range(0..16))).collect();
+    serde_json::json!({
+        "event_id": event_id,
+        "timestamp": report.timestamp.to_rfc3339(),
+        "level": if report.kind == "panic" { "fatal" } else { "error" },
+        "platform": "rust",
+        "logger": report.kind,
+        "environment": environment,
+        "message": { "formatted": report.message },
+        "culprit": report.location,
+        "transaction": report.route,
+        "request": {
+            "method": report.method,
+            "url": report.uri,
+            "headers": { "User-Agent": report.user_agent },
+        },
+        "tags": { "status": report.status },
+    })
+}
+
+async fn send(reporting: &ReportingConfig, report: ErrorReport) {
+    let client = reqwest::Client::new();
+
+    if let Some((url, key)) = reporting.sentry_dsn.as_deref().and_then(sentry_store_url) {
+        let auth = format!("Sentry sentry_version=7, sentry_key={}, sentry_client=capsules/0.1", key);
+        let result = client.post(&url)
+            .header("X-Sentry-Auth", auth)
+            .json(&sentry_event(&report, &reporting.environment))
+            .send().await;
+        if let Err(e) = result {
+            warn!("Failed to send error report to Sentry: {}", e);
+        }
+    }
+
+    if let Some(ref webhook_url) = reporting.webhook_url {
+        if let Err(e) = client.post(webhook_url).json(&report).send().await {
+            warn!("Failed to send error report to webhook: {}", e);
+        }
+    }
+}
+
+// Forward a report without blocking the caller, also usable from outside the request cycle
+pub fn dispatch(report: ErrorReport) {
+    let reporting = &config::get().reporting;
+    if !reporting.is_configured() {
+        return;
+    }
+    match rocket::tokio::runtime::Handle::try_current() {
+        Ok(handle) => { handle.spawn(send(reporting, report)); },
+        Err(_) => error!("Dropped error report outside of the runtime: {}", report.message),
+    }
+}
+
+// The request a handler answers, for the reports of panics in it
+struct RequestContext {
+    method: String,
+    path: String,
+    route: Option<String>,
+    user_agent: Option<String>,
+    panicked: AtomicBool,  // Already reported, the 500 answering the panic isn't reported again
+}
+
+fn context(request: &Request<'_>) -> Arc<RequestContext> {
+    request.local_cache(|| Arc::new(RequestContext {
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        route: request.route().map(|route| route.uri.to_string()),
+        user_agent: request.headers().get_one("User-Agent").map(String::from),
+        panicked: AtomicBool::new(false),
+    }))
+        .clone()
+}
+
+thread_local! {
+    // The request whose handler this thread is polling, see `InRequest`
+    static CURRENT: RefCell<Option<Arc<RequestContext>>> = const { RefCell::new(None) };
+}
+
+// A handler's future, polled with its request as the current one
+struct InRequest<F> {
+    context: Arc<RequestContext>,
+    future: F,
+}
+
+impl<F: Future + Unpin> Future for InRequest<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Put back on drop, a panicking handler unwinds through here
+        struct Restore(Option<Arc<RequestContext>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+        let _restore = Restore(CURRENT.with(|current| current.replace(Some(self.context.clone()))));
+        Pin::new(&mut self.future).poll(cx)
+    }
+}
+
+#[derive(Clone)]
+struct Reported(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Reported {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        InRequest { context: context(request), future: self.0.handle(request, data) }.await
+    }
+}
+
+// The routes, so that panics in their handlers are reported with the request
+pub fn reported(routes: Vec<Route>) -> Vec<Route> {
+    if !config::get().reporting.is_configured() {
+        return routes;
+    }
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Reported(route.handler.clone()));
+            route
+        })
+        .collect()
+}
+
+// Report every panic (handlers, background tasks) before the default hook prints it. A
+// panic in a handler comes with its request and stands for the 500 Rocket answers it with.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = info.payload().downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".to_string());
+
+        let context = CURRENT.with(|current| current.borrow().clone());
+        if let Some(context) = &context {
+            context.panicked.store(true, Ordering::SeqCst);
+        }
+        dispatch(ErrorReport {
+            kind: "panic".into(),
+            message,
+            timestamp: Utc::now(),
+            location: info.location().map(|l| format!("{}:{}", l.file(), l.line())),
+            method: context.as_ref().map(|context| context.method.clone()),
+            uri: context.as_ref().map(|context| context.path.clone()),
+            route: context.as_ref().and_then(|context| context.route.clone()),
+            status: context.as_ref().map(|_| 500),
+            user_agent: context.and_then(|context| context.user_agent.clone()),
+        });
+        default_hook(info);
+    }));
+}
+
+// Fairing reporting every 5xx response together with the request it answered, but for
+// the ones answering a panic, which was reported already
+pub struct ErrorReporter;
+
+#[rocket::async_trait]
+impl Fairing for ErrorReporter {
+    fn info(&self) -> Info {
+        Info { name: "Error reporting", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let status = response.status();
+        if status.code < 500 || context(request).panicked.load(Ordering::SeqCst) {
+            return;
+        }
+
+        dispatch(ErrorReport {
+            kind: "server_error".into(),
+            message: format!("{} {} responded with {}", request.method(), request.uri().path(), status),
+            timestamp: Utc::now(),
+            location: request.route().map(|r| r.uri.to_string()),
+            method: Some(request.method().to_string()),
+            uri: Some(request.uri().path().to_string()),
+            route: request.route().map(|r| r.uri.to_string()),
+            status: Some(status.code),
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentry_dsns_give_the_store_url_and_key() {
+        assert_eq!(
+            sentry_store_url("https://abc123@o1.ingest.sentry.io/42"),
+            Some(("https://o1.ingest.sentry.io/api/42/store/".to_string(), "abc123".to_string())),
+        );
+        assert_eq!(sentry_store_url("https://o1.ingest.sentry.io/42"), None);
+        assert_eq!(sentry_store_url("not a dsn"), None);
+    }
+}
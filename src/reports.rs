@@ -0,0 +1,204 @@
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use std::collections::BTreeMap;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::time_format;
+use crate::tokens::Caller;
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct PeriodCounts {
+    pub period: String,
+    pub opened: usize,     // Capsules whose open time in this period has passed
+    pub will_open: usize,  // Capsules scheduled to open later in this period
+    pub created: usize,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OpeningsReport {
+    pub group_by: String,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub to: Option<DateTime<Utc>>,
+    pub periods: Vec<PeriodCounts>,
+}
+
+#[derive(Clone, Copy)]
+pub enum GroupBy {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl GroupBy {
+    pub fn parse(value: &str) -> Option<GroupBy> {
+        match value {
+            "day" => Some(GroupBy::Day),
+            "week" => Some(GroupBy::Week),
+            "month" => Some(GroupBy::Month),
+            "year" => Some(GroupBy::Year),
+            _ => None,
+        }
+    }
+
+    // Sortable label of the period a time falls in
+    pub fn period(self, time: DateTime<Utc>) -> String {
+        match self {
+            GroupBy::Day => time.format("%Y-%m-%d").to_string(),
+            GroupBy::Week => {
+                let week = time.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            },
+            GroupBy::Month => time.format("%Y-%m").to_string(),
+            GroupBy::Year => time.format("%Y").to_string(),
+        }
+    }
+}
+
+// Accepts an RFC 3339 time or a plain date, which means midnight UTC
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    value.parse::<DateTime<Utc>>().ok().or_else(|| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|time| time.and_utc())
+    })
+}
+
+// Optional `from`/`to` query bound
+pub fn parse_bound(value: Option<&str>, name: &str) -> Result<Option<DateTime<Utc>>, ApiError> {
+    match value {
+        None => Ok(None),
+        Some(value) => parse_time(value).map(Some)
+            .ok_or_else(|| ApiError::InvalidTime(name.to_string())),
+    }
+}
+
+#[get("/reports/openings?<from>&<to>&<group_by>")]
+pub fn openings_report(from: Option<&str>, to: Option<&str>, group_by: Option<&str>, clock: &State<SharedClock>) -> Result<Json<OpeningsReport>, ApiError> {
+    let group_name = group_by.unwrap_or("month");
+    let group = GroupBy::parse(group_name)
+        .ok_or(ApiError::InvalidGroupBy)?;
+
+    let from = parse_bound(from, "from")?;
+    let to = parse_bound(to, "to")?;
+
+    // Only events inside [from, to) are counted
+    let in_range = |time: DateTime<Utc>| from.is_none_or(|from| time >= from) && to.is_none_or(|to| time < to);
+
+    let now = clock.now();
+    let mut periods: BTreeMap<String, PeriodCounts> = BTreeMap::new();
+    CAPSULES.for_each(|capsule| {
+        if in_range(capsule.time_open) {
+            let counts = periods.entry(group.period(capsule.time_open)).or_default();
+            if capsule.time_open <= now { counts.opened += 1 } else { counts.will_open += 1 }
+        }
+        if in_range(capsule.time_created) {
+            periods.entry(group.period(capsule.time_created)).or_default().created += 1;
+        }
+    });
+
+    let periods = periods.into_iter()
+        .map(|(period, counts)| PeriodCounts { period, ..counts })
+        .collect();
+
+    Ok(Json(OpeningsReport { group_by: group_name.to_string(), from, to, periods }))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UpcomingBucket {
+    pub window: String,
+    #[serde(serialize_with = "time_format::time")]
+    pub until: DateTime<Utc>,
+    pub count: usize,
+    pub capsule_ids: Vec<CapsuleId>,  // Soonest first
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UpcomingReport {
+    pub contributor_id: ContributorId,
+    #[serde(serialize_with = "time_format::time")]
+    pub now: DateTime<Utc>,
+    pub buckets: Vec<UpcomingBucket>,
+}
+
+// A window such as `12h`, `7d` or `2w`
+fn parse_window(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: i64 = amount.parse().ok().filter(|&amount| amount > 0)?;
+    match unit {
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+// How many of the caller's capsules open within each window from now. Windows all start
+// now, so a capsule opening in 3 days counts in every one of them.
+#[get("/reports/upcoming?<buckets>")]
+pub fn upcoming_report(buckets: Option<&str>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<UpcomingReport>, status::Custom<Json<String>>> {
+    let contributor_id = caller.0
+        .ok_or_else(|| status::Custom(Status::Unauthorized, Json("Send your API key".into())))?;
+    let windows = buckets.unwrap_or("7d,30d,365d").split(',')
+        .map(|window| {
+            let window = window.trim();
+            parse_window(window).map(|duration| (window.to_string(), duration))
+                .ok_or_else(|| status::Custom(Status::BadRequest, Json(format!("Invalid bucket '{}', use a number followed by h, d or w", window))))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let now = clock.now();
+    let mut upcoming: Vec<(DateTime<Utc>, CapsuleId)> = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if capsule.contributor_id == contributor_id && capsule.time_open > now {
+            upcoming.push((capsule.time_open, capsule.id));
+        }
+    });
+    upcoming.sort();
+
+    let buckets = windows.into_iter()
+        .map(|(window, duration)| {
+            let until = now + duration;
+            let capsule_ids: Vec<CapsuleId> = upcoming.iter().take_while(|(time_open, _)| *time_open <= until).map(|&(_, id)| id).collect();
+            UpcomingBucket { window, until, count: capsule_ids.len(), capsule_ids }
+        })
+        .collect();
+
+    Ok(Json(UpcomingReport { contributor_id, now, buckets }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_are_hours_days_or_weeks() {
+        assert_eq!(parse_window("12h"), Some(Duration::hours(12)));
+        assert_eq!(parse_window("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_window("2w"), Some(Duration::weeks(2)));
+        assert_eq!(parse_window("0d"), None);
+        assert_eq!(parse_window("3m"), None);
+        assert_eq!(parse_window(""), None);
+    }
+
+    #[test]
+    fn bounds_are_times_or_dates() {
+        let midnight = "2024-06-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert!(matches!(parse_bound(Some("2024-06-01"), "from"), Ok(Some(time)) if time == midnight));
+        assert!(matches!(parse_bound(Some("2024-06-01T00:00:00+00:00"), "from"), Ok(Some(time)) if time == midnight));
+        assert!(matches!(parse_bound(None, "from"), Ok(None)));
+        assert!(matches!(parse_bound(Some("June"), "to"), Err(ApiError::InvalidTime(name)) if name == "to"));
+    }
+}
@@ -0,0 +1,102 @@
+// Data residency: a contributor can carry a `region`, one of `residency.regions`. The files
+// written for their capsules (imported files, link snapshots, cold storage, archives and
+// ZIP exports) then go under that region's storage root instead of the usual directories,
+// e.g. `<root>/imports` for `imports.dir = "imports"`. Nothing is written when the region
+// has no root configured. Data doesn't cross regions either: a contributor's region is
+// fixed once they own capsules, capsules aren't reassigned and files aren't attached
+// across regions.
+use rocket::serde::json::Json;
+use rocket::http::Status;
+use rocket::response::status;
+use std::path::{Path, PathBuf};
+
+use crate::capsules::CAPSULES;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::indexes::INDEXES;
+
+pub fn check_region(region: &str) -> Result<(), status::Custom<Json<String>>> {
+    let regions = &config::get().residency.regions;
+    if regions.contains_key(region) {
+        return Ok(());
+    }
+    let known: Vec<&str> = regions.keys().map(String::as_str).collect();
+    let message = if known.is_empty() {
+        format!("Unknown region '{}', no regions are configured", region)
+    } else {
+        format!("Unknown region '{}', use {}", region, known.join(", "))
+    };
+    Err(status::Custom(Status::BadRequest, Json(message)))
+}
+
+pub fn region_of(contributor_id: ContributorId) -> Option<String> {
+    CONTRIBUTORS.read(contributor_id, |contributor| contributor.region.clone()).flatten()
+}
+
+// The region of the capsule's owner
+pub fn region_of_capsule(capsule_id: CapsuleId) -> Option<String> {
+    CAPSULES.read(capsule_id, |capsule| capsule.contributor_id).and_then(region_of)
+}
+
+fn name(region: Option<&str>) -> &str {
+    region.unwrap_or("the default storage")
+}
+
+// Where files usually kept in `dir` go for a region, `dir` itself without one
+pub fn storage_dir(dir: &str, region: Option<&str>) -> Result<PathBuf, String> {
+    let Some(region) = region else { return Ok(PathBuf::from(dir)) };
+    let root = config::get().residency.regions.get(region)
+        .ok_or_else(|| format!("No storage is configured for region {}", region))?;
+    Ok(Path::new(root).join(Path::new(dir).file_name().unwrap_or(dir.as_ref())))
+}
+
+pub fn capsule_dir(dir: &str, capsule_id: CapsuleId) -> Result<PathBuf, String> {
+    storage_dir(dir, region_of_capsule(capsule_id).as_deref())
+}
+
+// Every directory files of `dir` can be in, for lookups of files written earlier
+pub fn all_dirs(dir: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(dir)];
+    dirs.extend(config::get().residency.regions.keys().filter_map(|region| storage_dir(dir, Some(region)).ok()));
+    dirs
+}
+
+// The region whose storage root a file is under
+pub fn region_of_path(path: &str) -> Option<&'static str> {
+    config::get().residency.regions.iter()
+        .find(|(_, root)| Path::new(path).starts_with(root))
+        .map(|(region, _)| region.as_str())
+}
+
+// Refuses to put a stored file into a capsule of another region. Files outside every
+// region root are only refused when they are local files going into a region's capsule.
+pub fn check_file(path: &str, capsule_id: CapsuleId) -> Result<(), status::Custom<Json<String>>> {
+    let target = region_of_capsule(capsule_id);
+    let source = region_of_path(path);
+    let local = !path.starts_with("http://") && !path.starts_with("https://") && Path::new(path).is_file();
+    if source == target.as_deref() || (source.is_none() && !local) {
+        return Ok(());
+    }
+    Err(status::Custom(Status::Conflict, Json(format!("{} is stored in {} and can't go into capsule {} in {}", path, name(source), capsule_id, name(target.as_deref())))))
+}
+
+// Refuses to move a capsule to an owner in another region
+pub fn check_owner_change(capsule_id: CapsuleId, from: ContributorId, to: ContributorId) -> Result<(), status::Custom<Json<String>>> {
+    let (source, target) = (region_of(from), region_of(to));
+    if source == target {
+        return Ok(());
+    }
+    Err(status::Custom(Status::Conflict, Json(format!("Capsule {} is kept in {} and contributor {} in {}", capsule_id, name(source.as_deref()), to, name(target.as_deref())))))
+}
+
+// A contributor's region can only change while they own no capsules, whose files would
+// otherwise stay behind in the old region. The caller holds the contributor's lock.
+pub fn check_region_change(contributor_id: ContributorId, region: &str) -> Result<(), status::Custom<Json<String>>> {
+    check_region(region)?;
+    let current = region_of(contributor_id);
+    if current.as_deref() != Some(region) && !INDEXES.read().unwrap().capsules_of(contributor_id).is_empty() {
+        return Err(status::Custom(Status::Conflict, Json(format!("Contributor {} owns capsules in {}, the region can't change anymore", contributor_id, name(current.as_deref())))));
+    }
+    Ok(())
+}
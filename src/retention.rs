@@ -0,0 +1,74 @@
+// What happens to a capsule's items once it has opened:
+//   keep          they stay, with the usual contribution rules
+//   read_only     nothing can be added, changed or removed from the opening on
+//   delete_after  the scheduler deletes them `days` after the opening ("memento mori")
+// The policy is set with the capsule and can be replaced while the capsule can change.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::bus::{self, DomainEvent};
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::Clock;
+use crate::database;
+use crate::indexes::INDEXES;
+use crate::items::ITEMS;
+use crate::locks;
+
+const MAX_DAYS: u32 = 36500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(crate = "rocket::serde", tag = "mode", rename_all = "snake_case")]
+pub enum ItemRetention {
+    #[default]
+    Keep,
+    ReadOnly,
+    DeleteAfter { days: u32 },
+}
+
+pub fn check(retention: ItemRetention) -> Result<ItemRetention, status::Custom<Json<String>>> {
+    match retention {
+        ItemRetention::DeleteAfter { days } if days == 0 || days > MAX_DAYS => {
+            Err(status::Custom(Status::BadRequest, Json(format!("retention days must be between 1 and {}", MAX_DAYS))))
+        },
+        _ => Ok(retention),
+    }
+}
+
+// When the scheduler deletes the capsule's items, if it will
+pub fn delete_at(capsule: &Capsule) -> Option<DateTime<Utc>> {
+    match capsule.retention {
+        ItemRetention::DeleteAfter { days } => Some(capsule.time_open + Duration::days(days as i64)),
+        _ => None,
+    }
+}
+
+pub fn items_read_only(capsule: &Capsule, now: DateTime<Utc>) -> bool {
+    capsule.retention == ItemRetention::ReadOnly && now >= capsule.time_open
+}
+
+// Deletes the items of capsules whose retention ran out. Called by the scheduler.
+pub fn run_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if capsule.time_items_deleted.is_none() && delete_at(capsule).is_some_and(|delete_at| delete_at <= now) {
+            due.push(capsule.id);
+        }
+    });
+
+    for capsule_id in due {
+        let _guard = locks::lock_capsule(capsule_id);
+        let item_ids = INDEXES.read().unwrap().items_of(capsule_id);
+        // The items go together with the mark that they're gone
+        database::atomically(|| {
+            for &item_id in &item_ids {
+                ITEMS.remove(item_id);
+                INDEXES.write().unwrap().unlink_item(capsule_id, item_id);
+            }
+            CAPSULES.update(capsule_id, |capsule| capsule.time_items_deleted = Some(now));
+        });
+        bus::publish(DomainEvent::ItemsExpired { capsule_id, item_ids }, now);
+    }
+}
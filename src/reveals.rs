@@ -0,0 +1,229 @@
+// Open ceremonies: instead of showing everything at once, a capsule's items can be
+// revealed one by one at offsets after its open time, say one photo per day. The
+// scheduler reveals each step once it's due; until then the item is left out of every
+// items endpoint as if it wasn't there yet.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+
+use crate::bus::{self, DomainEvent};
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::{Clock, SharedClock};
+use crate::error_messages::ApiError;
+use crate::locks;
+use crate::indexes::INDEXES;
+use crate::ids::{CapsuleId, ItemId};
+use crate::time_format;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct RevealStep {
+    pub item_id: ItemId,
+    pub offset_secs: u64,  // After the capsule's open time
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_revealed: Option<DateTime<Utc>>,  // Set by the scheduler
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Reveal {
+    pub steps: Vec<RevealStep>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StepRequest {
+    item_id: ItemId,
+    offset_secs: u64,
+}
+
+// Either explicit steps, or an interval that reveals the items in the order they were added
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RevealRequest {
+    steps: Option<Vec<StepRequest>>,
+    interval_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StepStatus {
+    pub item_id: ItemId,
+    #[serde(serialize_with = "time_format::time")]
+    pub reveal_at: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_revealed: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RevealStatus {
+    pub capsule_id: CapsuleId,
+    pub revealed: usize,
+    pub remaining: usize,
+    pub steps: Vec<StepStatus>,
+}
+
+impl Reveal {
+    // Items of the ceremony the scheduler hasn't revealed yet
+    pub fn hidden(&self) -> impl Iterator<Item = ItemId> + '_ {
+        self.steps.iter().filter(|step| step.time_revealed.is_none()).map(|step| step.item_id)
+    }
+}
+
+pub fn reveal_status(capsule: &Capsule, reveal: &Reveal) -> RevealStatus {
+    let steps: Vec<StepStatus> = reveal.steps.iter()
+        .map(|step| StepStatus {
+            item_id: step.item_id,
+            reveal_at: reveal_at(capsule.time_open, step),
+            time_revealed: step.time_revealed,
+        })
+        .collect();
+    let revealed = steps.iter().filter(|step| step.time_revealed.is_some()).count();
+    RevealStatus { capsule_id: capsule.id, revealed, remaining: steps.len() - revealed, steps }
+}
+
+// Follows the open time, so moving it moves the whole ceremony
+fn reveal_at(time_open: DateTime<Utc>, step: &RevealStep) -> DateTime<Utc> {
+    time_open + Duration::seconds(step.offset_secs.min(i64::MAX as u64) as i64)
+}
+
+fn new_steps(capsule: &Capsule, request: RevealRequest) -> Result<Vec<RevealStep>, status::Custom<Json<String>>> {
+    let item_ids = INDEXES.read().unwrap().items_of(capsule.id);
+    let steps: Vec<(ItemId, u64)> = match (request.steps, request.interval_secs) {
+        (Some(steps), None) => steps.into_iter().map(|step| (step.item_id, step.offset_secs)).collect(),
+        (None, Some(interval)) => item_ids.iter().enumerate().map(|(i, &id)| (id, interval.saturating_mul(i as u64))).collect(),
+        _ => return Err(status::Custom(Status::BadRequest, Json("Give either `steps` or `interval_secs`".into()))),
+    };
+    if steps.is_empty() {
+        return Err(status::Custom(Status::BadRequest, Json(format!("Capsule {} has no items to reveal", capsule.id))));
+    }
+
+    let mut seen = HashSet::new();
+    for &(item_id, _) in &steps {
+        if !item_ids.contains(&item_id) {
+            return Err(status::Custom(Status::BadRequest, Json(format!("Item {} is not in capsule {}", item_id, capsule.id))));
+        }
+        if !seen.insert(item_id) {
+            return Err(status::Custom(Status::BadRequest, Json(format!("Item {} appears more than once", item_id))));
+        }
+    }
+
+    let mut steps: Vec<RevealStep> = steps.into_iter()
+        .map(|(item_id, offset_secs)| RevealStep { item_id, offset_secs, time_revealed: None })
+        .collect();
+    steps.sort_by_key(|step| step.offset_secs);
+    Ok(steps)
+}
+
+// Sets up the ceremony of a capsule that hasn't opened yet, replacing any earlier one
+#[put("/capsules/<cid>/reveal", format = "json", data = "<request>")]
+pub fn schedule_reveal(cid: CapsuleId, request: Json<RevealRequest>, clock: &State<SharedClock>) -> Result<Json<RevealStatus>, ApiError> {
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
+
+    CAPSULES.try_update(cid, |capsule| {
+        if capsule.time_open <= now {
+            return Err(ApiError::CapsuleOpened(cid));
+        }
+        let reveal = Reveal { steps: new_steps(capsule, request.into_inner())? };
+        let status = reveal_status(capsule, &reveal);
+        capsule.reveal = Some(reveal);
+        Ok(Json(status))
+    }).unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
+
+// Drops the ceremony, items that were still waiting show up right away
+#[delete("/capsules/<cid>/reveal")]
+pub fn cancel_reveal(cid: CapsuleId) -> Option<Status> {
+    let _guard = locks::lock_capsule(cid);
+
+    CAPSULES.update(cid, |capsule| {
+        capsule.reveal = None;
+        Status::NoContent
+    })
+}
+
+#[get("/capsules/<cid>/reveal")]
+pub fn get_reveal(cid: CapsuleId) -> Result<Json<RevealStatus>, ApiError> {
+    CAPSULES.read(cid, |capsule| {
+        capsule.reveal.as_ref()
+            .map(|reveal| Json(reveal_status(capsule, reveal)))
+            .ok_or_else(|| ApiError::Other(Status::NotFound, format!("Capsule {} has no reveal ceremony", cid)))
+    }).unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
+
+// Reveals every step that is due, called by the scheduler
+pub fn reveal_due(clock: &dyn Clock) {
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if let Some(reveal) = &capsule.reveal {
+            if reveal.steps.iter().any(|step| step.time_revealed.is_none() && reveal_at(capsule.time_open, step) <= now) {
+                due.push(capsule.id);
+            }
+        }
+    });
+
+    for capsule_id in due {
+        let _guard = locks::lock_capsule(capsule_id);
+        // Checked again under the lock, the ceremony may have been changed meanwhile
+        let revealed = CAPSULES.update(capsule_id, |capsule| {
+            let time_open = capsule.time_open;
+            let Some(reveal) = capsule.reveal.as_mut() else { return Vec::new() };
+            let mut revealed = Vec::new();
+            for step in reveal.steps.iter_mut().filter(|step| step.time_revealed.is_none()) {
+                if reveal_at(time_open, step) <= now {
+                    step.time_revealed = Some(now);
+                    revealed.push(step.item_id);
+                }
+            }
+            revealed
+        }).unwrap_or_default();
+
+        for item_id in revealed {
+            bus::publish(DomainEvent::ItemRevealed { capsule_id, item_id }, now);
+        }
+    }
+}
+
+// The given items of a capsule without those its ceremony still hides
+pub fn visible(capsule_id: CapsuleId, item_ids: Vec<ItemId>) -> Vec<ItemId> {
+    let hidden: HashSet<ItemId> = CAPSULES.read(capsule_id, |capsule| {
+        capsule.reveal.as_ref().map(|reveal| reveal.hidden().collect()).unwrap_or_default()
+    }).unwrap_or_default();
+    if hidden.is_empty() {
+        return item_ids;
+    }
+    item_ids.into_iter().filter(|id| !hidden.contains(id)).collect()
+}
+
+// The given items of a capsule that show right at its open time, when the steps without
+// an offset are revealed
+pub fn visible_at_open(capsule: &Capsule, item_ids: Vec<ItemId>) -> Vec<ItemId> {
+    let Some(reveal) = &capsule.reveal else { return item_ids };
+    let later: HashSet<ItemId> = reveal.steps.iter()
+        .filter(|step| step.time_revealed.is_none() && step.offset_secs > 0)
+        .map(|step| step.item_id)
+        .collect();
+    item_ids.into_iter().filter(|id| !later.contains(id)).collect()
+}
+
+pub fn is_hidden(capsule_id: CapsuleId, item_id: ItemId) -> bool {
+    CAPSULES.read(capsule_id, |capsule| capsule.reveal.as_ref().is_some_and(|reveal| reveal.hidden().any(|id| id == item_id)))
+        .unwrap_or(false)
+}
+
+// Items hidden by any ceremony, for listings across capsules
+pub fn all_hidden() -> HashSet<ItemId> {
+    let mut hidden = HashSet::new();
+    CAPSULES.for_each(|capsule| {
+        if let Some(reveal) = &capsule.reveal {
+            hidden.extend(reveal.hidden());
+        }
+    });
+    hidden
+}
@@ -0,0 +1,45 @@
+// Background loop for work that is due at a certain time rather than on a request.
+//
+// Started once the server is up; every tick runs the due jobs against the managed
+// clock, so moving the adjustable clock also moves the schedule. Maintenance tasks only
+// run in their windows, see schedules.rs.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::time::{self, Duration};
+use rocket::Rocket;
+
+use crate::clock::SharedClock;
+use crate::cold_storage;
+use crate::config;
+use crate::letters;
+use crate::open_notices;
+use crate::publishing;
+use crate::reveals;
+use crate::schedules;
+use crate::templates;
+
+pub struct Scheduler;
+
+#[rocket::async_trait]
+impl Fairing for Scheduler {
+    fn info(&self) -> Info {
+        Info { name: "Scheduler", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<rocket::Orbit>) {
+        let clock = rocket.state::<SharedClock>().expect("Clock is managed before launch").clone();
+        let mut interval = time::interval(Duration::from_secs(config::get().scheduler.interval_secs.max(1)));
+
+        rocket::tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                publishing::publish_due(clock.as_ref());
+                templates::fill_opened(clock.as_ref());  // Before the letters, so the emails have the filled in name
+                reveals::reveal_due(clock.as_ref());  // Before the letters, so they list the items revealed at opening
+                open_notices::notify_due(clock.as_ref()).await;  // Also after the reveals, for the same reason
+                letters::deliver_due(clock.as_ref()).await;
+                cold_storage::restore_due(clock.as_ref());
+                schedules::run_due(clock.as_ref());  // Last, so the letters still list the items the retention purge deletes
+            }
+        });
+    }
+}
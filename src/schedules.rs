@@ -0,0 +1,383 @@
+// Maintenance windows. The scheduler runs the housekeeping tasks (garbage collection, cold
+// storage archiving, the retention purge and the growth snapshots) only when their cron
+// schedule says so, instead of on every tick. Schedules start from `schedules` in the
+// config and can be changed at runtime with `PUT /admin/schedules`; changes are kept as
+// state (see state.rs) and win over the config after a restart. Work that is due at a certain time, like openings, reveals and
+// emails, isn't maintenance and still runs on every tick.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::clock::{Clock, SharedClock};
+use crate::cold_storage;
+use crate::config;
+use crate::duplicates;
+use crate::retention;
+use crate::state;
+use crate::stats;
+use crate::time_format;
+use crate::tokens;
+
+const OFF: &str = "off";
+
+// Steps tried when looking for the next run. Whole months, days and hours that don't
+// match are skipped at once, so this covers decades of the sparsest schedules.
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Task {
+    Gc,           // Drops stale rate limit windows and duplicate submissions
+    ColdStorage,  // Archives eligible capsules with `cold_storage.auto`
+    Retention,    // Deletes the items of capsules past their retention policy
+    Stats,        // Takes the day's growth snapshot
+}
+
+const TASKS: [Task; 4] = [Task::Gc, Task::ColdStorage, Task::Retention, Task::Stats];
+
+impl Task {
+    fn name(self) -> &'static str {
+        match self {
+            Task::Gc => "gc",
+            Task::ColdStorage => "cold_storage",
+            Task::Retention => "retention",
+            Task::Stats => "stats",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Task> {
+        TASKS.into_iter().find(|task| task.name() == name)
+    }
+
+    fn run(self, clock: &dyn Clock) {
+        match self {
+            Task::Gc => {
+                let now = clock.now();
+                tokens::sweep(now);
+                duplicates::sweep(now);
+            },
+            Task::ColdStorage => cold_storage::archive_due(clock),
+            Task::Retention => retention::run_due(clock),
+            Task::Stats => stats::snapshot_due(clock),
+        }
+    }
+}
+
+// A five field cron expression (minute, hour, day of month, month, day of week) in UTC.
+// Fields take `*`, numbers, ranges and lists, each with an optional `/step`; Sunday is 0
+// or 7. Like cron, a day matches either field when both day fields are restricted.
+#[derive(Clone)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn number(text: &str) -> Result<u32, String> {
+    text.parse().map_err(|_| format!("'{}' is not a number", text))
+}
+
+fn field(text: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, number(step).ok().filter(|step| *step > 0).ok_or_else(|| format!("'{}' has an invalid step", part))?),
+            None => (part, 1),
+        };
+        let (low, high) = if range == "*" {
+            (min, max)
+        } else if let Some((low, high)) = range.split_once('-') {
+            (number(low)?, number(high)?)
+        } else {
+            // `5/15` runs from 5 to the end of the range
+            let value = number(range)?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if low < min || high > max || low > high {
+            return Err(format!("'{}' is outside {}-{}", part, min, max));
+        }
+        for value in (low..=high).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    fn parse(text: &str) -> Result<Cron, String> {
+        let expanded = match text {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            _ => text,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err("expected five fields: minute hour day month weekday".into());
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        if has(weekdays, 7) {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let cron = Cron {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if cron.next_after(Utc::now()).is_none() {
+            return Err("it never runs".into());
+        }
+        Ok(cron)
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+
+    // The first minute after `time` the schedule runs at
+    fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).map(|time| time.and_utc());
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        for _ in 0..MAX_STEPS {
+            if !has(self.months, next.month()) {
+                let (year, month) = if next.month() == 12 { (next.year() + 1, 1) } else { (next.year(), next.month() + 1) };
+                next = midnight(NaiveDate::from_ymd_opt(year, month, 1)?)?;
+            } else if !self.day_matches(next) {
+                next = midnight(next.date_naive().succ_opt()?)?;
+            } else if !has(self.hours, next.hour()) {
+                next = next.with_minute(0)? + Duration::hours(1);
+            } else if !has(self.minutes, next.minute()) {
+                next += Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+        None
+    }
+}
+
+struct Schedule {
+    text: String,
+    cron: Option<Cron>,                // None when switched off
+    since: Option<DateTime<Utc>>,      // Last run or change, runs are looked for after it
+    last_run: Option<DateTime<Utc>>,
+    changed: bool,                     // Set with PUT /admin/schedules rather than the config
+}
+
+impl Schedule {
+    fn parse(text: &str) -> Result<Schedule, String> {
+        let text = text.trim();
+        let cron = if text == OFF { None } else { Some(Cron::parse(text)?) };
+        Ok(Schedule { text: text.to_string(), cron, since: None, last_run: None, changed: false })
+    }
+
+    fn configured(task: Task) -> Schedule {
+        let config = &config::get().schedules;
+        let text = match task {
+            Task::Gc => &config.gc,
+            Task::ColdStorage => &config.cold_storage,
+            Task::Retention => &config.retention,
+            Task::Stats => &config.stats,
+        };
+        Schedule::parse(text).unwrap_or_else(|e| panic!("Invalid schedule for {} in the config: {}", task.name(), e))
+    }
+}
+
+static SCHEDULES: Lazy<RwLock<BTreeMap<Task, Schedule>>> = Lazy::new(|| {
+    RwLock::new(TASKS.into_iter().map(|task| (task, Schedule::configured(task))).collect())
+});
+
+// Whether the task's next run has come, recorded as its run if so. A clock moved back
+// before the last run starts the schedule over from there.
+fn due(task: Task, now: DateTime<Utc>) -> bool {
+    let mut schedules = SCHEDULES.write().unwrap();
+    let Some(schedule) = schedules.get_mut(&task) else { return false };
+    let Some(cron) = &schedule.cron else { return false };
+    let since = *schedule.since.get_or_insert(now);
+    if now < since {
+        schedule.since = Some(now);
+        return false;
+    }
+    if cron.next_after(since).is_none_or(|next| next > now) {
+        return false;
+    }
+    schedule.since = Some(now);
+    schedule.last_run = Some(now);
+    true
+}
+
+// Runs the maintenance tasks whose time has come, in the order of `TASKS`. Called by the
+// scheduler; the schedules aren't held while a task runs.
+pub fn run_due(clock: &dyn Clock) {
+    let now = clock.now();
+    for task in TASKS {
+        if due(task, now) {
+            task.run(clock);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TaskSchedule {
+    pub task: Task,
+    pub schedule: String,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub last_run: Option<DateTime<Utc>>,  // Since the server started
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub next_run: Option<DateTime<Utc>>,  // None when switched off
+}
+
+fn list(now: DateTime<Utc>) -> Vec<TaskSchedule> {
+    SCHEDULES.read().unwrap().iter()
+        .map(|(&task, schedule)| TaskSchedule {
+            task,
+            schedule: schedule.text.clone(),
+            last_run: schedule.last_run,
+            next_run: schedule.cron.as_ref().and_then(|cron| cron.next_after(schedule.since.unwrap_or(now))),
+        })
+        .collect()
+}
+
+#[get("/admin/schedules")]
+pub fn get_schedules(clock: &State<SharedClock>) -> Json<Vec<TaskSchedule>> {
+    Json(list(clock.now()))
+}
+
+// Changes the schedules of the tasks in the body, by name, and leaves the others. All or
+// nothing: one invalid schedule rejects the whole update.
+#[put("/admin/schedules", format = "json", data = "<schedules_data>")]
+pub fn update_schedules(schedules_data: Json<HashMap<String, String>>, clock: &State<SharedClock>) -> Result<Json<Vec<TaskSchedule>>, status::Custom<Json<String>>> {
+    let now = clock.now();
+    let mut updates = Vec::new();
+    for (name, text) in schedules_data.into_inner() {
+        let task = Task::parse(&name).ok_or_else(|| status::Custom(Status::BadRequest, Json(
+            format!("Unknown task '{}', use {}", name, TASKS.map(Task::name).join(", ")))))?;
+        let schedule = Schedule::parse(&text)
+            .map_err(|e| status::Custom(Status::BadRequest, Json(format!("Invalid schedule for {}: {}", name, e))))?;
+        updates.push((task, schedule));
+    }
+
+    let mut schedules = SCHEDULES.write().unwrap();
+    for (task, mut schedule) in updates {
+        // The next run is looked for from now, the last one is still shown
+        schedule.since = Some(now);
+        schedule.last_run = schedules.get(&task).and_then(|old| old.last_run);
+        schedule.changed = true;
+        schedules.insert(task, schedule);
+        state::changed("schedules", task.name());
+    }
+    drop(schedules);
+    Ok(Json(list(now)))
+}
+
+// The schedules changed at runtime as state entries by task, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    SCHEDULES.read().unwrap().iter()
+        .filter(|(_, schedule)| schedule.changed)
+        .map(|(task, schedule)| (task.name().to_string(), serde_json::Value::String(schedule.text.clone())))
+        .collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    let task = Task::parse(key)?;
+    SCHEDULES.read().unwrap().get(&task)
+        .filter(|schedule| schedule.changed)
+        .map(|schedule| serde_json::Value::String(schedule.text.clone()))
+}
+
+// Without an entry the task goes back to its schedule in the config
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let Some(task) = Task::parse(key) else { return Ok(()) };
+    let schedule = match entry {
+        Some(entry) => {
+            let text: String = state::from_entry(entry)?;
+            let mut schedule = Schedule::parse(&text).map_err(rocket::serde::de::Error::custom)?;
+            schedule.changed = true;
+            schedule
+        },
+        None => Schedule::configured(task),
+    };
+    let mut schedules = SCHEDULES.write().unwrap();
+    let last_run = schedules.get(&task).and_then(|old| old.last_run);
+    schedules.insert(task, Schedule { last_run, ..schedule });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn fields_take_ranges_steps_and_lists() {
+        assert_eq!(field("*", 0, 3), Ok(0b1111));
+        assert_eq!(field("1-3", 0, 59), Ok(0b1110));
+        assert_eq!(field("*/20", 0, 59), Ok((1 << 0) | (1 << 20) | (1 << 40)));
+        assert_eq!(field("50/5", 0, 59), Ok((1 << 50) | (1 << 55)));
+        assert_eq!(field("1,5", 0, 59), Ok((1 << 1) | (1 << 5)));
+        assert!(field("60", 0, 59).is_err());
+        assert!(field("5-1", 0, 59).is_err());
+        assert!(field("*/0", 0, 59).is_err());
+        assert!(field("a", 0, 59).is_err());
+    }
+
+    #[test]
+    fn schedules_run_at_the_next_matching_minute() {
+        let daily = Cron::parse("@daily").unwrap();
+        assert_eq!(daily.next_after(at("2024-01-01T10:15:30Z")), Some(at("2024-01-02T00:00:00Z")));
+        let mondays = Cron::parse("30 9 * * 1").unwrap();
+        assert_eq!(mondays.next_after(at("2024-01-07T12:00:00Z")), Some(at("2024-01-08T09:30:00Z")));
+        // The run itself isn't due again
+        assert_eq!(mondays.next_after(at("2024-01-08T09:30:00Z")), Some(at("2024-01-15T09:30:00Z")));
+    }
+
+    #[test]
+    fn sunday_is_0_or_7() {
+        let sundays = Cron::parse("0 0 * * 7").unwrap();
+        assert_eq!(sundays.weekdays, 1);
+        assert_eq!(sundays.next_after(at("2024-01-01T00:00:00Z")), Some(at("2024-01-07T00:00:00Z")));
+    }
+
+    #[test]
+    fn a_day_and_a_weekday_match_either() {
+        let cron = Cron::parse("0 0 13 * 5").unwrap();
+        assert_eq!(cron.next_after(at("2024-01-01T00:00:00Z")), Some(at("2024-01-05T00:00:00Z")));
+        assert_eq!(cron.next_after(at("2024-01-12T00:00:00Z")), Some(at("2024-01-13T00:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(Cron::parse("* * *").is_err());
+        assert!(Cron::parse("61 * * * *").is_err());
+        assert!(Cron::parse("0 0 31 2 *").is_err());
+        assert!(Schedule::parse(" off ").is_ok_and(|schedule| schedule.cron.is_none()));
+    }
+}
@@ -0,0 +1,45 @@
+// Text search over capsules and items. Both the query and the searched text are folded
+// the same way: transliterated to ASCII, which drops accents and spells other scripts in
+// Latin letters, then lowercased. So "zoe" finds "Zoë" and "rik" finds "Рік".
+use deunicode::deunicode;
+
+// Folded form of a text, with runs of whitespace collapsed to single spaces
+pub fn normalize(text: &str) -> String {
+    deunicode(text).to_lowercase().split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A search query, matching texts that contain every one of its words
+pub struct Query(Vec<String>);
+
+impl Query {
+    // None for a query without any words, which shouldn't filter anything
+    pub fn parse(query: &str) -> Option<Query> {
+        let words: Vec<String> = normalize(query).split(' ').filter(|word| !word.is_empty()).map(str::to_string).collect();
+        (!words.is_empty()).then_some(Query(words))
+    }
+
+    pub fn matches<'a>(&self, texts: impl IntoIterator<Item = &'a str>) -> bool {
+        let text = texts.into_iter().map(normalize).collect::<Vec<_>>().join(" ");
+        self.0.iter().all(|word| text.contains(word.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texts_are_folded_to_lowercase_ascii() {
+        assert_eq!(normalize("Zoë"), "zoe");
+        assert_eq!(normalize("Рік"), "rik");
+        assert_eq!(normalize("  Summer \n 2024 "), "summer 2024");
+    }
+
+    #[test]
+    fn queries_match_texts_with_every_word() {
+        let query = Query::parse("zoe  SUMMER").unwrap();
+        assert!(query.matches(["Zoë's", "summer photos"]));
+        assert!(!query.matches(["Zoë's winter photos"]));
+        assert!(Query::parse("   ").is_none());
+    }
+}
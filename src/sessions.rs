@@ -0,0 +1,67 @@
+// Sessions: every device a contributor signs in from holds an API key of its own, so the
+// keys are the sessions. They're listed with the device that last used them, and a lost
+// phone is signed out by revoking its session, which revokes its key.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::status;
+use rocket::Request;
+use chrono::{DateTime, Utc};
+
+use crate::time_format;
+use crate::tokens::{self, Caller};
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Session {
+    pub id: u32,  // Of the API key
+    pub name: String,
+    pub prefix: String,
+    pub device: Option<String>,  // User-Agent of the last request
+    #[serde(serialize_with = "time_format::time")]
+    pub created_at: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub last_used: Option<DateTime<Utc>>,
+    pub current: bool,  // The session of this request
+}
+
+// The API key the request came with
+pub struct CurrentKey(Option<u32>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CurrentKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(CurrentKey(tokens::key_of_request(request)))
+    }
+}
+
+// The caller's sessions, most recently used first
+#[get("/auth/sessions")]
+pub fn list_sessions(caller: Caller, current: CurrentKey) -> Result<Json<Vec<Session>>, status::Custom<Json<String>>> {
+    let caller = caller.required()?;
+    let mut sessions: Vec<Session> = tokens::keys_of(caller).into_iter()
+        .map(|token| Session {
+            id: token.id,
+            name: token.name,
+            prefix: token.prefix,
+            device: token.device,
+            created_at: token.created_at,
+            last_used: token.last_used,
+            current: current.0 == Some(token.id),
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.last_used.cmp(&a.last_used).then(b.id.cmp(&a.id)));
+    Ok(Json(sessions))
+}
+
+// Signs a device out, this one too
+#[delete("/auth/sessions/<id>")]
+pub fn end_session(id: u32, caller: Caller) -> Result<Status, status::Custom<Json<String>>> {
+    let caller = caller.required()?;
+    if !tokens::revoke(caller, id) {
+        return Err(status::Custom(Status::NotFound, Json(format!("You have no session {}", id))));
+    }
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,151 @@
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::ContentType;
+use rocket::{Request, State};
+use rocket::request::{self, FromRequest};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::config;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::ITEMS;
+use crate::reveals;
+use crate::moderation;
+use crate::ownership;
+use crate::state;
+use crate::time_format;
+use crate::widgets::escape;
+use crate::i18n::AcceptLanguage;
+use crate::ids::CapsuleId;
+use crate::tokens::Caller;
+
+// Unguessable link to a capsule that can be handed out to people outside the app
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ShareLink {
+    pub token: String,
+    pub capsule_id: CapsuleId,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_created: DateTime<Utc>,
+}
+
+pub static SHARES: Lazy<RwLock<HashMap<String, ShareLink>>> = Lazy::new(|| {
+    RwLock::new(HashMap::new())
+});
+
+// The capsule a share token points to, if both still exist
+pub fn shared_capsule(token: &str) -> Option<Capsule> {
+    let capsule_id = SHARES.read().unwrap().get(token)?.capsule_id;
+    CAPSULES.get(capsule_id).filter(|capsule| !moderation::is_hidden(capsule))
+}
+
+// Only the capsule's owner or co-owners hand out links to it
+#[post("/capsules/<cid>/shares")]
+pub fn create_share(cid: CapsuleId, caller: Caller) -> Result<Json<ShareLink>, ApiError> {
+    CAPSULES.read(cid, |capsule| ownership::check_editor(capsule, &caller))
+        .ok_or(ApiError::CapsuleNotFound(cid))??;
+
+    Ok(Json(new_share(cid)))
+}
+
+// Issues a new share token for a capsule
+pub fn new_share(cid: CapsuleId) -> ShareLink {
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let share = ShareLink { token: token.clone(), capsule_id: cid, time_created: Utc::now() };
+    SHARES.write().unwrap().insert(token.clone(), share.clone());
+    state::changed("shares", token);
+    share
+}
+
+// The links as state entries by token, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    SHARES.read().unwrap().iter().map(|(token, share)| (token.clone(), state::to_entry(share))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    SHARES.read().unwrap().get(key).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let share: Option<ShareLink> = entry.map(state::from_entry).transpose()?;
+    let mut shares = SHARES.write().unwrap();
+    match share {
+        Some(share) => shares.insert(key.to_string(), share),
+        None => shares.remove(key),
+    };
+    Ok(())
+}
+
+// Base URL for absolute links, `public_url` from the config or the request's Host header
+pub struct BaseUrl(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let base = match (&config::get().public_url, request.host()) {
+            (Some(url), _) => url.trim_end_matches('/').to_string(),
+            (None, Some(host)) => format!("http://{}", host),
+            (None, None) => String::new(),
+        };
+        request::Outcome::Success(BaseUrl(base))
+    }
+}
+
+// Cover image of an opened capsule, its first photo that has a public URL
+fn cover_url(capsule_id: CapsuleId) -> Option<String> {
+    let item_ids = reveals::visible(capsule_id, INDEXES.read().unwrap().items_of(capsule_id));
+    item_ids.into_iter()
+        .filter_map(|id| ITEMS.get(id))
+        .find(|item| item.type_c == "photo" && (item.path.starts_with("https://") || item.path.starts_with("http://")))
+        .map(|item| item.path)
+}
+
+// Open Graph tags for link unfurling. Until the capsule opens only its name and the
+// countdown are shown, the description and items stay hidden.
+#[get("/shared/<token>/preview")]
+pub fn share_preview(token: &str, base: BaseUrl, languages: AcceptLanguage, clock: &State<SharedClock>) -> Option<(ContentType, String)> {
+    let capsule = shared_capsule(token)?.localized(&languages.0);
+    let is_open = clock.now() >= capsule.time_open;
+
+    let description = if is_open {
+        capsule.description.default_text().to_string()
+    } else {
+        format!("A time capsule opening on {}", capsule.time_open.format("%B %-d, %Y"))
+    };
+    let image = is_open.then(|| cover_url(capsule.id)).flatten()
+        .unwrap_or_else(|| format!("{}/capsules/{}/widget.svg", base.0, capsule.id));
+    let url = format!("{}/shared/{}/preview", base.0, token);
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<meta property="og:type" content="website">
+<meta property="og:title" content="{title}">
+<meta property="og:description" content="{description}">
+<meta property="og:image" content="{image}">
+<meta property="og:url" content="{url}">
+<meta name="twitter:card" content="summary_large_image">
+</head>
+<body>
+<h1>{title}</h1>
+<p>{description}</p>
+</body>
+</html>"#,
+        title = escape(capsule.name.default_text()),
+        description = escape(&description),
+        image = escape(&image),
+        url = escape(&url),
+    );
+    Some((ContentType::HTML, html))
+}
@@ -0,0 +1,153 @@
+// Co-signing: contributors invited on a capsule sign it with a short message before
+// it is sealed, each with their own API key. A capsule is sealed once its edit window
+// has closed, and when it requires signatures, only after enough of the invited signers
+// have signed. Signatures are stored with the capsule, but left out of it when it's
+// shown; the messages stay hidden until the capsule opens.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::locks;
+use crate::retention;
+use crate::store;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::time_format;
+use crate::tokens::Caller;
+
+const MAX_MESSAGE_LEN: usize = 500;
+
+// Who is asked to sign a capsule and how many signatures it needs to seal
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Signing {
+    pub signers: Vec<ContributorId>,  // Invited contributor ids
+    pub required: u32,
+    #[serde(default, skip_serializing_if = "store::answering")]
+    pub signatures: Vec<Signature>,  // Shown through /signatures only, see `summary`
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Signature {
+    pub contributor_id: ContributorId,
+    pub message: String,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_signed: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewSignature {
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Signatures {
+    pub signers: Vec<ContributorId>,
+    pub required: u32,
+    pub count: usize,
+    pub sealed: bool,
+    pub signatures: Option<Vec<Signature>>,  // Revealed once the capsule opens
+}
+
+// Signing settings for a new capsule, None when nobody is invited
+pub fn new_signing(signers: &[ContributorId], required: Option<u32>) -> Result<Option<Signing>, status::Custom<Json<String>>> {
+    let mut signers = signers.to_vec();
+    signers.sort();
+    signers.dedup();
+    let required = required.unwrap_or(0);
+    if required as usize > signers.len() {
+        return Err(status::Custom(Status::BadRequest, Json(format!("required_signatures can be at most the {} invited signers", signers.len()))));
+    }
+    if let Some(missing) = signers.iter().find(|&&id| !CONTRIBUTORS.contains(id)) {
+        return Err(status::Custom(Status::BadRequest, Json(format!("Signer {} is not a contributor", missing))));
+    }
+    if signers.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Signing { signers, required, signatures: Vec::new() }))
+}
+
+impl Signing {
+    // Signatures of contributors who are still invited
+    fn signed(&self) -> impl Iterator<Item = &Signature> {
+        self.signatures.iter().filter(|signature| self.signers.contains(&signature.contributor_id))
+    }
+}
+
+// Whether enough of the invited signers have signed, always when nobody is invited
+fn has_signatures(capsule: &Capsule) -> bool {
+    capsule.signing.as_ref().is_none_or(|signing| signing.signed().count() >= signing.required as usize)
+}
+
+// Sealed capsules can no longer be changed, merged, deleted or signed
+pub fn is_sealed(capsule: &Capsule, now: DateTime<Utc>) -> bool {
+    now > capsule.time_until_changed && has_signatures(capsule)
+}
+
+// Items follow the contribution deadline instead, which may be later than the seal
+pub fn contributions_closed(capsule: &Capsule, now: DateTime<Utc>) -> bool {
+    (now > capsule.contributions_deadline() && has_signatures(capsule)) || retention::items_read_only(capsule, now)
+}
+
+// Signs as the caller, who has to be invited
+#[post("/capsules/<cid>/signatures", format = "json", data = "<signature>")]
+pub fn sign_capsule(cid: CapsuleId, signature: Json<NewSignature>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<Signatures>, ApiError> {
+    let contributor_id = caller.0
+        .ok_or_else(|| status::Custom(Status::Unauthorized, Json("Send the signer's API key".into())))?;
+    let _guard = locks::lock_capsule(cid);
+    let now = clock.now();
+
+    let capsule = CAPSULES.get(cid)
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    let invited = capsule.signing.as_ref().is_some_and(|signing| signing.signers.contains(&contributor_id));
+    if !invited {
+        return Err(ApiError::NotInvitedSigner);
+    }
+    if is_sealed(&capsule, now) {
+        return Err(ApiError::Other(Status::Conflict, "The capsule is already sealed".into()));
+    }
+
+    let message = signature.message.trim();
+    if message.is_empty() || message.chars().count() > MAX_MESSAGE_LEN {
+        return Err(ApiError::Other(Status::BadRequest, format!("The message must be between 1 and {} characters", MAX_MESSAGE_LEN)));
+    }
+
+    if capsule.signing.as_ref().is_some_and(|signing| signing.signatures.iter().any(|s| s.contributor_id == contributor_id)) {
+        return Err(ApiError::AlreadySigned);
+    }
+
+    let capsule = CAPSULES.update(cid, |capsule| {
+        if let Some(signing) = &mut capsule.signing {
+            signing.signatures.push(Signature { contributor_id, message: message.to_string(), time_signed: now });
+        }
+        capsule.clone()
+    })
+        .ok_or(ApiError::CapsuleNotFound(cid))?;
+    Ok(Json(summary(&capsule, now)))
+}
+
+#[get("/capsules/<cid>/signatures")]
+pub fn get_signatures(cid: CapsuleId, clock: &State<SharedClock>) -> Option<Json<Signatures>> {
+    let capsule = CAPSULES.get(cid)?;
+    Some(Json(summary(&capsule, clock.now())))
+}
+
+fn summary(capsule: &Capsule, now: DateTime<Utc>) -> Signatures {
+    let (signers, required, signatures) = capsule.signing.as_ref()
+        .map_or((Vec::new(), 0, Vec::new()), |signing| (signing.signers.clone(), signing.required, signing.signed().cloned().collect()));
+    Signatures {
+        signers,
+        required,
+        count: signatures.len(),
+        sealed: is_sealed(capsule, now),
+        signatures: (now >= capsule.time_open).then_some(signatures),
+    }
+}
@@ -0,0 +1,138 @@
+// A dry run of a capsule's opening for its owner: what the recipients will see at the
+// open time, how the reveal ceremony continues from there and which emails and webhooks
+// will go out, so the capsule can be checked before it's sealed. Nothing is changed, no
+// share link is issued and nothing is sent.
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::i18n::AcceptLanguage;
+use crate::ids::{CapsuleId, ItemId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::letters::{self, DeliveryStatus};
+use crate::open_notices::Channel;
+use crate::ownership;
+use crate::retention;
+use crate::reveals::{self, RevealStatus};
+use crate::time_format;
+use crate::tokens::Caller;
+use crate::webhooks;
+
+// Stands in for the share token, which is only issued when the emails are sent
+const SHARE_TOKEN_PLACEHOLDER: &str = "{share_token}";
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "channel", rename_all = "snake_case")]
+pub enum Notification {
+    Email {
+        #[serde(serialize_with = "time_format::time")]
+        time: DateTime<Utc>,
+        to: String,
+        subject: String,
+        body: String,
+    },
+    Webhook {
+        #[serde(serialize_with = "time_format::time")]
+        time: DateTime<Utc>,
+        webhook_id: u32,
+        url: String,
+        event: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        item_id: Option<ItemId>,
+    },
+}
+
+impl Notification {
+    fn time(&self) -> DateTime<Utc> {
+        match self {
+            Notification::Email { time, .. } | Notification::Webhook { time, .. } => *time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenSimulation {
+    #[serde(serialize_with = "time_format::time")]
+    pub time_open: DateTime<Utc>,
+    pub capsule: Capsule,
+    pub items: Vec<Item>,                            // Visible right at the open time
+    pub reveal: Option<RevealStatus>,                // The whole ceremony, with the later steps
+    pub notifications: Vec<Notification>,            // In the order they go out
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub items_deleted_at: Option<DateTime<Utc>>,     // When the retention policy deletes the items
+}
+
+fn simulate(capsule: &Capsule, languages: &[String]) -> OpenSimulation {
+    let item_ids = reveals::visible_at_open(capsule, INDEXES.read().unwrap().items_of(capsule.id));
+    let items: Vec<Item> = item_ids.into_iter().filter_map(|id| ITEMS.get(id)).collect();
+
+    let mut notifications = Vec::new();
+    if let Some(delivery) = &capsule.delivery {
+        let share_url = letters::share_url(delivery.share_token.as_deref().unwrap_or(SHARE_TOKEN_PLACEHOLDER));
+        let (subject, body) = letters::render_email(capsule, &items, share_url.as_deref());
+        for recipient in delivery.recipients.iter().filter(|recipient| recipient.status == DeliveryStatus::Pending) {
+            notifications.push(Notification::Email {
+                time: capsule.time_open,
+                to: recipient.email.clone(),
+                subject: subject.clone(),
+                body: letters::greet(recipient.name.as_deref(), &body),
+            });
+        }
+    }
+    // The owner and co-owners are told too, through the channels they picked
+    let (subject, body) = letters::render_email(capsule, &items, None);
+    let opened_webhooks = webhooks::receiving(capsule, "capsule.opened");
+    for contributor_id in std::iter::once(capsule.contributor_id).chain(capsule.co_owner_ids.iter().copied()) {
+        let Some((email, name, channels)) = CONTRIBUTORS.read(contributor_id, |contributor| (contributor.email.clone(), contributor.name.clone(), contributor.open_channels.clone())) else { continue };
+        if channels.contains(&Channel::Email) {
+            notifications.push(Notification::Email { time: capsule.time_open, to: email, subject: subject.clone(), body: letters::greet(Some(&name), &body) });
+        }
+        if channels.contains(&Channel::Webhook) {
+            for webhook in opened_webhooks.iter().filter(|webhook| webhook.contributor_id == contributor_id) {
+                notifications.push(Notification::Webhook { time: capsule.time_open, webhook_id: webhook.id, url: webhook.url.clone(), event: "capsule.opened", item_id: None });
+            }
+        }
+    }
+    let reveal = capsule.reveal.as_ref().map(|reveal| reveals::reveal_status(capsule, reveal));
+    let receiving = webhooks::receiving(capsule, "item.revealed");
+    for step in reveal.iter().flat_map(|reveal| &reveal.steps).filter(|step| step.time_revealed.is_none()) {
+        for webhook in &receiving {
+            notifications.push(Notification::Webhook {
+                time: step.reveal_at,
+                webhook_id: webhook.id,
+                url: webhook.url.clone(),
+                event: "item.revealed",
+                item_id: Some(step.item_id),
+            });
+        }
+    }
+    notifications.sort_by_key(Notification::time);
+
+    OpenSimulation {
+        time_open: capsule.time_open,
+        capsule: capsule.localized(languages),
+        items,
+        reveal,
+        notifications,
+        items_deleted_at: retention::delete_at(capsule),
+    }
+}
+
+// Only for capsules that haven't opened yet, later the real thing can be looked at
+#[post("/capsules/<cid>/simulate-open")]
+pub fn simulate_open(cid: CapsuleId, caller: Caller, languages: AcceptLanguage, clock: &State<SharedClock>) -> Result<Json<OpenSimulation>, ApiError> {
+    let now = clock.now();
+    CAPSULES.read(cid, |capsule| {
+        ownership::check_owner(capsule, &caller)?;
+        if capsule.time_open <= now {
+            return Err(ApiError::CapsuleOpened(cid));
+        }
+        Ok(Json(simulate(capsule, &languages.0)))
+    }).unwrap_or(Err(ApiError::CapsuleNotFound(cid)))
+}
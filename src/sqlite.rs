@@ -0,0 +1,46 @@
+// SQLite, with `storage.backend = "sqlite"`: a database file next to the data files, for a
+// single instance. Writing and loading are the same for every database, see database.rs.
+use rocket_db_pools::sqlx::{self, Executor, Row, SqlitePool};
+use rocket_db_pools::Pool;
+use std::fs;
+
+use crate::config;
+use crate::database;
+
+// Applied in order to a database at `user_version` below their position, each once
+const MIGRATIONS: &[&str] = &[
+    // 1: Rows as JSON, with the ids they're looked up by
+    "CREATE TABLE contributors (id INTEGER PRIMARY KEY, data TEXT NOT NULL);
+     CREATE TABLE capsules (id INTEGER PRIMARY KEY, contributor_id INTEGER NOT NULL, data TEXT NOT NULL);
+     CREATE INDEX capsules_contributor ON capsules (contributor_id);
+     CREATE TABLE items (id INTEGER PRIMARY KEY, capsule_id INTEGER NOT NULL, data TEXT NOT NULL);
+     CREATE INDEX items_capsule ON items (capsule_id);",
+    // 2: Entries of the state collections
+    "CREATE TABLE state (collection TEXT NOT NULL, key TEXT NOT NULL, data TEXT NOT NULL, PRIMARY KEY (collection, key));",
+    // 3: Capsule versions, compared and moved on in the database, see database::claim_version
+    "ALTER TABLE capsules ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+     UPDATE capsules SET version = COALESCE(json_extract(data, '$.version'), 0);",
+];
+
+// Migrates the database, returns whether it was new
+async fn migrate(pool: &SqlitePool) -> Result<bool, sqlx::Error> {
+    let version: i64 = sqlx::query("PRAGMA user_version").fetch_one(pool).await?.get(0);
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let mut transaction = pool.begin().await?;
+        transaction.execute(*migration).await?;
+        transaction.execute(format!("PRAGMA user_version = {}", index + 1).as_str()).await?;
+        transaction.commit().await?;
+    }
+    Ok(version == 0)
+}
+
+// Opens and migrates the database, returns whether it was new
+pub async fn open() -> Result<(SqlitePool, bool), sqlx::Error> {
+    let _ = fs::create_dir_all(config::data_path(""));
+    // A file in `data_dir` unless `url` is set
+    let url = format!("sqlite://{}", config::data_path("capsules.db").display());
+    let pool = <SqlitePool as Pool>::init(&database::figment(Some(url))).await
+        .unwrap_or_else(|e| panic!("Failed to open the database: {:?}", e));
+    let fresh = migrate(&pool).await?;
+    Ok((pool, fresh))
+}
@@ -0,0 +1,106 @@
+// State kept next to the records: API keys with their emailed codes, webhooks, read receipts, ownership requests,
+// merge records, feature flags, abuse reports, share links, the capsules' hash chains, the audit log and the schedules
+// changed with PUT /admin/schedules. Each is a collection of JSON entries by key, so the
+// storage backends can keep them without knowing their types. The modules owning them
+// report every changed entry with `changed`. With the memory backend a collection is
+// loaded from `<name>.json` in `data_dir` when it's there, an object of entries by key,
+// and written back to it by persistence.rs. With a database storage.backend the entries
+// are written to its `state` table like rows, and other instances read them back.
+use rocket::serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config;
+use crate::audit;
+use crate::database;
+use crate::flags;
+use crate::hash_chain;
+use crate::merges;
+use crate::moderation;
+use crate::ownership;
+use crate::reads;
+use crate::schedules;
+use crate::shares;
+use crate::store;
+use crate::tokens;
+use crate::webhooks;
+
+pub struct Collection {
+    pub name: &'static str,
+    pub entries: fn() -> Vec<(String, Value)>,  // All entries, by key
+    pub entry: fn(&str) -> Option<Value>,  // One entry as it is now, None once it's gone
+    pub put: fn(&str, Option<Value>) -> Result<(), serde_json::Error>,  // Replaces or removes one entry
+}
+
+pub static COLLECTIONS: [Collection; 12] = [
+    Collection { name: "tokens", entries: tokens::entries, entry: tokens::entry, put: tokens::put },
+    Collection { name: "token_codes", entries: tokens::code_entries, entry: tokens::code_entry, put: tokens::put_code },
+    Collection { name: "webhooks", entries: webhooks::entries, entry: webhooks::entry, put: webhooks::put },
+    Collection { name: "reads", entries: reads::entries, entry: reads::entry, put: reads::put },
+    Collection { name: "ownership_requests", entries: ownership::entries, entry: ownership::entry, put: ownership::put },
+    Collection { name: "merges", entries: merges::entries, entry: merges::entry, put: merges::put },
+    Collection { name: "flags", entries: flags::entries, entry: flags::entry, put: flags::put },
+    Collection { name: "reports", entries: moderation::entries, entry: moderation::entry, put: moderation::put },
+    Collection { name: "shares", entries: shares::entries, entry: shares::entry, put: shares::put },
+    Collection { name: "hash_chains", entries: hash_chain::entries, entry: hash_chain::entry, put: hash_chain::put },
+    Collection { name: "audit_log", entries: audit::entries, entry: audit::entry, put: audit::put },
+    Collection { name: "schedules", entries: schedules::entries, entry: schedules::entry, put: schedules::put },
+];
+
+// Counts changes of the entries, like the tables' revision in store.rs
+static REVISION: AtomicU64 = AtomicU64::new(1);
+
+pub fn current_revision() -> u64 {
+    REVISION.load(Ordering::SeqCst)
+}
+
+pub fn collection(name: &str) -> Option<&'static Collection> {
+    COLLECTIONS.iter().find(|collection| collection.name == name)
+}
+
+// An entry as it's stored, with the fields responses leave out
+pub fn to_entry<T: Serialize>(value: &T) -> Value {
+    store::storing(|| serde_json::to_value(value)).expect("State serializes to JSON")
+}
+
+pub fn from_entry<T: DeserializeOwned>(entry: Value) -> Result<T, serde_json::Error> {
+    store::loading(|| serde_json::from_value(entry))
+}
+
+// Reports a changed, added or removed entry
+pub fn changed(collection: &'static str, key: impl ToString) {
+    REVISION.fetch_add(1, Ordering::SeqCst);
+    database::entry_changed(collection, key.to_string());
+}
+
+// The entries of the collection files in `data_dir`, by collection and key
+pub fn read_files() -> Vec<(&'static str, String, Value)> {
+    let mut entries = Vec::new();
+    for collection in &COLLECTIONS {
+        let path = config::data_path(&format!("{}.json", collection.name));
+        if !path.is_file() {
+            continue;
+        }
+        let json = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+        let file: Map<String, Value> = serde_json::from_str(&json).unwrap_or_else(|e| panic!("Invalid format in {}: {}", path.display(), e));
+        entries.extend(file.into_iter().map(|(key, entry)| (collection.name, key, entry)));
+    }
+    entries
+}
+
+// Puts the entries of the collection files into memory, with the memory backend
+pub fn load_files() {
+    for (name, key, entry) in read_files() {
+        let put = collection(name).map(|collection| collection.put).expect("Entries are read for known collections");
+        put(&key, Some(entry)).unwrap_or_else(|e| panic!("Invalid entry {} in {}.json: {}", key, name, e));
+    }
+}
+
+// A new id for an entry, taken from the database when instances share it so two of them
+// never hand out the same one, otherwise `next` counted up
+pub fn next_id(next: &mut u32) -> u32 {
+    let id = database::next_entry_id().unwrap_or(*next);
+    *next = (*next).max(id + 1);
+    id
+}
@@ -0,0 +1,280 @@
+// Growth over time. Once a day the scheduler takes a snapshot of how many capsules and
+// items there are and how many bytes they take, overall and per contributor, and appends
+// it to `stats.file`. `GET /reports/growth` reads the trend from those snapshots, so it
+// goes back further than the server's uptime and isn't rewritten by later deletions.
+// `GET /public/stats` shows the latest totals to everyone, with a leaderboard of the
+// contributors who opted in, ranked by what they added over the last `activity_days`.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::capsules::CAPSULES;
+use crate::clock::Clock;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::ids::ContributorId;
+use crate::quotas;
+use crate::reports::{self, GroupBy};
+use crate::store;
+use crate::time_format;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct Counts {
+    pub capsules: usize,
+    pub items: usize,  // Items in capsules, orphans aren't counted
+    pub bytes: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.capsules += other.capsules;
+        self.items += other.items;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ContributorCounts {
+    contributor_id: ContributorId,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+// A line of `stats.file`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Snapshot {
+    day: NaiveDate,
+    #[serde(serialize_with = "time_format::time")]
+    time_taken: DateTime<Utc>,
+    total: Counts,
+    contributors: Vec<ContributorCounts>,
+}
+
+#[derive(Default)]
+struct History {
+    snapshots: Vec<Snapshot>,  // Oldest first, one per day
+    file: Option<File>,
+}
+
+static HISTORY: Lazy<Mutex<History>> = Lazy::new(|| Mutex::new(History::load(&config::get().stats.file)));
+
+impl History {
+    fn load(path: &str) -> History {
+        let mut history = History::default();
+        if let Ok(file) = File::open(path) {
+            for (number, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let snapshot: Snapshot = store::loading(|| serde_json::from_str(&line))
+                    .unwrap_or_else(|e| panic!("Invalid snapshot on line {} of {}: {}", number + 1, path, e));
+                history.snapshots.push(snapshot);
+            }
+        }
+        history
+    }
+
+    fn append(&mut self, snapshot: Snapshot) {
+        if self.file.is_none() {
+            let path = Path::new(&config::get().stats.file);
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                let _ = fs::create_dir_all(dir);
+            }
+            self.file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| eprintln!("Failed to open {}: {}", path.display(), e))
+                .ok();
+        }
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&snapshot).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("Failed to record the growth snapshot of {}: {}", snapshot.day, e);
+            }
+        }
+        self.snapshots.push(snapshot);
+    }
+}
+
+fn take_snapshot(now: DateTime<Utc>) -> Snapshot {
+    let mut by_contributor: BTreeMap<ContributorId, Counts> = CONTRIBUTORS.ids().into_iter()
+        .map(|id| (id, Counts::default()))
+        .collect();
+    let mut total = Counts::default();
+    for capsule_id in CAPSULES.ids() {
+        let Some(owner) = CAPSULES.read(capsule_id, |capsule| capsule.contributor_id) else { continue };
+        let limits = quotas::limits(capsule_id);
+        let counts = Counts { capsules: 1, items: limits.items, bytes: limits.bytes };
+        total.add(counts);
+        if let Some(owned) = by_contributor.get_mut(&owner) {
+            owned.add(counts);
+        }
+    }
+    let contributors = by_contributor.into_iter()
+        .map(|(contributor_id, counts)| ContributorCounts { contributor_id, counts })
+        .collect();
+    Snapshot { day: now.date_naive(), time_taken: now, total, contributors }
+}
+
+// Takes the day's snapshot if there isn't one yet. Called by the scheduler.
+pub fn snapshot_due(clock: &dyn Clock) {
+    let now = clock.now();
+    // A clock moved back doesn't add days before the last one
+    let taken = |history: &History| history.snapshots.last().is_some_and(|last| last.day >= now.date_naive());
+    if taken(&HISTORY.lock().unwrap()) {
+        return;
+    }
+    // Counted without holding the history, the tables come first in the lock order
+    let snapshot = take_snapshot(now);
+    let mut history = HISTORY.lock().unwrap();
+    if !taken(&history) {
+        history.append(snapshot);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GrowthPoint {
+    pub period: String,
+    pub day: NaiveDate,  // Of the snapshot, the last one of the period
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GrowthReport {
+    pub contributor_id: Option<ContributorId>,  // None for the whole server
+    pub group_by: String,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub to: Option<DateTime<Utc>>,
+    pub points: Vec<GrowthPoint>,
+}
+
+#[get("/reports/growth?<contributor_id>&<from>&<to>&<group_by>")]
+pub fn growth_report(contributor_id: Option<ContributorId>, from: Option<&str>, to: Option<&str>, group_by: Option<&str>) -> Result<Json<GrowthReport>, ApiError> {
+    let group_name = group_by.unwrap_or("day");
+    let group = GroupBy::parse(group_name)
+        .ok_or(ApiError::InvalidGroupBy)?;
+    let from = reports::parse_bound(from, "from")?;
+    let to = reports::parse_bound(to, "to")?;
+
+    // Only snapshots of days inside [from, to) are used
+    let in_range = |time: DateTime<Utc>| from.is_none_or(|from| time >= from) && to.is_none_or(|to| time < to);
+
+    let mut known = contributor_id.is_none_or(|id| CONTRIBUTORS.contains(id));
+    let history = HISTORY.lock().unwrap();
+    let mut points: BTreeMap<String, GrowthPoint> = BTreeMap::new();
+    for snapshot in &history.snapshots {
+        let Some(time) = snapshot.day.and_hms_opt(0, 0, 0).map(|time| time.and_utc()) else { continue };
+        let counts = match contributor_id {
+            None => Some(snapshot.total),
+            Some(id) => snapshot.contributors.iter().find(|entry| entry.contributor_id == id).map(|entry| entry.counts),
+        };
+        let Some(counts) = counts else { continue };
+        known = true;
+        if in_range(time) {
+            // Later snapshots of the period replace earlier ones, it ends with the last
+            let period = group.period(time);
+            points.insert(period.clone(), GrowthPoint { period, day: snapshot.day, counts });
+        }
+    }
+    if !known {
+        return Err(ApiError::ContributorNotFound);
+    }
+
+    Ok(Json(GrowthReport { contributor_id, group_by: group_name.to_string(), from, to, points: points.into_values().collect() }))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub contributor_id: ContributorId,
+    pub name: String,
+    pub capsules: usize,
+    pub items: usize,
+    pub new_capsules: usize,  // Over the last `activity_days`
+    pub new_items: usize,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicStats {
+    pub day: Option<NaiveDate>,  // Of the latest snapshot, None until the first one is taken
+    pub contributors: usize,
+    #[serde(flatten)]
+    pub total: Counts,
+    pub activity_days: u32,
+    pub leaderboard: Vec<LeaderboardEntry>,
+}
+
+// Contributors of the latest snapshot that added something since the one before the
+// window (or the first, for a shorter history), most new items first
+fn most_active(history: &History, activity_days: u32) -> Vec<(ContributorId, Counts, Counts)> {
+    let Some(latest) = history.snapshots.last() else { return Vec::new() };
+    let start = latest.day - Duration::days(activity_days.into());
+    let baseline = history.snapshots.iter().rev().find(|snapshot| snapshot.day <= start)
+        .unwrap_or(&history.snapshots[0]);
+
+    let mut active: Vec<(ContributorId, Counts, Counts)> = latest.contributors.iter()
+        .filter_map(|entry| {
+            let before = baseline.contributors.iter().find(|old| old.contributor_id == entry.contributor_id)
+                .map_or_else(Counts::default, |old| old.counts);
+            let added = Counts {
+                capsules: entry.counts.capsules.saturating_sub(before.capsules),
+                items: entry.counts.items.saturating_sub(before.items),
+                bytes: entry.counts.bytes.saturating_sub(before.bytes),
+            };
+            (added.capsules > 0 || added.items > 0).then_some((entry.contributor_id, entry.counts, added))
+        })
+        .collect();
+    active.sort_by(|a, b| b.2.items.cmp(&a.2.items).then(b.2.capsules.cmp(&a.2.capsules)).then(a.0.cmp(&b.0)));
+    active
+}
+
+#[get("/public/stats")]
+pub fn public_stats() -> Json<PublicStats> {
+    let config = &config::get().stats;
+    let (day, contributors, total, active) = {
+        let history = HISTORY.lock().unwrap();
+        let latest = history.snapshots.last();
+        (
+            latest.map(|snapshot| snapshot.day),
+            latest.map_or(0, |snapshot| snapshot.contributors.len()),
+            latest.map_or_else(Counts::default, |snapshot| snapshot.total),
+            most_active(&history, config.activity_days),
+        )
+    };
+
+    // Names are read after letting go of the history, the tables come first in the lock order
+    let leaderboard = active.into_iter()
+        .filter_map(|(contributor_id, counts, added)| {
+            let name = CONTRIBUTORS.read(contributor_id, |contributor| contributor.leaderboard.then(|| contributor.name.clone())).flatten()?;
+            Some((contributor_id, name, counts, added))
+        })
+        .take(config.leaderboard_size)
+        .enumerate()
+        .map(|(index, (contributor_id, name, counts, added))| LeaderboardEntry {
+            rank: index + 1,
+            contributor_id,
+            name,
+            capsules: counts.capsules,
+            items: counts.items,
+            new_capsules: added.capsules,
+            new_items: added.items,
+        })
+        .collect();
+
+    Json(PublicStats { day, contributors, total, activity_days: config.activity_days, leaderboard })
+}
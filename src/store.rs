@@ -0,0 +1,456 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard};
+use chrono::{DateTime, Utc};
+
+use crate::config::{self, StorageBackend};
+use crate::ids::{self, EntityId};
+use crate::database::DatabaseStore;
+
+// Anything stored in a Table is keyed by its typed id, see ids.rs
+pub trait Entity {
+    type Id: EntityId;
+
+    fn id(&self) -> Self::Id;
+}
+
+const SHARDS: usize = 16;
+
+// Revisions come from one sequence shared by all tables, so a single number can
+// tell which changes across the whole data set a client has already seen
+static REVISION: AtomicU64 = AtomicU64::new(1);
+
+// The revision the next change will get; every change below it is already in a change log
+pub fn current_revision() -> u64 {
+    REVISION.load(Ordering::SeqCst)
+}
+
+thread_local! {
+    // Set while rows are serialized to be stored rather than answered, see `storing`
+    static STORING: Cell<bool> = const { Cell::new(false) };
+    // Set while stored rows are read back, see `loading`
+    static LOADING: Cell<bool> = const { Cell::new(false) };
+}
+
+struct Restore(&'static std::thread::LocalKey<Cell<bool>>, bool);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        self.0.with(|flag| flag.set(self.1));
+    }
+}
+
+// Runs `f`, in which rows serialize the way they're stored: with the fields responses
+// leave out, like the signatures of a capsule
+pub fn storing<R>(f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(&STORING, STORING.with(|storing| storing.replace(true)));
+    f()
+}
+
+// Runs `f`, in which rows deserialize the way they're stored: with plain numeric ids,
+// which request bodies can't use with the uuid strategy
+pub fn loading<R>(f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(&LOADING, LOADING.with(|loading| loading.replace(true)));
+    f()
+}
+
+pub fn is_loading() -> bool {
+    LOADING.with(Cell::get)
+}
+
+// For `skip_serializing_if` on fields that are stored but never answered
+pub fn answering<T>(_: &T) -> bool {
+    !STORING.with(Cell::get)
+}
+
+// Latest change of a row, kept in the change log under the revision it produced
+#[derive(Clone, Copy)]
+pub struct Change<I> {
+    pub id: I,
+    pub deleted: bool,
+    pub at: DateTime<Utc>,
+}
+
+pub type ChangeLog<I> = BTreeMap<u64, Change<I>>;
+
+// Told about every change with the row's new state, None once it is removed
+type Observer<T> = Box<dyn Fn(<T as Entity>::Id, Option<&T>) + Send + Sync>;
+
+// Where a table keeps its rows, each with the revision of its last change. `Table` adds
+// the change log, tombstones, id sequence and observers on top, so a backend only stores
+// and finds rows and the handlers work with any backend unchanged. The in-memory
+// `MemoryStore` is the default.
+//
+// The callbacks run while the row can't be changed by anyone else, so a read-modify-write
+// through `update` is atomic and observers see the changes to a row in order. Like the
+// closures given to `Table::read`/`update` they must not use the same table.
+pub trait Store<T: Entity>: Send + Sync {
+    fn len(&self) -> usize;
+
+    fn contains(&self, id: T::Id) -> bool;
+
+    // Calls `f` with the row and its revision, false if there is no such row
+    fn read(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> bool;
+
+    // Calls `f` with the row and its revision to change them, false if there is no such row
+    fn update(&self, id: T::Id, f: &mut dyn FnMut(&mut T, &mut u64)) -> bool;
+
+    // Inserts or replaces the row with its id; `revision` gets the row and the revision of
+    // the row it replaces and returns the revision to store it with
+    fn insert(&self, row: T, revision: &mut dyn FnMut(&T, Option<u64>) -> u64);
+
+    // Removes the row, `f` is called with it before anyone can see it gone
+    fn remove(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> Option<(T, u64)>;
+
+    // `count` ids starting at position `start` in id order
+    fn ids(&self, start: usize, count: usize) -> Vec<T::Id>;
+
+    fn last_id(&self) -> Option<T::Id>;
+
+    fn any(&self, predicate: &mut dyn FnMut(&T) -> bool) -> bool;
+
+    fn clear(&self);
+
+    // The id for a new row when the backend hands them out, None leaves it to the table
+    fn next_id(&self) -> Option<T::Id> {
+        None
+    }
+}
+
+// Rows of one shard with their revisions
+type Shard<T> = RwLock<HashMap<<T as Entity>::Id, (T, u64)>>;
+
+// In-memory rows with O(1) lookup by id and an ordered id index for pagination.
+//
+// Rows are spread over independently locked shards so operations on unrelated ids don't
+// contend. Every method takes at most one lock at a time (a shard or the order index,
+// never both); the callbacks run under the row's shard lock.
+pub struct MemoryStore<T: Entity> {
+    shards: Vec<Shard<T>>,
+    order: RwLock<BTreeSet<T::Id>>,
+}
+
+impl<T: Entity> MemoryStore<T> {
+    pub fn new() -> Self {
+        MemoryStore {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            order: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    fn shard(&self, id: T::Id) -> &Shard<T> {
+        &self.shards[id.number() as usize % SHARDS]
+    }
+}
+
+impl<T: Entity> Default for MemoryStore<T> {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+impl<T: Entity + Send + Sync> Store<T> for MemoryStore<T> {
+    fn len(&self) -> usize {
+        self.order.read().unwrap().len()
+    }
+
+    fn contains(&self, id: T::Id) -> bool {
+        self.shard(id).read().unwrap().contains_key(&id)
+    }
+
+    fn read(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> bool {
+        self.shard(id).read().unwrap().get(&id).map(|(row, revision)| f(row, *revision)).is_some()
+    }
+
+    fn update(&self, id: T::Id, f: &mut dyn FnMut(&mut T, &mut u64)) -> bool {
+        self.shard(id).write().unwrap().get_mut(&id).map(|(row, revision)| f(row, revision)).is_some()
+    }
+
+    fn insert(&self, row: T, revision: &mut dyn FnMut(&T, Option<u64>) -> u64) {
+        let id = row.id();
+        {
+            let mut shard = self.shard(id).write().unwrap();
+            let revision = revision(&row, shard.get(&id).map(|(_, revision)| *revision));
+            shard.insert(id, (row, revision));
+        }
+        self.order.write().unwrap().insert(id);
+    }
+
+    fn remove(&self, id: T::Id, f: &mut dyn FnMut(&T, u64)) -> Option<(T, u64)> {
+        let removed = {
+            let mut shard = self.shard(id).write().unwrap();
+            let (row, revision) = shard.remove(&id)?;
+            f(&row, revision);
+            (row, revision)
+        };
+        self.order.write().unwrap().remove(&id);
+        Some(removed)
+    }
+
+    fn ids(&self, start: usize, count: usize) -> Vec<T::Id> {
+        self.order.read().unwrap().iter().skip(start).take(count).copied().collect()
+    }
+
+    fn last_id(&self) -> Option<T::Id> {
+        self.order.read().unwrap().last().copied()
+    }
+
+    fn any(&self, predicate: &mut dyn FnMut(&T) -> bool) -> bool {
+        self.shards.iter().any(|shard| shard.read().unwrap().values().any(|(row, _)| predicate(row)))
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+        self.order.write().unwrap().clear();
+    }
+}
+
+// The store for the rows of `table` in the configured `storage.backend`
+pub fn configured<T: Entity + Clone + Send + Sync + 'static>(table: &'static str) -> Box<dyn Store<T>> {
+    match config::get().storage.backend {
+        StorageBackend::Memory => Box::new(MemoryStore::new()),
+        StorageBackend::Sqlite | StorageBackend::Postgres => Box::new(DatabaseStore::new(table)),
+    }
+}
+
+// A collection of rows kept in a `Store`, with what every table needs on top of it.
+//
+// Every row carries the revision of its last insert or update, which lets response
+// caches and delta sync notice changes without hooking into every handler. Removed
+// rows leave a tombstone in the change log, and their removal time is kept by id until
+// the id is taken again, see tombstones.rs. The change log is taken briefly while the
+// store holds a row; the closures passed to `read`/`update` run while it does, so they
+// must not call back into the same table.
+pub struct Table<T: Entity> {
+    rows: Box<dyn Store<T>>,
+    changes: RwLock<ChangeLog<T::Id>>,
+    removed: RwLock<HashMap<T::Id, DateTime<Utc>>>,
+    next_id: AtomicU32,
+    observers: RwLock<Vec<Observer<T>>>,
+}
+
+impl<T: Entity + Clone + Send + Sync + 'static> Table<T> {
+    pub fn new() -> Self {
+        Table::with_store(Box::new(MemoryStore::new()))
+    }
+
+    // A table keeping its rows in the given backend
+    pub fn with_store(rows: Box<dyn Store<T>>) -> Self {
+        Table {
+            rows,
+            changes: RwLock::new(BTreeMap::new()),
+            removed: RwLock::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            observers: RwLock::new(Vec::new()),
+        }
+    }
+
+    // Replaces the row's previous entry in the change log and returns the new revision.
+    // The revision is taken under the change log lock, so it is visible to anyone who
+    // later reads the log together with `current_revision`.
+    fn record_change(&self, previous: Option<u64>, id: T::Id, deleted: bool) -> u64 {
+        let mut changes = self.changes.write().unwrap();
+        let revision = REVISION.fetch_add(1, Ordering::SeqCst);
+        if let Some(previous) = previous {
+            changes.remove(&previous);
+        }
+        let at = Utc::now();
+        changes.insert(revision, Change { id, deleted, at });
+        let mut removed = self.removed.write().unwrap();
+        if deleted {
+            removed.insert(id, at);
+        } else {
+            removed.remove(&id);
+        }
+        revision
+    }
+
+    // Runs the observers, while the store holds the row so they see changes to a row in order
+    fn notify(&self, id: T::Id, row: Option<&T>) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer(id, row);
+        }
+    }
+
+    // Calls `observer` after every insert, update and remove, see events.rs and hash_chain.rs.
+    // Like the closures given to `update` it must not use the table
+    pub fn observe(&self, observer: impl Fn(T::Id, Option<&T>) + Send + Sync + 'static) {
+        self.observers.write().unwrap().push(Box::new(observer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    // When the row was removed, if it existed and its id wasn't taken again since
+    pub fn removed_at(&self, id: T::Id) -> Option<DateTime<Utc>> {
+        self.removed.read().unwrap().get(&id).copied()
+    }
+
+    pub fn contains(&self, id: T::Id) -> bool {
+        self.rows.contains(id)
+    }
+
+    pub fn get(&self, id: T::Id) -> Option<T> {
+        self.read(id, T::clone)
+    }
+
+    // The row together with its current revision, read atomically
+    pub fn get_with_revision(&self, id: T::Id) -> Option<(T, u64)> {
+        let mut found = None;
+        self.rows.read(id, &mut |row, revision| found = Some((row.clone(), revision)));
+        found
+    }
+
+    pub fn revision(&self, id: T::Id) -> Option<u64> {
+        let mut found = None;
+        self.rows.read(id, &mut |_, revision| found = Some(revision));
+        found
+    }
+
+    pub fn read<R>(&self, id: T::Id, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let mut f = Some(f);
+        let mut result = None;
+        self.rows.read(id, &mut |row, _| result = f.take().map(|f| f(row)));
+        result
+    }
+
+    pub fn update<R>(&self, id: T::Id, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.try_update(id, |row| Ok::<R, Infallible>(f(row))).map(|result| match result {
+            Ok(result) => result,
+            Err(never) => match never {},
+        })
+    }
+
+    // Like `update`, for a change that may be refused: an Err leaves the row as it was,
+    // so nothing is recorded or announced. The closure checks before it changes the row.
+    pub fn try_update<R, E>(&self, id: T::Id, f: impl FnOnce(&mut T) -> Result<R, E>) -> Option<Result<R, E>> {
+        let mut f = Some(f);
+        let mut result = None;
+        self.rows.update(id, &mut |row, revision| {
+            let Some(f) = f.take() else { return };
+            let outcome = f(row);
+            if outcome.is_ok() {
+                *revision = self.record_change(Some(*revision), id, false);
+                self.notify(id, Some(row));
+            }
+            result = Some(outcome);
+        });
+        result
+    }
+
+    // Records a change of a row whose derived data changed (see indexes.rs), so caches and
+    // /sync pick it up
+    pub fn touch(&self, id: T::Id) {
+        self.update(id, |_| ());
+    }
+
+    // Reserves a fresh id, never handed out twice even under concurrent creates
+    pub fn next_id(&self) -> T::Id {
+        if let Some(id) = self.rows.next_id() {
+            self.reserve_through(id);
+            return id;
+        }
+        T::Id::from_number(self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    // Makes sure `id` and the ids below it are never handed out, for rows that live outside the table
+    pub fn reserve_through(&self, id: T::Id) {
+        self.next_id.fetch_max(id.number() + 1, Ordering::SeqCst);
+    }
+
+    // Moves the id sequence past every stored id, in case it fell behind
+    pub fn reserve_stored_ids(&self) {
+        if let Some(max) = self.rows.last_id() {
+            self.reserve_through(max);
+        }
+    }
+
+    // Inserts a new row or replaces the row with the same id
+    pub fn insert(&self, row: T) {
+        let id = row.id();
+        self.reserve_through(id);
+        ids::assign(id);
+        self.rows.insert(row, &mut |row, previous| {
+            let revision = self.record_change(previous, id, false);
+            self.notify(id, Some(row));
+            revision
+        });
+    }
+
+    pub fn remove(&self, id: T::Id) -> Option<T> {
+        self.rows.remove(id, &mut |_, revision| {
+            self.record_change(Some(revision), id, true);
+            self.notify(id, None);
+        })
+            .map(|(row, _)| row)
+    }
+
+    // Records a new row that is kept outside the table (lazily loaded items) and returns its revision
+    pub fn record_external(&self, id: T::Id) -> u64 {
+        ids::assign(id);
+        self.record_change(None, id, false)
+    }
+
+    // Puts a row back in memory with the revision it had, without recording a change
+    pub fn load(&self, row: T, revision: u64) {
+        self.rows.insert(row, &mut |_, _| revision);
+    }
+
+    // Takes a row out of memory together with its revision, without recording a change
+    pub fn unload(&self, id: T::Id) -> Option<(T, u64)> {
+        self.rows.remove(id, &mut |_, _| ())
+    }
+
+    // Read access to the change log, see sync.rs
+    pub fn change_log(&self) -> RwLockReadGuard<'_, ChangeLog<T::Id>> {
+        self.changes.read().unwrap()
+    }
+
+    // Ids in order
+    pub fn ids(&self) -> Vec<T::Id> {
+        self.rows.ids(0, usize::MAX)
+    }
+
+    // Clones of `count` rows starting at position `start` in id order, only the page is allocated
+    pub fn page(&self, start: usize, count: usize) -> Vec<T> {
+        let ids = self.rows.ids(start, count);
+        let mut rows = Vec::with_capacity(ids.len());
+        for id in ids {
+            self.read(id, |row| rows.push(row.clone()));
+        }
+        rows
+    }
+
+    // Visits every row by reference in id order without cloning the collection
+    pub fn for_each(&self, mut f: impl FnMut(&T)) {
+        for id in self.ids() {
+            self.read(id, &mut f);
+        }
+    }
+
+    pub fn any(&self, mut predicate: impl FnMut(&T) -> bool) -> bool {
+        self.rows.any(&mut predicate)
+    }
+
+    // Swaps in a whole new data set, used when loading from disk
+    pub fn replace_all(&self, rows: Vec<T>) {
+        self.rows.clear();
+        self.changes.write().unwrap().clear();
+        self.removed.write().unwrap().clear();
+        self.next_id.store(1, Ordering::SeqCst);
+        for row in rows {
+            self.insert(row);
+        }
+    }
+}
+
+impl<T: Entity + Clone + Send + Sync + 'static> Default for Table<T> {
+    fn default() -> Self {
+        Table::new()
+    }
+}
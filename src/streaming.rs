@@ -0,0 +1,65 @@
+use rocket::futures::future::ready;
+use rocket::futures::stream::{self, Stream, StreamExt};
+use rocket::http::ContentType;
+use rocket::response::stream::TextStream;
+use rocket::serde::Serialize;
+use rocket::State;
+use std::sync::Arc;
+
+use crate::capsules::CAPSULES;
+use crate::clock::SharedClock;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::items::ITEMS;
+use crate::sync::View;
+use crate::time_format;
+use crate::tokens::Caller;
+
+// JSON body written to the client piece by piece instead of being built in memory first
+pub type JsonStream<S> = (ContentType, TextStream<S>);
+
+fn chunk(text: &str) -> impl Stream<Item = String> {
+    stream::once(ready(text.to_string()))
+}
+
+// A JSON array whose records are fetched and serialized one at a time as the client reads,
+// with times in the format of the request. Records that disappear while the response is
+// being written are skipped.
+fn array<K, T, F>(keys: Vec<K>, fetch: F) -> impl Stream<Item = String>
+where
+    T: Serialize,
+    F: Fn(K) -> Option<T>,
+{
+    let format = time_format::current();
+    let mut first = true;
+    let rows = stream::iter(keys).filter_map(move |key| {
+        let json = fetch(key).and_then(|row| time_format::rendering(format, || serde_json::to_string(&row)).ok()).map(|json| {
+            if first { first = false; json } else { format!(",{}", json) }
+        });
+        ready(json)
+    });
+    chunk("[").chain(rows).chain(chunk("]"))
+}
+
+pub fn json_array<K, T, F>(keys: Vec<K>, fetch: F) -> JsonStream<impl Stream<Item = String>>
+where
+    T: Serialize,
+    F: Fn(K) -> Option<T>,
+{
+    (ContentType::JSON, TextStream(array(keys, fetch)))
+}
+
+// Dump of the contributors, capsules and items the caller sees, as on /sync
+#[get("/export")]
+pub fn export_all(caller: Caller, clock: &State<SharedClock>) -> Result<JsonStream<impl Stream<Item = String>>, ApiError> {
+    let view = Arc::new(View::new(caller.required()?, clock.now()));
+    let (contributors, capsules, items) = (view.clone(), view.clone(), view);
+    let body = chunk("{\"contributors\":")
+        .chain(array(CONTRIBUTORS.ids(), move |id| contributors.contributor(id)))
+        .chain(chunk(",\"capsules\":"))
+        .chain(array(CAPSULES.ids(), move |id| capsules.capsule(id)))
+        .chain(chunk(",\"items\":"))
+        .chain(array(ITEMS.ids(), move |id| items.item(id)))
+        .chain(chunk("}"));
+    Ok((ContentType::JSON, TextStream(body)))
+}
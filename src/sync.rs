@@ -0,0 +1,150 @@
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::contributors::{Contributor, CONTRIBUTORS};
+use crate::ids::{CapsuleId, ContributorId, EntityId, ItemId};
+use crate::indexes::INDEXES;
+use crate::items::{Item, ITEMS};
+use crate::store::{self, ChangeLog};
+use crate::tokens::Caller;
+use crate::{moderation, owner_only, ownership, reveals};
+
+// Changes of one kind of record since the client's cursor. Created and updated
+// records are both in `updated`, clients should upsert them by id.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncChanges<T, I> {
+    pub updated: Vec<T>,
+    pub deleted: Vec<I>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncResponse {
+    pub cursor: String,  // Pass back as `since` on the next sync
+    pub contributors: SyncChanges<Contributor, ContributorId>,
+    pub capsules: SyncChanges<Capsule, CapsuleId>,
+    pub items: SyncChanges<Item, ItemId>,
+}
+
+// Where the client's last sync left off
+enum Since {
+    Cursor(u64),
+    Time(DateTime<Utc>),
+}
+
+impl Since {
+    fn parse(value: Option<&str>) -> Option<Since> {
+        match value {
+            None => Some(Since::Cursor(0)),
+            Some(value) => value.parse().ok().map(Since::Cursor)
+                .or_else(|| value.parse().ok().map(Since::Time)),
+        }
+    }
+}
+
+// Ids changed after `since` from a change log, split into (updated, deleted)
+fn changed_ids<I: EntityId>(log: &ChangeLog<I>, since: &Since) -> (Vec<I>, Vec<I>) {
+    let changes: Box<dyn Iterator<Item = _>> = match since {
+        Since::Cursor(cursor) => Box::new(log.range(cursor + 1..).map(|(_, change)| change)),
+        Since::Time(time) => Box::new(log.values().filter(move |change| change.at > *time)),
+    };
+
+    let (mut updated, mut deleted) = (Vec::new(), Vec::new());
+    for change in changes {
+        if change.deleted { deleted.push(change.id) } else { updated.push(change.id) }
+    }
+    (updated, deleted)
+}
+
+// What a caller gets of the records, for /sync and /export: the capsules they edit and
+// those in the public feed, their items without the ones that owner-only marks or a reveal
+// ceremony still hide, and the contributors without anyone else's email
+pub struct View {
+    caller: ContributorId,
+    hidden_items: HashSet<ItemId>,
+}
+
+impl View {
+    pub fn new(caller: ContributorId, now: DateTime<Utc>) -> View {
+        let mut hidden_items = reveals::all_hidden();
+        hidden_items.extend(owner_only::all_hidden(&Caller(Some(caller)), now));
+        View { caller, hidden_items }
+    }
+
+    fn sees(&self, capsule: &Capsule) -> bool {
+        ownership::is_editor(capsule, self.caller) || moderation::is_listed(capsule)
+    }
+
+    pub fn capsule(&self, id: CapsuleId) -> Option<Capsule> {
+        CAPSULES.get(id).filter(|capsule| self.sees(capsule))
+    }
+
+    pub fn item(&self, id: ItemId) -> Option<Item> {
+        if self.hidden_items.contains(&id) {
+            return None;
+        }
+        ITEMS.get(id).filter(|item| CAPSULES.read(item.id_capsule, |capsule| self.sees(capsule)).unwrap_or(false))
+    }
+
+    pub fn contributor(&self, id: ContributorId) -> Option<Contributor> {
+        let mut contributor = CONTRIBUTORS.get(id)?;
+        if contributor.id != self.caller {
+            contributor.email.clear();
+        }
+        Some(contributor)
+    }
+}
+
+fn collect<T, I: EntityId>((updated, mut deleted): (Vec<I>, Vec<I>), fetch: impl Fn(I) -> Option<T>) -> SyncChanges<T, I> {
+    let mut rows = Vec::with_capacity(updated.len());
+    for id in updated {
+        // Removed since the logs were read, it will show up as deleted on the next sync
+        match fetch(id) {
+            Some(row) => rows.push(row),
+            None => deleted.push(id),
+        }
+    }
+    SyncChanges { updated: rows, deleted }
+}
+
+#[get("/sync?<since>")]
+pub fn sync_changes(since: Option<&str>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<SyncResponse>, status::Custom<Json<String>>> {
+    let view = View::new(caller.required()?, clock.now());
+    let since = Since::parse(since).ok_or_else(|| {
+        status::Custom(Status::BadRequest, Json("`since` must be a sync cursor or an RFC 3339 timestamp".into()))
+    })?;
+
+    // Hold all change logs while taking the cursor, so no change below it can be missed
+    let (contributor_ids, capsule_ids, mut item_ids, cursor) = {
+        let contributor_log = CONTRIBUTORS.change_log();
+        let capsule_log = CAPSULES.change_log();
+        let item_log = ITEMS.change_log();
+        let cursor = store::current_revision() - 1;
+        (changed_ids(&contributor_log, &since), changed_ids(&capsule_log, &since), changed_ids(&item_log, &since), cursor)
+    };
+
+    // A changed capsule may show or hide items that didn't change themselves, like a reveal
+    // or a capsule leaving the public feed, so its items are sent again
+    let listed: HashSet<ItemId> = item_ids.0.iter().chain(&item_ids.1).copied().collect();
+    let indexes = INDEXES.read().unwrap();
+    let again: Vec<ItemId> = capsule_ids.0.iter()
+        .flat_map(|&capsule_id| indexes.items_of(capsule_id))
+        .filter(|id| !listed.contains(id))
+        .collect();
+    drop(indexes);
+    item_ids.0.extend(again);
+
+    Ok(Json(SyncResponse {
+        cursor: cursor.to_string(),
+        contributors: collect(contributor_ids, |id| view.contributor(id)),
+        capsules: collect(capsule_ids, |id| view.capsule(id)),
+        items: collect(item_ids, |id| view.item(id)),
+    }))
+}
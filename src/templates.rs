@@ -0,0 +1,104 @@
+// Placeholders in capsule names and descriptions, like "Letters of {{year}}". Most are
+// filled in when the capsule is created; the ones that can only be known once it opens
+// stay in the text until then and are filled in by the scheduler. Dates are in the
+// capsule's timezone, UTC without one. Unknown placeholders are left as they are.
+use chrono::{DateTime, Utc};
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::Clock;
+use crate::i18n::LocalizedText;
+use crate::indexes::INDEXES;
+use crate::locks;
+use crate::timezones;
+
+// Filled in when the capsule opens, the rest at creation
+const OPENING: [&str; 2] = ["item_count", "years_sealed"];
+
+// The next `{{name}}` of the text: what comes before it, its trimmed name and what follows
+fn next_placeholder(text: &str) -> Option<(&str, &str, &str)> {
+    let start = text.find("{{")?;
+    let length = text[start + 2..].find("}}")?;
+    Some((&text[..start], text[start + 2..start + 2 + length].trim(), &text[start + 2 + length + 2..]))
+}
+
+// Replaces every placeholder the lookup knows, spaces inside the braces are allowed
+fn expand(text: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((before, name, after)) = next_placeholder(rest) {
+        expanded.push_str(before);
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None => expanded.push_str(&rest[before.len()..rest.len() - after.len()]),
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn expand_text(text: &LocalizedText, lookup: &dyn Fn(&str) -> Option<String>) -> LocalizedText {
+    text.map(|text| expand(text, lookup))
+}
+
+fn has_placeholder(text: &LocalizedText, names: &[&str]) -> bool {
+    text.texts().into_iter().any(|mut rest| {
+        while let Some((_, name, after)) = next_placeholder(rest) {
+            if names.contains(&name) {
+                return true;
+            }
+            rest = after;
+        }
+        false
+    })
+}
+
+// A time formatted in the capsule's timezone
+fn local(capsule: &Capsule, time: DateTime<Utc>, format: &str) -> String {
+    match capsule.timezone.as_deref().and_then(|name| timezones::parse(name).ok()) {
+        Some(tz) => timezones::to_local(time, tz).format(format).to_string(),
+        None => time.format(format).to_string(),
+    }
+}
+
+// Fills in the creation placeholders of a new capsule's name and description
+pub fn fill_at_creation(capsule: &mut Capsule, contributor_name: &str) {
+    let lookup = |name: &str| match name {
+        "year" => Some(local(capsule, capsule.time_created, "%Y")),
+        "created_date" => Some(local(capsule, capsule.time_created, "%Y-%m-%d")),
+        "contributor_name" => Some(contributor_name.to_string()),
+        "open_date" => Some(local(capsule, capsule.time_open, "%Y-%m-%d")),
+        "open_year" => Some(local(capsule, capsule.time_open, "%Y")),
+        _ => None,
+    };
+    let name = expand_text(&capsule.name, &lookup);
+    let description = expand_text(&capsule.description, &lookup);
+    capsule.name = name;
+    capsule.description = description;
+}
+
+// Fills in the opening placeholders of capsules that have opened, called by the scheduler
+pub fn fill_opened(clock: &dyn Clock) {
+    let now = clock.now();
+    let mut due = Vec::new();
+    CAPSULES.for_each(|capsule| {
+        if capsule.time_open <= now && (has_placeholder(&capsule.name, &OPENING) || has_placeholder(&capsule.description, &OPENING)) {
+            due.push(capsule.id);
+        }
+    });
+
+    for capsule_id in due {
+        let _guard = locks::lock_capsule(capsule_id);
+        let item_count = INDEXES.read().unwrap().items_of(capsule_id).len();
+        CAPSULES.update(capsule_id, |capsule| {
+            let years_sealed = (capsule.time_open - capsule.time_created).num_days() / 365;
+            let lookup = |name: &str| match name {
+                "item_count" => Some(item_count.to_string()),
+                "years_sealed" => Some(years_sealed.to_string()),
+                _ => None,
+            };
+            capsule.name = expand_text(&capsule.name, &lookup);
+            capsule.description = expand_text(&capsule.description, &lookup);
+        });
+    }
+}
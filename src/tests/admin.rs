@@ -0,0 +1,38 @@
+// The /admin routes take the key of an admin
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{admin_key, body, id, TestServer};
+
+#[test]
+fn admin_routes_need_an_admin() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let reassign = json!({ "capsule_ids": [capsule["id"]], "contributor_id": other.id });
+
+    let response = server.post("/admin/capsules/reassign").header(other.key.clone()).json(&reassign).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(body(response), json!("Only admins can use the /admin routes"));
+    let flags = body(server.get("/admin/flags").header(admin_key()).dispatch());
+    let attempts = [
+        server.put("/admin/flags").json(&flags),
+        server.post("/admin/clock").json(&json!({ "advance_seconds": 60 })),
+        server.put("/admin/schedules").json(&json!({ "gc": "@daily" })),
+        server.post("/admin/rebuild"),
+        server.post("/admin/anonymize"),
+        server.get("/admin/audit"),
+        server.get("/admin/reports"),
+    ];
+    for attempt in attempts {
+        assert_eq!(attempt.header(owner.key.clone()).dispatch().status(), Status::Forbidden);
+    }
+    // Reads too, and they need a key like the changes
+    assert_eq!(server.get("/admin/flags").dispatch().status(), Status::Unauthorized);
+    assert_eq!(body(server.get(format!("/capsules/{}", id(&capsule))).dispatch())["contributor_id"], owner.id);
+
+    let response = server.post("/admin/capsules/reassign").header(admin_key()).json(&reassign).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(server.get(format!("/capsules/{}", id(&capsule))).dispatch())["contributor_id"], other.id);
+}
@@ -0,0 +1,129 @@
+// API keys: which requests need one, and the keys' own routes
+use rocket::http::{Header, Status};
+use serde_json::{json, Value};
+
+use super::{body, TestServer};
+
+#[test]
+fn changes_need_a_key() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+
+    let response = server.post("/capsules")
+        .json(&json!({ "name": "No key", "description": "", "contributor_id": owner.id, "time_open": "2040-01-01T00:00:00Z" }))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = server.post("/capsules")
+        .header(Header::new("Authorization", "Bearer not-a-key"))
+        .json(&json!({ "name": "Wrong key", "description": "", "contributor_id": owner.id, "time_open": "2040-01-01T00:00:00Z" }))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(body(response), json!("Unknown or revoked API key"));
+
+    // Reading and signing up don't
+    assert_eq!(server.get("/capsules").dispatch().status(), Status::Ok);
+    assert_eq!(server.get(format!("/contributors/{}", owner.id)).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn keys_are_listed_and_revoked() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+
+    let response = server.get("/tokens").header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let keys = body(response);
+    assert_eq!(keys.as_array().map(Vec::len), Some(1));
+    let key_id = keys[0]["id"].clone();
+    assert!(keys[0].get("hash").is_none());
+
+    let response = server.delete(format!("/tokens/{}", key_id)).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(server.get("/tokens").header(owner.key.clone()).dispatch().status(), Status::Unauthorized);
+}
+
+#[test]
+fn keys_belong_to_their_contributor() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+
+    let response = server.get(format!("/tokens?contributor_id={}", other.id)).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // Another contributor's key can't be revoked, it's as if it didn't exist
+    let other_keys = body(server.get("/tokens").header(other.key.clone()).dispatch());
+    let response = server.delete(format!("/tokens/{}", other_keys[0]["id"])).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = server.post("/tokens")
+        .header(owner.key.clone())
+        .json(&json!({ "name": "laptop", "contributor_id": other.id }))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn a_key_makes_more_keys() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+
+    let response = server.post("/tokens").header(owner.key.clone()).json(&json!({ "name": "laptop" })).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let created = body(response);
+    let key = created["key"].as_str().expect("The new key").to_string();
+    assert_eq!(created["name"], json!("laptop"));
+
+    let response = server.get("/tokens").header(Header::new("Authorization", format!("Bearer {}", key))).dispatch();
+    assert_eq!(body(response).as_array().map(Vec::len), Some(2));
+}
+
+#[test]
+fn a_first_key_needs_a_code() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let email = body(server.get(format!("/contributors/{}", owner.id)).dispatch())["contributor"]["email"].clone();
+
+    let response = server.post("/tokens").json(&json!({ "name": "phone" })).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = server.post("/tokens").json(&json!({ "name": "phone", "email": email, "code": "123456" })).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Codes go out by email, which the tests don't configure
+    let response = server.post("/tokens/codes").json(&json!({ "email": email })).dispatch();
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+}
+
+#[test]
+fn keys_are_rate_limited() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+
+    let response = server.post("/tokens").header(owner.key.clone()).json(&json!({ "name": "script", "rate_limit_per_minute": 2 })).dispatch();
+    let limited = Header::new("Authorization", format!("Bearer {}", body(response)["key"].as_str().unwrap()));
+
+    for _ in 0..2 {
+        let response = server.get("/capsules").header(limited.clone()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let response = server.get("/capsules").header(limited).dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.headers().get_one("Retry-After").is_some());
+}
+
+#[test]
+fn sessions_are_the_keys() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+
+    let response = server.get("/auth/sessions").header(owner.key.clone()).header(Header::new("User-Agent", "phone")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let sessions: Value = body(response);
+    assert_eq!(sessions[0]["current"], json!(true));
+
+    let response = server.delete(format!("/auth/sessions/{}", sessions[0]["id"])).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(server.get("/auth/sessions").header(owner.key.clone()).dispatch().status(), Status::Unauthorized);
+}
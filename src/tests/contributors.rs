@@ -0,0 +1,31 @@
+// Contributors are changed and deleted only with their own API key
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, TestServer};
+
+#[test]
+fn contributors_change_only_themselves() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let path = format!("/contributors/{}", owner.id);
+
+    // Taking over the email would let the other ask for a key of the account
+    let response = server.patch(&path).header(other.key.clone()).json(&json!({ "email": "taken@example.com" })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.put(format!("{}/defaults", path)).header(other.key.clone()).json(&json!({ "edit_window_days": 30 })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.put(format!("{}/capsule-order", path)).header(other.key.clone())
+        .json(&json!({ "pinned_capsule_ids": [], "capsule_order": [] })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(server.delete(format!("{}?dry_run=true", path)).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    assert_eq!(server.delete(&path).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    let email = body(server.get(&path).dispatch())["contributor"]["email"].clone();
+    assert_ne!(email, json!("taken@example.com"));
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Renamed" })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["name"], json!("Renamed"));
+    assert_eq!(server.delete(&path).header(owner.key.clone()).dispatch().status(), Status::NoContent);
+}
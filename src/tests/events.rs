@@ -0,0 +1,45 @@
+// Event sourcing: every change to a capsule is appended to its log as an event
+use rocket::http::Status;
+use serde_json::json;
+use std::fs;
+
+use super::{body, id, TestServer};
+use crate::config;
+
+#[test]
+fn changes_are_appended_as_events() {
+    let env = [("ROCKET_EVENTS", "{enabled=true}")];
+    let Some(server) = TestServer::with(&env, module_path!(), "changes_are_appended_as_events") else { return };
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Renamed", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stale", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let events = body(server.get(format!("{}/events", path)).dispatch());
+    let events = events.as_array().unwrap();
+    let kinds: Vec<&str> = events.iter().map(|event| event["kind"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["created", "updated"]);
+    assert_eq!(events[0]["data"]["name"], capsule["name"]);
+    assert_eq!(events[1]["data"]["name"], json!("Renamed"));
+    assert!(events[1]["data"].get("description").is_none(), "Only the changed fields are recorded");
+    assert!(events[1]["seq"].as_u64() > events[0]["seq"].as_u64());
+
+    // Written to the log the capsules are replayed from
+    let log = fs::read_to_string(&config::get().events.file).unwrap();
+    assert!(log.lines().any(|line| line.contains("\"Renamed\"")));
+
+    assert!(server.delete(&path).header(owner.key.clone()).dispatch().status().class().is_success());
+    let events = body(server.get(format!("{}/events", path)).dispatch());
+    assert_eq!(events.as_array().unwrap().last().unwrap()["kind"], json!("deleted"));
+}
+
+#[test]
+fn events_need_event_sourcing() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    assert_eq!(server.get(format!("/capsules/{}/events", id(&capsule))).dispatch().status(), Status::NotFound);
+}
@@ -0,0 +1,57 @@
+// Hash chains: every change that went through is linked, and the chains are kept as state
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::{body, id, TestServer};
+use crate::state;
+
+fn chain(server: &TestServer, capsule: &Value) -> Value {
+    let response = server.get(format!("/capsules/{}/hash-chain", id(capsule))).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    body(response)
+}
+
+#[test]
+fn only_changes_that_went_through_are_linked() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    assert_eq!(chain(&server, &capsule)["links"].as_array().unwrap().len(), 1);
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Second", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stale", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let chain = chain(&server, &capsule);
+    let links = chain["links"].as_array().unwrap();
+    assert_eq!(links.len(), 2);
+    assert_eq!(links[1]["prev_hash"], links[0]["hash"]);
+    assert_eq!(chain["head"], links[1]["hash"]);
+    assert_eq!(chain["verified"], json!(true));
+}
+
+#[test]
+fn chains_are_kept_as_state_and_checked() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let key = id(&capsule);
+    let before = chain(&server, &capsule);
+
+    let chains = state::collection("hash_chains").expect("Hash chains are a state collection");
+    let entry = (chains.entry)(&key).expect("The capsule's chain");
+
+    // As loaded again after a restart
+    (chains.put)(&key, None).unwrap();
+    assert_eq!(chain(&server, &capsule)["verified"], json!(false));
+    (chains.put)(&key, Some(entry.clone())).unwrap();
+    assert_eq!(chain(&server, &capsule), before);
+
+    // A stored link that was edited breaks the chain
+    let mut tampered = entry;
+    tampered[0]["state_hash"] = json!("0".repeat(64));
+    (chains.put)(&key, Some(tampered)).unwrap();
+    assert_eq!(chain(&server, &capsule)["verified"], json!(false));
+}
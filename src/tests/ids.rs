@@ -0,0 +1,57 @@
+// How ids are shown and taken: numbers, or UUIDs with `ids.strategy = "uuid"`
+use rocket::http::Status;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::{body, id, key, TestServer};
+
+fn is_uuid(id: &Value) -> bool {
+    id.as_str().is_some_and(|id| Uuid::parse_str(id).is_ok())
+}
+
+#[test]
+fn sequential_ids_are_numbers() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    assert!(owner.id.is_u64() && capsule["id"].is_u64());
+    assert_eq!(capsule["contributor_id"], owner.id);
+
+    assert_eq!(server.get("/capsules/1").dispatch().status(), Status::Ok);
+    // Only UUIDs that clients picked are known
+    assert_eq!(server.get(format!("/capsules/{}", Uuid::new_v4())).dispatch().status(), Status::UnprocessableEntity);
+}
+
+#[test]
+fn uuid_ids_hide_the_numbers() {
+    let env = [("ROCKET_IDS", "{strategy=\"uuid\"}")];
+    let Some(server) = TestServer::with(&env, module_path!(), "uuid_ids_hide_the_numbers") else { return };
+
+    // Loaded records get theirs too
+    let capsules = body(server.get("/capsules").dispatch());
+    let loaded = &capsules[0];
+    assert!(is_uuid(&loaded["id"]) && is_uuid(&loaded["contributor_id"]));
+    assert_eq!(server.get(format!("/capsules/{}", id(loaded))).dispatch().status(), Status::Ok);
+    assert_eq!(server.get("/capsules/1").dispatch().status(), Status::UnprocessableEntity);
+
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    assert!(is_uuid(&owner.id) && is_uuid(&capsule["id"]));
+    let item = server.item(&capsule, &owner.key, false);
+    assert!(is_uuid(&item["id"]));
+    assert_eq!(item["id_capsule"], capsule["id"]);
+
+    // Bodies have to use the UUIDs as well
+    let response = server.post("/capsules").header(key(&owner.id))
+        .json(&json!({ "name": "By number", "description": "", "contributor_id": 1, "time_open": "2040-01-01T00:00:00Z" }))
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    // A client may pick the UUID of a new capsule
+    let picked = Uuid::new_v4();
+    let response = server.put(format!("/capsules/{}", picked)).header(owner.key.clone())
+        .json(&json!({ "name": "Offline", "description": "", "contributor_id": owner.id, "time_open": "2040-01-01T00:00:00Z" }))
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(body(response)["id"], json!(picked.to_string()));
+}
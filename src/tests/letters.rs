@@ -0,0 +1,27 @@
+// Letter recipients: managed only by the capsule's editors
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn only_editors_manage_recipients() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let recipients = format!("/capsules/{}/recipients", id(&capsule));
+    let recipient = json!({ "name": "Olena", "email": "olena@example.com" });
+    let remove = format!("{}?email=olena@example.com", recipients);
+
+    assert_eq!(server.post(&recipients).json(&recipient).dispatch().status(), Status::Unauthorized);
+    assert_eq!(server.post(&recipients).header(other.key.clone()).json(&recipient).dispatch().status(), Status::Forbidden);
+    assert_eq!(server.post(&recipients).header(owner.key.clone()).json(&recipient).dispatch().status(), Status::Created);
+
+    assert_eq!(server.delete(&remove).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    let delivery = &body(server.get(format!("/capsules/{}", id(&capsule))).dispatch())["delivery"];
+    assert_eq!(delivery["recipients"][0]["email"], json!("olena@example.com"));
+
+    assert_eq!(server.delete(&remove).header(owner.key.clone()).dispatch().status(), Status::NoContent);
+    assert_eq!(server.delete(&remove).header(owner.key.clone()).dispatch().status(), Status::NotFound);
+}
@@ -0,0 +1,24 @@
+// Linked items: added by a capsule's editors, never fetched from internal addresses
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{id, TestServer};
+
+#[test]
+fn links_to_internal_addresses_are_refused() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let link = format!("/capsules/{}/items/link", id(&capsule));
+    let request = json!({ "url": "http://169.254.169.254/latest/meta-data/" });
+
+    assert_eq!(server.post(&link).json(&request).dispatch().status(), Status::Unauthorized);
+    assert_eq!(server.post(&link).header(other.key.clone()).json(&request).dispatch().status(), Status::Forbidden);
+
+    for url in ["http://169.254.169.254/latest/meta-data/", "http://127.0.0.1:8000/", "http://[::1]/", "http://10.0.0.1/"] {
+        let response = server.post(&link).header(owner.key.clone()).json(&json!({ "url": url, "snapshot": true })).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity, "{} was fetched", url);
+        assert!(response.into_string().unwrap().contains("isn't a public address"));
+    }
+}
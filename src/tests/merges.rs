@@ -0,0 +1,61 @@
+// Merging two capsules of the same owner into the first
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn merging_moves_the_items_into_the_first_capsule() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let kept = server.capsule(&owner);
+    let removed = server.capsule(&owner);
+    let item = server.item(&removed, &owner.key, false);
+    let merge = json!({ "capsule_id1": kept["id"], "capsule_id2": removed["id"] });
+
+    let response = server.post("/merges?dry_run=true").header(owner.key.clone()).json(&merge).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["moved_item_ids"], json!([item["id"]]));
+    assert_eq!(server.get(format!("/capsules/{}", id(&removed))).dispatch().status(), Status::Ok);
+
+    let response = server.post("/merges").header(owner.key.clone()).json(&merge).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["item_ids"], json!([item["id"]]));
+
+    assert_eq!(server.get(format!("/capsules/{}", id(&removed))).dispatch().status(), Status::Gone);
+    let capsule = body(server.get(format!("/capsules/{}", id(&kept))).dispatch());
+    assert_eq!(capsule["merged_from"], json!([removed["id"]]));
+    let moved = body(server.get(format!("/capsules/{}/items/{}", id(&kept), id(&item))).dispatch());
+    assert_eq!(moved["provenance"][1]["origin"], json!("merge"));
+}
+
+#[test]
+fn merges_need_an_editor_of_both_capsules() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let first = server.capsule(&owner);
+    let second = server.capsule(&owner);
+    let foreign = server.capsule(&other);
+
+    let response = server.post("/merges").header(other.key.clone()).json(&json!({ "capsule_id1": first["id"], "capsule_id2": second["id"] })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.post("/merges").header(owner.key.clone()).json(&json!({ "capsule_id1": first["id"], "capsule_id2": foreign["id"] })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.post("/merges").header(owner.key.clone()).json(&json!({ "capsule_id1": first["id"], "capsule_id2": first["id"] })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = server.post("/merges").json(&json!({ "capsule_id1": first["id"], "capsule_id2": second["id"] })).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn sealed_capsules_are_not_merged() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let first = server.capsule(&owner);
+    let second = server.capsule(&owner);
+
+    server.advance(8);
+    let response = server.post("/merges").header(owner.key.clone()).json(&json!({ "capsule_id1": first["id"], "capsule_id2": second["id"] })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
@@ -0,0 +1,230 @@
+// Route tests, run against the whole server through Rocket's local client. A test
+// process reads its data from a copy of src/data and writes everything else to its own
+// directory, so the data files are never changed. The tables are global, so servers run
+// one at a time; every test starts its own, which loads the copied files again.
+// Settings that are read once per process, like the storage backend and the id
+// strategy, are tested in a child process, see `TestServer::with`.
+use rocket::figment::providers::Serialized;
+use rocket::http::{Header, Status};
+use rocket::local::blocking::Client;
+use rocket::tokio::runtime::Runtime;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::ids::{ContributorId, EntityId};
+use crate::state;
+use crate::tokens;
+
+mod admin;
+mod auth;
+mod contributors;
+mod events;
+mod hash_chain;
+mod ids;
+mod letters;
+mod links;
+mod merges;
+mod moderation;
+mod owner_only;
+mod ownership;
+mod persistence;
+mod publishing;
+mod reveals;
+mod shares;
+mod signatures;
+mod storage;
+mod sync;
+mod versions;
+mod webhooks;
+
+// Where this test process keeps its data and everything the server writes
+static DIR: Lazy<PathBuf> = Lazy::new(|| {
+    let dir = std::env::temp_dir().join(format!("capsules-tests-{}", std::process::id()));
+    let data = dir.join("data");
+    fs::create_dir_all(&data).expect("Failed to create the test directory");
+    for file in ["contributors.json", "capsule.json", "items.json"] {
+        fs::copy(Path::new("src/data").join(file), data.join(file)).expect("Failed to copy the data files");
+    }
+    dir
+});
+
+// Merged over Rocket's figment by config.rs. The clock is adjustable so tests can move
+// past edit windows, and the scheduler's maintenance stays off. Contributor 1 of the
+// data files is the admin.
+pub fn settings() -> Serialized<Value> {
+    let path = |name: &str| DIR.join(name).display().to_string();
+    Serialized::defaults(json!({
+        "data_dir": path("data"),
+        "adjustable_clock": true,
+        "duplicates": { "window_secs": 0 },
+        "tokens": { "admin_contributor_ids": [ADMIN] },
+        "ids": { "file": path("ids/uuids.jsonl") },
+        "events": { "file": path("events/capsules.jsonl") },
+        "stats": { "file": path("stats/snapshots.jsonl") },
+        "imports": { "dir": path("imports") },
+        "archives": { "dir": path("archives") },
+        "exports": { "dir": path("exports") },
+        "cold_storage": { "dir": path("cold") },
+        "links": { "snapshot_dir": path("snapshots") },
+        "items": { "spill_dir": path("spill") },
+        "schedules": { "gc": "off", "cold_storage": "off", "retention": "off", "stats": "off" },
+    }))
+}
+
+// The stored number of the admin
+const ADMIN: u32 = 1;
+
+// Held by the running server
+static RUNNING: Mutex<()> = Mutex::new(());
+
+// Makes the emails of new contributors unique
+static CONTRIBUTORS: AtomicU32 = AtomicU32::new(1);
+
+// A server started like main starts it, for one test
+pub struct TestServer {
+    client: Client,
+    runtime: Runtime,  // Keeps what `rocket()` started running, like the database writer
+    _running: MutexGuard<'static, ()>,
+}
+
+// A contributor made by the test, with an API key
+pub struct Contributor {
+    pub id: Value,
+    pub key: Header<'static>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        // A test that failed while running its server doesn't concern the others
+        let running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+        // The tables are loaded again, the state collections are only added to, see state.rs
+        for collection in &state::COLLECTIONS {
+            for (key, _) in (collection.entries)() {
+                (collection.put)(&key, None).expect("Entries can be removed");
+            }
+        }
+        let runtime = Runtime::new().expect("Failed to start a runtime");
+        let rocket = runtime.block_on(crate::rocket());
+        let client = Client::tracked(rocket).expect("Failed to start the server");
+        TestServer { client, runtime, _running: running }
+    }
+
+    // Runs the test `module::test` again in a child process with `env` set, ROCKET_*
+    // variables such as ROCKET_STORAGE='{backend="sqlite"}'. The test only gets a server
+    // in the child; here it's None once the child passed.
+    pub fn with(env: &[(&str, &str)], module: &str, test: &str) -> Option<TestServer> {
+        if env.iter().all(|&(name, value)| std::env::var(name).is_ok_and(|set| set == value)) {
+            return Some(TestServer::start());
+        }
+        let module = module.split_once("::").map_or(module, |(_, path)| path);
+        let name = format!("{}::{}", module, test);
+        let exe = std::env::current_exe().expect("The test binary has a path");
+        let output = Command::new(exe)
+            .args([name.as_str(), "--exact"])
+            .envs(env.iter().copied())
+            .output()
+            .expect("Failed to run the test process");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success() && stdout.contains("1 passed"),
+            "{} failed with {:?}:\n{}{}", name, env, stdout, String::from_utf8_lossy(&output.stderr));
+        None
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub fn contributor(&self) -> Contributor {
+        let n = CONTRIBUTORS.fetch_add(1, Ordering::SeqCst);
+        let response = self.post("/contributors")
+            .json(&json!({ "name": format!("Tester {}", n), "email": format!("tester{}@example.com", n) }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let id = body(response)["id"].clone();
+        let key = key(&id);
+        Contributor { id, key }
+    }
+
+    // A capsule of the owner that opens in a year, still in its 7 day edit window
+    pub fn capsule(&self, owner: &Contributor) -> Value {
+        self.capsule_with(owner, json!({}))
+    }
+
+    // Same, with `fields` added to the body
+    pub fn capsule_with(&self, owner: &Contributor, fields: Value) -> Value {
+        let mut capsule = json!({
+            "name": "Test capsule",
+            "description": "Made by a test",
+            "contributor_id": owner.id,
+            "time_open": (chrono::Utc::now() + chrono::Duration::days(365)).to_rfc3339(),
+        });
+        capsule.as_object_mut().unwrap().extend(fields.as_object().cloned().unwrap_or_default());
+        let response = self.post("/capsules").header(owner.key.clone()).json(&capsule).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        body(response)
+    }
+
+    pub fn item(&self, capsule: &Value, key: &Header<'static>, owner_only: bool) -> Value {
+        let response = self.post(format!("/capsules/{}/items", id(capsule)))
+            .header(key.clone())
+            .json(&json!({
+                "type_c": "photo",
+                "description": "Beach",
+                "size": "1MB",
+                "path": "photos/beach.jpg",
+                "metadata": {},
+                "owner_only": owner_only,
+            }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        body(response)
+    }
+
+    // Moves the server's clock by `days`
+    pub fn advance(&self, days: i64) {
+        let response = self.post("/admin/clock")
+            .header(admin_key())
+            .json(&json!({ "advance_seconds": days * 24 * 60 * 60 }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
+
+impl Deref for TestServer {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+// A new API key of the contributor with this id, as the header to send it in
+pub fn key(contributor_id: &Value) -> Header<'static> {
+    let contributor_id: ContributorId = serde_json::from_value(contributor_id.clone()).expect("A contributor id");
+    let (key, _) = tokens::issue(contributor_id, "test", None, chrono::Utc::now());
+    Header::new("Authorization", format!("Bearer {}", key))
+}
+
+// A new API key of the admin
+pub fn admin_key() -> Header<'static> {
+    let (key, _) = tokens::issue(ContributorId::from_number(ADMIN), "admin", None, chrono::Utc::now());
+    Header::new("Authorization", format!("Bearer {}", key))
+}
+
+// The id of a record as it's written in paths
+pub fn id(record: &Value) -> String {
+    match &record["id"] {
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    }
+}
+
+pub fn body(response: rocket::local::blocking::LocalResponse<'_>) -> Value {
+    response.into_json().expect("A JSON body")
+}
@@ -0,0 +1,53 @@
+// Abuse reports on public capsules and the admins' moderation queue
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{admin_key, body, id, TestServer};
+use crate::state;
+
+#[test]
+fn only_admins_moderate() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule_with(&owner, json!({ "visibility": "public" }));
+
+    let response = server.post(format!("/public/capsules/{}/report", id(&capsule)))
+        .json(&json!({ "reason": "Spam", "contact": "reporter@example.com" })).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let report_id = body(response)["id"].clone();
+
+    // The reporter's contact isn't for everyone
+    assert_eq!(server.get("/admin/reports").dispatch().status(), Status::Unauthorized);
+    assert_eq!(server.get("/admin/reports").header(owner.key.clone()).dispatch().status(), Status::Forbidden);
+    let resolve = format!("/admin/reports/{}/resolve", report_id);
+    let response = server.post(&resolve).header(owner.key.clone()).json(&json!({ "action": "dismiss" })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let reports = body(server.get("/admin/reports").header(admin_key()).dispatch());
+    assert_eq!(reports[0]["contact"], json!("reporter@example.com"));
+    let response = server.post(&resolve).header(admin_key()).json(&json!({ "action": "hide" })).dispatch();
+    assert_eq!(body(response)["status"], json!("actioned"));
+
+    let moderation = format!("/admin/capsules/{}/moderation", id(&capsule));
+    assert_eq!(server.delete(&moderation).header(owner.key.clone()).dispatch().status(), Status::Forbidden);
+    assert_eq!(server.delete(&moderation).header(admin_key()).dispatch().status(), Status::NoContent);
+}
+
+#[test]
+fn reports_are_kept_as_state() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule_with(&owner, json!({ "visibility": "public" }));
+    let response = server.post(format!("/public/capsules/{}/report", id(&capsule))).json(&json!({ "reason": "Spam" })).dispatch();
+    let report_id = body(response)["id"].to_string();
+
+    let reports = state::collection("reports").expect("Reports are a state collection");
+    let entry = (reports.entry)(&report_id).expect("The report's entry");
+    assert_eq!(entry["reason"], json!("Spam"));
+
+    // As loaded again after a restart
+    (reports.put)(&report_id, None).unwrap();
+    assert_eq!(body(server.get("/admin/reports").header(admin_key()).dispatch()), json!([]));
+    (reports.put)(&report_id, Some(entry)).unwrap();
+    assert_eq!(body(server.get("/admin/reports").header(admin_key()).dispatch())[0]["id"].to_string(), report_id);
+}
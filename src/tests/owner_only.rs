@@ -0,0 +1,63 @@
+// Items the owner keeps to themselves until the capsule opens
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::{body, id, TestServer};
+
+fn item_ids(items: Value) -> Vec<Value> {
+    items.as_array().expect("A list of items").iter().map(|item| item["id"].clone()).collect()
+}
+
+#[test]
+fn owner_only_items_are_hidden_from_others() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let shared = server.item(&capsule, &owner.key, false);
+    let surprise = server.item(&capsule, &owner.key, true);
+    let items = format!("/capsules/{}/items", id(&capsule));
+
+    let response = server.get(&items).header(owner.key.clone()).dispatch();
+    assert_eq!(item_ids(body(response)), vec![shared["id"].clone(), surprise["id"].clone()]);
+    for caller in [Some(&other), None] {
+        let mut request = server.get(&items);
+        if let Some(caller) = caller {
+            request = request.header(caller.key.clone());
+        }
+        assert_eq!(item_ids(body(request.dispatch())), vec![shared["id"].clone()]);
+    }
+    let response = server.get(format!("{}/{}", items, id(&surprise))).header(other.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    // Everyone sees it once the capsule opens
+    server.advance(366);
+    let response = server.get(format!("{}/{}", items, id(&surprise))).header(other.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn only_the_owner_marks_items() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let helper = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    // Co-owners may add items, but not keep them from the owner
+    let request = body(server.post(format!("{}/ownership-requests", path)).header(helper.key.clone())
+        .json(&json!({ "contributor_id": helper.id })).dispatch());
+    server.post(format!("{}/ownership-requests/{}/approve", path, request["id"])).header(owner.key.clone()).dispatch();
+    let item = server.item(&capsule, &helper.key, false);
+    let owner_only = format!("{}/items/{}/owner-only", path, id(&item));
+
+    let response = server.put(&owner_only).header(helper.key.clone()).json(&json!({ "owner_only": true })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.put(&owner_only).header(owner.key.clone()).json(&json!({ "owner_only": true })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["owner_only"], json!(true));
+
+    server.advance(366);
+    let response = server.put(&owner_only).header(owner.key.clone()).json(&json!({ "owner_only": false })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+}
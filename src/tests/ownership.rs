@@ -0,0 +1,108 @@
+// Who may change a capsule: its owner and the co-owners they approved
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn only_editors_change_a_capsule() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    let response = server.patch(&path).header(other.key.clone()).json(&json!({ "name": "Mine now", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.delete(&path).header(other.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Renamed", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.delete(&path).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(server.get(&path).dispatch().status(), Status::Gone);
+}
+
+#[test]
+fn approved_co_owners_edit_the_capsule() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let helper = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    let response = server.post(format!("{}/ownership-requests", path)).header(helper.key.clone())
+        .json(&json!({ "contributor_id": helper.id, "message": "Let me help" })).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let request = body(response);
+    assert_eq!(request["status"], json!("pending"));
+
+    // Only the owner decides
+    let approve = format!("{}/ownership-requests/{}/approve", path, request["id"]);
+    assert_eq!(server.post(&approve).header(helper.key.clone()).dispatch().status(), Status::Forbidden);
+    let response = server.post(&approve).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["status"], json!("approved"));
+    assert_eq!(server.post(&approve).header(owner.key.clone()).dispatch().status(), Status::Conflict);
+
+    let response = server.patch(&path).header(helper.key.clone()).json(&json!({ "name": "Ours", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["co_owner_ids"], json!([helper.id]));
+
+    // Giving co-ownership up again
+    let response = server.delete(format!("{}/co-owners/{}", path, helper.id)).header(helper.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    let response = server.patch(&path).header(helper.key.clone()).json(&json!({ "name": "Not ours", "version": 2 })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn co_ownership_is_requested_for_yourself() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let helper = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let requests = format!("/capsules/{}/ownership-requests", id(&capsule));
+
+    let response = server.post(&requests).header(other.key.clone()).json(&json!({ "contributor_id": helper.id })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.post(&requests).header(owner.key.clone()).json(&json!({ "contributor_id": owner.id })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = server.post(&requests).header(helper.key.clone()).json(&json!({ "contributor_id": helper.id })).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let response = server.post(&requests).header(helper.key.clone()).json(&json!({ "contributor_id": helper.id })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let request_id = body(server.get(&requests).dispatch())[0]["id"].clone();
+    let response = server.post(format!("{}/{}/reject", requests, request_id)).header(owner.key.clone()).dispatch();
+    assert_eq!(body(response)["status"], json!("rejected"));
+    let response = server.patch(format!("/capsules/{}", id(&capsule))).header(helper.key.clone()).json(&json!({ "name": "Rejected", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn only_the_owner_removes_co_owners() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let first = server.contributor();
+    let second = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    for helper in [&first, &second] {
+        let request = body(server.post(format!("{}/ownership-requests", path)).header(helper.key.clone())
+            .json(&json!({ "contributor_id": helper.id })).dispatch());
+        let response = server.post(format!("{}/ownership-requests/{}/approve", path, request["id"])).header(owner.key.clone()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let response = server.delete(format!("{}/co-owners/{}", path, second.id)).header(first.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.delete(format!("{}/co-owners/{}", path, second.id)).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    let response = server.delete(format!("{}/co-owners/{}", path, second.id)).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
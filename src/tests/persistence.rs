@@ -0,0 +1,69 @@
+// State collections: what's written out comes back the same when it's loaded again
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{admin_key, body, id, TestServer};
+use crate::state;
+
+// Every entry removed and put back from its stored form, as after a restart
+fn reload(name: &str) {
+    let collection = state::collection(name).unwrap_or_else(|| panic!("{} is a state collection", name));
+    let entries = (collection.entries)();
+    for (key, _) in &entries {
+        (collection.put)(key, None).unwrap();
+    }
+    for (key, entry) in entries {
+        (collection.put)(&key, Some(entry)).unwrap();
+    }
+}
+
+#[test]
+fn the_audit_log_survives_a_reload() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Second", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let before = body(server.get("/admin/audit").header(admin_key()).dispatch());
+    assert_eq!(before.as_array().unwrap().len(), 2);
+    reload("audit_log");
+    assert_eq!(body(server.get("/admin/audit").header(admin_key()).dispatch()), before);
+}
+
+#[test]
+fn changed_schedules_survive_a_reload() {
+    let server = TestServer::start();
+    let schedules = state::collection("schedules").expect("Schedules are a state collection");
+    let configured = body(server.get("/admin/schedules").header(admin_key()).dispatch());
+    assert!((schedules.entries)().is_empty());
+
+    let response = server.put("/admin/schedules").header(admin_key()).json(&json!({ "gc": "off" })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let changed = body(response);
+    assert_eq!((schedules.entry)("gc"), Some(json!("off")));
+    reload("schedules");
+    assert_eq!(body(server.get("/admin/schedules").header(admin_key()).dispatch()), changed);
+
+    // Without its entry a task is back on the config's schedule
+    (schedules.put)("gc", None).unwrap();
+    assert_eq!(body(server.get("/admin/schedules").header(admin_key()).dispatch()), configured);
+}
+
+#[test]
+fn every_collection_survives_a_reload() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    server.post(format!("/capsules/{}/shares", id(&capsule))).header(owner.key.clone()).dispatch();
+
+    for collection in &state::COLLECTIONS {
+        let mut before = (collection.entries)();
+        reload(collection.name);
+        let mut after = (collection.entries)();
+        before.sort_by(|a, b| a.0.cmp(&b.0));
+        after.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(before, after, "{} changed when it was loaded again", collection.name);
+    }
+}
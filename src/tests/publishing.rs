@@ -0,0 +1,29 @@
+// Scheduled publishing: only a capsule's editors decide when it goes public
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn only_editors_schedule_publishing() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let publishing = format!("/capsules/{}/publishing", id(&capsule));
+    let request = json!({ "publish_at": "2031-01-01T00:00:00Z" });
+
+    assert_eq!(server.put(&publishing).json(&request).dispatch().status(), Status::Unauthorized);
+    assert_eq!(server.put(&publishing).header(other.key.clone()).json(&request).dispatch().status(), Status::Forbidden);
+    assert_eq!(body(server.get(&publishing).dispatch())["publish_at"], json!(null));
+
+    let response = server.put(&publishing).header(owner.key.clone()).json(&request).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["publish_at"], json!("2031-01-01T00:00:00Z"));
+
+    assert_eq!(server.delete(&publishing).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    assert_eq!(body(server.get(&publishing).dispatch())["publish_at"], json!("2031-01-01T00:00:00Z"));
+    let response = server.delete(&publishing).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["publish_at"], json!(null));
+}
@@ -0,0 +1,70 @@
+// Open ceremonies: items show up one step at a time once the capsule has opened
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::{body, id, TestServer};
+use crate::clock::SharedClock;
+use crate::reveals;
+
+fn shown(server: &TestServer, capsule: &Value) -> Vec<String> {
+    let response = server.get(format!("/capsules/{}/items", id(capsule))).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    body(response).as_array().unwrap().iter().map(id).collect()
+}
+
+// What the scheduler does on its next tick
+fn tick(server: &TestServer) {
+    let clock = server.rocket().state::<SharedClock>().expect("The clock is managed");
+    reveals::reveal_due(clock.as_ref());
+}
+
+#[test]
+fn items_are_revealed_step_by_step() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let first = id(&server.item(&capsule, &owner.key, false));
+    let second = id(&server.item(&capsule, &owner.key, false));
+    let reveal = format!("/capsules/{}/reveal", id(&capsule));
+
+    let response = server.put(&reveal).header(owner.key.clone()).json(&json!({ "interval_secs": 86400 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let status = body(response);
+    assert_eq!(status["remaining"], json!(2));
+    assert!(shown(&server, &capsule).is_empty());
+
+    server.advance(365);
+    tick(&server);
+    assert_eq!(shown(&server, &capsule), vec![first.clone()]);
+
+    server.advance(1);
+    tick(&server);
+    assert_eq!(shown(&server, &capsule), vec![first, second]);
+    assert_eq!(body(server.get(&reveal).dispatch())["remaining"], json!(0));
+
+    // Too late for another ceremony
+    let response = server.put(&reveal).header(owner.key.clone()).json(&json!({ "interval_secs": 60 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+}
+
+#[test]
+fn ceremonies_only_take_the_capsules_own_items() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let other = server.capsule(&owner);
+    let item = server.item(&capsule, &owner.key, false);
+    let elsewhere = server.item(&other, &owner.key, false);
+    let reveal = format!("/capsules/{}/reveal", id(&capsule));
+
+    let requests = [
+        json!({ "steps": [{ "item_id": item["id"], "offset_secs": 0 }], "interval_secs": 60 }),
+        json!({ "steps": [{ "item_id": elsewhere["id"], "offset_secs": 0 }] }),
+        json!({ "steps": [{ "item_id": item["id"], "offset_secs": 0 }, { "item_id": item["id"], "offset_secs": 60 }] }),
+    ];
+    for request in requests {
+        let response = server.put(&reveal).header(owner.key.clone()).json(&request).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{} was accepted", request);
+    }
+    assert_eq!(server.get(&reveal).dispatch().status(), Status::NotFound);
+}
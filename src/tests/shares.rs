@@ -0,0 +1,42 @@
+// Share links: handed out by a capsule's editors, kept across restarts
+use rocket::http::Status;
+
+use super::{body, id, TestServer};
+use crate::state;
+
+#[test]
+fn only_editors_share_a_capsule() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let shares = format!("/capsules/{}/shares", id(&capsule));
+
+    assert_eq!(server.post(&shares).dispatch().status(), Status::Unauthorized);
+    assert_eq!(server.post(&shares).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    assert_eq!(server.post("/capsules/999999/shares").header(owner.key.clone()).dispatch().status(), Status::NotFound);
+
+    let response = server.post(&shares).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let token = body(response)["token"].as_str().unwrap().to_string();
+    assert_eq!(server.get(format!("/shared/{}/preview", token)).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn shares_are_kept_as_state() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let response = server.post(format!("/capsules/{}/shares", id(&capsule))).header(owner.key.clone()).dispatch();
+    let token = body(response)["token"].as_str().unwrap().to_string();
+    let preview = format!("/shared/{}/preview", token);
+
+    let shares = state::collection("shares").expect("Shares are a state collection");
+    let entry = (shares.entry)(&token).expect("The link's entry");
+
+    // As loaded again after a restart
+    (shares.put)(&token, None).unwrap();
+    assert_eq!(server.get(&preview).dispatch().status(), Status::NotFound);
+    (shares.put)(&token, Some(entry)).unwrap();
+    assert_eq!(server.get(&preview).dispatch().status(), Status::Ok);
+}
@@ -0,0 +1,52 @@
+// Sealing: capsules seal when their edit window closes, or once their invited signers signed
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, key, TestServer};
+
+#[test]
+fn capsules_seal_when_the_edit_window_closes() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    server.advance(8);
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Too late", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(server.delete(&path).header(owner.key.clone()).dispatch().status(), Status::BadRequest);
+
+    let response = server.post(format!("{}/items", path)).header(owner.key.clone())
+        .json(&json!({ "type_c": "photo", "description": "Late", "size": "1KB", "path": "late.jpg", "metadata": {} }))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn capsules_with_signers_seal_once_signed() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let signer = server.contributor();
+    let capsule = server.capsule_with(&owner, json!({ "signers": [signer.id], "required_signatures": 1 }));
+    let path = format!("/capsules/{}", id(&capsule));
+
+    let response = server.post(format!("{}/signatures", path)).header(owner.key.clone()).json(&json!({ "message": "Me too" })).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // Past the edit window it still changes while the signature is missing
+    server.advance(8);
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Still open", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = server.post(format!("{}/signatures", path)).header(signer.key.clone()).json(&json!({ "message": "Signed" })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let signatures = body(response);
+    assert_eq!((signatures["count"].clone(), signatures["sealed"].clone()), (json!(1), json!(true)));
+    // The messages stay hidden until the capsule opens
+    assert!(signatures["signatures"].is_null());
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Sealed", "version": 2 })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = server.post(format!("{}/signatures", path)).header(key(&signer.id)).json(&json!({ "message": "Again" })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+}
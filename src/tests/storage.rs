@@ -0,0 +1,81 @@
+// Where the records are kept: the data files written back by persistence, or SQLite
+use rocket::http::Status;
+use rocket_db_pools::sqlx::{self, Row, SqlitePool};
+use serde_json::{json, Value};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use super::{id, TestServer};
+use crate::config;
+use crate::database;
+
+#[test]
+fn memory_keeps_changes_in_memory() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    server.capsule_with(&owner, json!({ "name": "Only in memory" }));
+
+    let capsules = fs::read_to_string(config::data_path("capsule.json")).unwrap();
+    assert!(!capsules.contains("Only in memory"));
+}
+
+#[test]
+fn persistence_writes_the_data_files() {
+    let env = [("ROCKET_PERSISTENCE", "{enabled=true}")];
+    let Some(server) = TestServer::with(&env, module_path!(), "persistence_writes_the_data_files") else { return };
+    let owner = server.contributor();
+    server.capsule_with(&owner, json!({ "name": "Written back" }));
+
+    for _ in 0..50 {
+        let capsules = fs::read_to_string(config::data_path("capsule.json")).unwrap();
+        if capsules.contains("Written back") {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("capsule.json wasn't written");
+}
+
+#[test]
+fn sqlite_writes_every_change() {
+    let env = [("ROCKET_STORAGE", "{backend=\"sqlite\"}")];
+    let Some(server) = TestServer::with(&env, module_path!(), "sqlite_writes_every_change") else { return };
+
+    // A new database is seeded from the data files
+    assert_eq!(server.get("/capsules/1").dispatch().status(), Status::Ok);
+
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stored", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stale", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let (data, version, keys) = server.runtime().block_on(async {
+        let pool = written().await;
+        let row = sqlx::query("SELECT data, version FROM capsules WHERE id = ?").bind(capsule["id"].as_i64()).fetch_one(&pool).await.unwrap();
+        let keys: i64 = sqlx::query("SELECT COUNT(*) FROM state WHERE collection = 'tokens'").fetch_one(&pool).await.unwrap().get(0);
+        (row.get::<String, _>("data"), row.get::<i64, _>("version"), keys)
+    });
+    let data: Value = serde_json::from_str(&data).unwrap();
+    assert_eq!((data["name"].clone(), version), (json!("Stored"), 2));
+    assert!(keys >= 1);
+
+    let response = server.delete(&path).header(owner.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    let remaining: i64 = server.runtime().block_on(async {
+        let pool = written().await;
+        sqlx::query("SELECT COUNT(*) FROM capsules WHERE id = ?").bind(capsule["id"].as_i64()).fetch_one(&pool).await.unwrap().get(0)
+    });
+    assert_eq!(remaining, 0);
+    assert_ne!(server.get(&path).dispatch().status(), Status::Ok);
+}
+
+// The database file, once the queued changes are written to it
+async fn written() -> SqlitePool {
+    database::flushed().await;
+    let url = format!("sqlite://{}", config::data_path("capsules.db").display());
+    SqlitePool::connect(&url).await.expect("The database file")
+}
@@ -0,0 +1,74 @@
+// /sync and /export: each caller gets only what they may see
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::{body, TestServer};
+
+// The ids of the records of one kind in a /sync or /export body
+fn ids(records: &Value) -> Vec<Value> {
+    records.as_array().expect("A list of records").iter().map(|record| record["id"].clone()).collect()
+}
+
+fn email(records: &Value, id: &Value) -> Value {
+    records.as_array().unwrap().iter().find(|record| record["id"] == *id).expect("The contributor")["email"].clone()
+}
+
+#[test]
+fn sync_shows_only_what_the_caller_sees() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let private = server.capsule(&owner);
+    let public = server.capsule_with(&owner, json!({ "visibility": "public" }));
+    let hidden = server.item(&public, &owner.key, true);
+    let shown = server.item(&public, &owner.key, false);
+    let private_item = server.item(&private, &owner.key, false);
+
+    assert_eq!(server.get("/sync").dispatch().status(), Status::Unauthorized);
+
+    let response = server.get("/sync").header(other.key.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let changes = body(response);
+    let capsules = ids(&changes["capsules"]["updated"]);
+    assert!(capsules.contains(&public["id"]) && !capsules.contains(&private["id"]));
+    let items = ids(&changes["items"]["updated"]);
+    assert!(items.contains(&shown["id"]));
+    assert!(!items.contains(&hidden["id"]) && !items.contains(&private_item["id"]));
+    assert_eq!(email(&changes["contributors"]["updated"], &owner.id), json!(""));
+    assert_ne!(email(&changes["contributors"]["updated"], &other.id), json!(""));
+
+    let changes = body(server.get("/sync").header(owner.key.clone()).dispatch());
+    let items = ids(&changes["items"]["updated"]);
+    assert!(ids(&changes["capsules"]["updated"]).contains(&private["id"]));
+    assert!(items.contains(&hidden["id"]) && items.contains(&private_item["id"]));
+
+    // A capsule leaving the public feed takes its items along
+    let cursor = body(server.get("/sync").header(other.key.clone()).dispatch())["cursor"].clone();
+    let response = server.patch("/capsules").header(owner.key.clone())
+        .json(&json!({ "ids": [public["id"]], "changes": { "visibility": "private" } })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let changes = body(server.get(format!("/sync?since={}", cursor.as_str().unwrap())).header(other.key.clone()).dispatch());
+    assert!(changes["capsules"]["deleted"].as_array().unwrap().contains(&public["id"]));
+    assert!(changes["items"]["deleted"].as_array().unwrap().contains(&shown["id"]));
+}
+
+#[test]
+fn export_shows_only_what_the_caller_sees() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let capsule = server.capsule(&owner);
+    let item = server.item(&capsule, &owner.key, false);
+
+    assert_eq!(server.get("/export").dispatch().status(), Status::Unauthorized);
+
+    let export = body(server.get("/export").header(other.key.clone()).dispatch());
+    assert!(!ids(&export["capsules"]).contains(&capsule["id"]));
+    assert!(!ids(&export["items"]).contains(&item["id"]));
+    assert_eq!(email(&export["contributors"], &owner.id), json!(""));
+
+    let export = body(server.get("/export").header(owner.key.clone()).dispatch());
+    assert!(ids(&export["capsules"]).contains(&capsule["id"]));
+    assert!(ids(&export["items"]).contains(&item["id"]));
+    assert_ne!(email(&export["contributors"], &owner.id), json!(""));
+}
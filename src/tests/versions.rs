@@ -0,0 +1,91 @@
+// Versions of a capsule: PATCH and PUT are made against the current one
+use rocket::http::{Header, Status};
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn patches_are_made_against_the_current_version() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "No version" })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Second", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["version"], json!(2));
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stale", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = server.patch(format!("{}?etag=2", path)).header(owner.key.clone()).json(&json!({ "name": "Mixed", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn puts_check_the_version() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    let update = |version: u32| json!({ "name": "Replaced", "description": "", "time_open": capsule["time_open"], "version": version });
+
+    let response = server.put(&path).header(owner.key.clone()).header(Header::new("If-Match", "\"2\"")).json(&update(1)).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = server.put(&path).header(owner.key.clone()).header(Header::new("If-Match", "\"1\"")).json(&update(1)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response)["version"], json!(2));
+
+    let response = server.put(&path).header(owner.key.clone()).json(&update(1)).dispatch();
+    assert_eq!(response.status(), Status::PreconditionFailed);
+}
+
+#[test]
+fn auto_merge_applies_patches_of_unchanged_fields() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Second", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // The description didn't change since version 1, the name did
+    let response = server.patch(format!("{}?auto_merge=true", path)).header(owner.key.clone())
+        .json(&json!({ "description": "Merged", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let merged = body(response);
+    assert_eq!((merged["name"].clone(), merged["description"].clone(), merged["version"].clone()), (json!("Second"), json!("Merged"), json!(3)));
+
+    let response = server.patch(format!("{}?auto_merge=true", path)).header(owner.key.clone())
+        .json(&json!({ "name": "Lost update", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    // A retry of a patch that went through is harmless
+    let response = server.patch(format!("{}?auto_merge=true", path)).header(owner.key.clone())
+        .json(&json!({ "name": "Second", "version": 1 })).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn refused_changes_leave_the_capsule_alone() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+    let path = format!("/capsules/{}", id(&capsule));
+    let etag = || server.get(&path).dispatch().headers().get_one("ETag").map(String::from);
+    let before = etag();
+
+    let response = server.patch(&path).header(owner.key.clone()).json(&json!({ "name": "Stale", "version": 5 })).dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+    let update = json!({ "name": "Replaced", "description": "", "time_open": capsule["time_open"], "tags": ["new"], "version": 5 });
+    let response = server.put(&path).header(owner.key.clone()).json(&update).dispatch();
+    assert_eq!(response.status(), Status::PreconditionFailed);
+
+    assert_eq!(etag(), before);
+    assert_eq!(body(server.get(&path).dispatch())["name"], capsule["name"]);
+}
@@ -0,0 +1,60 @@
+// Contributor webhooks: managed with the contributor's own key, never aimed at internal
+// addresses, their secrets rotated with a grace period
+use rocket::http::Status;
+use serde_json::json;
+
+use super::{body, id, TestServer};
+
+#[test]
+fn webhooks_to_internal_addresses_are_refused() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let webhooks = format!("/contributors/{}/webhooks", owner.id);
+
+    for url in ["http://169.254.169.254/latest/meta-data/", "http://127.0.0.1:8000/hooks", "http://[::1]/", "http://192.168.1.1/"] {
+        let response = server.post(&webhooks).header(owner.key.clone()).json(&json!({ "url": url })).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{} was registered", url);
+    }
+
+    let response = server.post(&webhooks).header(owner.key.clone()).json(&json!({ "url": "http://93.184.216.34/hooks" })).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let webhook = format!("{}/{}", webhooks, id(&response.into_json().unwrap()));
+    let response = server.patch(&webhook).header(owner.key.clone()).json(&json!({ "url": "http://10.0.0.1/hooks" })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn imports_from_internal_addresses_are_refused() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let capsule = server.capsule(&owner);
+
+    let response = server.post(format!("/capsules/{}/import", id(&capsule))).header(owner.key.clone())
+        .json(&json!({ "source": "url_list", "urls": ["http://169.254.169.254/latest/meta-data/iam"] })).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn secrets_are_rotated_with_a_grace_period() {
+    let server = TestServer::start();
+    let owner = server.contributor();
+    let other = server.contributor();
+    let response = server.post(format!("/contributors/{}/webhooks", owner.id)).header(owner.key.clone())
+        .json(&json!({ "url": "http://93.184.216.34/hooks" })).dispatch();
+    let created = body(response);
+    let webhook = format!("/contributors/{}/webhooks/{}", owner.id, id(&created));
+    assert!(created["secret"].as_str().unwrap().starts_with("whsec_"));
+    assert!(body(server.get(&webhook).header(owner.key.clone()).dispatch()).get("secret").is_none());
+
+    let rotate = format!("{}/rotate-secret", webhook);
+    assert_eq!(server.post(&rotate).header(other.key.clone()).dispatch().status(), Status::Forbidden);
+    assert_eq!(server.post(format!("{}?grace_hours=25", rotate)).header(owner.key.clone()).dispatch().status(), Status::BadRequest);
+
+    let rotated = body(server.post(&rotate).header(owner.key.clone()).dispatch());
+    assert_ne!(rotated["secret"], created["secret"]);
+    assert!(rotated["previous_secret_expires_at"].is_string());
+
+    // A leaked secret stops signing at once
+    let rotated = body(server.post(format!("{}?grace_hours=0", rotate)).header(owner.key.clone()).dispatch());
+    assert!(rotated["previous_secret_expires_at"].is_null());
+}
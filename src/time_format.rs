@@ -0,0 +1,164 @@
+// How times are written in JSON responses: RFC 3339 strings, or whole seconds since the
+// Unix epoch for clients asking for `epoch` (with `?time_format=epoch`, or
+// `Accept: application/json; profile=epoch`). `time_format` in the config sets the format
+// of clients that don't ask. Routes run in the format of their request, see `formatted`,
+// and `DateTime` fields of responses are written with `time` or `optional_time`, which
+// read it. Local wall-clock times like `time_open_local` have no offset and stay as they
+// are. What's stored is always RFC 3339.
+use rocket::data::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Status};
+use rocket::route::{self, Handler, Route};
+use rocket::serde::{Serialize, Serializer};
+use rocket::{Request, Response};
+use chrono::{DateTime, Utc};
+use std::cell::Cell;
+use std::future::Future;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::config::{self, TimeFormat};
+use crate::store;
+
+const PARAM: &str = "time_format";
+
+thread_local! {
+    // Format of the request running on this thread
+    static FORMAT: Cell<TimeFormat> = const { Cell::new(TimeFormat::Rfc3339) };
+}
+
+impl TimeFormat {
+    fn parse(value: &str) -> Option<TimeFormat> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "rfc3339" => Some(TimeFormat::Rfc3339),
+            "epoch" => Some(TimeFormat::Epoch),
+            _ => None,
+        }
+    }
+}
+
+// The format asked for by the query, then the Accept header, then the config. Err holds
+// a query value that isn't a format; Accept profiles that aren't are someone else's.
+fn requested(request: &Request<'_>) -> Result<TimeFormat, String> {
+    let query = request.uri().query().and_then(|query| query.segments().find(|(name, _)| *name == PARAM).map(|(_, value)| value.to_string()));
+    if let Some(value) = query {
+        return TimeFormat::parse(&value).ok_or(value);
+    }
+    let profile = request.accept().and_then(|accept| accept.media_types()
+        .find_map(|media_type| media_type.param("profile").and_then(TimeFormat::parse)));
+    Ok(profile.unwrap_or(config::get().time_format))
+}
+
+// The format times are written in on this thread, RFC 3339 outside of requests
+pub fn current() -> TimeFormat {
+    FORMAT.with(Cell::get)
+}
+
+// Runs `f` with times written in `format`
+pub fn rendering<R>(format: TimeFormat, f: impl FnOnce() -> R) -> R {
+    struct Restore(TimeFormat);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FORMAT.with(|current| current.set(self.0));
+        }
+    }
+    let _restore = Restore(FORMAT.with(|current| current.replace(format)));
+    f()
+}
+
+// For `serialize_with` on `DateTime` fields
+pub fn time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    match current() {
+        TimeFormat::Epoch if store::answering(time) => serializer.serialize_i64(time.timestamp()),
+        _ => time.serialize(serializer),
+    }
+}
+
+// For `serialize_with` on optional `DateTime` fields
+pub fn optional_time<S: Serializer>(value: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&Formatted(value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+// A time serialized with `time`
+struct Formatted<'a>(&'a DateTime<Utc>);
+
+impl Serialize for Formatted<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        time(self.0, serializer)
+    }
+}
+
+// A future polled in the format of its request
+struct InFormat<F> {
+    format: TimeFormat,
+    future: F,
+}
+
+impl<F: Future + Unpin> Future for InFormat<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let format = self.format;
+        rendering(format, || Pin::new(&mut self.future).poll(cx))
+    }
+}
+
+// A route's handler run in the format of the request, responders and all
+#[derive(Clone)]
+struct InRequestFormat(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for InRequestFormat {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let format = request.local_cache(|| requested(request)).clone().unwrap_or_default();
+        InFormat { format, future: self.0.handle(request, data) }.await
+    }
+}
+
+// The routes, each run in the format of its request
+pub fn formatted(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(InRequestFormat(route.handler.clone()));
+            route
+        })
+        .collect()
+}
+
+// Fairing answering unknown formats and telling caches that the format depends on Accept
+pub struct TimeFormatting;
+
+#[rocket::async_trait]
+impl Fairing for TimeFormatting {
+    fn info(&self) -> Info {
+        Info { name: "Time format", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let format = requested(request);
+        if format.is_err() {
+            // Route the request nowhere so no handler runs, the response says why
+            request.set_uri(Origin::parse("/__time_format").unwrap());
+        }
+        request.local_cache(|| format);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Err(value) = request.local_cache(|| Ok::<_, String>(TimeFormat::Rfc3339)) {
+            let message = serde_json::to_string(&format!("Unknown time format '{}', use rfc3339 or epoch", value)).unwrap_or_default();
+            response.set_status(Status::BadRequest);
+            response.set_header(ContentType::JSON);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
+        }
+        // Clients of the same URL can get either format through the Accept header
+        if response.content_type().is_some_and(|content_type| content_type.is_json()) {
+            response.adjoin_header(Header::new("Vary", "Accept"));
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::error_messages::ApiError;
+
+// Parses an IANA timezone name such as "Europe/Warsaw"
+pub fn parse(name: &str) -> Result<Tz, ApiError> {
+    name.parse::<Tz>()
+        .map_err(|_| ApiError::UnknownTimezone(name.to_string()))
+}
+
+// The UTC instant of a wall-clock time in the given timezone. A time repeated when
+// clocks go back resolves to its first occurrence, a time skipped when clocks go
+// forward resolves to the same wall-clock distance after the jump (02:30 -> 03:30).
+pub fn resolve_local(local: NaiveDateTime, tz: Tz) -> DateTime<Utc> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(time) => time.with_timezone(&Utc),
+        LocalResult::Ambiguous(earliest, _) => earliest.with_timezone(&Utc),
+        LocalResult::None => {
+            // Use the offset in effect before the gap, DST gaps are at most a few hours wide
+            let offset = tz.offset_from_utc_datetime(&(local - Duration::hours(6))).fix();
+            (local - Duration::seconds(offset.local_minus_utc() as i64)).and_utc()
+        }
+    }
+}
+
+// Wall-clock time of an instant in the given timezone
+pub fn to_local(time: DateTime<Utc>, tz: Tz) -> NaiveDateTime {
+    time.with_timezone(&tz).naive_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(text: &str) -> NaiveDateTime {
+        text.parse().unwrap()
+    }
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn wall_clock_times_resolve_to_utc() {
+        let warsaw = parse("Europe/Warsaw").ok().unwrap();
+        assert_eq!(resolve_local(local("2024-01-15T12:00:00"), warsaw), utc("2024-01-15T11:00:00Z"));
+        assert_eq!(to_local(utc("2024-07-15T10:00:00Z"), warsaw), local("2024-07-15T12:00:00"));
+    }
+
+    #[test]
+    fn skipped_and_repeated_times_resolve() {
+        let warsaw = parse("Europe/Warsaw").ok().unwrap();
+        // Skipped when clocks go forward, taken as 03:30 summer time
+        assert_eq!(resolve_local(local("2024-03-31T02:30:00"), warsaw), utc("2024-03-31T01:30:00Z"));
+        // Repeated when clocks go back, taken as the first, summer time one
+        assert_eq!(resolve_local(local("2024-10-27T02:30:00"), warsaw), utc("2024-10-27T00:30:00Z"));
+    }
+
+    #[test]
+    fn unknown_timezones_are_rejected() {
+        assert!(matches!(parse("Europe/Atlantis"), Err(ApiError::UnknownTimezone(name)) if name == "Europe/Atlantis"));
+    }
+}
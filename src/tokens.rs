@@ -0,0 +1,619 @@
+// API keys for running the service in public. A key belongs to a contributor and is
+// sent as `Authorization: Bearer <key>`; every use is counted, and a key can carry its
+// own limit of requests per minute. Changes need a key, only signing up, getting a key,
+// validating and reporting a public capsule don't; reads without a key are let through.
+//
+// A contributor with a key creates further keys with it. The first one takes proof that
+// the caller is the contributor: a code sent to their email with `POST /tokens/codes`.
+//
+// The /admin routes, reads included, take the key of an admin, a contributor listed in
+// `tokens.admin_contributor_ids`.
+//
+// Expensive endpoints (searches, exports, ZIP downloads, bulk operations) belong to a cost
+// class with a stricter limit of its own from `rate_limits`, counted per contributor for
+// requests with a key and per client address for those without.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Method, Status};
+use rocket::http::uri::Origin;
+use rocket::request::{self, FromRequest};
+use rocket::response::status;
+use rocket::{Data, Request, Response, State};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+
+use crate::clock::SharedClock;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::ids::{ContributorId, EntityId};
+use crate::notifications::{self, Email};
+use crate::state;
+use crate::store;
+use crate::time_format;
+
+const KEY_PREFIX: &str = "cap_";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiToken {
+    pub id: u32,
+    pub contributor_id: ContributorId,
+    pub name: String,
+    pub prefix: String,  // Start of the key, to tell keys apart without storing them
+    #[serde(serialize_with = "time_format::time")]
+    pub created_at: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub last_used: Option<DateTime<Utc>>,
+    pub request_count: u64,
+    pub rejected_count: u64,  // Requests refused for going over the rate limit
+    pub rate_limit_per_minute: Option<u32>,  // None falls back to `tokens.default_rate_limit_per_minute`
+    #[serde(default)]
+    pub device: Option<String>,  // User-Agent of the last use, to tell sessions apart
+    #[serde(default, skip_serializing_if = "store::answering")]
+    hash: String,  // SHA-256 of the key, only stored
+    #[serde(skip)]
+    window: (DateTime<Utc>, u32),  // Start of the current minute and the requests in it
+}
+
+// With a key the new one is the caller's, without one `email` and `code` prove who it's for
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewToken {
+    contributor_id: Option<ContributorId>,
+    name: String,
+    rate_limit_per_minute: Option<u32>,
+    email: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CodeRequest {
+    email: String,
+}
+
+// An emailed code for a first key, by contributor. Only its SHA-256 is kept.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+struct KeyCode {
+    hash: String,
+    #[serde(serialize_with = "time_format::time")]
+    expires_at: DateTime<Utc>,
+    attempts_left: u32,
+}
+
+static CODES: Lazy<Mutex<HashMap<ContributorId, KeyCode>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The key itself is only ever shown here
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CreatedToken {
+    pub key: String,
+    #[serde(flatten)]
+    pub token: ApiToken,
+}
+
+#[derive(Default)]
+struct TokenStore {
+    tokens: HashMap<String, ApiToken>,  // By SHA-256 of the key
+    next_id: u32,
+}
+
+static TOKENS: Lazy<Mutex<TokenStore>> = Lazy::new(|| Mutex::new(TokenStore { next_id: 1, ..Default::default() }));
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+impl ApiToken {
+    fn rate_limit(&self) -> Option<u32> {
+        self.rate_limit_per_minute.or(config::get().tokens.default_rate_limit_per_minute)
+    }
+}
+
+// What the gate decided for a request, applied to its response
+#[derive(Clone, Copy)]
+enum Verdict {
+    NoKey,
+    Allowed { token_id: u32, contributor_id: ContributorId, limit: Option<u32>, remaining: u32 },
+    UnknownKey,
+    KeyRequired,
+    AdminRequired,
+    Limited { retry_after: i64, class: Option<CostClass> },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum CostClass {
+    Search,
+    Export,
+    Download,
+    Bulk,
+}
+
+impl CostClass {
+    fn name(self) -> &'static str {
+        match self {
+            CostClass::Search => "search",
+            CostClass::Export => "export",
+            CostClass::Download => "download",
+            CostClass::Bulk => "bulk",
+        }
+    }
+
+    fn limit(self) -> u32 {
+        let limits = &config::get().rate_limits;
+        match self {
+            CostClass::Search => limits.search,
+            CostClass::Export => limits.export,
+            CostClass::Download => limits.download,
+            CostClass::Bulk => limits.bulk,
+        }
+    }
+}
+
+// What is left of the class limit after a request, applied to its response
+#[derive(Clone, Copy)]
+struct Charge {
+    class: CostClass,
+    limit: u32,
+    remaining: u32,
+}
+
+// Start of the current minute and the requests in it
+type Window = (DateTime<Utc>, u32);
+
+// Windows of the cost classes, by class and caller. Idle ones are dropped once there are many.
+static CLASS_WINDOWS: Lazy<Mutex<HashMap<(CostClass, String), Window>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+const MAX_CLASS_WINDOWS: usize = 10_000;
+
+// Long enough for any real User-Agent
+const MAX_DEVICE_LEN: usize = 200;
+
+fn check(key: &str, device: Option<&str>, now: DateTime<Utc>) -> Verdict {
+    let mut store = TOKENS.lock().unwrap();
+    let Some(token) = store.tokens.get_mut(&hash_key(key)) else { return Verdict::UnknownKey };
+
+    if now - token.window.0 >= Duration::minutes(1) || now < token.window.0 {
+        token.window = (now, 0);
+    }
+    let limit = token.rate_limit();
+    if limit.is_some_and(|limit| token.window.1 >= limit) {
+        token.rejected_count += 1;
+        state::changed("tokens", token.id);
+        let retry_after = (token.window.0 + Duration::minutes(1) - now).num_seconds().max(1);
+        return Verdict::Limited { retry_after, class: None };
+    }
+    token.window.1 += 1;
+    token.request_count += 1;
+    token.last_used = Some(now);
+    if let Some(device) = device {
+        token.device = Some(device.chars().take(MAX_DEVICE_LEN).collect());
+    }
+    state::changed("tokens", token.id);
+    Verdict::Allowed { token_id: token.id, contributor_id: token.contributor_id, limit, remaining: limit.map_or(0, |limit| limit - token.window.1) }
+}
+
+// Matched before routing, on the method and path of the routes in the class
+fn cost_class(request: &Request<'_>) -> Option<CostClass> {
+    let segments: Vec<&str> = request.uri().path().segments().collect();
+    let searching = request.query_value::<&str>("q").is_some_and(|q| q.is_ok_and(|q| !q.trim().is_empty()));
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["capsules"] | ["items"]) if searching => Some(CostClass::Search),
+        (Method::Get, ["export"]) | (Method::Post, ["exports"] | ["capsules", _, "archive-export"]) => Some(CostClass::Export),
+        (Method::Get, ["capsules", _, "items", "download"] | ["exports", _, "download"]) => Some(CostClass::Download),
+        (Method::Post, ["contributors", "bulk"] | ["capsules", _, "import"]) => Some(CostClass::Bulk),
+        _ => None,
+    }
+}
+
+// Counts a request against its class, Err with the seconds to wait once over the limit
+fn charge(class: CostClass, caller: String, now: DateTime<Utc>) -> Result<Option<Charge>, i64> {
+    let limit = class.limit();
+    if limit == 0 {
+        return Ok(None);
+    }
+    let mut windows = CLASS_WINDOWS.lock().unwrap();
+    if windows.len() >= MAX_CLASS_WINDOWS {
+        windows.retain(|_, window| now - window.0 < Duration::minutes(1));
+    }
+    let window = windows.entry((class, caller)).or_insert((now, 0));
+    if now - window.0 >= Duration::minutes(1) || now < window.0 {
+        *window = (now, 0);
+    }
+    if window.1 >= limit {
+        return Err((window.0 + Duration::minutes(1) - now).num_seconds().max(1));
+    }
+    window.1 += 1;
+    Ok(Some(Charge { class, limit, remaining: limit - window.1 }))
+}
+
+// Drops the windows of callers that have gone quiet. Run by the scheduler's `gc` task,
+// between runs they are only dropped once there are too many.
+pub fn sweep(now: DateTime<Utc>) {
+    CLASS_WINDOWS.lock().unwrap().retain(|_, window| now - window.0 < Duration::minutes(1));
+}
+
+fn set_limit_headers(response: &mut Response<'_>, limit: u32, remaining: u32, class: Option<CostClass>) {
+    response.set_raw_header("X-RateLimit-Limit", limit.to_string());
+    response.set_raw_header("X-RateLimit-Remaining", remaining.to_string());
+    if let Some(class) = class {
+        response.set_raw_header("X-RateLimit-Class", class.name());
+    }
+}
+
+// Changes without a key are refused, except for the routes a contributor needs before
+// having one and those that change nothing
+fn needs_key(request: &Request<'_>) -> bool {
+    let segments: Vec<&str> = request.uri().path().segments().collect();
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["contributors"] | ["tokens"] | ["tokens", "codes"]) => false,
+        (Method::Post, ["public", "capsules", _, "report"]) => false,
+        (Method::Post, ["capsules", "validate"] | ["capsules", _, "items", "validate"]) => false,
+        (Method::Post | Method::Put | Method::Patch | Method::Delete, _) => true,
+        _ => false,
+    }
+}
+
+fn needs_admin(request: &Request<'_>) -> bool {
+    request.uri().path().segments().next() == Some("admin")
+}
+
+fn is_admin(contributor_id: ContributorId) -> bool {
+    config::get().tokens.admin_contributor_ids.contains(&contributor_id.number())
+}
+
+fn presented_key<'a>(request: &'a Request<'_>) -> Option<&'a str> {
+    request.headers().get_one("Authorization")?.strip_prefix("Bearer ").map(str::trim)
+}
+
+// Fairing counting key usage and refusing unknown keys and requests over a key's or a
+// cost class's limit
+pub struct TokenGate;
+
+#[rocket::async_trait]
+impl Fairing for TokenGate {
+    fn info(&self) -> Info {
+        Info { name: "API key gate", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let now = request.rocket().state::<SharedClock>().map_or_else(Utc::now, |clock| clock.now());
+        let mut verdict = match presented_key(request) {
+            None if needs_key(request) || needs_admin(request) => Verdict::KeyRequired,
+            None => Verdict::NoKey,
+            Some(key) => check(key, request.headers().get_one("User-Agent"), now),
+        };
+        if needs_admin(request) && matches!(verdict, Verdict::Allowed { contributor_id, .. } if !is_admin(contributor_id)) {
+            verdict = Verdict::AdminRequired;
+        }
+        let mut charged = None;
+        if let Some(class) = cost_class(request) {
+            let caller = match verdict {
+                Verdict::NoKey => request.client_ip().map(|ip| ip.to_string()),
+                Verdict::Allowed { contributor_id, .. } => Some(format!("contributor {}", contributor_id)),
+                _ => None,
+            };
+            match caller.map(|caller| charge(class, caller, now)) {
+                Some(Ok(charge)) => charged = charge,
+                Some(Err(retry_after)) => verdict = Verdict::Limited { retry_after, class: Some(class) },
+                None => {},
+            }
+        }
+        if matches!(verdict, Verdict::UnknownKey | Verdict::KeyRequired | Verdict::AdminRequired | Verdict::Limited { .. }) {
+            // Route the request nowhere so no handler runs and no state is changed
+            request.set_uri(Origin::parse("/__tokens").unwrap());
+        }
+        request.local_cache(|| verdict);
+        request.local_cache(|| charged);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let charged = *request.local_cache(|| None::<Charge>);
+        let (status, message) = match *request.local_cache(|| Verdict::NoKey) {
+            Verdict::NoKey | Verdict::Allowed { limit: None, .. } => {
+                if let Some(Charge { class, limit, remaining }) = charged {
+                    set_limit_headers(response, limit, remaining, Some(class));
+                }
+                return;
+            },
+            // The headers show whichever limit runs out first
+            Verdict::Allowed { limit: Some(limit), remaining, .. } => {
+                match charged {
+                    Some(Charge { class, limit, remaining: left }) if left < remaining => set_limit_headers(response, limit, left, Some(class)),
+                    _ => set_limit_headers(response, limit, remaining, None),
+                }
+                return;
+            },
+            Verdict::UnknownKey => (Status::Unauthorized, "Unknown or revoked API key".to_string()),
+            Verdict::KeyRequired => (Status::Unauthorized, "Send your API key as Authorization: Bearer <key>, see POST /tokens".to_string()),
+            Verdict::AdminRequired => (Status::Forbidden, "Only admins can use the /admin routes".to_string()),
+            Verdict::Limited { retry_after, class } => {
+                response.set_raw_header("Retry-After", retry_after.to_string());
+                match class {
+                    Some(class) => {
+                        response.set_raw_header("X-RateLimit-Class", class.name());
+                        (Status::TooManyRequests, format!("Rate limit for {} requests exceeded, retry in {} seconds", class.name(), retry_after))
+                    },
+                    None => (Status::TooManyRequests, format!("Rate limit exceeded, retry in {} seconds", retry_after)),
+                }
+            },
+        };
+        let body = serde_json::to_string(&message).unwrap();
+        response.set_status(status);
+        response.set_header(ContentType::JSON);
+        response.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
+
+// The contributor whose API key came with the request, None for requests without one
+pub struct Caller(pub Option<ContributorId>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let contributor_id = match *request.local_cache(|| Verdict::NoKey) {
+            Verdict::Allowed { contributor_id, .. } => Some(contributor_id),
+            _ => None,
+        };
+        request::Outcome::Success(Caller(contributor_id))
+    }
+}
+
+impl Caller {
+    // The caller's contributor, 401 for requests without a key
+    pub fn required(&self) -> Result<ContributorId, status::Custom<Json<String>>> {
+        self.0.ok_or_else(|| status::Custom(Status::Unauthorized, Json("Send your API key as Authorization: Bearer <key>".into())))
+    }
+
+    // For changes to a contributor, which only their own key makes. 401 without a key,
+    // 403 with anyone else's.
+    pub fn must_be(&self, contributor_id: ContributorId) -> Result<(), ApiError> {
+        if self.required()? != contributor_id {
+            return Err(ApiError::NotOwnAccount);
+        }
+        Ok(())
+    }
+}
+
+fn unauthorized(message: &str) -> status::Custom<Json<String>> {
+    status::Custom(Status::Unauthorized, Json(message.into()))
+}
+
+fn contributor_with_email(email: &str) -> Option<ContributorId> {
+    let mut found = None;
+    CONTRIBUTORS.for_each(|contributor| {
+        if found.is_none() && contributor.email.eq_ignore_ascii_case(email.trim()) {
+            found = Some(contributor.id);
+        }
+    });
+    found
+}
+
+// Takes the contributor's code, a wrong one uses up an attempt
+fn redeem_code(contributor_id: ContributorId, code: &str, now: DateTime<Utc>) -> bool {
+    let mut codes = CODES.lock().unwrap();
+    let Some(sent) = codes.get_mut(&contributor_id) else { return false };
+    let redeemed = sent.expires_at > now && sent.attempts_left > 0 && sent.hash == hash_key(code.trim());
+    if redeemed || sent.expires_at <= now || sent.attempts_left <= 1 {
+        codes.remove(&contributor_id);
+    } else {
+        sent.attempts_left -= 1;
+    }
+    state::changed("token_codes", contributor_id.number());
+    redeemed
+}
+
+// Sends a code for a first key to the contributor with the email. The answer is the same
+// whether there is one or not, so it doesn't tell which emails are known.
+#[post("/tokens/codes", format = "json", data = "<request>")]
+pub fn send_code(request: Json<CodeRequest>, clock: &State<SharedClock>) -> Result<status::Accepted<Json<String>>, status::Custom<Json<String>>> {
+    if config::get().mail.smtp_url.is_none() {
+        return Err(status::Custom(Status::ServiceUnavailable, Json("Codes can't be sent, mail.smtp_url is not configured".into())));
+    }
+    let accepted = status::Accepted(Json("If a contributor has this email, a code was sent to it".to_string()));
+    let Some(contributor_id) = contributor_with_email(&request.email) else { return Ok(accepted) };
+
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let settings = &config::get().tokens;
+    CODES.lock().unwrap().insert(contributor_id, KeyCode {
+        hash: hash_key(&code),
+        expires_at: clock.now() + Duration::minutes(settings.code_valid_minutes as i64),
+        attempts_left: settings.code_attempts,
+    });
+    state::changed("token_codes", contributor_id.number());
+
+    let email = Email {
+        to: request.email.trim().to_string(),
+        subject: "Your code for an API key".into(),
+        body: format!("Your code for a new API key is {}. It can be used for {} minutes.", code, settings.code_valid_minutes),
+    };
+    rocket::tokio::spawn(async move {
+        if let Err(e) = notifications::send_email(email).await {
+            eprintln!("Failed to send the API key code of contributor {}: {}", contributor_id, e.message);
+        }
+    });
+    Ok(accepted)
+}
+
+// The codes as state entries by contributor, see state.rs
+pub fn code_entries() -> Vec<(String, serde_json::Value)> {
+    CODES.lock().unwrap().iter().map(|(id, code)| (id.number().to_string(), state::to_entry(code))).collect()
+}
+
+pub fn code_entry(key: &str) -> Option<serde_json::Value> {
+    let id = ContributorId::from_number(key.parse().ok()?);
+    CODES.lock().unwrap().get(&id).map(state::to_entry)
+}
+
+pub fn put_code(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let Ok(number) = key.parse() else { return Ok(()) };
+    let id = ContributorId::from_number(number);
+    let code: Option<KeyCode> = entry.map(state::from_entry).transpose()?;
+    let mut codes = CODES.lock().unwrap();
+    match code {
+        Some(code) => codes.insert(id, code),
+        None => codes.remove(&id),
+    };
+    Ok(())
+}
+
+#[post("/tokens", format = "json", data = "<new_token>")]
+pub fn create_token(new_token: Json<NewToken>, caller: Caller, clock: &State<SharedClock>) -> Result<status::Created<Json<CreatedToken>>, ApiError> {
+    let new_token = new_token.into_inner();
+    if new_token.name.trim().is_empty() {
+        return Err(ApiError::NameRequired);
+    }
+    if new_token.rate_limit_per_minute == Some(0) {
+        return Err(ApiError::Other(Status::BadRequest, "rate_limit_per_minute must be at least 1".into()));
+    }
+    let now = clock.now();
+    let contributor_id = match (caller.0, new_token.email.as_deref(), new_token.code.as_deref()) {
+        (Some(caller), _, _) => {
+            if new_token.contributor_id.is_some_and(|id| id != caller) {
+                return Err(ApiError::Other(Status::Forbidden, "Keys can only be created for yourself".into()));
+            }
+            caller
+        },
+        (None, Some(email), Some(code)) => {
+            let contributor_id = contributor_with_email(email).filter(|&id| redeem_code(id, code, now))
+                .ok_or_else(|| unauthorized("Wrong or expired code, ask for a new one with POST /tokens/codes"))?;
+            if new_token.contributor_id.is_some_and(|id| id != contributor_id) {
+                return Err(ApiError::Other(Status::Forbidden, "Keys can only be created for yourself".into()));
+            }
+            contributor_id
+        },
+        (None, _, _) => return Err(unauthorized("Send your API key, or the email and the code sent to it with POST /tokens/codes").into()),
+    };
+
+    let (key, token) = issue(contributor_id, new_token.name.trim(), new_token.rate_limit_per_minute, now);
+    Ok(status::Created::new(format!("/tokens/{}", token.id)).body(Json(CreatedToken { key, token })))
+}
+
+// Makes a new key for the contributor, returned with its record
+pub(crate) fn issue(contributor_id: ContributorId, name: &str, rate_limit_per_minute: Option<u32>, now: DateTime<Utc>) -> (String, ApiToken) {
+    let secret: String = rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+    let key = format!("{}{}", KEY_PREFIX, secret);
+
+    let mut store = TOKENS.lock().unwrap();
+    let hash = hash_key(&key);
+    let token = ApiToken {
+        id: state::next_id(&mut store.next_id),
+        contributor_id,
+        name: name.to_string(),
+        prefix: key[..KEY_PREFIX.len() + 6].to_string(),
+        created_at: now,
+        last_used: None,
+        request_count: 0,
+        rejected_count: 0,
+        rate_limit_per_minute,
+        device: None,
+        hash: hash.clone(),
+        window: (now, 0),
+    };
+    store.tokens.insert(hash, token.clone());
+    state::changed("tokens", token.id);
+    (key, token)
+}
+
+// The caller's keys and their usage. `contributor_id` can only be the caller.
+#[get("/tokens?<contributor_id>")]
+pub fn list_tokens(contributor_id: Option<ContributorId>, caller: Caller) -> Result<Json<Vec<ApiToken>>, status::Custom<Json<String>>> {
+    let caller = caller.required()?;
+    if contributor_id.is_some_and(|id| id != caller) {
+        return Err(status::Custom(Status::Forbidden, Json("Only your own keys can be listed".into())));
+    }
+    Ok(Json(keys_of(caller)))
+}
+
+// Revokes one of the caller's keys, the key of the request itself too
+#[delete("/tokens/<id>")]
+pub fn revoke_token(id: u32, caller: Caller) -> Result<Status, status::Custom<Json<String>>> {
+    let caller = caller.required()?;
+    if revoke(caller, id) {
+        Ok(Status::NoContent)
+    } else {
+        Err(status::Custom(Status::NotFound, Json(format!("You have no API key {}", id))))
+    }
+}
+
+// Revokes a key of the contributor, false if it has none with the id
+pub fn revoke(contributor_id: ContributorId, id: u32) -> bool {
+    let mut store = TOKENS.lock().unwrap();
+    let before = store.tokens.len();
+    store.tokens.retain(|_, token| token.id != id || token.contributor_id != contributor_id);
+    let revoked = store.tokens.len() < before;
+    if revoked {
+        state::changed("tokens", id);
+    }
+    revoked
+}
+
+// The contributor's keys, oldest first
+pub fn keys_of(contributor_id: ContributorId) -> Vec<ApiToken> {
+    let mut tokens: Vec<ApiToken> = TOKENS.lock().unwrap().tokens.values()
+        .filter(|token| token.contributor_id == contributor_id)
+        .cloned()
+        .collect();
+    tokens.sort_by_key(|token| token.id);
+    tokens
+}
+
+// The id of the key the request came with
+pub fn key_of_request(request: &Request<'_>) -> Option<u32> {
+    match *request.local_cache(|| Verdict::NoKey) {
+        Verdict::Allowed { token_id, .. } => Some(token_id),
+        _ => None,
+    }
+}
+
+// Revokes the keys and drops the code of a deleted contributor
+pub fn forget_contributor(contributor_id: ContributorId) {
+    if CODES.lock().unwrap().remove(&contributor_id).is_some() {
+        state::changed("token_codes", contributor_id.number());
+    }
+    TOKENS.lock().unwrap().tokens.retain(|_, token| {
+        let kept = token.contributor_id != contributor_id;
+        if !kept {
+            state::changed("tokens", token.id);
+        }
+        kept
+    });
+}
+
+// The keys as state entries by id, see state.rs
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    TOKENS.lock().unwrap().tokens.values().map(|token| (token.id.to_string(), state::to_entry(token))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    TOKENS.lock().unwrap().tokens.values().find(|token| token.id.to_string() == key).map(state::to_entry)
+}
+
+// The current minute of a replaced key is kept, it's counted by each instance
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let token: Option<ApiToken> = entry.map(state::from_entry).transpose()?;
+    let mut store = TOKENS.lock().unwrap();
+    let mut window = None;
+    store.tokens.retain(|_, old| {
+        let kept = old.id.to_string() != key;
+        if !kept {
+            window = Some(old.window);
+        }
+        kept
+    });
+    if let Some(mut token) = token {
+        token.window = window.unwrap_or(token.window);
+        store.next_id = store.next_id.max(token.id + 1);
+        store.tokens.insert(token.hash.clone(), token);
+    }
+    Ok(())
+}
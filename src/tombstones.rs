@@ -0,0 +1,53 @@
+// Capsules, items and contributors that existed but were removed answer `410 Gone` with
+// when that happened, instead of the `404` of ids that never existed, so sync clients can
+// tell them apart. A capsule merged into another also names that capsule. Removal times
+// come from the tables (see store.rs) and, like the change log, only cover removals since
+// the server started.
+use rocket::serde::{json::Json, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::Either;
+use chrono::{DateTime, Utc};
+
+use crate::capsules::CAPSULES;
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId, ItemId};
+use crate::items::ITEMS;
+use crate::merges;
+use crate::time_format;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Tombstone<I> {
+    pub id: I,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_deleted: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merged_into: Option<CapsuleId>,  // Capsules only, the capsule that took over its items
+}
+
+pub type Gone<I> = status::Custom<Json<Tombstone<I>>>;
+
+// A 410 for removed records, the usual 404 for the rest
+pub type Missing<I> = Either<ApiError, Gone<I>>;
+
+pub fn or_not_found<I>(gone: Option<Gone<I>>, error: ApiError) -> Missing<I> {
+    gone.map_or_else(|| Either::Left(error), Either::Right)
+}
+
+fn gone<I>(id: I, time_deleted: DateTime<Utc>, merged_into: Option<CapsuleId>) -> Gone<I> {
+    status::Custom(Status::Gone, Json(Tombstone { id, time_deleted, merged_into }))
+}
+
+pub fn capsule(id: CapsuleId) -> Option<Gone<CapsuleId>> {
+    CAPSULES.removed_at(id).map(|time| gone(id, time, merges::lineage(id).merged_into))
+}
+
+pub fn item(id: ItemId) -> Option<Gone<ItemId>> {
+    ITEMS.removed_at(id).map(|time| gone(id, time, None))
+}
+
+pub fn contributor(id: ContributorId) -> Option<Gone<ContributorId>> {
+    CONTRIBUTORS.removed_at(id).map(|time| gone(id, time, None))
+}
@@ -0,0 +1,95 @@
+// What a contributor uses: storage, requests and quota consumption, grouped into
+// periods so a billing or fair-use system can look at it over time.
+use rocket::serde::{json::Json, Serialize};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+use crate::contributors::CONTRIBUTORS;
+use crate::error_messages::ApiError;
+use crate::indexes::INDEXES;
+use crate::items::ITEMS;
+use crate::metrics;
+use crate::quotas::{self, CapsuleLimits};
+use crate::reports::{self, GroupBy};
+use crate::ids::ContributorId;
+use crate::time_format;
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct UsagePeriod {
+    pub period: String,
+    pub requests: u64,
+    pub failed_requests: u64,
+    pub items_added: usize,  // Items still stored that were added in this period
+    pub bytes_added: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ContributorUsage {
+    pub contributor_id: ContributorId,
+    pub group_by: String,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub to: Option<DateTime<Utc>>,
+    pub capsules: usize,
+    pub items: usize,
+    pub storage_bytes: u64,
+    pub quotas: Vec<CapsuleLimits>,  // Current consumption per capsule
+    pub periods: Vec<UsagePeriod>,
+}
+
+#[get("/contributors/<id>/usage?<from>&<to>&<group_by>")]
+pub fn contributor_usage(id: ContributorId, from: Option<&str>, to: Option<&str>, group_by: Option<&str>) -> Result<Json<ContributorUsage>, ApiError> {
+    let group_name = group_by.unwrap_or("day");
+    let group = GroupBy::parse(group_name)
+        .ok_or(ApiError::InvalidGroupBy)?;
+    let from = reports::parse_bound(from, "from")?;
+    let to = reports::parse_bound(to, "to")?;
+    if !CONTRIBUTORS.contains(id) {
+        return Err(ApiError::ContributorNotFound);
+    }
+
+    // Only usage inside [from, to) is counted
+    let in_range = |time: DateTime<Utc>| from.is_none_or(|from| time >= from) && to.is_none_or(|to| time < to);
+    let mut periods: BTreeMap<String, UsagePeriod> = BTreeMap::new();
+
+    for (day, counts) in metrics::daily_counts(id) {
+        let Some(time) = day.and_hms_opt(0, 0, 0).map(|time| time.and_utc()) else { continue };
+        if in_range(time) {
+            let period = periods.entry(group.period(time)).or_default();
+            period.requests += counts.requests;
+            period.failed_requests += counts.failed;
+        }
+    }
+
+    let capsule_ids = INDEXES.read().unwrap().capsules_of(id);
+    let quotas: Vec<CapsuleLimits> = capsule_ids.iter().map(|&capsule_id| quotas::limits(capsule_id)).collect();
+    for &capsule_id in &capsule_ids {
+        let item_ids = INDEXES.read().unwrap().items_of(capsule_id);
+        for item in item_ids.into_iter().filter_map(|item_id| ITEMS.get(item_id)) {
+            if in_range(item.time_added) {
+                let period = periods.entry(group.period(item.time_added)).or_default();
+                period.items_added += 1;
+                period.bytes_added += quotas::parse_size(&item.size).unwrap_or(0);
+            }
+        }
+    }
+
+    let periods = periods.into_iter()
+        .map(|(period, usage)| UsagePeriod { period, ..usage })
+        .collect();
+
+    Ok(Json(ContributorUsage {
+        contributor_id: id,
+        group_by: group_name.to_string(),
+        from,
+        to,
+        capsules: capsule_ids.len(),
+        items: quotas.iter().map(|limits| limits.items).sum(),
+        storage_bytes: quotas.iter().map(|limits| limits.bytes).sum(),
+        quotas,
+        periods,
+    }))
+}
@@ -0,0 +1,69 @@
+// Dry runs of the create endpoints for forms: the payload goes through the same checks
+// as a real request and every failure is reported at once, without creating anything.
+use rocket::serde::{json::Json, DeserializeOwned, Serialize};
+use rocket::http::Status;
+use rocket::State;
+
+use crate::capsules::{self, NewCapsule};
+use crate::clock::SharedClock;
+use crate::error_messages::{self, ApiError};
+use crate::flags;
+use crate::i18n::AcceptLanguage;
+use crate::ids::CapsuleId;
+use crate::items::{self, NewItem};
+use crate::payload;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationError {
+    pub status: u16,  // What the real request would have answered with
+    pub message: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub errors: Vec<ValidationError>,
+}
+
+impl ValidationReport {
+    // With the messages in the client's language
+    fn new(errors: Vec<ApiError>, languages: &AcceptLanguage) -> Json<ValidationReport> {
+        let language = error_messages::language_of(&languages.0);
+        let errors: Vec<ValidationError> = errors.into_iter()
+            .map(|error| ValidationError { status: error.status().code, message: error.message(language) })
+            .collect();
+        Json(ValidationReport { valid: errors.is_empty(), errors })
+    }
+}
+
+// Parsed here rather than by the Payload guard, so a malformed payload is reported too
+fn parse<T: DeserializeOwned>(body: &str, languages: &AcceptLanguage) -> Result<T, Json<ValidationReport>> {
+    payload::parse(body).map_err(|problem| {
+        ValidationReport::new(vec![ApiError::Other(Status::UnprocessableEntity, format!("Invalid payload: {}", problem.detail))], languages)
+    })
+}
+
+#[post("/capsules/validate", format = "json", data = "<body>")]
+pub fn validate_capsule(body: String, languages: AcceptLanguage, clock: &State<SharedClock>) -> Json<ValidationReport> {
+    let new_capsule: NewCapsule = match parse(&body, &languages) {
+        Ok(new_capsule) => new_capsule,
+        Err(report) => return report,
+    };
+    ValidationReport::new(capsules::check_new_capsule(&new_capsule, clock.now()).err().unwrap_or_default(), &languages)
+}
+
+#[post("/capsules/<cid>/items/validate", format = "json", data = "<body>")]
+pub fn validate_item(cid: CapsuleId, body: String, languages: AcceptLanguage, clock: &State<SharedClock>) -> Json<ValidationReport> {
+    let item_data: NewItem = match parse(&body, &languages) {
+        Ok(item_data) => item_data,
+        Err(report) => return report,
+    };
+    let mut errors = Vec::new();
+    if !flags::current().uploads_enabled {
+        errors.push(flags::disabled("Uploading items").into());
+    }
+    errors.extend(items::check_new_item(cid, &item_data, clock.now()));
+    ValidationReport::new(errors, &languages)
+}
@@ -0,0 +1,617 @@
+// Personal webhooks of contributors. A contributor registers URLs that receive the domain
+// events of their own capsules, owned or co-owned, optionally only some event types or
+// capsules. Every delivery is signed with the webhook's secret, retried a few times and
+// logged with the webhook. A rotated secret keeps signing next to the new one for
+// `webhooks.rotation_grace_hours`, so receivers can switch over without missing events.
+// A receiver that was down can ask for the events it missed again, as far as the bus
+// still keeps them.
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::tokio::sync::broadcast::{error::RecvError, Receiver};
+use rocket::tokio::time;
+use rocket::futures::future::join_all;
+use rocket::State;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::{Method, RequestBuilder, Url};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+
+use crate::bus::{self, DomainEvent, Published};
+use crate::capsules::{Capsule, CAPSULES};
+use crate::clock::SharedClock;
+use crate::config;
+use crate::contributors::CONTRIBUTORS;
+use crate::destinations;
+use crate::error_messages::ApiError;
+use crate::ids::{CapsuleId, ContributorId};
+use crate::ownership;
+use crate::reports;
+use crate::state;
+use crate::store;
+use crate::time_format;
+use crate::tokens::Caller;
+
+const SECRET_PREFIX: &str = "whsec_";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Webhook {
+    pub id: u32,
+    pub contributor_id: ContributorId,
+    pub url: String,
+    pub events: Vec<String>,          // Event types to send, all if empty
+    pub capsule_ids: Vec<CapsuleId>,  // Capsules to send events of, all of the contributor's if empty
+    pub active: bool,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_created: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_secret_rotated: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub previous_secret_expires_at: Option<DateTime<Utc>>,  // The secret before the last rotation signs until then
+    #[serde(default, skip_serializing_if = "store::answering")]
+    secret: String,
+    #[serde(default, skip_serializing_if = "store::answering")]
+    previous_secret: Option<String>,
+}
+
+// The secret itself is only shown when it's created or rotated
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookWithSecret {
+    pub secret: String,
+    #[serde(flatten)]
+    pub webhook: Webhook,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewWebhook {
+    url: String,
+    #[serde(default)]
+    events: Vec<String>,
+    #[serde(default)]
+    capsule_ids: Vec<CapsuleId>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookPatch {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    capsule_ids: Option<Vec<CapsuleId>>,
+    active: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Delivery {
+    pub id: u64,
+    pub seq: u64,  // Of the event on the bus
+    pub event: &'static str,
+    pub capsule_id: CapsuleId,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub response_status: Option<u16>,  // Of the last attempt
+    pub error: Option<String>,
+    #[serde(serialize_with = "time_format::time")]
+    pub time_created: DateTime<Utc>,
+    #[serde(serialize_with = "time_format::optional_time")]
+    pub time_finished: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct WebhookStore {
+    webhooks: BTreeMap<u32, Webhook>,
+    deliveries: HashMap<u32, VecDeque<Delivery>>,  // Newest last, by webhook
+    next_id: u32,
+    next_delivery_id: u64,
+}
+
+static WEBHOOKS: Lazy<RwLock<WebhookStore>> = Lazy::new(|| RwLock::new(WebhookStore { next_id: 1, next_delivery_id: 1, ..Default::default() }));
+
+// Owner and co-owners of every capsule seen, so events of deleted capsules still find them
+static EDITORS: Lazy<Mutex<HashMap<CapsuleId, Vec<ContributorId>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn new_secret() -> String {
+    let secret: String = rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+    format!("{}{}", SECRET_PREFIX, secret)
+}
+
+// `sha256=<hex>` of the body for every secret that currently signs, newest first
+fn signature(webhook: &Webhook, body: &[u8], now: DateTime<Utc>) -> String {
+    let previous = webhook.previous_secret.as_ref().filter(|_| webhook.previous_secret_expires_at.is_some_and(|until| until > now));
+    std::iter::once(&webhook.secret).chain(previous)
+        .map(|secret| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+            mac.update(body);
+            let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("sha256={}", digest)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn bad_request(message: String) -> status::Custom<Json<String>> {
+    status::Custom(Status::BadRequest, Json(message))
+}
+
+// Webhooks only go to public addresses, see destinations.rs. Deliveries are checked again.
+async fn check_url(url: &str) -> Result<String, status::Custom<Json<String>>> {
+    let url = url.trim();
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {
+            destinations::check(&parsed).await.map_err(bad_request)?;
+            Ok(url.to_string())
+        },
+        _ => Err(bad_request("url must be an http or https URL".into())),
+    }
+}
+
+// Without repeats, in the order given
+fn unique<T: Clone + PartialEq>(values: &[T]) -> Vec<T> {
+    let mut unique: Vec<T> = Vec::new();
+    for value in values {
+        if !unique.contains(value) {
+            unique.push(value.clone());
+        }
+    }
+    unique
+}
+
+fn check_events(events: &[String]) -> Result<Vec<String>, status::Custom<Json<String>>> {
+    if let Some(unknown) = events.iter().find(|event| !DomainEvent::NAMES.contains(&event.as_str())) {
+        return Err(bad_request(format!("Unknown event '{}', use one of {}", unknown, DomainEvent::NAMES.join(", "))));
+    }
+    Ok(unique(events))
+}
+
+// Only capsules the contributor owns or co-owns can be picked
+fn check_capsules(contributor_id: ContributorId, capsule_ids: &[CapsuleId]) -> Result<Vec<CapsuleId>, status::Custom<Json<String>>> {
+    for &capsule_id in capsule_ids {
+        if CAPSULES.read(capsule_id, |capsule| ownership::is_editor(capsule, contributor_id)) != Some(true) {
+            return Err(bad_request(format!("Capsule {} isn't a capsule of contributor {}", capsule_id, contributor_id)));
+        }
+    }
+    Ok(unique(capsule_ids))
+}
+
+// The contributor has to exist, and only its own API key may manage its webhooks
+fn check_contributor(id: ContributorId, caller: &Caller) -> Result<(), ApiError> {
+    if caller.required()? != id {
+        return Err(ApiError::NotWebhookOwner);
+    }
+    if !CONTRIBUTORS.contains(id) {
+        return Err(ApiError::ContributorNotFound);
+    }
+    Ok(())
+}
+
+fn webhook_not_found(id: ContributorId, wid: u32) -> ApiError {
+    ApiError::NoWebhook(id, wid)
+}
+
+// Runs `f` on one of the contributor's webhooks
+fn with_webhook<T>(id: ContributorId, wid: u32, f: impl FnOnce(&mut Webhook) -> T) -> Result<T, ApiError> {
+    let mut store = WEBHOOKS.write().unwrap();
+    store.webhooks.get_mut(&wid)
+        .filter(|webhook| webhook.contributor_id == id)
+        .map(f)
+        .ok_or_else(|| webhook_not_found(id, wid))
+}
+
+#[post("/contributors/<id>/webhooks", format = "json", data = "<new_webhook>")]
+pub async fn create_webhook(id: ContributorId, new_webhook: Json<NewWebhook>, caller: Caller, clock: &State<SharedClock>) -> Result<status::Created<Json<WebhookWithSecret>>, ApiError> {
+    check_contributor(id, &caller)?;
+    let new_webhook = new_webhook.into_inner();
+    let url = check_url(&new_webhook.url).await?;
+    let events = check_events(&new_webhook.events)?;
+    let capsule_ids = check_capsules(id, &new_webhook.capsule_ids)?;
+
+    let mut store = WEBHOOKS.write().unwrap();
+    let max = config::get().webhooks.max_per_contributor;
+    if store.webhooks.values().filter(|webhook| webhook.contributor_id == id).count() >= max {
+        return Err(ApiError::Other(Status::Conflict, format!("A contributor can have at most {} webhooks", max)));
+    }
+    let webhook = Webhook {
+        id: state::next_id(&mut store.next_id),
+        contributor_id: id,
+        url,
+        events,
+        capsule_ids,
+        active: true,
+        time_created: clock.now(),
+        time_secret_rotated: None,
+        secret: new_secret(),
+        previous_secret_expires_at: None,
+        previous_secret: None,
+    };
+    store.webhooks.insert(webhook.id, webhook.clone());
+    state::changed("webhooks", webhook.id);
+    let location = format!("/contributors/{}/webhooks/{}", id, webhook.id);
+    Ok(status::Created::new(location).body(Json(WebhookWithSecret { secret: webhook.secret.clone(), webhook })))
+}
+
+#[get("/contributors/<id>/webhooks")]
+pub fn list_webhooks(id: ContributorId, caller: Caller) -> Result<Json<Vec<Webhook>>, ApiError> {
+    check_contributor(id, &caller)?;
+    let store = WEBHOOKS.read().unwrap();
+    Ok(Json(store.webhooks.values().filter(|webhook| webhook.contributor_id == id).cloned().collect()))
+}
+
+#[get("/contributors/<id>/webhooks/<wid>")]
+pub fn get_webhook(id: ContributorId, wid: u32, caller: Caller) -> Result<Json<Webhook>, ApiError> {
+    check_contributor(id, &caller)?;
+    with_webhook(id, wid, |webhook| Json(webhook.clone()))
+}
+
+#[patch("/contributors/<id>/webhooks/<wid>", format = "json", data = "<patch>")]
+pub async fn patch_webhook(id: ContributorId, wid: u32, patch: Json<WebhookPatch>, caller: Caller) -> Result<Json<Webhook>, ApiError> {
+    check_contributor(id, &caller)?;
+    let patch = patch.into_inner();
+    let url = match &patch.url {
+        Some(url) => Some(check_url(url).await?),
+        None => None,
+    };
+    let events = patch.events.as_deref().map(check_events).transpose()?;
+    let capsule_ids = patch.capsule_ids.as_deref().map(|capsule_ids| check_capsules(id, capsule_ids)).transpose()?;
+
+    with_webhook(id, wid, |webhook| {
+        if let Some(url) = url {
+            webhook.url = url;
+        }
+        if let Some(events) = events {
+            webhook.events = events;
+        }
+        if let Some(capsule_ids) = capsule_ids {
+            webhook.capsule_ids = capsule_ids;
+        }
+        if let Some(active) = patch.active {
+            webhook.active = active;
+        }
+        state::changed("webhooks", wid);
+        Json(webhook.clone())
+    })
+}
+
+#[delete("/contributors/<id>/webhooks/<wid>")]
+pub fn delete_webhook(id: ContributorId, wid: u32, caller: Caller) -> Result<Status, ApiError> {
+    check_contributor(id, &caller)?;
+    let mut store = WEBHOOKS.write().unwrap();
+    if store.webhooks.get(&wid).is_none_or(|webhook| webhook.contributor_id != id) {
+        return Err(webhook_not_found(id, wid));
+    }
+    store.webhooks.remove(&wid);
+    store.deliveries.remove(&wid);
+    state::changed("webhooks", wid);
+    Ok(Status::NoContent)
+}
+
+// A new secret, the old one keeps signing for the grace period. `grace_hours` shortens it,
+// 0 stops a leaked secret at once; it can't be longer than the configured one.
+#[post("/contributors/<id>/webhooks/<wid>/rotate-secret?<grace_hours>")]
+pub fn rotate_webhook_secret(id: ContributorId, wid: u32, grace_hours: Option<u64>, caller: Caller, clock: &State<SharedClock>) -> Result<Json<WebhookWithSecret>, ApiError> {
+    check_contributor(id, &caller)?;
+    let max_hours = config::get().webhooks.rotation_grace_hours;
+    if grace_hours.is_some_and(|hours| hours > max_hours) {
+        return Err(bad_request(format!("grace_hours can be at most {}", max_hours)).into());
+    }
+    let now = clock.now();
+    let grace = Duration::hours(grace_hours.unwrap_or(max_hours) as i64);
+    with_webhook(id, wid, |webhook| {
+        let old = std::mem::replace(&mut webhook.secret, new_secret());
+        let signs = grace > Duration::zero();
+        webhook.previous_secret = signs.then_some(old);
+        webhook.previous_secret_expires_at = signs.then_some(now + grace);
+        webhook.time_secret_rotated = Some(now);
+        state::changed("webhooks", wid);
+        Json(WebhookWithSecret { secret: webhook.secret.clone(), webhook: webhook.clone() })
+    })
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookReplay {
+    pub webhook_id: u32,
+    pub events: usize,       // Sent again, oldest first
+    pub seqs: Vec<u64>,
+    pub complete: bool,      // False when some events since then aren't kept anymore
+}
+
+// Sends the kept events since `since` again, a `seq` or a time, for a receiver that was
+// down. They go out one after the other in their order and show up in the deliveries.
+#[post("/contributors/<id>/webhooks/<wid>/replay?<since>")]
+pub fn replay_webhook(id: ContributorId, wid: u32, since: Option<&str>, caller: Caller) -> Result<status::Accepted<Json<WebhookReplay>>, ApiError> {
+    check_contributor(id, &caller)?;
+    let since = since.ok_or_else(|| bad_request("since must be an event seq or a time".into()))?;
+    let (events, complete) = match since.parse::<u64>() {
+        Ok(seq) => {
+            let (events, missed) = bus::replay_after(seq);
+            (events, missed == 0)
+        },
+        Err(_) => {
+            let time = reports::parse_bound(Some(since), "since")?.unwrap_or_default();
+            let (events, incomplete) = bus::replay_from(time);
+            (events, !incomplete)
+        },
+    };
+    let webhook = with_webhook(id, wid, |webhook| webhook.clone())?;
+    if !webhook.active {
+        return Err(ApiError::WebhookPaused(wid));
+    }
+
+    let events: Vec<Published> = events.into_iter()
+        .filter(|published| {
+            let capsule_id = published.event.capsule_id();
+            receives(&webhook, &editors_of(capsule_id), capsule_id, published.event.name())
+        })
+        .collect();
+    let seqs = events.iter().map(|published| published.seq).collect();
+    let replay = WebhookReplay { webhook_id: wid, events: events.len(), seqs, complete };
+    rocket::tokio::spawn(async move {
+        for published in events {
+            deliver(wid, published).await;
+        }
+    });
+    Ok(status::Accepted(Json(replay)))
+}
+
+// Deliveries of a webhook, newest first
+#[get("/contributors/<id>/webhooks/<wid>/deliveries")]
+pub fn list_webhook_deliveries(id: ContributorId, wid: u32, caller: Caller) -> Result<Json<Vec<Delivery>>, ApiError> {
+    check_contributor(id, &caller)?;
+    with_webhook(id, wid, |_| ())?;
+    let store = WEBHOOKS.read().unwrap();
+    Ok(Json(store.deliveries.get(&wid).map(|log| log.iter().rev().cloned().collect()).unwrap_or_default()))
+}
+
+// The webhooks with their secrets as state entries by id, see state.rs. Their delivery
+// logs are kept by each instance.
+pub fn entries() -> Vec<(String, serde_json::Value)> {
+    WEBHOOKS.read().unwrap().webhooks.values().map(|webhook| (webhook.id.to_string(), state::to_entry(webhook))).collect()
+}
+
+pub fn entry(key: &str) -> Option<serde_json::Value> {
+    let id = key.parse().ok()?;
+    WEBHOOKS.read().unwrap().webhooks.get(&id).map(state::to_entry)
+}
+
+pub fn put(key: &str, entry: Option<serde_json::Value>) -> Result<(), serde_json::Error> {
+    let Ok(id) = key.parse::<u32>() else { return Ok(()) };
+    let webhook: Option<Webhook> = entry.map(state::from_entry).transpose()?;
+    let mut store = WEBHOOKS.write().unwrap();
+    match webhook {
+        Some(webhook) => {
+            store.next_id = store.next_id.max(id + 1);
+            store.webhooks.insert(id, webhook);
+        },
+        None => {
+            store.webhooks.remove(&id);
+            store.deliveries.remove(&id);
+        },
+    }
+    Ok(())
+}
+
+fn update_delivery(webhook_id: u32, delivery_id: u64, f: impl FnOnce(&mut Delivery)) {
+    let mut store = WEBHOOKS.write().unwrap();
+    if let Some(delivery) = store.deliveries.get_mut(&webhook_id).and_then(|log| log.iter_mut().find(|delivery| delivery.id == delivery_id)) {
+        f(delivery);
+    }
+}
+
+// Logs a new delivery, dropping the oldest ones past `webhooks.log_size`
+fn start_delivery(webhook_id: u32, published: &Published) -> u64 {
+    let mut store = WEBHOOKS.write().unwrap();
+    let id = store.next_delivery_id;
+    store.next_delivery_id += 1;
+    let log = store.deliveries.entry(webhook_id).or_default();
+    log.push_back(Delivery {
+        id,
+        seq: published.seq,
+        event: published.event.name(),
+        capsule_id: published.event.capsule_id(),
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        response_status: None,
+        error: None,
+        time_created: Utc::now(),
+        time_finished: None,
+    });
+    while log.len() > config::get().webhooks.log_size.max(1) {
+        log.pop_front();
+    }
+    id
+}
+
+// Posts the event until the receiver answers with a 2xx or the attempts run out, returns
+// the attempts made and the error of the last one
+async fn deliver(webhook_id: u32, published: Published) -> (u32, Option<String>) {
+    let settings = &config::get().webhooks;
+    let delivery_id = start_delivery(webhook_id, &published);
+    let body = serde_json::to_vec(&published).unwrap_or_default();
+    let max_attempts = settings.max_attempts.max(1);
+    let timeout = std::time::Duration::from_secs(settings.timeout_secs);
+
+    for attempt in 1..=max_attempts {
+        // Read again on every attempt, the webhook may have been changed or deleted
+        let Some(webhook) = WEBHOOKS.read().unwrap().webhooks.get(&webhook_id).cloned() else {
+            return (attempt - 1, Some(format!("Webhook {} was deleted", webhook_id)));
+        };
+        let request = |request: RequestBuilder| request
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Event", published.event.name())
+            .header("X-Webhook-Delivery", delivery_id.to_string())
+            .header("X-Webhook-Signature", signature(&webhook, &body, Utc::now()))
+            .body(body.clone());
+        let response = match Url::parse(&webhook.url) {
+            Ok(url) => destinations::send(Method::POST, &url, Some(timeout), request).await,
+            Err(e) => Err(e.to_string()),
+        };
+        let (response_status, error) = match response {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+            Ok(response) => (Some(response.status().as_u16()), Some(format!("Answered {}", response.status()))),
+            Err(e) => (None, Some(e)),
+        };
+        let done = error.is_none() || attempt == max_attempts;
+        update_delivery(webhook_id, delivery_id, |delivery| {
+            delivery.attempts = attempt;
+            delivery.response_status = response_status;
+            if done {
+                delivery.status = if error.is_none() { DeliveryStatus::Delivered } else { DeliveryStatus::Failed };
+                delivery.time_finished = Some(Utc::now());
+            }
+            delivery.error = error.clone();
+        });
+        if done {
+            return (attempt, error);
+        }
+        let backoff = settings.retry_base_secs.saturating_mul(1 << (attempt - 1).min(20));
+        time::sleep(std::time::Duration::from_secs(backoff)).await;
+    }
+    (0, None)
+}
+
+// Sends `capsule.opened` to the contributor's webhooks that take it, for open_notices.rs.
+// The attempts are those of the webhook that needed the most, the error that of one that
+// didn't take it.
+pub async fn deliver_opened(contributor_id: ContributorId, published: Published) -> (u32, Option<String>) {
+    let capsule_id = published.event.capsule_id();
+    let editors = editors_of(capsule_id);
+    let matching: Vec<u32> = WEBHOOKS.read().unwrap().webhooks.values()
+        .filter(|webhook| webhook.contributor_id == contributor_id && receives(webhook, &editors, capsule_id, published.event.name()))
+        .map(|webhook| webhook.id)
+        .collect();
+    if matching.is_empty() {
+        return (0, Some(format!("Contributor {} has no webhook for capsule.opened anymore", contributor_id)));
+    }
+    let mut outcome = (0, None);
+    for result in join_all(matching.into_iter().map(|webhook_id| deliver(webhook_id, published.clone()))).await {
+        outcome.0 = outcome.0.max(result.0);
+        outcome.1 = outcome.1.or(result.1);
+    }
+    outcome
+}
+
+// Whether the contributor has a webhook that takes `capsule.opened` of the capsule
+pub fn takes_opened(capsule: &Capsule, contributor_id: ContributorId) -> bool {
+    receiving(capsule, "capsule.opened").iter().any(|webhook| webhook.contributor_id == contributor_id)
+}
+
+// Owner and co-owners of the event's capsule, remembered for when it's deleted
+fn editors_of(capsule_id: CapsuleId) -> Vec<ContributorId> {
+    let mut editors = EDITORS.lock().unwrap();
+    match CAPSULES.read(capsule_id, |capsule| std::iter::once(capsule.contributor_id).chain(capsule.co_owner_ids.iter().copied()).collect::<Vec<_>>()) {
+        Some(current) => {
+            editors.insert(capsule_id, current.clone());
+            current
+        },
+        None => editors.get(&capsule_id).cloned().unwrap_or_default(),
+    }
+}
+
+fn receives(webhook: &Webhook, editors: &[ContributorId], capsule_id: CapsuleId, event: &str) -> bool {
+    webhook.active && editors.contains(&webhook.contributor_id)
+        && (webhook.events.is_empty() || webhook.events.iter().any(|name| name == event))
+        && (webhook.capsule_ids.is_empty() || webhook.capsule_ids.contains(&capsule_id))
+}
+
+// The webhooks an event of the capsule would be sent to, see simulation.rs
+pub fn receiving(capsule: &Capsule, event: &str) -> Vec<Webhook> {
+    let editors: Vec<ContributorId> = std::iter::once(capsule.contributor_id).chain(capsule.co_owner_ids.iter().copied()).collect();
+    WEBHOOKS.read().unwrap().webhooks.values()
+        .filter(|webhook| receives(webhook, &editors, capsule.id, event))
+        .cloned()
+        .collect()
+}
+
+// Sends every published event to the webhooks of the contributors it concerns
+pub async fn follow(mut events: Receiver<Published>) {
+    {
+        let mut editors = EDITORS.lock().unwrap();
+        CAPSULES.for_each(|capsule| {
+            editors.insert(capsule.id, std::iter::once(capsule.contributor_id).chain(capsule.co_owner_ids.iter().copied()).collect());
+        });
+    }
+    loop {
+        let published = match events.recv().await {
+            Ok(published) => published,
+            Err(RecvError::Closed) => return,
+            Err(RecvError::Lagged(missed)) => {
+                eprintln!("Webhooks fell behind the event bus, {} events are missing", missed);
+                continue;
+            },
+        };
+        // Sent by open_notices.rs, to those who want it this way
+        if matches!(published.event, DomainEvent::CapsuleOpened { .. }) {
+            continue;
+        }
+        let capsule_id = published.event.capsule_id();
+        let editors = editors_of(capsule_id);
+        let matching: Vec<u32> = WEBHOOKS.read().unwrap().webhooks.values()
+            .filter(|webhook| receives(webhook, &editors, capsule_id, published.event.name()))
+            .map(|webhook| webhook.id)
+            .collect();
+        for webhook_id in matching {
+            rocket::tokio::spawn(deliver(webhook_id, published.clone()));
+        }
+        if matches!(published.event, DomainEvent::CapsuleDeleted { .. }) {
+            EDITORS.lock().unwrap().remove(&capsule_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(secret: &str, previous: Option<(&str, DateTime<Utc>)>) -> Webhook {
+        Webhook {
+            id: 1,
+            contributor_id: ContributorId::default(),
+            url: "https://example.com/hooks".into(),
+            events: vec![],
+            capsule_ids: vec![],
+            active: true,
+            time_created: Utc::now(),
+            time_secret_rotated: None,
+            previous_secret_expires_at: previous.map(|(_, until)| until),
+            secret: secret.into(),
+            previous_secret: previous.map(|(secret, _)| secret.to_string()),
+        }
+    }
+
+    // RFC 4231, test case 2
+    const JEFE: &str = "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+    const BODY: &[u8] = b"what do ya want for nothing?";
+
+    #[test]
+    fn deliveries_are_signed_with_hmac_sha256() {
+        assert_eq!(signature(&webhook("Jefe", None), BODY, Utc::now()), JEFE);
+    }
+
+    #[test]
+    fn rotated_secrets_sign_until_their_grace_ends() {
+        let now = Utc::now();
+        let rotated = webhook("new", Some(("Jefe", now + Duration::hours(1))));
+        let signatures = signature(&rotated, BODY, now);
+        let (newest, previous) = signatures.split_once(',').expect("Both secrets sign");
+        assert_eq!(newest, signature(&webhook("new", None), BODY, now));
+        assert_eq!(previous, JEFE);
+
+        assert_eq!(signature(&rotated, BODY, now + Duration::hours(2)), newest);
+    }
+}
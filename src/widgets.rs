@@ -0,0 +1,128 @@
+use rocket::http::ContentType;
+use rocket::response::{self, Responder, Response};
+use rocket::{Request, State};
+use chrono::{DateTime, Utc};
+use std::io::Cursor;
+
+use crate::capsules::{Capsule, CAPSULES};
+use crate::i18n::AcceptLanguage;
+use crate::clock::SharedClock;
+use crate::ids::CapsuleId;
+
+// How long embedding pages and CDNs may reuse a rendered widget
+const WIDGET_MAX_AGE: u32 = 60;
+
+// Rendered widget with caching headers, meant to be embedded on other sites
+pub struct Widget {
+    content_type: ContentType,
+    body: String,
+}
+
+impl<'r> Responder<'r, 'static> for Widget {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header("Cache-Control", format!("public, max-age={}", WIDGET_MAX_AGE))
+            // Allow framing from any site, this takes precedence over the default X-Frame-Options
+            .raw_header("Content-Security-Policy", "frame-ancestors *")
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+// Escapes text for use inside SVG/HTML elements and attributes
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// "Opens in 3d 4h 12m" or "Opened" style summary of the time left
+fn countdown_text(time_open: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    if now >= time_open {
+        return "Opened".to_string();
+    }
+    let left = time_open - now;
+    let (days, hours, minutes) = (left.num_days(), left.num_hours() % 24, left.num_minutes() % 60);
+    if days > 0 {
+        format!("Opens in {}d {}h {}m", days, hours, minutes)
+    } else {
+        format!("Opens in {}h {}m", hours, minutes.max(1))
+    }
+}
+
+fn render_svg(capsule: &Capsule, now: DateTime<Utc>) -> String {
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="80" viewBox="0 0 320 80" role="img" aria-label="{title}: {countdown}">
+<rect width="320" height="80" rx="8" fill="#1f2937"/>
+<text x="16" y="32" font-family="sans-serif" font-size="16" fill="#f9fafb">{title}</text>
+<text x="16" y="58" font-family="sans-serif" font-size="14" fill="#fbbf24">{countdown}</text>
+</svg>"##,
+        title = escape(capsule.name.default_text()),
+        countdown = escape(&countdown_text(capsule.time_open, now)),
+    )
+}
+
+#[get("/capsules/<cid>/widget.svg")]
+pub fn capsule_widget_svg(cid: CapsuleId, languages: AcceptLanguage, clock: &State<SharedClock>) -> Option<Widget> {
+    let capsule = CAPSULES.get(cid)?.localized(&languages.0);
+    Some(Widget { content_type: ContentType::SVG, body: render_svg(&capsule, clock.now()) })
+}
+
+// Same widget as a small page for iframes, the countdown keeps ticking client-side
+#[get("/capsules/<cid>/widget.html")]
+pub fn capsule_widget_html(cid: CapsuleId, languages: AcceptLanguage, clock: &State<SharedClock>) -> Option<Widget> {
+    let capsule = CAPSULES.get(cid)?.localized(&languages.0);
+    let body = format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>body{{margin:0;font-family:sans-serif}}.widget{{background:#1f2937;color:#f9fafb;padding:12px 16px;border-radius:8px;width:288px}}.countdown{{color:#fbbf24;margin-top:6px}}</style>
+</head>
+<body>
+<div class="widget"><div>{title}</div><div class="countdown" id="countdown" data-open="{open}">{countdown}</div></div>
+<script>
+(function () {{
+  var el = document.getElementById("countdown");
+  var open = Date.parse(el.dataset.open);
+  function tick() {{
+    var left = Math.floor((open - Date.now()) / 60000);
+    if (left <= 0) {{ el.textContent = "Opened"; return; }}
+    var d = Math.floor(left / 1440), h = Math.floor(left / 60) % 24, m = left % 60;
+    el.textContent = "Opens in " + (d > 0 ? d + "d " + h + "h " + m + "m" : h + "h " + Math.max(m, 1) + "m");
+    setTimeout(tick, 1000);
+  }}
+  tick();
+}})();
+</script>
+</body>
+</html>"##,
+        title = escape(capsule.name.default_text()),
+        open = capsule.time_open.to_rfc3339(),
+        countdown = escape(&countdown_text(capsule.time_open, clock.now())),
+    );
+    Some(Widget { content_type: ContentType::HTML, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(escape("<a href=\"x\">Tom & Jerry's</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+    }
+
+    #[test]
+    fn countdowns_show_the_time_left() {
+        let now = Utc::now();
+        assert_eq!(countdown_text(now + Duration::days(3) + Duration::hours(4) + Duration::minutes(12), now), "Opens in 3d 4h 12m");
+        assert_eq!(countdown_text(now + Duration::seconds(20), now), "Opens in 0h 1m");
+        assert_eq!(countdown_text(now, now), "Opened");
+    }
+}